@@ -0,0 +1,196 @@
+//! Acknowledgements sent by the receiver, and the retransmission schedule of the sender.
+//!
+//! A sender which sets [`crate::Flags::ACK_REQUESTED`] in a frame listens for an acknowledgement
+//! after transmitting the message, and transmits it again if none arrives. Every attempt is
+//! stamped with the current uptime and encrypted with a new frame counter, so the receiver gets a
+//! frame it has already accepted again if only the acknowledgement was lost. It recognizes it by
+//! its first point and acknowledges it again without storing the points twice. The acknowledgement
+//! is a single packet:
+//!
+//! | byte | content                                                  |
+//! |------|----------------------------------------------------------|
+//! | 0    | [`ACK_MARKER`] combined with the sender ID               |
+//! | 1    | key epoch of the acknowledged message                    |
+//! | 2-5  | frame counter of the acknowledged message, big-endian    |
+//! | 6    | recommended spreading factor, or 0 for no recommendation |
+//! | 7    | recommended transmit power in dBm                        |
+//! | 8-23 | AES-GCM tag                                              |
+//!
+//! The tag is calculated with the key of the sender over an empty plaintext, with the header as
+//! associated data, so a sender only accepts acknowledgements made by someone who knows its key.
+//! The recommended settings are explained in [`crate::adr`].
+//! Sender IDs are at most [`crate::MAX_SENDER_ID`], so the marker bit is never set in the first
+//! byte of a fragment, see [`crate::fragment`].
+
+use core::fmt;
+
+use crate::adr::RadioSettings;
+use crate::counter::{self, Direction, NONCE_SIZE};
+
+/// Set in the first byte of every acknowledgement.
+pub const ACK_MARKER: u8 = 0x80;
+
+pub const ACK_HEADER_SIZE: usize = 8;
+
+pub const TAG_SIZE: usize = 16;
+
+pub const ACK_SIZE: usize = ACK_HEADER_SIZE + TAG_SIZE;
+
+/// Delay before the first retransmission. Each following retransmission waits twice as long, up to
+/// [`MAX_RETRY_DELAY_MS`].
+pub const BASE_RETRY_DELAY_MS: u32 = 1_000;
+
+pub const MAX_RETRY_DELAY_MS: u32 = 30_000;
+
+/// Time the sender listens for an acknowledgement after the last packet of a message. An
+/// acknowledgement which would only end later is not sent.
+pub const ACK_TIMEOUT_MS: u32 = 2_000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AckHeader {
+    pub sender_id: u8,
+    pub key_epoch: u8,
+    pub counter: u32,
+    /// Settings the sender should use for its next messages.
+    pub radio: Option<RadioSettings>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AckError {
+    /// The packet is a fragment of a message rather than an acknowledgement.
+    NotAnAck,
+    /// The packet is not [`ACK_SIZE`] bytes long.
+    InvalidLength { len: usize },
+}
+
+impl fmt::Display for AckError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::NotAnAck => write!(f, "packet is not an acknowledgement"),
+            Self::InvalidLength { len } => write!(
+                f,
+                "acknowledgement of {len} bytes, expected {ACK_SIZE} bytes"
+            ),
+        }
+    }
+}
+
+/// Returns true if the packet is an acknowledgement rather than a fragment of a message.
+pub fn is_ack(packet: &[u8]) -> bool {
+    matches!(packet.first(), Some(b) if b & ACK_MARKER != 0)
+}
+
+impl AckHeader {
+    pub fn to_bytes(&self) -> [u8; ACK_HEADER_SIZE] {
+        let counter = self.counter.to_be_bytes();
+        let radio = self.radio.map_or([0; 2], |radio| radio.to_bytes());
+        [
+            ACK_MARKER | self.sender_id,
+            self.key_epoch,
+            counter[0],
+            counter[1],
+            counter[2],
+            counter[3],
+            radio[0],
+            radio[1],
+        ]
+    }
+
+    /// Bytes which are authenticated by the tag.
+    pub fn associated_data(&self) -> [u8; ACK_HEADER_SIZE] {
+        self.to_bytes()
+    }
+
+    /// AES-GCM nonce of the acknowledgement.
+    pub fn nonce(&self) -> [u8; NONCE_SIZE] {
+        counter::nonce(Direction::Downlink, self.sender_id, self.counter)
+    }
+
+    /// Splits an acknowledgement into its header and tag.
+    pub fn parse(packet: &[u8]) -> Result<(AckHeader, &[u8]), AckError> {
+        if !is_ack(packet) {
+            return Err(AckError::NotAnAck);
+        }
+        if packet.len() != ACK_SIZE {
+            return Err(AckError::InvalidLength { len: packet.len() });
+        }
+        let (header, tag) = packet.split_at(ACK_HEADER_SIZE);
+        Ok((
+            AckHeader {
+                sender_id: header[0] & !ACK_MARKER,
+                key_epoch: header[1],
+                counter: u32::from_be_bytes([header[2], header[3], header[4], header[5]]),
+                radio: RadioSettings::from_bytes([header[6], header[7]]),
+            },
+            tag,
+        ))
+    }
+}
+
+/// Time to wait before retransmission number `attempt` (starting at 0). Half of the delay is
+/// taken from `random`, so that senders whose messages collided do not retransmit at the same
+/// time again.
+pub fn retry_delay_ms(attempt: u32, random: u32) -> u32 {
+    let factor = 1u32.checked_shl(attempt).unwrap_or(u32::MAX);
+    let delay = BASE_RETRY_DELAY_MS
+        .saturating_mul(factor)
+        .min(MAX_RETRY_DELAY_MS);
+    delay / 2 + random % (delay / 2 + 1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn roundtrip() {
+        let header = AckHeader {
+            sender_id: 75,
+            key_epoch: 2,
+            counter: 0x01020304,
+            radio: Some(RadioSettings {
+                spreading_factor: 9,
+                tx_power_dbm: 11,
+            }),
+        };
+        let mut packet = header.to_bytes().to_vec();
+        assert_eq!(packet, [0x80 | 75, 2, 1, 2, 3, 4, 9, 11]);
+        packet.extend_from_slice(&[9; TAG_SIZE]);
+        assert!(is_ack(&packet));
+        assert_eq!(AckHeader::parse(&packet), Ok((header, &[9; TAG_SIZE][..])));
+        assert_eq!(
+            AckHeader::parse(&packet[..10]),
+            Err(AckError::InvalidLength { len: 10 })
+        );
+
+        let header = AckHeader {
+            radio: None,
+            ..header
+        };
+        let mut packet = header.to_bytes().to_vec();
+        assert_eq!(packet[6..], [0, 0]);
+        packet.extend_from_slice(&[9; TAG_SIZE]);
+        assert_eq!(AckHeader::parse(&packet).unwrap().0.radio, None);
+    }
+
+    #[test]
+    fn fragments_are_not_acks() {
+        let packets = crate::fragment::fragment(crate::MAX_SENDER_ID, 0, &[1; 300]).unwrap();
+        assert!(packets.iter().all(|p| !is_ack(p)));
+        assert_eq!(AckHeader::parse(&packets[0]), Err(AckError::NotAnAck));
+        assert!(!is_ack(&[]));
+    }
+
+    #[test]
+    fn retry_delay_grows_and_is_capped() {
+        for attempt in 0..40 {
+            let min = retry_delay_ms(attempt, 0);
+            let max = retry_delay_ms(attempt, u32::MAX - 1);
+            assert!(min <= max && max <= MAX_RETRY_DELAY_MS);
+        }
+        assert_eq!(retry_delay_ms(0, 0), 500);
+        assert_eq!(retry_delay_ms(1, 0), 1_000);
+        assert_eq!(retry_delay_ms(2, 4_000), 2_000 + 4_000 % 2_001);
+        assert_eq!(retry_delay_ms(39, 0), MAX_RETRY_DELAY_MS / 2);
+    }
+}
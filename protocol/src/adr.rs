@@ -0,0 +1,498 @@
+//! Adaptive data rate of the raw link between our senders and receivers.
+//!
+//! The receiver keeps the SNR of the last messages of every sender, together with the transmit
+//! power from the authenticated envelope header (see [`crate::envelope`]), and recommends a spreading factor and
+//! transmit power in its acknowledgements (see [`crate::ack`]). A sender far away from the
+//! receiver thus gets a higher spreading factor, and a sender close to it saves power.
+//!
+//! An SX1276 only receives a single spreading factor at a time, so every sender of a receiver uses
+//! the same one, which is the one needed by the sender with the worst link. The transmit power is
+//! chosen for each sender. A new spreading factor is first announced to every sender heard in the
+//! last [`ACTIVE_MS`], which switch to it as soon as they get the acknowledgement. The receiver
+//! switches once all of them have been told, until then the messages of the senders which already
+//! switched stay in their queues. Only senders which ask for acknowledgements can be told, so the
+//! spreading factor is only changed while no sender without acknowledgements is active. A sender
+//! which missed a change finds the receiver again by trying the other spreading factors, except
+//! for [`ACTIVE_MS`] after it switched itself, since the receiver may still be waiting for the
+//! other senders. See [`SenderAdr`].
+//!
+//! Both only use spreading factors up to a maximum, which is lower than
+//! [`MAX_SPREADING_FACTOR`] in frequency plans with a dwell time, see
+//! [`crate::plan::FrequencyPlan::max_spreading_factor`].
+
+use alloc::collections::{BTreeMap, VecDeque};
+
+pub const MIN_SPREADING_FACTOR: u8 = 7;
+pub const MAX_SPREADING_FACTOR: u8 = 12;
+
+/// Range of the PA_BOOST pin of the SX1276.
+pub const MIN_TX_POWER_DBM: u8 = 2;
+pub const MAX_TX_POWER_DBM: u8 = 17;
+
+const TX_POWER_STEP_DB: i16 = 3;
+
+/// Margin kept above the SNR needed to receive a packet, for fading and changing weather.
+pub const INSTALLATION_MARGIN_DB: i16 = 10;
+
+/// Number of messages of a sender whose SNR is kept.
+pub const HISTORY_LEN: usize = 20;
+
+/// Number of messages needed before the settings of a sender are changed.
+pub const MIN_HISTORY_LEN: usize = 5;
+
+/// Senders which have not been heard for this long are not waited for when the spreading factor
+/// changes.
+pub const ACTIVE_MS: u64 = 3_600_000;
+
+/// Number of failed messages in a row at full power after which a sender tries the next spreading
+/// factor.
+pub const FAILURES_BEFORE_SCAN: u32 = 3;
+
+/// Settings of the raw link, as recommended by the receiver.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RadioSettings {
+    pub spreading_factor: u8,
+    pub tx_power_dbm: u8,
+}
+
+impl RadioSettings {
+    /// Settings before the receiver has recommended any.
+    pub const DEFAULT: Self = Self {
+        spreading_factor: MIN_SPREADING_FACTOR,
+        tx_power_dbm: MAX_TX_POWER_DBM,
+    };
+
+    pub fn is_valid(&self) -> bool {
+        (MIN_SPREADING_FACTOR..=MAX_SPREADING_FACTOR).contains(&self.spreading_factor)
+            && (MIN_TX_POWER_DBM..=MAX_TX_POWER_DBM).contains(&self.tx_power_dbm)
+    }
+
+    pub fn to_bytes(&self) -> [u8; 2] {
+        [self.spreading_factor, self.tx_power_dbm]
+    }
+
+    /// Returns `None` if the settings are out of range.
+    pub fn from_bytes(bytes: [u8; 2]) -> Option<Self> {
+        let settings = Self {
+            spreading_factor: bytes[0],
+            tx_power_dbm: bytes[1],
+        };
+        settings.is_valid().then_some(settings)
+    }
+}
+
+/// Lowest SNR in dB at which the SX1276 receives a spreading factor, rounded up. It is -7.5 dB at
+/// SF7 and 2.5 dB lower for every step.
+pub fn required_snr_db(spreading_factor: u8) -> i16 {
+    -(5 * (spreading_factor as i16 - 4) / 2)
+}
+
+/// Lowest spreading factor up to `max_spreading_factor` which leaves the installation margin at
+/// full power, or the highest one if none does.
+fn needed_spreading_factor(snr_at_max_power: i16, max_spreading_factor: u8) -> u8 {
+    (MIN_SPREADING_FACTOR..=max_spreading_factor)
+        .find(|&sf| snr_at_max_power - required_snr_db(sf) >= INSTALLATION_MARGIN_DB)
+        .unwrap_or(max_spreading_factor)
+}
+
+/// Transmit power which leaves the installation margin at the spreading factor, in steps of 3 dB.
+fn needed_tx_power_dbm(snr_at_max_power: i16, spreading_factor: u8) -> u8 {
+    let excess = snr_at_max_power - required_snr_db(spreading_factor) - INSTALLATION_MARGIN_DB;
+    let steps = excess.max(0) / TX_POWER_STEP_DB;
+    (MAX_TX_POWER_DBM as i16 - steps * TX_POWER_STEP_DB).max(MIN_TX_POWER_DBM as i16) as u8
+}
+
+struct SenderLink {
+    /// SNR of the last messages, as if they had been sent at full power.
+    snr_at_max_power: VecDeque<i16>,
+    last_heard_ms: u64,
+    ack_requested: bool,
+    /// The sender has been told about the pending spreading factor.
+    told: bool,
+}
+
+impl SenderLink {
+    /// Best SNR of the last messages, which is what the link is capable of.
+    fn best_snr(&self) -> Option<i16> {
+        if self.snr_at_max_power.len() < MIN_HISTORY_LEN {
+            return None;
+        }
+        self.snr_at_max_power.iter().copied().max()
+    }
+}
+
+/// Link quality of all senders of a receiver, see the module documentation.
+pub struct ReceiverAdr {
+    spreading_factor: u8,
+    max_spreading_factor: u8,
+    pending: Option<u8>,
+    senders: BTreeMap<u8, SenderLink>,
+}
+
+impl ReceiverAdr {
+    /// Starts at the spreading factor which the receiver currently listens at, which is lowered to
+    /// `max_spreading_factor` if it is higher.
+    pub fn new(spreading_factor: u8, max_spreading_factor: u8) -> Self {
+        Self {
+            spreading_factor: spreading_factor.min(max_spreading_factor),
+            max_spreading_factor,
+            pending: None,
+            senders: BTreeMap::new(),
+        }
+    }
+
+    /// Spreading factor which the receiver listens at.
+    pub fn spreading_factor(&self) -> u8 {
+        self.spreading_factor
+    }
+
+    /// Spreading factor which is announced to the senders, but not used yet.
+    pub fn pending_spreading_factor(&self) -> Option<u8> {
+        self.pending
+    }
+
+    fn is_active(link: &SenderLink, now_ms: u64) -> bool {
+        now_ms.saturating_sub(link.last_heard_ms) < ACTIVE_MS
+    }
+
+    /// Records a message of a sender. `snr_db` is the lowest SNR of its packets and
+    /// `tx_power_dbm` the power they were sent at, or 0 if the sender did not report it.
+    pub fn record(
+        &mut self,
+        sender_id: u8,
+        snr_db: i16,
+        tx_power_dbm: u8,
+        ack_requested: bool,
+        now_ms: u64,
+    ) {
+        let link = self.senders.entry(sender_id).or_insert(SenderLink {
+            snr_at_max_power: VecDeque::new(),
+            last_heard_ms: now_ms,
+            ack_requested,
+            told: false,
+        });
+        link.last_heard_ms = now_ms;
+        link.ack_requested = ack_requested;
+        if (MIN_TX_POWER_DBM..=MAX_TX_POWER_DBM).contains(&tx_power_dbm) {
+            if link.snr_at_max_power.len() == HISTORY_LEN {
+                link.snr_at_max_power.pop_front();
+            }
+            link.snr_at_max_power
+                .push_back(snr_db + (MAX_TX_POWER_DBM - tx_power_dbm) as i16);
+        }
+
+        if self.pending.is_some() {
+            return;
+        }
+        let active = || {
+            self.senders
+                .values()
+                .filter(|link| Self::is_active(link, now_ms))
+        };
+        if active().any(|link| !link.ack_requested) {
+            return;
+        }
+        let Some(needed) = active()
+            .filter_map(SenderLink::best_snr)
+            .map(|snr| needed_spreading_factor(snr, self.max_spreading_factor))
+            .max()
+        else {
+            return;
+        };
+        if needed != self.spreading_factor {
+            self.pending = Some(needed);
+            for link in self.senders.values_mut() {
+                link.told = false;
+            }
+        }
+    }
+
+    /// Settings for the acknowledgement of a message of the sender, which must have been recorded
+    /// before. Returns `None` while there is not enough history to recommend anything.
+    pub fn recommend(&mut self, sender_id: u8) -> Option<RadioSettings> {
+        let spreading_factor = self.pending.unwrap_or(self.spreading_factor);
+        let link = self.senders.get_mut(&sender_id)?;
+        link.told = true;
+        let tx_power_dbm = match link.best_snr() {
+            Some(snr) => needed_tx_power_dbm(snr, spreading_factor),
+            // Only the spreading factor is known, so go for a safe power
+            None if self.pending.is_some() => MAX_TX_POWER_DBM,
+            None => return None,
+        };
+        Some(RadioSettings {
+            spreading_factor,
+            tx_power_dbm,
+        })
+    }
+
+    /// Switches to the pending spreading factor once every active sender which asks for
+    /// acknowledgements has been told about it. Returns the spreading factor to listen at from now
+    /// on, if it changed.
+    pub fn poll_switch(&mut self, now_ms: u64) -> Option<u8> {
+        let pending = self.pending?;
+        let waiting = self
+            .senders
+            .values()
+            .any(|link| Self::is_active(link, now_ms) && link.ack_requested && !link.told);
+        if waiting {
+            return None;
+        }
+        self.pending = None;
+        self.spreading_factor = pending;
+        Some(pending)
+    }
+}
+
+/// Radio settings of a sender, which follow the recommendations of the receiver and fall back to
+/// full power and other spreading factors if messages stop arriving.
+#[derive(Debug)]
+pub struct SenderAdr {
+    settings: RadioSettings,
+    max_spreading_factor: u8,
+    failures: u32,
+    /// When the sender switched to a spreading factor which no message has been acknowledged at
+    /// yet.
+    switched_at_ms: Option<u64>,
+}
+
+impl SenderAdr {
+    /// Starts with `settings`, at most at `max_spreading_factor`.
+    pub fn new(mut settings: RadioSettings, max_spreading_factor: u8) -> Self {
+        settings.spreading_factor = settings.spreading_factor.min(max_spreading_factor);
+        Self {
+            settings,
+            max_spreading_factor,
+            failures: 0,
+            switched_at_ms: None,
+        }
+    }
+
+    pub fn settings(&self) -> RadioSettings {
+        self.settings
+    }
+
+    /// A message was acknowledged at `now_ms` with the recommended settings. Settings above the
+    /// maximum spreading factor are ignored. Returns whether the settings changed.
+    pub fn acknowledged(&mut self, recommended: Option<RadioSettings>, now_ms: u64) -> bool {
+        self.failures = 0;
+        self.switched_at_ms = None;
+        match recommended {
+            Some(recommended)
+                if recommended.is_valid()
+                    && recommended.spreading_factor <= self.max_spreading_factor
+                    && recommended != self.settings =>
+            {
+                if recommended.spreading_factor != self.settings.spreading_factor {
+                    self.switched_at_ms = Some(now_ms);
+                }
+                self.settings = recommended;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// A message was not acknowledged at `now_ms`. The next one is sent at full power, and after
+    /// [`FAILURES_BEFORE_SCAN`] failures at full power with the next spreading factor, in case the
+    /// receiver changed it while this sender was not listening. Within [`ACTIVE_MS`] of switching
+    /// to a spreading factor recommended by the receiver, the sender keeps it, since the receiver
+    /// only switches once every active sender has been told. Returns whether the settings changed.
+    pub fn failed(&mut self, now_ms: u64) -> bool {
+        if self.settings.tx_power_dbm < MAX_TX_POWER_DBM {
+            self.settings.tx_power_dbm = MAX_TX_POWER_DBM;
+            self.failures = 0;
+            return true;
+        }
+        match self.switched_at_ms {
+            Some(switched_at) if now_ms.saturating_sub(switched_at) < ACTIVE_MS => return false,
+            _ => self.switched_at_ms = None,
+        }
+        self.failures += 1;
+        if self.failures < FAILURES_BEFORE_SCAN {
+            return false;
+        }
+        self.failures = 0;
+        self.settings.spreading_factor =
+            if self.settings.spreading_factor >= self.max_spreading_factor {
+                MIN_SPREADING_FACTOR
+            } else {
+                self.settings.spreading_factor + 1
+            };
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn required_snr() {
+        let required = (7..=12).map(required_snr_db).collect::<Vec<_>>();
+        assert_eq!(required, [-7, -10, -12, -15, -17, -20]);
+    }
+
+    #[test]
+    fn settings_follow_the_link_budget() {
+        // 10 dB above the margin at SF7 allows three steps of power reduction
+        assert_eq!(needed_spreading_factor(13, MAX_SPREADING_FACTOR), 7);
+        assert_eq!(needed_tx_power_dbm(13, 7), 8);
+        assert_eq!(needed_tx_power_dbm(40, 7), MIN_TX_POWER_DBM);
+        assert_eq!(needed_spreading_factor(0, MAX_SPREADING_FACTOR), 8);
+        assert_eq!(needed_tx_power_dbm(0, 8), MAX_TX_POWER_DBM);
+        assert_eq!(
+            needed_spreading_factor(-30, MAX_SPREADING_FACTOR),
+            MAX_SPREADING_FACTOR
+        );
+        assert_eq!(needed_spreading_factor(-30, 10), 10);
+        assert_eq!(needed_tx_power_dbm(-30, 12), MAX_TX_POWER_DBM);
+    }
+
+    #[test]
+    fn settings_roundtrip() {
+        let settings = RadioSettings {
+            spreading_factor: 9,
+            tx_power_dbm: 5,
+        };
+        assert_eq!(
+            RadioSettings::from_bytes(settings.to_bytes()),
+            Some(settings)
+        );
+        assert_eq!(RadioSettings::from_bytes([6, 17]), None);
+        assert_eq!(RadioSettings::from_bytes([7, 20]), None);
+    }
+
+    #[test]
+    fn power_is_lowered_for_strong_senders() {
+        let mut adr = ReceiverAdr::new(7, MAX_SPREADING_FACTOR);
+        for i in 0..MIN_HISTORY_LEN as u64 {
+            assert_eq!(adr.recommend(1), None);
+            // Sent at 8 dB, so the SNR at full power would be 9 dB higher
+            adr.record(1, 4, 8, true, i * 1000);
+        }
+        assert_eq!(
+            adr.recommend(1),
+            Some(RadioSettings {
+                spreading_factor: 7,
+                tx_power_dbm: 8
+            })
+        );
+        assert_eq!(adr.poll_switch(10_000), None);
+    }
+
+    #[test]
+    fn spreading_factor_changes_once_every_sender_was_told() {
+        let mut adr = ReceiverAdr::new(7, MAX_SPREADING_FACTOR);
+        for i in 0..MIN_HISTORY_LEN as u64 {
+            adr.record(1, 10, 17, true, i * 1000);
+            adr.record(2, 10, 17, true, i * 1000);
+        }
+        assert_eq!(adr.pending_spreading_factor(), None);
+
+        // Sender 2 moved further away
+        adr.record(2, -5, 17, true, 10_000);
+        assert_eq!(adr.pending_spreading_factor(), None);
+        for i in 1..HISTORY_LEN as u64 {
+            adr.record(2, -5, 17, true, 10_000 + i);
+        }
+        assert_eq!(adr.pending_spreading_factor(), Some(10));
+
+        let told = adr.recommend(2).unwrap();
+        assert_eq!(told.spreading_factor, 10);
+        assert_eq!(adr.poll_switch(20_000), None);
+        adr.record(1, 10, 17, true, 20_000);
+        assert_eq!(adr.recommend(1).unwrap().spreading_factor, 10);
+        assert_eq!(adr.poll_switch(20_000), Some(10));
+        assert_eq!(adr.spreading_factor(), 10);
+        assert_eq!(adr.poll_switch(20_000), None);
+    }
+
+    #[test]
+    fn inactive_senders_are_not_waited_for() {
+        let mut adr = ReceiverAdr::new(10, MAX_SPREADING_FACTOR);
+        adr.record(3, 20, 17, true, 0);
+        for i in 0..MIN_HISTORY_LEN as u64 {
+            adr.record(1, 20, 17, true, ACTIVE_MS + i);
+        }
+        assert_eq!(adr.pending_spreading_factor(), Some(7));
+        adr.recommend(1);
+        assert_eq!(adr.poll_switch(ACTIVE_MS), Some(7));
+    }
+
+    #[test]
+    fn spreading_factor_stays_with_senders_without_acks() {
+        let mut adr = ReceiverAdr::new(10, MAX_SPREADING_FACTOR);
+        adr.record(2, 20, 17, false, 0);
+        for i in 0..MIN_HISTORY_LEN as u64 {
+            adr.record(1, 20, 17, true, i);
+        }
+        assert_eq!(adr.pending_spreading_factor(), None);
+        assert_eq!(adr.recommend(1).unwrap().spreading_factor, 10);
+    }
+
+    #[test]
+    fn sender_falls_back_and_scans() {
+        let mut adr = SenderAdr::new(RadioSettings::DEFAULT, MAX_SPREADING_FACTOR);
+        let recommended = RadioSettings {
+            spreading_factor: 11,
+            tx_power_dbm: 5,
+        };
+        assert!(adr.acknowledged(Some(recommended), 0));
+        assert!(!adr.acknowledged(Some(recommended), 0));
+        assert!(!adr.acknowledged(None, 0));
+
+        assert!(adr.failed(0));
+        assert_eq!(adr.settings().tx_power_dbm, MAX_TX_POWER_DBM);
+        let mut spreading_factors = vec![];
+        for _ in 0..3 * FAILURES_BEFORE_SCAN {
+            adr.failed(0);
+            spreading_factors.push(adr.settings().spreading_factor);
+        }
+        assert_eq!(spreading_factors, [11, 11, 12, 12, 12, 7, 7, 7, 8]);
+    }
+
+    #[test]
+    fn sender_waits_for_the_receiver_to_switch() {
+        let mut adr = SenderAdr::new(RadioSettings::DEFAULT, MAX_SPREADING_FACTOR);
+        let recommended = RadioSettings {
+            spreading_factor: 10,
+            tx_power_dbm: MAX_TX_POWER_DBM,
+        };
+        assert!(adr.acknowledged(Some(recommended), 1_000));
+
+        // The receiver still listens at SF7 while it tells the other senders
+        for i in 0..10 * FAILURES_BEFORE_SCAN as u64 {
+            assert!(!adr.failed(1_000 + i * 60_000));
+        }
+        assert_eq!(adr.settings(), recommended);
+
+        // It never followed, so the sender starts looking for it
+        for _ in 0..FAILURES_BEFORE_SCAN {
+            adr.failed(1_000 + ACTIVE_MS);
+        }
+        assert_eq!(adr.settings().spreading_factor, 11);
+    }
+
+    #[test]
+    fn spreading_factor_stays_below_the_maximum() {
+        let mut adr = ReceiverAdr::new(12, 9);
+        assert_eq!(adr.spreading_factor(), 9);
+        for i in 0..MIN_HISTORY_LEN as u64 {
+            adr.record(1, -30, 17, true, i);
+        }
+        assert_eq!(adr.pending_spreading_factor(), None);
+
+        let mut adr = SenderAdr::new(RadioSettings::DEFAULT, 8);
+        let recommended = RadioSettings {
+            spreading_factor: 10,
+            tx_power_dbm: MAX_TX_POWER_DBM,
+        };
+        assert!(!adr.acknowledged(Some(recommended), 0));
+        let mut spreading_factors = vec![];
+        for _ in 0..3 * FAILURES_BEFORE_SCAN {
+            adr.failed(0);
+            spreading_factors.push(adr.settings().spreading_factor);
+        }
+        assert_eq!(spreading_factors, [7, 7, 8, 8, 8, 7, 7, 7, 8]);
+    }
+}
@@ -0,0 +1,314 @@
+//! Time on air of LoRa and FSK packets, and the duty cycle limits of the EU868 band.
+//!
+//! European regulations (ETSI EN 300 220) limit the share of time a device may transmit in each
+//! sub-band, measured over one hour. [`DutyCycleLimiter`] keeps track of the airtime used in the
+//! last hour and tells how long to wait before the next packet fits in the budget.
+
+use alloc::collections::VecDeque;
+use core::fmt;
+
+/// Settings of the radio which determine how long a packet takes to transmit.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Modulation {
+    pub spreading_factor: u8,
+    pub bandwidth_hz: u32,
+    /// Denominator of the coding rate, 5 to 8 for 4/5 to 4/8.
+    pub coding_rate: u8,
+    pub preamble_symbols: u16,
+    /// The packet starts with a header telling its length and coding rate.
+    pub explicit_header: bool,
+    pub crc: bool,
+}
+
+/// Time in microseconds a packet with `payload_len` bytes takes to transmit, see the Semtech
+/// application note AN1200.13.
+pub fn time_on_air_us(modulation: &Modulation, payload_len: usize) -> u64 {
+    let sf = modulation.spreading_factor as i64;
+    let bandwidth = modulation.bandwidth_hz as u64;
+    // Low data rate optimization, which the SX1276 needs for symbols longer than 16 ms. SF11 at
+    // 125 kHz is just above it, with 16.384 ms.
+    let low_data_rate = (1u64 << sf) * 1_000_000 / bandwidth > 16_000;
+
+    let numerator = 8 * payload_len as i64 - 4 * sf + 28 + if modulation.crc { 16 } else { 0 }
+        - if modulation.explicit_header { 0 } else { 20 };
+    let denominator = 4 * (sf - if low_data_rate { 2 } else { 0 });
+    let blocks = if numerator > 0 {
+        (numerator + denominator - 1) / denominator
+    } else {
+        0
+    };
+    let payload_symbols = 8 + blocks as u64 * modulation.coding_rate as u64;
+
+    // The preamble is followed by 4.25 symbols of sync word, so everything is counted in quarter
+    // symbols
+    let quarter_symbols = 4 * modulation.preamble_symbols as u64 + 17 + 4 * payload_symbols;
+    quarter_symbols * (1u64 << sf) * 1_000_000 / (4 * bandwidth)
+}
+
+/// Time in microseconds an FSK packet with `payload_len` bytes takes to transmit at `bitrate`
+/// bits per second. The packet engine sends the preamble, the sync word, a length byte, the
+/// payload and a CRC of 2 bytes.
+pub fn fsk_time_on_air_us(
+    bitrate: u32,
+    preamble_bytes: u16,
+    sync_word_len: usize,
+    payload_len: usize,
+) -> u64 {
+    let bytes = preamble_bytes as u64 + sync_word_len as u64 + 1 + payload_len as u64 + 2;
+    let bitrate = bitrate as u64;
+    (bytes * 8 * 1_000_000 + bitrate - 1) / bitrate
+}
+
+/// Sub-bands of EU868 with their own duty cycle limit.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SubBand {
+    /// 863.0 - 868.0 MHz
+    G,
+    /// 868.0 - 868.6 MHz, used by the default LoRaWAN channels
+    G1,
+    /// 868.7 - 869.2 MHz
+    G2,
+    /// 869.4 - 869.65 MHz, used by RX2 of LoRaWAN
+    G3,
+    /// 869.7 - 870.0 MHz
+    G4,
+}
+
+impl SubBand {
+    /// Returns `None` for frequencies outside of the sub-bands, where transmitting is not allowed
+    /// without further restrictions.
+    pub fn from_frequency(frequency_hz: u32) -> Option<Self> {
+        match frequency_hz {
+            863_000_000..=867_999_999 => Some(Self::G),
+            868_000_000..=868_600_000 => Some(Self::G1),
+            868_700_000..=869_200_000 => Some(Self::G2),
+            869_400_000..=869_650_000 => Some(Self::G3),
+            869_700_000..=870_000_000 => Some(Self::G4),
+            _ => None,
+        }
+    }
+
+    /// Share of time a device may transmit, in thousandths.
+    pub fn duty_cycle_permille(self) -> u64 {
+        match self {
+            Self::G | Self::G1 | Self::G4 => 10,
+            Self::G2 => 1,
+            Self::G3 => 100,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DutyCycleError {
+    /// The frequency is not in any sub-band of EU868.
+    NotInSubBand { frequency_hz: u32 },
+    /// The packet alone takes more airtime than the sub-band allows in an hour.
+    TooLong { airtime_us: u64 },
+}
+
+impl fmt::Display for DutyCycleError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::NotInSubBand { frequency_hz } => {
+                write!(f, "{frequency_hz} Hz is not in a sub-band of EU868")
+            }
+            Self::TooLong { airtime_us } => write!(
+                f,
+                "packet of {} ms exceeds the duty cycle of its sub-band",
+                airtime_us / 1000
+            ),
+        }
+    }
+}
+
+/// Period over which the duty cycle is measured.
+pub const DUTY_CYCLE_WINDOW_MS: u64 = 3_600_000;
+
+struct Transmission {
+    sub_band: SubBand,
+    started_ms: u64,
+    airtime_us: u64,
+}
+
+/// Airtime used in each sub-band during the last [`DUTY_CYCLE_WINDOW_MS`].
+#[derive(Default)]
+pub struct DutyCycleLimiter {
+    transmissions: VecDeque<Transmission>,
+}
+
+impl DutyCycleLimiter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn expire(&mut self, now_ms: u64) {
+        while let Some(oldest) = self.transmissions.front() {
+            if now_ms.saturating_sub(oldest.started_ms) < DUTY_CYCLE_WINDOW_MS {
+                break;
+            }
+            self.transmissions.pop_front();
+        }
+    }
+
+    /// Airtime in microseconds used in the sub-band during the last hour.
+    pub fn used_us(&mut self, now_ms: u64, sub_band: SubBand) -> u64 {
+        self.expire(now_ms);
+        self.transmissions
+            .iter()
+            .filter(|t| t.sub_band == sub_band)
+            .map(|t| t.airtime_us)
+            .sum()
+    }
+
+    /// Milliseconds to wait until a packet of `airtime_us` may be sent on the frequency, which is 0
+    /// if it may be sent right away.
+    pub fn wait_ms(
+        &mut self,
+        now_ms: u64,
+        frequency_hz: u32,
+        airtime_us: u64,
+    ) -> Result<u64, DutyCycleError> {
+        let sub_band = SubBand::from_frequency(frequency_hz)
+            .ok_or(DutyCycleError::NotInSubBand { frequency_hz })?;
+        let budget_us = DUTY_CYCLE_WINDOW_MS * sub_band.duty_cycle_permille();
+        if airtime_us > budget_us {
+            return Err(DutyCycleError::TooLong { airtime_us });
+        }
+
+        let used_us = self.used_us(now_ms, sub_band);
+        if used_us + airtime_us <= budget_us {
+            return Ok(0);
+        }
+        let mut excess_us = used_us + airtime_us - budget_us;
+        // Wait until enough of the oldest transmissions have left the window
+        for transmission in self.transmissions.iter().filter(|t| t.sub_band == sub_band) {
+            if transmission.airtime_us >= excess_us {
+                return Ok(transmission.started_ms + DUTY_CYCLE_WINDOW_MS - now_ms);
+            }
+            excess_us -= transmission.airtime_us;
+        }
+        unreachable!("the packet fits in an empty window")
+    }
+
+    /// Records a packet which was sent at `now_ms`.
+    pub fn record(&mut self, now_ms: u64, frequency_hz: u32, airtime_us: u64) {
+        if let Some(sub_band) = SubBand::from_frequency(frequency_hz) {
+            self.transmissions.push_back(Transmission {
+                sub_band,
+                started_ms: now_ms,
+                airtime_us,
+            });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lora(spreading_factor: u8, bandwidth_hz: u32) -> Modulation {
+        Modulation {
+            spreading_factor,
+            bandwidth_hz,
+            coding_rate: 5,
+            preamble_symbols: 8,
+            explicit_header: true,
+            crc: true,
+        }
+    }
+
+    #[test]
+    fn airtime_formula() {
+        // An empty LoRaWAN uplink is 13 bytes
+        assert_eq!(time_on_air_us(&lora(7, 125_000), 13), 46_336);
+        assert_eq!(time_on_air_us(&lora(12, 125_000), 13), 1_155_072);
+        assert_eq!(time_on_air_us(&lora(12, 125_000), 64), 2_793_472);
+        assert_eq!(time_on_air_us(&lora(7, 250_000), 64), 59_008);
+        assert_eq!(time_on_air_us(&lora(7, 125_000), 255), 399_616);
+        // The shortest symbols which need low data rate optimization, which carries 2 bits less
+        // per symbol
+        assert_eq!(time_on_air_us(&lora(11, 125_000), 13), 577_536);
+        assert_eq!(time_on_air_us(&lora(11, 125_000), 64), 1_560_576);
+
+        let modulation = Modulation {
+            coding_rate: 8,
+            ..lora(9, 125_000)
+        };
+        assert_eq!(time_on_air_us(&modulation, 20), 246_784);
+
+        // Without header and CRC, an empty payload still has 8 symbols
+        let modulation = Modulation {
+            explicit_header: false,
+            crc: false,
+            ..lora(7, 125_000)
+        };
+        assert_eq!(time_on_air_us(&modulation, 0), 20_736);
+    }
+
+    #[test]
+    fn fsk_airtime_formula() {
+        assert_eq!(fsk_time_on_air_us(50_000, 5, 4, 63), 12_000);
+        assert_eq!(fsk_time_on_air_us(50_000, 5, 4, 0), 1_920);
+        // Rounded up to whole microseconds
+        assert_eq!(fsk_time_on_air_us(300_000, 3, 0, 0), 160);
+        assert_eq!(fsk_time_on_air_us(300_000, 3, 0, 1), 187);
+    }
+
+    #[test]
+    fn sub_bands() {
+        assert_eq!(SubBand::from_frequency(868_100_000), Some(SubBand::G1));
+        assert_eq!(SubBand::from_frequency(867_100_000), Some(SubBand::G));
+        assert_eq!(SubBand::from_frequency(869_525_000), Some(SubBand::G3));
+        assert_eq!(SubBand::from_frequency(868_650_000), None);
+        assert_eq!(SubBand::from_frequency(915_000_000), None);
+    }
+
+    #[test]
+    fn budget_is_enforced_per_sub_band() {
+        let mut limiter = DutyCycleLimiter::new();
+        // 1% of an hour is 36 seconds, which fits 90 packets of 400 ms
+        for i in 0..90 {
+            assert_eq!(limiter.wait_ms(i * 1000, 868_100_000, 400_000), Ok(0));
+            limiter.record(i * 1000, 868_100_000, 400_000);
+        }
+        assert_eq!(limiter.used_us(90_000, SubBand::G1), 36_000_000);
+        // The next one has to wait until the first one is an hour old
+        assert_eq!(
+            limiter.wait_ms(90_000, 868_300_000, 400_000),
+            Ok(DUTY_CYCLE_WINDOW_MS - 90_000)
+        );
+        // A packet twice as long needs two packets to leave the window
+        assert_eq!(
+            limiter.wait_ms(90_000, 868_300_000, 800_000),
+            Ok(DUTY_CYCLE_WINDOW_MS + 1000 - 90_000)
+        );
+        // Other sub-bands have their own budget
+        assert_eq!(limiter.wait_ms(90_000, 869_525_000, 400_000), Ok(0));
+
+        assert_eq!(
+            limiter.wait_ms(DUTY_CYCLE_WINDOW_MS, 868_100_000, 400_000),
+            Ok(0)
+        );
+        assert_eq!(
+            limiter.used_us(DUTY_CYCLE_WINDOW_MS, SubBand::G1),
+            35_600_000
+        );
+    }
+
+    #[test]
+    fn invalid_transmissions() {
+        let mut limiter = DutyCycleLimiter::new();
+        assert_eq!(
+            limiter.wait_ms(0, 868_900_000, 4_000_000),
+            Err(DutyCycleError::TooLong {
+                airtime_us: 4_000_000
+            })
+        );
+        assert_eq!(
+            limiter.wait_ms(0, 433_000_000, 1000),
+            Err(DutyCycleError::NotInSubBand {
+                frequency_hz: 433_000_000
+            })
+        );
+    }
+}
@@ -0,0 +1,142 @@
+//! Delta and zigzag varint encoding of measurement points.
+//!
+//! Voltage and current are each stored as the difference to the previous point (the first point
+//! is compared to 0). The difference is zigzag encoded, so that small negative values become small
+//! positive values, and then written as a LEB128 varint. Consecutive points of a sweep or of MPPT
+//! tracking usually only differ slightly, so most values fit in one or two bytes instead of the
+//! two bytes per value used by the raw encoding. A value never takes more than three bytes.
+
+use alloc::vec::Vec;
+
+use crate::{DecodeError, MeasurementPoint};
+
+const MAX_VARINT_SIZE: usize = 3;
+
+fn zigzag(value: i16) -> u16 {
+    ((value << 1) ^ (value >> 15)) as u16
+}
+
+fn unzigzag(value: u16) -> i16 {
+    (value >> 1) as i16 ^ -((value & 1) as i16)
+}
+
+fn write_varint(message: &mut Vec<u8>, mut value: u16) {
+    while value >= 0x80 {
+        message.push(value as u8 | 0x80);
+        value >>= 7;
+    }
+    message.push(value as u8);
+}
+
+fn read_varint(bytes: &mut &[u8]) -> Result<u16, DecodeError> {
+    let mut value: u32 = 0;
+    for i in 0..MAX_VARINT_SIZE {
+        let (&byte, rest) = bytes.split_first().ok_or(DecodeError::InvalidVarint)?;
+        *bytes = rest;
+        value |= ((byte & 0x7f) as u32) << (7 * i);
+        if byte & 0x80 == 0 {
+            return u16::try_from(value).map_err(|_| DecodeError::InvalidVarint);
+        }
+    }
+    Err(DecodeError::InvalidVarint)
+}
+
+pub fn encode_points(message: &mut Vec<u8>, points: &[MeasurementPoint]) {
+    let mut previous = MeasurementPoint {
+        voltage: 0,
+        current: 0,
+    };
+    for point in points {
+        write_varint(
+            message,
+            zigzag(point.voltage.wrapping_sub(previous.voltage) as i16),
+        );
+        write_varint(
+            message,
+            zigzag(point.current.wrapping_sub(previous.current) as i16),
+        );
+        previous = *point;
+    }
+}
+
+pub fn decode_points(mut bytes: &[u8]) -> Result<Vec<MeasurementPoint>, DecodeError> {
+    let mut points = Vec::new();
+    let mut previous = MeasurementPoint {
+        voltage: 0,
+        current: 0,
+    };
+    while !bytes.is_empty() {
+        let voltage_delta = unzigzag(read_varint(&mut bytes)?);
+        let current_delta = unzigzag(read_varint(&mut bytes)?);
+        previous = MeasurementPoint {
+            voltage: previous.voltage.wrapping_add(voltage_delta as u16),
+            current: previous.current.wrapping_add(current_delta as u16),
+        };
+        points.push(previous);
+    }
+    Ok(points)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+
+    #[test]
+    fn zigzag_values() {
+        assert_eq!(zigzag(0), 0);
+        assert_eq!(zigzag(-1), 1);
+        assert_eq!(zigzag(1), 2);
+        assert_eq!(zigzag(i16::MIN), u16::MAX);
+        assert_eq!(zigzag(i16::MAX), u16::MAX - 1);
+    }
+
+    #[test]
+    fn small_deltas_take_one_byte() {
+        let points = [
+            MeasurementPoint {
+                voltage: 20,
+                current: 30,
+            },
+            MeasurementPoint {
+                voltage: 19,
+                current: 33,
+            },
+        ];
+        let mut message = Vec::new();
+        encode_points(&mut message, &points);
+        assert_eq!(message, [40, 60, 1, 6]);
+    }
+
+    #[test]
+    fn invalid_varints() {
+        // Truncated after a continuation bit
+        assert_eq!(decode_points(&[0x80]), Err(DecodeError::InvalidVarint));
+        // Longer than three bytes
+        assert_eq!(
+            decode_points(&[0x80, 0x80, 0x80, 0x00]),
+            Err(DecodeError::InvalidVarint)
+        );
+        // Larger than u16::MAX
+        assert_eq!(
+            decode_points(&[0xff, 0xff, 0x7f, 0x00]),
+            Err(DecodeError::InvalidVarint)
+        );
+        // Voltage without current
+        assert_eq!(decode_points(&[0x02]), Err(DecodeError::InvalidVarint));
+    }
+
+    proptest! {
+        #[test]
+        fn roundtrip(values in prop::collection::vec(any::<(u16, u16)>(), 0..200)) {
+            let points = values
+                .into_iter()
+                .map(|(voltage, current)| MeasurementPoint { voltage, current })
+                .collect::<Vec<_>>();
+            let mut message = Vec::new();
+            encode_points(&mut message, &points);
+            prop_assert!(message.len() <= points.len() * 2 * MAX_VARINT_SIZE);
+            prop_assert_eq!(decode_points(&message).unwrap(), points);
+        }
+    }
+}
@@ -0,0 +1,182 @@
+//! Frame counters and the AES-GCM nonces derived from them.
+//!
+//! AES-GCM breaks down completely if the same key and nonce are ever used twice, so the nonce of
+//! every message is built from the sender ID and a frame counter which is never reused:
+//!
+//! | byte | content                                 |
+//! |------|-----------------------------------------|
+//! | 0    | sender ID                               |
+//! | 1    | direction, see [`Direction`]            |
+//! | 2-7  | reserved, zero                          |
+//! | 8-11 | frame counter of the sender, big-endian |
+//!
+//! Since the sender ID is part of the nonce, two senders never use the same nonce even if their
+//! counters are equal. Acknowledgements from the receiver use the key of the sender and the
+//! counter of the acknowledged message, and are kept apart from the messages of the sender by the
+//! direction byte. The counter is persisted through a [`CounterStore`]. Writing flash for every
+//! message would wear it out, so [`CounterAllocator`] reserves a block of counters at a time and
+//! only stores the end of the block. After a reboot allocation continues from the stored value,
+//! which skips the unused remainder of the block but never hands out a counter twice.
+
+use core::fmt;
+
+pub const NONCE_SIZE: usize = 12;
+
+/// Number of counters reserved with each write to the store.
+pub const RESERVATION: u32 = 16;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    /// A message from a sender to the receiver.
+    Uplink = 0,
+    /// An acknowledgement from the receiver to a sender.
+    Downlink = 1,
+}
+
+/// Builds the 96-bit AES-GCM nonce for a message.
+pub fn nonce(direction: Direction, sender_id: u8, counter: u32) -> [u8; NONCE_SIZE] {
+    let mut nonce = [0; NONCE_SIZE];
+    nonce[0] = sender_id;
+    nonce[1] = direction as u8;
+    nonce[8..].copy_from_slice(&counter.to_be_bytes());
+    nonce
+}
+
+/// Persistent storage of the first counter which has not been reserved yet.
+pub trait CounterStore {
+    fn load(&mut self) -> Option<u32>;
+    fn store(&mut self, value: u32);
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CounterError {
+    /// Every counter has been used. Using the key any further would reuse a nonce.
+    Exhausted,
+}
+
+impl fmt::Display for CounterError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Exhausted => write!(f, "all frame counters have been used"),
+        }
+    }
+}
+
+pub struct CounterAllocator<S> {
+    store: S,
+    next: u32,
+    reserved_until: u32,
+}
+
+impl<S: CounterStore> CounterAllocator<S> {
+    pub fn new(mut store: S) -> Self {
+        let next = store.load().unwrap_or(0);
+        Self {
+            store,
+            next,
+            reserved_until: next,
+        }
+    }
+
+    /// Returns a counter which has never been returned before, including before a reboot.
+    pub fn allocate(&mut self) -> Result<u32, CounterError> {
+        if self.next == self.reserved_until {
+            // The last counter is never handed out, since the store could then not record that
+            // it has been used.
+            if self.next == u32::MAX {
+                return Err(CounterError::Exhausted);
+            }
+            self.reserved_until = self.next.saturating_add(RESERVATION);
+            self.store.store(self.reserved_until);
+        }
+        let counter = self.next;
+        self.next += 1;
+        Ok(counter)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::RefCell;
+    use std::collections::HashSet;
+    use std::rc::Rc;
+
+    /// Simulates flash which survives reboots, and counts the writes to it.
+    #[derive(Clone, Default)]
+    struct Flash {
+        value: Rc<RefCell<Option<u32>>>,
+        writes: Rc<RefCell<usize>>,
+    }
+
+    impl CounterStore for Flash {
+        fn load(&mut self) -> Option<u32> {
+            *self.value.borrow()
+        }
+
+        fn store(&mut self, value: u32) {
+            *self.value.borrow_mut() = Some(value);
+            *self.writes.borrow_mut() += 1;
+        }
+    }
+
+    #[test]
+    fn nonce_layout() {
+        assert_eq!(
+            nonce(Direction::Downlink, 75, 0x01020304),
+            [75, 1, 0, 0, 0, 0, 0, 0, 1, 2, 3, 4]
+        );
+    }
+
+    #[test]
+    fn nonces_differ_between_senders_and_directions() {
+        assert_ne!(
+            nonce(Direction::Uplink, 1, 7),
+            nonce(Direction::Uplink, 2, 7)
+        );
+        assert_ne!(
+            nonce(Direction::Uplink, 1, 7),
+            nonce(Direction::Downlink, 1, 7)
+        );
+    }
+
+    #[test]
+    fn no_repeats_across_reboots() {
+        let flash = Flash::default();
+        let mut seen = HashSet::new();
+
+        // Reboot after a varying number of messages, including right after a reservation
+        for messages in [0, 1, 5, 15, 16, 17, 40, 3] {
+            let mut allocator = CounterAllocator::new(flash.clone());
+            for _ in 0..messages {
+                let counter = allocator.allocate().unwrap();
+                assert!(
+                    seen.insert(nonce(Direction::Uplink, 9, counter)),
+                    "nonce reused"
+                );
+            }
+        }
+        assert!(*flash.writes.borrow() < seen.len());
+    }
+
+    #[test]
+    fn refuses_to_wrap_around() {
+        let flash = Flash::default();
+        flash.clone().store(u32::MAX - 20);
+
+        let mut allocator = CounterAllocator::new(flash.clone());
+        let mut last = None;
+        while let Ok(counter) = allocator.allocate() {
+            if let Some(last) = last {
+                assert!(counter > last);
+            }
+            last = Some(counter);
+        }
+        assert_eq!(last, Some(u32::MAX - 1));
+        assert_eq!(allocator.allocate(), Err(CounterError::Exhausted));
+
+        // Still exhausted after a reboot
+        let mut allocator = CounterAllocator::new(flash);
+        assert_eq!(allocator.allocate(), Err(CounterError::Exhausted));
+    }
+}
@@ -0,0 +1,162 @@
+//! Clear-text prefix of an encrypted message.
+//!
+//! | byte | content                                 |
+//! |------|-----------------------------------------|
+//! | 0    | protocol version                        |
+//! | 1    | message type                            |
+//! | 2    | sender ID                               |
+//! | 3    | key epoch, see [`crate::key`]           |
+//! | 4-7  | frame counter of the sender, big-endian |
+//! | 8    | transmit power in dBm, or 0 if unknown  |
+//!
+//! The header is passed to AES-GCM as associated data, so the receiver can route, rate-limit and
+//! reject messages by version, type and sender before decrypting them, while any change to the
+//! header still makes decryption fail. The counter is never reused by a sender, see
+//! [`crate::counter`]. Together with the sender ID it makes up the AES-GCM nonce, and the receiver
+//! uses it to reject replayed messages, see [`crate::replay`].
+//!
+//! The transmit power lets the receiver tell how much margin the link has, see [`crate::adr`].
+//! The sender encrypts every transmission anew, so it is the power the message was actually sent
+//! at, and it cannot be changed without the key.
+
+use core::fmt;
+
+use crate::counter::{self, Direction, NONCE_SIZE};
+use crate::{MessageType, PROTOCOL_VERSION};
+
+pub const ENVELOPE_HEADER_SIZE: usize = 9;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct EnvelopeHeader {
+    pub version: u8,
+    /// Raw message type, which may be unknown to this version of the crate.
+    pub message_type: u8,
+    pub sender_id: u8,
+    pub key_epoch: u8,
+    pub counter: u32,
+    pub tx_power_dbm: u8,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum EnvelopeError {
+    /// The message was shorter than the envelope header.
+    Truncated { len: usize },
+    /// The message was sent with a protocol version this crate does not understand. The rest of
+    /// the header is not parsed, since its layout may differ.
+    UnsupportedVersion(u8),
+}
+
+impl fmt::Display for EnvelopeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Truncated { len } => write!(
+                f,
+                "message of {len} bytes is shorter than the envelope header"
+            ),
+            Self::UnsupportedVersion(version) => write!(
+                f,
+                "protocol version {version} is not supported (expected {PROTOCOL_VERSION})"
+            ),
+        }
+    }
+}
+
+impl EnvelopeHeader {
+    /// Header for a message sent with the current protocol version.
+    pub fn new(
+        message_type: MessageType,
+        sender_id: u8,
+        key_epoch: u8,
+        counter: u32,
+        tx_power_dbm: u8,
+    ) -> Self {
+        Self {
+            version: PROTOCOL_VERSION,
+            message_type: message_type as u8,
+            sender_id,
+            key_epoch,
+            counter,
+            tx_power_dbm,
+        }
+    }
+
+    pub fn to_bytes(&self) -> [u8; ENVELOPE_HEADER_SIZE] {
+        let counter = self.counter.to_be_bytes();
+        [
+            self.version,
+            self.message_type,
+            self.sender_id,
+            self.key_epoch,
+            counter[0],
+            counter[1],
+            counter[2],
+            counter[3],
+            self.tx_power_dbm,
+        ]
+    }
+
+    /// Bytes which are authenticated but not encrypted.
+    pub fn associated_data(&self) -> [u8; ENVELOPE_HEADER_SIZE] {
+        self.to_bytes()
+    }
+
+    /// AES-GCM nonce of the message.
+    pub fn nonce(&self) -> [u8; NONCE_SIZE] {
+        counter::nonce(Direction::Uplink, self.sender_id, self.counter)
+    }
+
+    /// Splits an encrypted message into its envelope header and ciphertext.
+    pub fn parse(message: &[u8]) -> Result<(EnvelopeHeader, &[u8]), EnvelopeError> {
+        match message.first() {
+            Some(&version) if version != PROTOCOL_VERSION => {
+                return Err(EnvelopeError::UnsupportedVersion(version))
+            }
+            _ => {}
+        }
+        if message.len() < ENVELOPE_HEADER_SIZE {
+            return Err(EnvelopeError::Truncated { len: message.len() });
+        }
+        let (header, ciphertext) = message.split_at(ENVELOPE_HEADER_SIZE);
+        Ok((
+            EnvelopeHeader {
+                version: header[0],
+                message_type: header[1],
+                sender_id: header[2],
+                key_epoch: header[3],
+                counter: u32::from_be_bytes([header[4], header[5], header[6], header[7]]),
+                tx_power_dbm: header[8],
+            },
+            ciphertext,
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn roundtrip() {
+        let header = EnvelopeHeader::new(MessageType::Sweep, 75, 2, 0x03040506, 14);
+        let mut message = header.to_bytes().to_vec();
+        assert_eq!(message, [PROTOCOL_VERSION, 1, 75, 2, 3, 4, 5, 6, 14]);
+        message.push(99);
+        assert_eq!(EnvelopeHeader::parse(&message), Ok((header, &[99][..])));
+        assert_eq!(
+            EnvelopeHeader::parse(&message[..8]),
+            Err(EnvelopeError::Truncated { len: 8 })
+        );
+        assert_eq!(
+            EnvelopeHeader::parse(&[]),
+            Err(EnvelopeError::Truncated { len: 0 })
+        );
+    }
+
+    #[test]
+    fn rejects_other_versions_before_parsing() {
+        assert_eq!(
+            EnvelopeHeader::parse(&[PROTOCOL_VERSION + 1]),
+            Err(EnvelopeError::UnsupportedVersion(PROTOCOL_VERSION + 1))
+        );
+    }
+}
@@ -0,0 +1,468 @@
+//! Splitting of encrypted messages into LoRa packets and reassembly on the receiver.
+//!
+//! A LoRa packet carries at most [`MAX_PACKET_SIZE`] bytes. Every packet starts with a clear-text
+//! [`FragmentHeader`], followed by a slice of the encrypted message. Messages which fit in one
+//! packet are sent as a single fragment with a count of 1.
+//!
+//! | byte | content                              |
+//! |------|--------------------------------------|
+//! | 0    | sender ID                            |
+//! | 1    | sequence number, wraps around at 255 |
+//! | 2    | index of this fragment               |
+//! | 3    | total number of fragments            |
+//!
+//! The header is not authenticated. The receiver only trusts what it learns from a fragment, such
+//! as the signal strength it was received with, once the message the fragment belongs to has been
+//! decrypted, see [`Reassembler`].
+
+use alloc::collections::BTreeMap;
+use alloc::vec::Vec;
+use core::fmt;
+
+/// Largest packet which the radio can transmit.
+pub const MAX_PACKET_SIZE: usize = 255;
+
+pub const FRAGMENT_HEADER_SIZE: usize = 4;
+
+/// Number of message bytes which fit in a single packet.
+pub const MAX_FRAGMENT_PAYLOAD: usize = MAX_PACKET_SIZE - FRAGMENT_HEADER_SIZE;
+
+/// Largest message which can be split into fragments.
+pub const MAX_MESSAGE_SIZE: usize = MAX_FRAGMENT_PAYLOAD * u8::MAX as usize;
+
+/// Number of incomplete messages which the [`Reassembler`] keeps at most.
+pub const MAX_PENDING: usize = 32;
+
+/// Number of incomplete messages of a single sender which the [`Reassembler`] keeps at most.
+pub const MAX_PENDING_PER_SENDER: usize = 4;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FragmentHeader {
+    pub sender_id: u8,
+    pub sequence: u8,
+    pub index: u8,
+    pub count: u8,
+}
+
+impl FragmentHeader {
+    pub fn to_bytes(&self) -> [u8; FRAGMENT_HEADER_SIZE] {
+        [self.sender_id, self.sequence, self.index, self.count]
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FragmentError {
+    /// The message needs more than 255 fragments, which is the case for messages larger than
+    /// [`MAX_MESSAGE_SIZE`] with packets of the largest size.
+    MessageTooLarge { len: usize },
+    /// The packet is larger than [`MAX_PACKET_SIZE`].
+    PacketTooLarge { len: usize },
+    /// Packets of at most `max_packet_size` bytes leave no room after the fragment header.
+    PacketSizeTooSmall { max_packet_size: usize },
+    /// The packet was shorter than the fragment header.
+    TruncatedHeader { len: usize },
+    /// The fragment index is not smaller than the fragment count.
+    InvalidIndex { index: u8, count: u8 },
+}
+
+impl fmt::Display for FragmentError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::MessageTooLarge { len } => {
+                write!(f, "message of {len} bytes needs more than 255 fragments")
+            }
+            Self::PacketTooLarge { len } => write!(
+                f,
+                "packet of {len} bytes is larger than {MAX_PACKET_SIZE} bytes"
+            ),
+            Self::PacketSizeTooSmall { max_packet_size } => write!(
+                f,
+                "packets of {max_packet_size} bytes leave no room after the fragment header"
+            ),
+            Self::TruncatedHeader { len } => write!(
+                f,
+                "packet of {len} bytes is shorter than the fragment header"
+            ),
+            Self::InvalidIndex { index, count } => {
+                write!(
+                    f,
+                    "fragment index {index} is out of range for {count} fragments"
+                )
+            }
+        }
+    }
+}
+
+/// Splits a message into packets of at most [`MAX_PACKET_SIZE`] bytes.
+pub fn fragment(
+    sender_id: u8,
+    sequence: u8,
+    message: &[u8],
+) -> Result<Vec<Vec<u8>>, FragmentError> {
+    fragment_with_size(sender_id, sequence, message, MAX_PACKET_SIZE)
+}
+
+/// Splits a message into packets of at most `max_packet_size` bytes, for links which carry less
+/// than a full radio packet, such as a LoRaWAN uplink at a low data rate. `max_packet_size` has to
+/// be larger than [`FRAGMENT_HEADER_SIZE`].
+pub fn fragment_with_size(
+    sender_id: u8,
+    sequence: u8,
+    message: &[u8],
+    max_packet_size: usize,
+) -> Result<Vec<Vec<u8>>, FragmentError> {
+    if max_packet_size <= FRAGMENT_HEADER_SIZE {
+        return Err(FragmentError::PacketSizeTooSmall { max_packet_size });
+    }
+    let max_payload = max_packet_size.min(MAX_PACKET_SIZE) - FRAGMENT_HEADER_SIZE;
+    if message.len() > max_payload * u8::MAX as usize {
+        return Err(FragmentError::MessageTooLarge { len: message.len() });
+    }
+
+    // An empty message still needs one (empty) fragment.
+    let chunks: Vec<&[u8]> = if message.is_empty() {
+        alloc::vec![&[]]
+    } else {
+        message.chunks(max_payload).collect()
+    };
+    let count = chunks.len();
+
+    Ok(chunks
+        .into_iter()
+        .enumerate()
+        .map(|(index, chunk)| {
+            let header = FragmentHeader {
+                sender_id,
+                sequence,
+                index: index as u8,
+                count: count as u8,
+            };
+            let mut packet = Vec::with_capacity(FRAGMENT_HEADER_SIZE + chunk.len());
+            packet.extend_from_slice(&header.to_bytes());
+            packet.extend_from_slice(chunk);
+            packet
+        })
+        .collect())
+}
+
+/// Splits a received packet into its fragment header and payload.
+pub fn parse_fragment(packet: &[u8]) -> Result<(FragmentHeader, &[u8]), FragmentError> {
+    if packet.len() > MAX_PACKET_SIZE {
+        return Err(FragmentError::PacketTooLarge { len: packet.len() });
+    }
+    if packet.len() < FRAGMENT_HEADER_SIZE {
+        return Err(FragmentError::TruncatedHeader { len: packet.len() });
+    }
+    let (header, payload) = packet.split_at(FRAGMENT_HEADER_SIZE);
+    let header = FragmentHeader {
+        sender_id: header[0],
+        sequence: header[1],
+        index: header[2],
+        count: header[3],
+    };
+    if header.index >= header.count {
+        return Err(FragmentError::InvalidIndex {
+            index: header.index,
+            count: header.count,
+        });
+    }
+    Ok((header, payload))
+}
+
+struct Pending<T> {
+    first_received_ms: u64,
+    fragments: Vec<Option<(Vec<u8>, T)>>,
+}
+
+/// Collects fragments until a message is complete.
+///
+/// Every fragment comes with metadata of type `T`, such as how it was received, which is returned
+/// together with the message for exactly the fragments the message was made of. Fragments are
+/// grouped by sender ID and sequence number. Incomplete messages are dropped once
+/// `timeout_ms` has passed since their first fragment arrived.
+///
+/// The fragment header is not authenticated, so anybody can start messages in the name of any
+/// sender. To keep the memory bounded, at most [`MAX_PENDING_PER_SENDER`] and
+/// [`MAX_PENDING`] incomplete messages are kept, and the oldest one is dropped to make room for a
+/// new one.
+pub struct Reassembler<T = ()> {
+    timeout_ms: u64,
+    pending: BTreeMap<(u8, u8), Pending<T>>,
+}
+
+impl<T> Reassembler<T> {
+    pub fn new(timeout_ms: u64) -> Self {
+        Self {
+            timeout_ms,
+            pending: BTreeMap::new(),
+        }
+    }
+
+    /// Number of messages which are waiting for more fragments.
+    pub fn pending(&self) -> usize {
+        self.pending.len()
+    }
+
+    /// Drops incomplete messages older than the timeout.
+    pub fn expire(&mut self, now_ms: u64) {
+        let timeout_ms = self.timeout_ms;
+        self.pending
+            .retain(|_, pending| now_ms.saturating_sub(pending.first_received_ms) < timeout_ms);
+    }
+
+    /// Drops the incomplete message which was started first among those matching `filter`.
+    fn evict_oldest(&mut self, filter: impl Fn(&(u8, u8)) -> bool) {
+        let oldest = self
+            .pending
+            .iter()
+            .filter(|(key, _)| filter(key))
+            .min_by_key(|(_, pending)| pending.first_received_ms)
+            .map(|(key, _)| *key);
+        if let Some(key) = oldest {
+            self.pending.remove(&key);
+        }
+    }
+
+    /// Makes room for a new incomplete message of the sender.
+    fn make_room(&mut self, sender_id: u8) {
+        let of_sender = |key: &(u8, u8)| key.0 == sender_id;
+        if self.pending.keys().filter(|key| of_sender(key)).count() >= MAX_PENDING_PER_SENDER {
+            self.evict_oldest(of_sender);
+        }
+        if self.pending.len() >= MAX_PENDING {
+            self.evict_oldest(|_| true);
+        }
+    }
+
+    /// Adds a fragment, returning the full message and the metadata of its fragments in order if
+    /// this was the last missing fragment. A fragment which was already received keeps the
+    /// metadata of its first copy.
+    pub fn insert(
+        &mut self,
+        header: FragmentHeader,
+        payload: &[u8],
+        metadata: T,
+        now_ms: u64,
+    ) -> Option<(Vec<u8>, Vec<T>)> {
+        self.expire(now_ms);
+
+        if header.count == 1 {
+            return Some((payload.to_vec(), alloc::vec![metadata]));
+        }
+
+        let key = (header.sender_id, header.sequence);
+        if !self.pending.contains_key(&key) {
+            self.make_room(header.sender_id);
+        }
+        let pending = self.pending.entry(key).or_insert_with(|| Pending {
+            first_received_ms: now_ms,
+            fragments: Vec::new(),
+        });
+
+        let slot = pending.fragments.get(header.index as usize);
+        let stale = matches!(slot, Some(Some((fragment, _))) if fragment != payload);
+        if pending.fragments.len() != header.count as usize || stale {
+            // Either a new message, or the sequence number was reused for another message, for
+            // example after the sender restarted. In both cases start over, rather than mixing the
+            // fragments of two messages.
+            pending.first_received_ms = now_ms;
+            pending.fragments = (0..header.count).map(|_| None).collect();
+        }
+
+        let slot = &mut pending.fragments[header.index as usize];
+        if slot.is_none() {
+            *slot = Some((payload.to_vec(), metadata));
+        }
+
+        if pending.fragments.iter().all(Option::is_some) {
+            let pending = self.pending.remove(&key).unwrap();
+            let (payloads, metadata): (Vec<_>, Vec<_>) =
+                pending.fragments.into_iter().flatten().unzip();
+            Some((payloads.concat(), metadata))
+        } else {
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+
+    fn reassemble(
+        reassembler: &mut Reassembler,
+        packets: &[Vec<u8>],
+        now_ms: u64,
+    ) -> Option<Vec<u8>> {
+        let mut result = None;
+        for packet in packets {
+            let (header, payload) = parse_fragment(packet).unwrap();
+            result = reassembler
+                .insert(header, payload, (), now_ms)
+                .map(|(message, _)| message);
+        }
+        result
+    }
+
+    #[test]
+    fn small_message_is_single_fragment() {
+        let packets = fragment(75, 3, &[1, 2, 3]).unwrap();
+        assert_eq!(packets, [vec![75, 3, 0, 1, 1, 2, 3]]);
+        assert_eq!(fragment(75, 3, &[]).unwrap(), [vec![75, 3, 0, 1]]);
+    }
+
+    #[test]
+    fn packets_fit_in_radio_buffer() {
+        let message = (0..1000).map(|i| i as u8).collect::<Vec<_>>();
+        let packets = fragment(1, 0, &message).unwrap();
+        assert_eq!(packets.len(), 4);
+        assert!(packets.iter().all(|p| p.len() <= MAX_PACKET_SIZE));
+
+        let packets = fragment_with_size(1, 0, &message, 51).unwrap();
+        assert_eq!(packets.len(), 22);
+        assert!(packets.iter().all(|p| p.len() <= 51));
+        let mut reassembler = Reassembler::new(1000);
+        assert_eq!(reassemble(&mut reassembler, &packets, 0), Some(message));
+    }
+
+    #[test]
+    fn rejects_invalid_input() {
+        assert_eq!(
+            fragment(1, 0, &vec![0; MAX_MESSAGE_SIZE + 1]),
+            Err(FragmentError::MessageTooLarge {
+                len: MAX_MESSAGE_SIZE + 1
+            })
+        );
+        assert_eq!(
+            fragment_with_size(1, 0, &[1], FRAGMENT_HEADER_SIZE),
+            Err(FragmentError::PacketSizeTooSmall {
+                max_packet_size: FRAGMENT_HEADER_SIZE
+            })
+        );
+        assert_eq!(
+            parse_fragment(&[1, 0, 0]),
+            Err(FragmentError::TruncatedHeader { len: 3 })
+        );
+        assert_eq!(
+            parse_fragment(&[1, 0, 2, 2]),
+            Err(FragmentError::InvalidIndex { index: 2, count: 2 })
+        );
+    }
+
+    #[test]
+    fn out_of_order_and_interleaved() {
+        let a = fragment(1, 7, &[1; 600]).unwrap();
+        let b = fragment(2, 7, &[2; 300]).unwrap();
+        let mut reassembler = Reassembler::new(10_000);
+
+        assert_eq!(
+            reassemble(&mut reassembler, &[a[2].clone(), b[1].clone()], 0),
+            None
+        );
+        assert_eq!(reassemble(&mut reassembler, &[a[0].clone()], 0), None);
+        assert_eq!(reassembler.pending(), 2);
+        assert_eq!(
+            reassemble(&mut reassembler, &[b[0].clone()], 0),
+            Some(vec![2; 300])
+        );
+        assert_eq!(
+            reassemble(&mut reassembler, &[a[1].clone()], 0),
+            Some(vec![1; 600])
+        );
+        assert_eq!(reassembler.pending(), 0);
+    }
+
+    #[test]
+    fn incomplete_messages_time_out() {
+        let packets = fragment(1, 0, &[1; 600]).unwrap();
+        let mut reassembler = Reassembler::new(10_000);
+
+        assert_eq!(reassemble(&mut reassembler, &packets[..2], 0), None);
+        assert_eq!(reassemble(&mut reassembler, &packets[2..], 10_000), None);
+        assert_eq!(reassembler.pending(), 1);
+        assert_eq!(
+            reassemble(&mut reassembler, &packets[..2], 15_000),
+            Some(vec![1; 600])
+        );
+    }
+
+    #[test]
+    fn pending_messages_are_bounded() {
+        let mut reassembler = Reassembler::new(10_000);
+        let first = fragment(1, 0, &[1; 600]).unwrap();
+        assert_eq!(reassemble(&mut reassembler, &first[..1], 0), None);
+
+        // A flood of first fragments in the name of sender 2 only pushes out its own messages
+        for sequence in 1..=100 {
+            let packets = fragment(2, sequence, &[2; 600]).unwrap();
+            assert_eq!(
+                reassemble(&mut reassembler, &packets[..1], sequence as u64),
+                None
+            );
+        }
+        assert_eq!(reassembler.pending(), 1 + MAX_PENDING_PER_SENDER);
+        assert_eq!(
+            reassemble(&mut reassembler, &first[1..], 200),
+            Some(vec![1; 600])
+        );
+
+        // A flood from many senders pushes out the oldest messages
+        for sender_id in 10..100 {
+            let packets = fragment(sender_id, 0, &[3; 600]).unwrap();
+            reassemble(&mut reassembler, &packets[..1], 300 + sender_id as u64);
+        }
+        assert_eq!(reassembler.pending(), MAX_PENDING);
+        let newest = fragment(99, 0, &[3; 600]).unwrap();
+        assert_eq!(
+            reassemble(&mut reassembler, &newest[1..], 500),
+            Some(vec![3; 600])
+        );
+    }
+
+    #[test]
+    fn stale_fragments_are_not_mixed_in() {
+        let old = fragment(1, 0, &[1; 600]).unwrap();
+        let new = fragment(1, 0, &[2; 600]).unwrap();
+        let mut reassembler = Reassembler::new(10_000);
+
+        // The sender restarted and reused the sequence number for a message of the same size
+        assert_eq!(reassemble(&mut reassembler, &old[..2], 0), None);
+        assert_eq!(reassemble(&mut reassembler, &new[..1], 10), None);
+        assert_eq!(
+            reassemble(&mut reassembler, &new[1..], 20),
+            Some(vec![2; 600])
+        );
+        assert_eq!(reassembler.pending(), 0);
+    }
+
+    #[test]
+    fn metadata_of_used_fragments() {
+        let packets = fragment(1, 0, &[1; 600]).unwrap();
+        let decoy = fragment(1, 0, &[2; 600]).unwrap();
+        let mut reassembler = Reassembler::new(10_000);
+        let mut insert = |packet: &[u8], metadata: u32| {
+            let (header, payload) = parse_fragment(packet).unwrap();
+            reassembler.insert(header, payload, metadata, 0)
+        };
+
+        // A forged fragment makes the reassembler start over, so its metadata is dropped with it
+        assert_eq!(insert(&decoy[0], 666), None);
+        assert_eq!(insert(&packets[0], 1), None);
+        assert_eq!(insert(&packets[2], 3), None);
+        // A copy of a fragment, for example relayed by someone else, does not replace the first one
+        assert_eq!(insert(&packets[2], 333), None);
+        assert_eq!(insert(&packets[1], 2), Some((vec![1; 600], vec![1, 2, 3])));
+        assert_eq!(insert(&[1, 1, 0, 1, 5], 4), Some((vec![5], vec![4])));
+    }
+
+    proptest! {
+        #[test]
+        fn roundtrip(message in prop::collection::vec(any::<u8>(), 0..2000), sequence: u8) {
+            let mut packets = fragment(9, sequence, &message).unwrap();
+            packets.reverse();
+            let mut reassembler = Reassembler::new(1);
+            prop_assert_eq!(reassemble(&mut reassembler, &packets, 0), Some(message));
+        }
+    }
+}
@@ -0,0 +1,114 @@
+use crate::DecodeError;
+
+/// Version of the message layout produced by this crate, sent both in the clear-text envelope
+/// header and in the frame header. Increment this whenever a change is made which an older
+/// receiver would not be able to parse.
+pub const PROTOCOL_VERSION: u8 = 7;
+
+/// Number of bytes in front of the payload of every frame.
+pub const HEADER_SIZE: usize = 8;
+
+/// Kind of message carried in a frame. New kinds are appended with a new value, which older
+/// receivers report as [`DecodeError::UnknownMessageType`] and can skip.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MessageType {
+    Mppt = 0,
+    Sweep = 1,
+}
+
+impl MessageType {
+    pub fn from_u8(value: u8) -> Option<Self> {
+        match value {
+            0 => Some(Self::Mppt),
+            1 => Some(Self::Sweep),
+            _ => None,
+        }
+    }
+}
+
+/// Bit flags describing how the payload is encoded.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Flags(pub u8);
+
+impl Flags {
+    /// The measurement points are delta and zigzag varint encoded.
+    pub const COMPRESSED: Flags = Flags(0b0000_0001);
+
+    /// The sender waits for an acknowledgement and retransmits the message if none arrives, see
+    /// [`crate::ack`].
+    pub const ACK_REQUESTED: Flags = Flags(0b0000_0010);
+
+    /// Flags understood by this version of the crate. Frames with any other flag set are
+    /// rejected, since the flag might change the meaning of the payload.
+    pub const KNOWN: Flags = Flags(Self::COMPRESSED.0 | Self::ACK_REQUESTED.0);
+
+    pub fn contains(self, other: Flags) -> bool {
+        self.0 & other.0 == other.0
+    }
+}
+
+impl core::ops::BitOr for Flags {
+    type Output = Flags;
+
+    fn bitor(self, rhs: Flags) -> Flags {
+        Flags(self.0 | rhs.0)
+    }
+}
+
+/// Header in front of every frame.
+///
+/// | byte | content           |
+/// |------|-------------------|
+/// | 0    | protocol version  |
+/// | 1    | message type      |
+/// | 2    | flags             |
+/// | 3    | sender ID         |
+/// | 4-7  | sender uptime     |
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Header {
+    pub version: u8,
+    pub message_type: u8,
+    pub flags: Flags,
+    pub sender_id: u8,
+    /// Sender uptime in milliseconds when the frame was sent, see [`crate::set_uptime`]. Wraps
+    /// around after 49 days.
+    pub uptime_ms: u32,
+}
+
+impl Header {
+    pub fn to_bytes(&self) -> [u8; HEADER_SIZE] {
+        let uptime = self.uptime_ms.to_be_bytes();
+        [
+            self.version,
+            self.message_type,
+            self.flags.0,
+            self.sender_id,
+            uptime[0],
+            uptime[1],
+            uptime[2],
+            uptime[3],
+        ]
+    }
+
+    /// Splits a message into its header and payload. Only the length is checked, so that the
+    /// caller can inspect the header of frames it does not understand.
+    pub fn parse(bytes: &[u8]) -> Result<(Header, &[u8]), DecodeError> {
+        if bytes.is_empty() {
+            return Err(DecodeError::Empty);
+        }
+        if bytes.len() < HEADER_SIZE {
+            return Err(DecodeError::TruncatedHeader { len: bytes.len() });
+        }
+        let (header, payload) = bytes.split_at(HEADER_SIZE);
+        Ok((
+            Header {
+                version: header[0],
+                message_type: header[1],
+                flags: Flags(header[2]),
+                sender_id: header[3],
+                uptime_ms: u32::from_be_bytes([header[4], header[5], header[6], header[7]]),
+            },
+            payload,
+        ))
+    }
+}
@@ -0,0 +1,223 @@
+//! Encryption keys and key rotation.
+//!
+//! Keys are provisioned at runtime, through the web interface of the receiver or the serial
+//! console of a sender, and are entered as 64 hexadecimal characters together with a key epoch.
+//! The epoch is sent in clear text with every message, see [`crate::envelope`], and tells the
+//! receiver which key the message was encrypted with.
+//!
+//! To rotate the key of a sender, first provision the new key with a new epoch on the receiver.
+//! The receiver keeps the previous key in its [`KeyRing`], so it can decrypt messages from the
+//! sender both before and after the sender is given the new key.
+
+use alloc::vec::Vec;
+use core::fmt;
+
+/// Size of an AES-256 key in bytes.
+pub const KEY_SIZE: usize = 32;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum KeyError {
+    /// The key did not consist of exactly `expected` characters.
+    InvalidLength { len: usize, expected: usize },
+    /// The key contained a character which is not a hexadecimal digit.
+    InvalidCharacter(char),
+}
+
+impl fmt::Display for KeyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::InvalidLength { len, expected } => write!(
+                f,
+                "key has {len} characters, expected {expected} hexadecimal characters"
+            ),
+            Self::InvalidCharacter(c) => write!(f, "key contains {c:?}, which is not hexadecimal"),
+        }
+    }
+}
+
+/// Parses a key written as hexadecimal characters. Surrounding whitespace is ignored.
+pub fn parse_key(hex: &str) -> Result<[u8; KEY_SIZE], KeyError> {
+    parse_hex(hex)
+}
+
+/// Parses `N` bytes written as hexadecimal characters, most significant first. Used for keys and
+/// identifiers of other sizes, such as the LoRaWAN credentials. Surrounding whitespace is ignored.
+pub fn parse_hex<const N: usize>(hex: &str) -> Result<[u8; N], KeyError> {
+    let hex = hex.trim();
+    if hex.chars().count() != 2 * N {
+        return Err(KeyError::InvalidLength {
+            len: hex.chars().count(),
+            expected: 2 * N,
+        });
+    }
+    if let Some(c) = hex.chars().find(|c| !c.is_ascii_hexdigit()) {
+        return Err(KeyError::InvalidCharacter(c));
+    }
+
+    let mut key = [0; N];
+    for (byte, pair) in key.iter_mut().zip(hex.as_bytes().chunks_exact(2)) {
+        // Both characters are ASCII hex digits, checked above
+        let pair = core::str::from_utf8(pair).unwrap();
+        *byte = u8::from_str_radix(pair, 16).unwrap();
+    }
+    Ok(key)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct EpochKey {
+    pub epoch: u8,
+    pub key: [u8; KEY_SIZE],
+}
+
+const EPOCH_KEY_SIZE: usize = 1 + KEY_SIZE;
+
+impl EpochKey {
+    fn to_bytes(self) -> [u8; EPOCH_KEY_SIZE] {
+        let mut bytes = [0; EPOCH_KEY_SIZE];
+        bytes[0] = self.epoch;
+        bytes[1..].copy_from_slice(&self.key);
+        bytes
+    }
+
+    fn from_bytes(bytes: &[u8]) -> Self {
+        Self {
+            epoch: bytes[0],
+            key: bytes[1..].try_into().unwrap(),
+        }
+    }
+}
+
+/// The current and previous key of a sender.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct KeyRing {
+    pub current: Option<EpochKey>,
+    pub previous: Option<EpochKey>,
+}
+
+impl KeyRing {
+    /// Largest number of bytes returned by [`KeyRing::to_bytes`].
+    pub const MAX_SIZE: usize = 2 * EPOCH_KEY_SIZE;
+
+    /// Returns the key for an epoch, if it is the current or previous one.
+    pub fn get(&self, epoch: u8) -> Option<&[u8; KEY_SIZE]> {
+        [self.current.as_ref(), self.previous.as_ref()]
+            .into_iter()
+            .flatten()
+            .find(|k| k.epoch == epoch)
+            .map(|k| &k.key)
+    }
+
+    /// Makes `key` the current key. The current key becomes the previous one, unless it has the
+    /// same epoch, in which case it is replaced.
+    pub fn install(&mut self, key: EpochKey) {
+        match self.current {
+            Some(current) if current.epoch != key.epoch => self.previous = Some(current),
+            _ => {}
+        }
+        if self.previous.map(|p| p.epoch) == Some(key.epoch) {
+            self.previous = None;
+        }
+        self.current = Some(key);
+    }
+
+    /// Serializes the ring for persistent storage, current key first.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        [self.current, self.previous]
+            .into_iter()
+            .flatten()
+            .flat_map(EpochKey::to_bytes)
+            .collect()
+    }
+
+    /// Parses the output of [`KeyRing::to_bytes`]. Returns `None` if the length is invalid.
+    pub fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        if bytes.len() > Self::MAX_SIZE || bytes.len() % EPOCH_KEY_SIZE != 0 {
+            return None;
+        }
+        let mut keys = bytes.chunks_exact(EPOCH_KEY_SIZE).map(EpochKey::from_bytes);
+        Some(Self {
+            current: keys.next(),
+            previous: keys.next(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_hex() {
+        let hex = "000102030405060708090a0b0c0d0e0f101112131415161718191A1B1C1D1E1F\r\n";
+        let expected: Vec<u8> = (0..32).collect();
+        assert_eq!(parse_key(hex).unwrap().to_vec(), expected);
+    }
+
+    #[test]
+    fn rejects_invalid_keys() {
+        assert_eq!(
+            parse_key("0011"),
+            Err(KeyError::InvalidLength {
+                len: 4,
+                expected: 64
+            })
+        );
+        assert_eq!(parse_hex::<2>("0011"), Ok([0x00, 0x11]));
+        assert_eq!(
+            parse_key(&"g".repeat(64)),
+            Err(KeyError::InvalidCharacter('g'))
+        );
+        // Multi-byte characters must not be mistaken for pairs of hex digits
+        assert_eq!(
+            parse_key(&"é".repeat(64)),
+            Err(KeyError::InvalidCharacter('é'))
+        );
+    }
+
+    #[test]
+    fn rotation_keeps_previous_key() {
+        let key = |epoch, byte| EpochKey {
+            epoch,
+            key: [byte; KEY_SIZE],
+        };
+        let mut ring = KeyRing::default();
+        assert_eq!(ring.get(0), None);
+
+        ring.install(key(0, 1));
+        ring.install(key(1, 2));
+        assert_eq!(ring.get(0), Some(&[1; KEY_SIZE]));
+        assert_eq!(ring.get(1), Some(&[2; KEY_SIZE]));
+
+        // Re-entering the current epoch replaces it without dropping the previous key
+        ring.install(key(1, 3));
+        assert_eq!(ring.get(0), Some(&[1; KEY_SIZE]));
+        assert_eq!(ring.get(1), Some(&[3; KEY_SIZE]));
+
+        ring.install(key(2, 4));
+        assert_eq!(ring.get(0), None);
+        assert_eq!(ring.get(1), Some(&[3; KEY_SIZE]));
+
+        // Going back to the previous epoch must not leave two keys with the same epoch
+        ring.install(key(1, 5));
+        assert_eq!(ring.current, Some(key(1, 5)));
+        assert_eq!(ring.previous, Some(key(2, 4)));
+    }
+
+    #[test]
+    fn ring_roundtrip() {
+        let mut ring = KeyRing::default();
+        assert_eq!(KeyRing::from_bytes(&ring.to_bytes()), Some(ring.clone()));
+        ring.install(EpochKey {
+            epoch: 7,
+            key: [7; KEY_SIZE],
+        });
+        assert_eq!(KeyRing::from_bytes(&ring.to_bytes()), Some(ring.clone()));
+        ring.install(EpochKey {
+            epoch: 8,
+            key: [8; KEY_SIZE],
+        });
+        assert_eq!(ring.to_bytes().len(), KeyRing::MAX_SIZE);
+        assert_eq!(KeyRing::from_bytes(&ring.to_bytes()), Some(ring));
+        assert_eq!(KeyRing::from_bytes(&[0; KEY_SIZE]), None);
+    }
+}
@@ -0,0 +1,50 @@
+//! Listen-before-talk on the raw link.
+//!
+//! Before a packet is sent, the radio checks the channel with channel activity detection (CAD),
+//! which finds the preamble of another LoRa packet at the same spreading factor. While the channel
+//! is busy, the sender waits for a random [`backoff_ms`] and checks again, up to [`MAX_ATTEMPTS`]
+//! times. Senders which found the channel busy at the same time then do not all start
+//! transmitting the moment it is free.
+//!
+//! CAD only detects preambles, so a packet whose preamble is already over is not noticed. This
+//! still avoids most collisions between senders which wake up at about the same time.
+
+/// Number of times the channel is checked before giving up on a packet.
+pub const MAX_ATTEMPTS: u32 = 5;
+
+/// Smallest backoff window, so short packets at a low spreading factor still spread out.
+const MIN_WINDOW_MS: u32 = 50;
+
+/// Time to wait before checking the channel again, after check number `attempt` (starting at 0)
+/// found it busy while sending a packet of `airtime_ms`. The packet on the channel probably lasts
+/// about as long as ours, so that time is always waited. On top of that comes a random part taken
+/// from `random`, whose window starts at the airtime of the packet and doubles with every attempt.
+pub fn backoff_ms(attempt: u32, airtime_ms: u32, random: u32) -> u32 {
+    let factor = 1u32 << attempt.min(MAX_ATTEMPTS);
+    let window = airtime_ms.max(MIN_WINDOW_MS).saturating_mul(factor);
+    airtime_ms.saturating_add(random % window.saturating_add(1))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn backoff_waits_for_other_packet() {
+        assert_eq!(backoff_ms(0, 100, 0), 100);
+        assert_eq!(backoff_ms(0, 100, 100), 200);
+        assert_eq!(backoff_ms(0, 100, 101), 100);
+        assert_eq!(backoff_ms(0, 10, 50), 60);
+    }
+
+    #[test]
+    fn backoff_window_grows_and_is_capped() {
+        for attempt in 0..40 {
+            let max = backoff_ms(attempt, 200, u32::MAX - 1);
+            assert!((200..=200 + 200 * 32).contains(&max));
+        }
+        assert_eq!(backoff_ms(1, 200, 400), 600);
+        assert_eq!(backoff_ms(3, 200, 1_600), 1_800);
+        assert_eq!(backoff_ms(0, u32::MAX, u32::MAX - 1), u32::MAX);
+    }
+}
@@ -0,0 +1,623 @@
+//! Wire protocol shared between the sender and the receiver.
+//!
+//! The sender encodes a [`Frame`] with [`encode`], encrypts it and transmits it over LoRa. The
+//! receiver decrypts the message and parses it with [`decode`]. Keeping both halves in this crate
+//! means the layout only has to be changed in one place.
+//!
+//! This crate does not depend on esp-idf, so the tests can be run on the host with `cargo test`
+//! from the `protocol` directory. For the same reason it also holds the logic around the protocol
+//! which does not need the hardware, such as the replay protection, the message queue of the
+//! sender, the adaptive data rate, duty cycle, frequency plans and listen-before-talk of the raw
+//! link, the MAC layer of LoRaWAN and the identifiers of the MPPT algorithms.
+//!
+//! ## Layout
+//!
+//! Every frame starts with a [`Header`] holding the protocol version, the message type, flags, the
+//! sender ID and the uptime of the sender when the frame was sent. For MPPT and sweep frames the
+//! header is followed by a big-endian u32 holding the sender uptime in milliseconds when the first
+//! point was measured, and a big-endian u16 holding the number of milliseconds between two
+//! consecutive points. MPPT frames then have a byte holding the [`mppt::Algorithm`] which chose
+//! the operating points. Then follow the measurement points, each encoded as a big-endian u16
+//! voltage followed by a big-endian u16 current.
+//!
+//! The receiver uses the two uptimes to calculate when the points were measured, see
+//! [`uptime_to_wall_time`].
+//!
+//! If [`Flags::COMPRESSED`] is set, the points are instead encoded as described in the
+//! `compression` module, which usually takes less airtime. See [`Encoding`].
+
+#![cfg_attr(not(test), no_std)]
+
+extern crate alloc;
+
+use alloc::vec::Vec;
+use core::fmt;
+
+pub mod ack;
+pub mod adr;
+pub mod airtime;
+mod compression;
+pub mod counter;
+pub mod envelope;
+pub mod fragment;
+mod header;
+pub mod key;
+pub mod lbt;
+pub mod lorawan;
+pub mod mppt;
+pub mod plan;
+pub mod queue;
+pub mod rate_limit;
+pub mod replay;
+
+pub use header::{Flags, Header, MessageType, HEADER_SIZE, PROTOCOL_VERSION};
+
+/// Largest sender ID which may be used. Higher values are reserved.
+pub const MAX_SENDER_ID: u8 = 127;
+
+const POINT_SIZE: usize = 4;
+
+/// Size of the start time and duration per point in front of the points.
+const TIMING_SIZE: usize = 6;
+
+/// A single raw ADC reading of the module voltage and current.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MeasurementPoint {
+    pub voltage: u16,
+    pub current: u16,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Frame {
+    /// Points sampled while tracking the maximum power point, oldest first.
+    Mppt {
+        sender_id: u8,
+        /// Sender uptime in milliseconds when the first point was measured.
+        started_at: u32,
+        duration_per_point: u16,
+        /// Raw value of the [`mppt::Algorithm`], which may be unknown to this version of the
+        /// crate.
+        algorithm: u8,
+        points: Vec<MeasurementPoint>,
+    },
+    /// Points of an I-V sweep, in the order they were measured.
+    Sweep {
+        sender_id: u8,
+        /// Sender uptime in milliseconds when the first point was measured.
+        started_at: u32,
+        duration_per_point: u16,
+        points: Vec<MeasurementPoint>,
+    },
+}
+
+impl Frame {
+    pub fn message_type(&self) -> MessageType {
+        match self {
+            Frame::Mppt { .. } => MessageType::Mppt,
+            Frame::Sweep { .. } => MessageType::Sweep,
+        }
+    }
+
+    pub fn sender_id(&self) -> u8 {
+        match self {
+            Frame::Mppt { sender_id, .. } | Frame::Sweep { sender_id, .. } => *sender_id,
+        }
+    }
+
+    pub fn points(&self) -> &[MeasurementPoint] {
+        match self {
+            Frame::Mppt { points, .. } | Frame::Sweep { points, .. } => points,
+        }
+    }
+
+    /// Sender uptime in milliseconds at which each point was measured.
+    pub fn point_uptimes(&self) -> impl Iterator<Item = u32> + '_ {
+        let (started_at, duration_per_point) = match self {
+            Frame::Mppt {
+                started_at,
+                duration_per_point,
+                ..
+            }
+            | Frame::Sweep {
+                started_at,
+                duration_per_point,
+                ..
+            } => (*started_at, *duration_per_point),
+        };
+        (0..self.points().len() as u32)
+            .map(move |i| started_at.wrapping_add(i.wrapping_mul(duration_per_point as u32)))
+    }
+}
+
+/// Maps a sender uptime to milliseconds since the Unix epoch.
+///
+/// `sent_uptime_ms` is the uptime in the header of the frame and `received_at_ms` the time at
+/// which the receiver got the frame. The time spent in the air is ignored. The uptime is allowed
+/// to wrap around between `uptime_ms` and `sent_uptime_ms`.
+pub fn uptime_to_wall_time(uptime_ms: u32, sent_uptime_ms: u32, received_at_ms: i64) -> i64 {
+    received_at_ms - sent_uptime_ms.wrapping_sub(uptime_ms) as i64
+}
+
+/// Replaces the sender uptime in the header of an encoded frame. A frame which waited in a queue is
+/// stamped again right before it is sent, so the receiver does not mistake the time it waited for
+/// the age of its points.
+pub fn set_uptime(message: &mut [u8], uptime_ms: u32) -> Result<(), DecodeError> {
+    let (mut header, _) = Header::parse(message)?;
+    header.uptime_ms = uptime_ms;
+    message[..HEADER_SIZE].copy_from_slice(&header.to_bytes());
+    Ok(())
+}
+
+/// How the measurement points of a frame are encoded.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Encoding {
+    /// Four bytes per point.
+    Raw,
+    /// Delta and zigzag varint encoding, signalled with [`Flags::COMPRESSED`].
+    Compressed,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum EncodeError {
+    /// The sender ID does not fit in seven bits.
+    SenderIdOutOfRange(u8),
+}
+
+impl fmt::Display for EncodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::SenderIdOutOfRange(id) => {
+                write!(f, "sender ID {id} is larger than {MAX_SENDER_ID}")
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DecodeError {
+    /// The message did not contain any bytes.
+    Empty,
+    /// The message was shorter than the header.
+    TruncatedHeader { len: usize },
+    /// The frame was produced by an incompatible version of the protocol.
+    UnsupportedVersion(u8),
+    /// The message type is not known to this version of the protocol, most likely because the
+    /// sender runs newer firmware.
+    UnknownMessageType { sender_id: u8, message_type: u8 },
+    /// The frame has flags set which this version of the protocol does not understand.
+    UnsupportedFlags(Flags),
+    /// The payload was too short to contain the start time and duration per point, or the
+    /// algorithm of an MPPT frame.
+    TruncatedPayload { len: usize },
+    /// The point data was not a whole number of 4-byte points.
+    MisalignedPoints { len: usize },
+    /// The compressed point data contained a truncated or too large varint.
+    InvalidVarint,
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Empty => write!(f, "message was empty"),
+            Self::TruncatedHeader { len } => {
+                write!(f, "message of {len} bytes is shorter than the header")
+            }
+            Self::UnsupportedVersion(version) => write!(
+                f,
+                "protocol version {version} is not supported (expected {PROTOCOL_VERSION})"
+            ),
+            Self::UnknownMessageType {
+                sender_id,
+                message_type,
+            } => write!(
+                f,
+                "unknown message type {message_type} from sender {sender_id}"
+            ),
+            Self::UnsupportedFlags(flags) => write!(f, "unsupported flags {:#010b}", flags.0),
+            Self::TruncatedPayload { len } => write!(
+                f,
+                "payload of {len} bytes is too short to contain the timing of the points"
+            ),
+            Self::MisalignedPoints { len } => write!(
+                f,
+                "point data of {len} bytes is not a multiple of {POINT_SIZE} bytes"
+            ),
+            Self::InvalidVarint => write!(f, "compressed point data is invalid"),
+        }
+    }
+}
+
+fn encode_points(message: &mut Vec<u8>, points: &[MeasurementPoint]) {
+    for point in points {
+        message.extend_from_slice(&point.voltage.to_be_bytes());
+        message.extend_from_slice(&point.current.to_be_bytes());
+    }
+}
+
+fn decode_points(bytes: &[u8]) -> Result<Vec<MeasurementPoint>, DecodeError> {
+    let chunks = bytes.chunks_exact(POINT_SIZE);
+    if !chunks.remainder().is_empty() {
+        return Err(DecodeError::MisalignedPoints { len: bytes.len() });
+    }
+
+    Ok(chunks
+        .map(|chunk| MeasurementPoint {
+            voltage: u16::from_be_bytes([chunk[0], chunk[1]]),
+            current: u16::from_be_bytes([chunk[2], chunk[3]]),
+        })
+        .collect())
+}
+
+/// Serializes a frame into the bytes which are passed on to encryption. `uptime_ms` is the current
+/// uptime of the sender, in the same clock as `started_at`.
+pub fn encode(frame: &Frame, encoding: Encoding, uptime_ms: u32) -> Result<Vec<u8>, EncodeError> {
+    encode_with_flags(frame, encoding, Flags::default(), uptime_ms)
+}
+
+/// Like [`encode`], but sets additional `flags` in the header, for example
+/// [`Flags::ACK_REQUESTED`]. The flag for the encoding is set automatically.
+pub fn encode_with_flags(
+    frame: &Frame,
+    encoding: Encoding,
+    flags: Flags,
+    uptime_ms: u32,
+) -> Result<Vec<u8>, EncodeError> {
+    let sender_id = frame.sender_id();
+    if sender_id > MAX_SENDER_ID {
+        return Err(EncodeError::SenderIdOutOfRange(sender_id));
+    }
+
+    let header = Header {
+        version: PROTOCOL_VERSION,
+        message_type: frame.message_type() as u8,
+        flags: flags
+            | match encoding {
+                Encoding::Raw => Flags::default(),
+                Encoding::Compressed => Flags::COMPRESSED,
+            },
+        sender_id,
+        uptime_ms,
+    };
+    let encode_points = match encoding {
+        Encoding::Raw => encode_points,
+        Encoding::Compressed => compression::encode_points,
+    };
+
+    let mut message =
+        Vec::with_capacity(HEADER_SIZE + TIMING_SIZE + frame.points().len() * POINT_SIZE);
+    message.extend_from_slice(&header.to_bytes());
+
+    match frame {
+        Frame::Mppt {
+            started_at,
+            duration_per_point,
+            algorithm,
+            points,
+            ..
+        } => {
+            message.extend_from_slice(&started_at.to_be_bytes());
+            message.extend_from_slice(&duration_per_point.to_be_bytes());
+            message.push(*algorithm);
+            encode_points(&mut message, points);
+        }
+        Frame::Sweep {
+            started_at,
+            duration_per_point,
+            points,
+            ..
+        } => {
+            message.extend_from_slice(&started_at.to_be_bytes());
+            message.extend_from_slice(&duration_per_point.to_be_bytes());
+            encode_points(&mut message, points);
+        }
+    }
+
+    Ok(message)
+}
+
+/// Parses decrypted bytes into a frame. The header is returned as well, since it contains the
+/// uptime of the sender which is needed to timestamp the points.
+pub fn decode(bytes: &[u8]) -> Result<(Header, Frame), DecodeError> {
+    let (header, payload) = Header::parse(bytes)?;
+
+    if header.version != PROTOCOL_VERSION {
+        return Err(DecodeError::UnsupportedVersion(header.version));
+    }
+
+    let sender_id = header.sender_id;
+    let Some(message_type) = MessageType::from_u8(header.message_type) else {
+        return Err(DecodeError::UnknownMessageType {
+            sender_id,
+            message_type: header.message_type,
+        });
+    };
+
+    if !Flags::KNOWN.contains(header.flags) {
+        return Err(DecodeError::UnsupportedFlags(header.flags));
+    }
+
+    let decode_points = if header.flags.contains(Flags::COMPRESSED) {
+        compression::decode_points
+    } else {
+        decode_points
+    };
+
+    if payload.len() < TIMING_SIZE {
+        return Err(DecodeError::TruncatedPayload { len: payload.len() });
+    }
+    let (timing, points) = payload.split_at(TIMING_SIZE);
+    let started_at = u32::from_be_bytes([timing[0], timing[1], timing[2], timing[3]]);
+    let duration_per_point = u16::from_be_bytes([timing[4], timing[5]]);
+
+    let frame = match message_type {
+        MessageType::Mppt => {
+            let Some((&algorithm, points)) = points.split_first() else {
+                return Err(DecodeError::TruncatedPayload { len: payload.len() });
+            };
+            Frame::Mppt {
+                sender_id,
+                started_at,
+                duration_per_point,
+                algorithm,
+                points: decode_points(points)?,
+            }
+        }
+        MessageType::Sweep => Frame::Sweep {
+            sender_id,
+            started_at,
+            duration_per_point,
+            points: decode_points(points)?,
+        },
+    };
+
+    Ok((header, frame))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+
+    fn point(voltage: u16, current: u16) -> MeasurementPoint {
+        MeasurementPoint { voltage, current }
+    }
+
+    #[test]
+    fn mppt_layout() {
+        let frame = Frame::Mppt {
+            sender_id: 75,
+            started_at: 0x0c0d0e0f,
+            duration_per_point: 0x0a0b,
+            algorithm: mppt::Algorithm::IncrementalConductance as u8,
+            points: vec![point(0x0102, 0x0304), point(0x0506, 0x0708)],
+        };
+        #[rustfmt::skip]
+        assert_eq!(
+            encode(&frame, Encoding::Raw, 0x11223344).unwrap(),
+            [
+                PROTOCOL_VERSION, 0, 0, 75, 0x11, 0x22, 0x33, 0x44,
+                0x0c, 0x0d, 0x0e, 0x0f, 0x0a, 0x0b,
+                1,
+                1, 2, 3, 4, 5, 6, 7, 8,
+            ]
+        );
+    }
+
+    #[test]
+    fn sweep_layout() {
+        let frame = Frame::Sweep {
+            sender_id: 75,
+            started_at: 1,
+            duration_per_point: 2,
+            points: vec![point(0x0102, 0x0304)],
+        };
+        #[rustfmt::skip]
+        assert_eq!(
+            encode(&frame, Encoding::Raw, 3).unwrap(),
+            [
+                PROTOCOL_VERSION, 1, 0, 75, 0, 0, 0, 3,
+                0, 0, 0, 1, 0, 2,
+                1, 2, 3, 4,
+            ]
+        );
+    }
+
+    #[test]
+    fn rejects_large_sender_id() {
+        let frame = Frame::Sweep {
+            sender_id: 128,
+            started_at: 0,
+            duration_per_point: 0,
+            points: vec![],
+        };
+        assert_eq!(
+            encode(&frame, Encoding::Raw, 0),
+            Err(EncodeError::SenderIdOutOfRange(128))
+        );
+    }
+
+    #[test]
+    fn decode_errors() {
+        const V: u8 = PROTOCOL_VERSION;
+        assert_eq!(decode(&[]), Err(DecodeError::Empty));
+        assert_eq!(
+            decode(&[V, 0, 0]),
+            Err(DecodeError::TruncatedHeader { len: 3 })
+        );
+        assert_eq!(
+            decode(&[V, 0, 0, 75, 0, 0, 0, 0, 1]),
+            Err(DecodeError::TruncatedPayload { len: 1 })
+        );
+        assert_eq!(
+            decode(&[V, 1, 0, 75, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1, 2, 3]),
+            Err(DecodeError::MisalignedPoints { len: 3 })
+        );
+        // MPPT frames also need the algorithm
+        assert_eq!(
+            decode(&[V, 0, 0, 75, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0]),
+            Err(DecodeError::TruncatedPayload { len: 6 })
+        );
+        assert_eq!(
+            decode(&[V, 0, 0, 75, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1, 2, 3]),
+            Err(DecodeError::MisalignedPoints { len: 3 })
+        );
+    }
+
+    #[test]
+    fn unknown_algorithm() {
+        let (_, frame) = decode(&[
+            PROTOCOL_VERSION,
+            0,
+            0,
+            75,
+            0,
+            0,
+            0,
+            0,
+            0,
+            0,
+            0,
+            0,
+            0,
+            0,
+            200,
+        ])
+        .unwrap();
+        assert!(matches!(frame, Frame::Mppt { algorithm: 200, .. }));
+        assert_eq!(mppt::Algorithm::from_u8(200), None);
+    }
+
+    #[test]
+    fn rejects_unknown_header_fields() {
+        assert_eq!(
+            decode(&[PROTOCOL_VERSION + 1, 0, 0, 75, 0, 0, 0, 0]),
+            Err(DecodeError::UnsupportedVersion(PROTOCOL_VERSION + 1))
+        );
+        assert_eq!(
+            decode(&[PROTOCOL_VERSION, 200, 0, 75, 0, 0, 0, 0]),
+            Err(DecodeError::UnknownMessageType {
+                sender_id: 75,
+                message_type: 200
+            })
+        );
+        assert_eq!(
+            decode(&[PROTOCOL_VERSION, 1, 0x80, 75, 0, 0, 0, 0]),
+            Err(DecodeError::UnsupportedFlags(Flags(0x80)))
+        );
+    }
+
+    #[test]
+    fn extra_flags() {
+        let frame = Frame::Sweep {
+            sender_id: 75,
+            started_at: 0,
+            duration_per_point: 0,
+            points: vec![point(1, 2)],
+        };
+        let bytes =
+            encode_with_flags(&frame, Encoding::Compressed, Flags::ACK_REQUESTED, 0).unwrap();
+        let (header, decoded) = decode(&bytes).unwrap();
+        assert_eq!(header.flags, Flags::ACK_REQUESTED | Flags::COMPRESSED);
+        assert_eq!(decoded, frame);
+    }
+
+    #[test]
+    fn point_timestamps() {
+        let frame = Frame::Mppt {
+            sender_id: 1,
+            started_at: u32::MAX - 1000,
+            duration_per_point: 1000,
+            algorithm: 0,
+            points: vec![point(0, 0); 3],
+        };
+        let uptimes = frame.point_uptimes().collect::<Vec<_>>();
+        assert_eq!(uptimes, [u32::MAX - 1000, u32::MAX, 999]);
+
+        // The frame was encoded 1.5 seconds after the last point and received at 10000
+        let times = uptimes
+            .into_iter()
+            .map(|uptime| uptime_to_wall_time(uptime, 2499, 10_000))
+            .collect::<Vec<_>>();
+        assert_eq!(times, [6500, 7500, 8500]);
+    }
+
+    #[test]
+    fn queued_frame_is_stamped_again() {
+        let frame = Frame::Sweep {
+            sender_id: 4,
+            started_at: 1_000,
+            duration_per_point: 10,
+            points: vec![point(1, 2)],
+        };
+        let mut message = encode(&frame, Encoding::Raw, 2_000).unwrap();
+        set_uptime(&mut message, 90_000).unwrap();
+        let (header, decoded) = decode(&message).unwrap();
+        assert_eq!(header.uptime_ms, 90_000);
+        assert_eq!(decoded, frame);
+        assert_eq!(
+            set_uptime(&mut [PROTOCOL_VERSION], 0),
+            Err(DecodeError::TruncatedHeader { len: 1 })
+        );
+    }
+
+    fn arb_points() -> impl Strategy<Value = Vec<MeasurementPoint>> {
+        prop::collection::vec(
+            (any::<u16>(), any::<u16>()).prop_map(|(voltage, current)| point(voltage, current)),
+            0..64,
+        )
+    }
+
+    fn arb_frame() -> impl Strategy<Value = Frame> {
+        (
+            any::<bool>(),
+            0..=MAX_SENDER_ID,
+            any::<u32>(),
+            any::<u16>(),
+            any::<u8>(),
+            arb_points(),
+        )
+            .prop_map(
+                |(sweep, sender_id, started_at, duration_per_point, algorithm, points)| {
+                    if sweep {
+                        Frame::Sweep {
+                            sender_id,
+                            started_at,
+                            duration_per_point,
+                            points,
+                        }
+                    } else {
+                        Frame::Mppt {
+                            sender_id,
+                            started_at,
+                            duration_per_point,
+                            algorithm,
+                            points,
+                        }
+                    }
+                },
+            )
+    }
+
+    proptest! {
+        #[test]
+        fn roundtrip(frame in arb_frame(), uptime_ms: u32) {
+            let encoded = encode(&frame, Encoding::Raw, uptime_ms).unwrap();
+            let (header, decoded) = decode(&encoded).unwrap();
+            prop_assert_eq!(header.uptime_ms, uptime_ms);
+            prop_assert_eq!(decoded, frame);
+        }
+
+        #[test]
+        fn roundtrip_compressed(frame in arb_frame(), uptime_ms: u32) {
+            let encoded = encode(&frame, Encoding::Compressed, uptime_ms).unwrap();
+            let (header, decoded) = decode(&encoded).unwrap();
+            prop_assert_eq!(header.flags, Flags::COMPRESSED);
+            prop_assert_eq!(decoded, frame);
+        }
+
+        #[test]
+        fn decode_never_panics(bytes in prop::collection::vec(any::<u8>(), 0..300)) {
+            let _ = decode(&bytes);
+        }
+    }
+}
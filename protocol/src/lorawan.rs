@@ -0,0 +1,1370 @@
+//! Parts of LoRaWAN 1.0.x Class A which do not need the radio or cryptography, for the EU868
+//! region.
+//!
+//! The firmware builds and decrypts frames with the `lorawan` crate. This module parses the
+//! decrypted downlinks, handles the MAC commands sent by the network server and keeps track of the
+//! settings they change, such as the data rate, the enabled channels and the receive windows.
+//!
+//! After every uplink the device opens two receive windows. RX1 opens
+//! [`MacState::rx1_delay_ms`] after the end of the uplink, on the uplink frequency and at a data
+//! rate which depends on the uplink data rate. RX2 opens one second later, on a fixed frequency and
+//! data rate. Both can be changed by the network server.
+
+use alloc::vec::Vec;
+use core::fmt;
+
+/// Default delay between the end of an uplink and the start of RX1.
+pub const RECEIVE_DELAY1_MS: u32 = 1_000;
+
+/// Delay between the end of a JoinRequest and the start of the first join receive window. The
+/// second window opens one second later.
+pub const JOIN_ACCEPT_DELAY1_MS: u32 = 5_000;
+
+/// Default frequency and data rate of RX2 in EU868.
+pub const RX2_FREQUENCY_HZ: u32 = 869_525_000;
+pub const RX2_DATA_RATE: u8 = 0;
+
+/// Channels which every EU868 device has, and which the network server cannot change.
+pub const DEFAULT_CHANNELS: [u32; 3] = [868_100_000, 868_300_000, 868_500_000];
+
+/// Largest number of channels a device keeps track of.
+pub const MAX_CHANNELS: usize = 16;
+
+/// Highest EU868 data rate which uses LoRa modulation. DR7 is FSK, which is not supported.
+pub const MAX_DATA_RATE: u8 = 6;
+
+/// Number of uplinks without any downlink after which the device asks the network server to
+/// respond, and the number of further uplinks after which it lowers the data rate.
+pub const ADR_ACK_LIMIT: u32 = 64;
+pub const ADR_ACK_DELAY: u32 = 32;
+
+/// Maximum EIRP in EU868. TX power index `n` is this minus `2 * n` dBm.
+pub const MAX_EIRP_DBM: i32 = 16;
+
+/// Largest number of bytes of MAC commands which fit in the FOpts field of a frame.
+pub const MAX_FOPTS_SIZE: usize = 15;
+
+/// Modulation used by a data rate.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DataRate {
+    pub spreading_factor: u8,
+    pub bandwidth_hz: u32,
+}
+
+/// Modulation of an EU868 data rate, or `None` if it is not a LoRa data rate.
+pub fn data_rate(data_rate: u8) -> Option<DataRate> {
+    let (spreading_factor, bandwidth_hz) = match data_rate {
+        0..=5 => (12 - data_rate, 125_000),
+        6 => (7, 250_000),
+        _ => return None,
+    };
+    Some(DataRate {
+        spreading_factor,
+        bandwidth_hz,
+    })
+}
+
+/// Largest application payload of an uplink at the data rate, when FOpts is empty.
+pub fn max_payload_size(data_rate: u8) -> usize {
+    match data_rate {
+        0..=2 => 51,
+        3 => 115,
+        _ => 222,
+    }
+}
+
+/// Transmit power in dBm for a TX power index, or `None` if the index is not defined in EU868.
+pub fn tx_power_dbm(index: u8) -> Option<i32> {
+    (index <= 7).then(|| MAX_EIRP_DBM - 2 * index as i32)
+}
+
+/// Message type from the MHDR of a frame.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MType {
+    JoinRequest = 0,
+    JoinAccept = 1,
+    UnconfirmedUp = 2,
+    UnconfirmedDown = 3,
+    ConfirmedUp = 4,
+    ConfirmedDown = 5,
+    RejoinRequest = 6,
+    Proprietary = 7,
+}
+
+impl MType {
+    pub fn from_mhdr(mhdr: u8) -> Self {
+        match mhdr >> 5 {
+            0 => Self::JoinRequest,
+            1 => Self::JoinAccept,
+            2 => Self::UnconfirmedUp,
+            3 => Self::UnconfirmedDown,
+            4 => Self::ConfirmedUp,
+            5 => Self::ConfirmedDown,
+            6 => Self::RejoinRequest,
+            _ => Self::Proprietary,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum LorawanError {
+    /// The frame is shorter than its header and MIC, or than FOpts claims.
+    Truncated { len: usize },
+    /// The frame is not a data downlink.
+    NotADownlink(MType),
+    /// The frame uses a major version other than LoRaWAN R1.
+    UnsupportedMajor(u8),
+}
+
+impl fmt::Display for LorawanError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Truncated { len } => write!(f, "frame of {len} bytes is truncated"),
+            Self::NotADownlink(mtype) => write!(f, "expected a data downlink, got {mtype:?}"),
+            Self::UnsupportedMajor(major) => write!(f, "unsupported major version {major}"),
+        }
+    }
+}
+
+/// Fields of a data downlink. The frame must already have been decrypted, since a FRMPayload on
+/// port 0 holds MAC commands.
+///
+/// | bytes   | content                                  |
+/// |---------|------------------------------------------|
+/// | 0       | MHDR                                     |
+/// | 1-4     | DevAddr, little-endian                   |
+/// | 5       | FCtrl                                    |
+/// | 6-7     | FCnt, little-endian                      |
+/// | 8-      | FOpts, 0-15 bytes as given by FCtrl      |
+/// |         | FPort and FRMPayload, if there is a port |
+/// | last 4  | MIC                                      |
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Downlink<'a> {
+    pub confirmed: bool,
+    pub dev_addr: u32,
+    pub adr: bool,
+    /// The network server received the last confirmed uplink.
+    pub ack: bool,
+    /// The network server has more downlinks queued.
+    pub frame_pending: bool,
+    /// Lower 16 bits of the downlink frame counter.
+    pub fcnt: u16,
+    pub fopts: &'a [u8],
+    pub port: Option<u8>,
+    pub payload: &'a [u8],
+}
+
+impl<'a> Downlink<'a> {
+    pub fn parse(phy: &'a [u8]) -> Result<Self, LorawanError> {
+        const MIN_SIZE: usize = 12;
+        if phy.len() < MIN_SIZE {
+            return Err(LorawanError::Truncated { len: phy.len() });
+        }
+        let mhdr = phy[0];
+        let confirmed = match MType::from_mhdr(mhdr) {
+            MType::UnconfirmedDown => false,
+            MType::ConfirmedDown => true,
+            mtype => return Err(LorawanError::NotADownlink(mtype)),
+        };
+        if mhdr & 0b11 != 0 {
+            return Err(LorawanError::UnsupportedMajor(mhdr & 0b11));
+        }
+
+        let fctrl = phy[5];
+        let fopts_end = 8 + (fctrl & 0x0f) as usize;
+        let mac_payload_end = phy.len() - 4;
+        if fopts_end > mac_payload_end {
+            return Err(LorawanError::Truncated { len: phy.len() });
+        }
+        let (port, payload) = if fopts_end < mac_payload_end {
+            (Some(phy[fopts_end]), &phy[fopts_end + 1..mac_payload_end])
+        } else {
+            (None, &[][..])
+        };
+
+        Ok(Downlink {
+            confirmed,
+            dev_addr: u32::from_le_bytes([phy[1], phy[2], phy[3], phy[4]]),
+            adr: fctrl & 0x80 != 0,
+            ack: fctrl & 0x20 != 0,
+            frame_pending: fctrl & 0x10 != 0,
+            fcnt: u16::from_le_bytes([phy[6], phy[7]]),
+            fopts: &phy[8..fopts_end],
+            port,
+            payload,
+        })
+    }
+
+    /// MAC commands of the downlink, which are either in FOpts or in the payload on port 0.
+    pub fn mac_commands(&self) -> Vec<MacCommand> {
+        if self.port == Some(0) {
+            parse_mac_commands(self.payload)
+        } else {
+            parse_mac_commands(self.fopts)
+        }
+    }
+}
+
+/// Only the lower 16 bits of the frame counter are sent. Returns the full counter of a received
+/// frame, which is the smallest one above `last` with the received lower bits.
+pub fn expand_fcnt(last: Option<u32>, received: u16) -> u32 {
+    let Some(last) = last else {
+        return received as u32;
+    };
+    let candidate = (last & 0xffff_0000) | received as u32;
+    if candidate > last {
+        candidate
+    } else {
+        candidate.wrapping_add(0x1_0000)
+    }
+}
+
+/// A MAC command sent by the network server.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MacCommand {
+    LinkCheckAns {
+        margin: u8,
+        gateway_count: u8,
+    },
+    LinkAdrReq {
+        data_rate: u8,
+        tx_power: u8,
+        channel_mask: u16,
+        channel_mask_control: u8,
+        nb_trans: u8,
+    },
+    DutyCycleReq {
+        max_duty_cycle: u8,
+    },
+    RxParamSetupReq {
+        rx1_dr_offset: u8,
+        rx2_data_rate: u8,
+        frequency_hz: u32,
+    },
+    DevStatusReq,
+    NewChannelReq {
+        index: u8,
+        frequency_hz: u32,
+        min_data_rate: u8,
+        max_data_rate: u8,
+    },
+    RxTimingSetupReq {
+        delay_s: u8,
+    },
+    TxParamSetupReq {
+        eirp_dwell_time: u8,
+    },
+    DlChannelReq {
+        index: u8,
+        frequency_hz: u32,
+    },
+    DeviceTimeAns {
+        seconds: u32,
+        fraction: u8,
+    },
+}
+
+/// Frequencies are sent as a 24-bit little-endian multiple of 100 Hz.
+fn frequency(bytes: &[u8]) -> u32 {
+    u32::from_le_bytes([bytes[0], bytes[1], bytes[2], 0]) * 100
+}
+
+/// Parses MAC commands sent by the network server. The length of a command depends on its ID, so
+/// parsing stops at the first unknown or truncated command.
+pub fn parse_mac_commands(mut bytes: &[u8]) -> Vec<MacCommand> {
+    let mut commands = Vec::new();
+    while let Some((&cid, rest)) = bytes.split_first() {
+        let len = match cid {
+            0x02 => 2,
+            0x03 => 4,
+            0x04 => 1,
+            0x05 => 4,
+            0x06 => 0,
+            0x07 => 5,
+            0x08 => 1,
+            0x09 => 1,
+            0x0a => 4,
+            0x0d => 5,
+            _ => break,
+        };
+        if rest.len() < len {
+            break;
+        }
+        let (p, rest) = rest.split_at(len);
+        commands.push(match cid {
+            0x02 => MacCommand::LinkCheckAns {
+                margin: p[0],
+                gateway_count: p[1],
+            },
+            0x03 => MacCommand::LinkAdrReq {
+                data_rate: p[0] >> 4,
+                tx_power: p[0] & 0x0f,
+                channel_mask: u16::from_le_bytes([p[1], p[2]]),
+                channel_mask_control: (p[3] >> 4) & 0x07,
+                nb_trans: p[3] & 0x0f,
+            },
+            0x04 => MacCommand::DutyCycleReq {
+                max_duty_cycle: p[0] & 0x0f,
+            },
+            0x05 => MacCommand::RxParamSetupReq {
+                rx1_dr_offset: (p[0] >> 4) & 0x07,
+                rx2_data_rate: p[0] & 0x0f,
+                frequency_hz: frequency(&p[1..]),
+            },
+            0x06 => MacCommand::DevStatusReq,
+            0x07 => MacCommand::NewChannelReq {
+                index: p[0],
+                frequency_hz: frequency(&p[1..]),
+                min_data_rate: p[4] & 0x0f,
+                max_data_rate: p[4] >> 4,
+            },
+            0x08 => MacCommand::RxTimingSetupReq {
+                delay_s: p[0] & 0x0f,
+            },
+            0x09 => MacCommand::TxParamSetupReq {
+                eirp_dwell_time: p[0],
+            },
+            0x0a => MacCommand::DlChannelReq {
+                index: p[0],
+                frequency_hz: frequency(&p[1..]),
+            },
+            _ => MacCommand::DeviceTimeAns {
+                seconds: u32::from_le_bytes([p[0], p[1], p[2], p[3]]),
+                fraction: p[4],
+            },
+        });
+        bytes = rest;
+    }
+    commands
+}
+
+/// An uplink channel.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Channel {
+    pub frequency_hz: u32,
+    /// Frequency of RX1 after an uplink on this channel, if it differs from the uplink frequency.
+    pub downlink_frequency_hz: Option<u32>,
+    pub min_data_rate: u8,
+    pub max_data_rate: u8,
+}
+
+impl Channel {
+    fn supports(&self, data_rate: u8) -> bool {
+        (self.min_data_rate..=self.max_data_rate).contains(&data_rate)
+    }
+}
+
+fn valid_frequency(frequency_hz: u32) -> bool {
+    (863_000_000..=870_000_000).contains(&frequency_hz)
+}
+
+/// An answer waiting to be sent in the FOpts of the next uplink.
+#[derive(Clone)]
+struct Answer {
+    bytes: Vec<u8>,
+    /// The answer is repeated in every uplink until a downlink is received, since the network
+    /// server cannot otherwise tell whether the device applied the new receive window settings.
+    sticky: bool,
+}
+
+/// Settings of the device which are controlled by the network server through MAC commands.
+#[derive(Clone)]
+pub struct MacState {
+    pub data_rate: u8,
+    pub tx_power: u8,
+    /// Number of times each unconfirmed uplink is sent.
+    pub nb_trans: u8,
+    pub channels: [Option<Channel>; MAX_CHANNELS],
+    pub channel_mask: u16,
+    pub rx1_dr_offset: u8,
+    pub rx1_delay_ms: u32,
+    pub rx2_data_rate: u8,
+    pub rx2_frequency_hz: u32,
+    /// The device may transmit at most `1 / 2^max_duty_cycle` of the time.
+    pub max_duty_cycle: u8,
+    /// Result of the last link check, as demodulation margin in dB and number of gateways.
+    pub link_check: Option<(u8, u8)>,
+    uplinks_without_downlink: u32,
+    answers: Vec<Answer>,
+}
+
+impl Default for MacState {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl MacState {
+    /// Settings of a device which has just joined or been activated. Starts at DR5, the fastest
+    /// data rate at 125 kHz, which the network server lowers if the link needs it.
+    pub fn new() -> Self {
+        let mut channels = [None; MAX_CHANNELS];
+        for (channel, frequency_hz) in channels.iter_mut().zip(DEFAULT_CHANNELS) {
+            *channel = Some(Channel {
+                frequency_hz,
+                downlink_frequency_hz: None,
+                min_data_rate: 0,
+                max_data_rate: 5,
+            });
+        }
+        Self {
+            data_rate: 5,
+            tx_power: 0,
+            nb_trans: 1,
+            channels,
+            channel_mask: 0b111,
+            rx1_dr_offset: 0,
+            rx1_delay_ms: RECEIVE_DELAY1_MS,
+            rx2_data_rate: RX2_DATA_RATE,
+            rx2_frequency_hz: RX2_FREQUENCY_HZ,
+            max_duty_cycle: 0,
+            link_check: None,
+            uplinks_without_downlink: 0,
+            answers: Vec::new(),
+        }
+    }
+
+    /// Data rate of RX1 for an uplink at the current data rate.
+    pub fn rx1_data_rate(&self) -> u8 {
+        self.data_rate.saturating_sub(self.rx1_dr_offset)
+    }
+
+    pub fn rx2_delay_ms(&self) -> u32 {
+        self.rx1_delay_ms + 1_000
+    }
+
+    fn enabled_channels(&self, channel_mask: u16) -> impl Iterator<Item = (usize, &Channel)> {
+        self.channels
+            .iter()
+            .enumerate()
+            .filter(move |(index, _)| channel_mask & (1 << index) != 0)
+            .filter_map(|(index, channel)| Some((index, channel.as_ref()?)))
+    }
+
+    /// Picks the channel of the next uplink among the enabled channels which support the current
+    /// data rate. `random` must be a fresh random number, so that the uplinks are spread over the
+    /// channels.
+    pub fn pick_channel(&self, random: u32) -> Option<(usize, Channel)> {
+        let usable = || {
+            self.enabled_channels(self.channel_mask)
+                .filter(|(_, channel)| channel.supports(self.data_rate))
+        };
+        let count = usable().count();
+        if count == 0 {
+            return None;
+        }
+        usable()
+            .nth(random as usize % count)
+            .map(|(index, channel)| (index, *channel))
+    }
+
+    /// Applies the receive window settings and extra channels of a JoinAccept.
+    pub fn apply_join_settings(&mut self, settings: &JoinSettings) {
+        self.rx1_dr_offset = settings.rx1_dr_offset;
+        self.rx2_data_rate = settings.rx2_data_rate;
+        self.rx1_delay_ms = settings.rx1_delay_s.max(1) as u32 * 1000;
+        for (index, &frequency_hz) in settings.channels.iter().enumerate() {
+            let index = DEFAULT_CHANNELS.len() + index;
+            if frequency_hz == 0 {
+                continue;
+            }
+            self.channels[index] = Some(Channel {
+                frequency_hz,
+                downlink_frequency_hz: None,
+                min_data_rate: 0,
+                max_data_rate: 5,
+            });
+            self.channel_mask |= 1 << index;
+        }
+    }
+
+    /// Frequency of RX1 after an uplink on the channel.
+    pub fn rx1_frequency_hz(&self, channel: &Channel) -> u32 {
+        channel
+            .downlink_frequency_hz
+            .unwrap_or(channel.frequency_hz)
+    }
+
+    /// Asks the network server for the link margin in the next uplink.
+    pub fn request_link_check(&mut self) {
+        self.answers.push(Answer {
+            bytes: alloc::vec![0x02],
+            sticky: false,
+        });
+    }
+
+    /// Called before every new uplink. Returns whether the ADRACKReq bit should be set, which asks
+    /// the network server to respond since nothing has been heard from it for a while. If there is
+    /// still no response, the data rate is lowered step by step to find a working link.
+    pub fn next_uplink(&mut self, adr: bool) -> bool {
+        if !adr {
+            return false;
+        }
+        self.uplinks_without_downlink += 1;
+        if self.uplinks_without_downlink >= ADR_ACK_LIMIT + ADR_ACK_DELAY {
+            self.uplinks_without_downlink = ADR_ACK_LIMIT;
+            if self.tx_power != 0 {
+                self.tx_power = 0;
+            } else if self.data_rate > 0 {
+                self.data_rate -= 1;
+            } else {
+                // Re-enable the default channels as a last resort
+                self.channel_mask |= 0b111;
+            }
+        }
+        self.uplinks_without_downlink >= ADR_ACK_LIMIT
+    }
+
+    /// MAC command answers for the FOpts of the next uplink. Answers which do not fit are kept for
+    /// the uplink after that.
+    pub fn uplink_fopts(&mut self) -> Vec<u8> {
+        let mut fopts = Vec::new();
+        self.answers.retain(|answer| {
+            if fopts.len() + answer.bytes.len() > MAX_FOPTS_SIZE {
+                return true;
+            }
+            fopts.extend_from_slice(&answer.bytes);
+            answer.sticky
+        });
+        fopts
+    }
+
+    /// Applies the MAC commands of a downlink and queues the answers. `battery` and `snr_db`
+    /// answer a DevStatusReq, where a battery level of 0 means external power and 255 that it
+    /// cannot be measured.
+    pub fn handle_downlink(&mut self, commands: &[MacCommand], battery: u8, snr_db: i8) {
+        self.uplinks_without_downlink = 0;
+        self.answers.retain(|answer| !answer.sticky);
+
+        for command in commands {
+            let (bytes, sticky) = match *command {
+                MacCommand::LinkCheckAns {
+                    margin,
+                    gateway_count,
+                } => {
+                    self.link_check = Some((margin, gateway_count));
+                    continue;
+                }
+                MacCommand::LinkAdrReq {
+                    data_rate,
+                    tx_power,
+                    channel_mask,
+                    channel_mask_control,
+                    nb_trans,
+                } => (
+                    alloc::vec![
+                        0x03,
+                        self.link_adr(
+                            data_rate,
+                            tx_power,
+                            channel_mask,
+                            channel_mask_control,
+                            nb_trans
+                        )
+                    ],
+                    false,
+                ),
+                MacCommand::DutyCycleReq { max_duty_cycle } => {
+                    self.max_duty_cycle = max_duty_cycle;
+                    (alloc::vec![0x04], false)
+                }
+                MacCommand::RxParamSetupReq {
+                    rx1_dr_offset,
+                    rx2_data_rate,
+                    frequency_hz,
+                } => {
+                    let frequency_ok = valid_frequency(frequency_hz);
+                    let rx2_data_rate_ok = data_rate(rx2_data_rate).is_some();
+                    let rx1_dr_offset_ok = rx1_dr_offset <= 5;
+                    if frequency_ok && rx2_data_rate_ok && rx1_dr_offset_ok {
+                        self.rx1_dr_offset = rx1_dr_offset;
+                        self.rx2_data_rate = rx2_data_rate;
+                        self.rx2_frequency_hz = frequency_hz;
+                    }
+                    let status = frequency_ok as u8
+                        | (rx2_data_rate_ok as u8) << 1
+                        | (rx1_dr_offset_ok as u8) << 2;
+                    (alloc::vec![0x05, status], true)
+                }
+                MacCommand::DevStatusReq => {
+                    // The margin is a signed 6-bit value
+                    let margin = snr_db.clamp(-32, 31) as u8 & 0x3f;
+                    (alloc::vec![0x06, battery, margin], false)
+                }
+                MacCommand::NewChannelReq {
+                    index,
+                    frequency_hz,
+                    min_data_rate,
+                    max_data_rate,
+                } => {
+                    let index = index as usize;
+                    // The default channels cannot be changed
+                    let index_ok = (DEFAULT_CHANNELS.len()..MAX_CHANNELS).contains(&index);
+                    let frequency_ok =
+                        index_ok && (frequency_hz == 0 || valid_frequency(frequency_hz));
+                    let data_rate_ok = index_ok
+                        && min_data_rate <= max_data_rate
+                        && max_data_rate <= MAX_DATA_RATE;
+                    if frequency_ok && data_rate_ok {
+                        self.channels[index] = (frequency_hz != 0).then_some(Channel {
+                            frequency_hz,
+                            downlink_frequency_hz: None,
+                            min_data_rate,
+                            max_data_rate,
+                        });
+                        if frequency_hz == 0 {
+                            self.channel_mask &= !(1 << index);
+                        } else {
+                            self.channel_mask |= 1 << index;
+                        }
+                    }
+                    let status = data_rate_ok as u8 | (frequency_ok as u8) << 1;
+                    (alloc::vec![0x07, status], false)
+                }
+                MacCommand::RxTimingSetupReq { delay_s } => {
+                    self.rx1_delay_ms = delay_s.max(1) as u32 * 1000;
+                    (alloc::vec![0x08], true)
+                }
+                // Not used in EU868, where the command must be ignored without an answer
+                MacCommand::TxParamSetupReq { .. } => continue,
+                MacCommand::DlChannelReq {
+                    index,
+                    frequency_hz,
+                } => {
+                    let frequency_ok = valid_frequency(frequency_hz);
+                    let channel = self
+                        .channels
+                        .get_mut(index as usize)
+                        .and_then(Option::as_mut);
+                    let uplink_frequency_exists = channel.is_some();
+                    if let (true, Some(channel)) = (frequency_ok, channel) {
+                        channel.downlink_frequency_hz = Some(frequency_hz);
+                    }
+                    let status = frequency_ok as u8 | (uplink_frequency_exists as u8) << 1;
+                    (alloc::vec![0x0a, status], true)
+                }
+                // Only sent in response to a DeviceTimeReq, which this device does not send
+                MacCommand::DeviceTimeAns { .. } => continue,
+            };
+            self.answers.push(Answer { bytes, sticky });
+        }
+    }
+
+    /// Handles a LinkADRReq and returns the status of the answer. Nothing is changed unless every
+    /// part of the request is acceptable.
+    fn link_adr(
+        &mut self,
+        data_rate: u8,
+        tx_power: u8,
+        channel_mask: u16,
+        channel_mask_control: u8,
+        nb_trans: u8,
+    ) -> u8 {
+        let new_mask = match channel_mask_control {
+            0 => Some(channel_mask),
+            // All defined channels on, regardless of the mask
+            6 => Some(
+                self.channels
+                    .iter()
+                    .enumerate()
+                    .filter(|(_, channel)| channel.is_some())
+                    .fold(0, |mask, (index, _)| mask | 1 << index),
+            ),
+            _ => None,
+        };
+        let channel_mask_ok = new_mask.is_some_and(|mask| {
+            self.enabled_channels(mask).count() > 0
+                // Every enabled channel must be defined
+                && (0..MAX_CHANNELS)
+                    .all(|index| mask & (1 << index) == 0 || self.channels[index].is_some())
+        });
+
+        // A value of 15 keeps the current setting
+        let new_data_rate = if data_rate == 0x0f {
+            self.data_rate
+        } else {
+            data_rate
+        };
+        let new_tx_power = if tx_power == 0x0f {
+            self.tx_power
+        } else {
+            tx_power
+        };
+
+        let data_rate_ok = new_data_rate <= MAX_DATA_RATE
+            && self
+                .enabled_channels(new_mask.unwrap_or(self.channel_mask))
+                .any(|(_, channel)| channel.supports(new_data_rate));
+        let tx_power_ok = tx_power_dbm(new_tx_power).is_some();
+
+        if let (true, true, true, Some(mask)) =
+            (channel_mask_ok, data_rate_ok, tx_power_ok, new_mask)
+        {
+            self.channel_mask = mask;
+            self.data_rate = new_data_rate;
+            self.tx_power = new_tx_power;
+            self.nb_trans = nb_trans.max(1);
+        }
+
+        channel_mask_ok as u8 | (data_rate_ok as u8) << 1 | (tx_power_ok as u8) << 2
+    }
+}
+
+/// Address and keys of an activated device, from ABP provisioning or an OTAA join. The address
+/// is in the byte order of the `lorawan` crate.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SessionKeys {
+    pub dev_addr: [u8; 4],
+    pub nwk_skey: [u8; 16],
+    pub app_skey: [u8; 16],
+}
+
+impl SessionKeys {
+    pub const SIZE: usize = 36;
+
+    pub fn to_bytes(&self) -> [u8; Self::SIZE] {
+        let mut bytes = [0; Self::SIZE];
+        bytes[..4].copy_from_slice(&self.dev_addr);
+        bytes[4..20].copy_from_slice(&self.nwk_skey);
+        bytes[20..].copy_from_slice(&self.app_skey);
+        bytes
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        if bytes.len() != Self::SIZE {
+            return None;
+        }
+        Some(Self {
+            dev_addr: bytes[..4].try_into().unwrap(),
+            nwk_skey: bytes[4..20].try_into().unwrap(),
+            app_skey: bytes[20..].try_into().unwrap(),
+        })
+    }
+}
+
+/// LoRaWAN credentials of a device, as registered on the network server.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Activation {
+    /// Over-the-air activation, where the device joins the network to get its session keys.
+    Otaa {
+        dev_eui: [u8; 8],
+        join_eui: [u8; 8],
+        app_key: [u8; 16],
+    },
+    /// Activation by personalization, where the session keys are provisioned directly.
+    Abp(SessionKeys),
+}
+
+impl Activation {
+    /// Size of the largest serialized activation.
+    pub const MAX_SIZE: usize = 1 + SessionKeys::SIZE;
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(Self::MAX_SIZE);
+        match self {
+            Self::Otaa {
+                dev_eui,
+                join_eui,
+                app_key,
+            } => {
+                bytes.push(0);
+                bytes.extend_from_slice(dev_eui);
+                bytes.extend_from_slice(join_eui);
+                bytes.extend_from_slice(app_key);
+            }
+            Self::Abp(keys) => {
+                bytes.push(1);
+                bytes.extend_from_slice(&keys.to_bytes());
+            }
+        }
+        bytes
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        match bytes.split_first()? {
+            (0, rest) if rest.len() == 32 => Some(Self::Otaa {
+                dev_eui: rest[..8].try_into().unwrap(),
+                join_eui: rest[8..16].try_into().unwrap(),
+                app_key: rest[16..].try_into().unwrap(),
+            }),
+            (1, rest) => SessionKeys::from_bytes(rest).map(Self::Abp),
+            _ => None,
+        }
+    }
+}
+
+/// Settings sent by the network server in a JoinAccept, which are kept with the session keys.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct JoinSettings {
+    pub rx1_dr_offset: u8,
+    pub rx2_data_rate: u8,
+    pub rx1_delay_s: u8,
+    /// Frequencies of channels 3 to 7, or 0 for channels which are not used.
+    pub channels: [u32; 5],
+}
+
+impl JoinSettings {
+    pub const SIZE: usize = 18;
+
+    /// Parses the settings from a decrypted JoinAccept.
+    ///
+    /// | bytes  | content                                 |
+    /// |--------|-----------------------------------------|
+    /// | 0      | MHDR                                    |
+    /// | 1-3    | JoinNonce                               |
+    /// | 4-6    | NetID                                   |
+    /// | 7-10   | DevAddr                                 |
+    /// | 11     | DLSettings                              |
+    /// | 12     | RxDelay                                 |
+    /// | 13-28  | CFList with five frequencies (optional) |
+    /// | last 4 | MIC                                     |
+    pub fn from_join_accept(phy: &[u8]) -> Option<Self> {
+        match phy.len() {
+            17 | 33 => Self::from_bytes(&phy[11..phy.len() - 4]),
+            _ => None,
+        }
+    }
+
+    /// Reads the settings in the layout of the JoinAccept, with or without a CFList.
+    pub fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        if bytes.len() != 2 && bytes.len() != Self::SIZE {
+            return None;
+        }
+        let mut channels = [0; 5];
+        if bytes.len() == Self::SIZE {
+            for (channel, bytes) in channels.iter_mut().zip(bytes[2..17].chunks_exact(3)) {
+                *channel = frequency(bytes);
+            }
+        }
+        Some(Self {
+            rx1_dr_offset: (bytes[0] >> 4) & 0x07,
+            rx2_data_rate: bytes[0] & 0x0f,
+            rx1_delay_s: bytes[1] & 0x0f,
+            channels,
+        })
+    }
+
+    pub fn to_bytes(&self) -> [u8; Self::SIZE] {
+        let mut bytes = [0; Self::SIZE];
+        bytes[0] = self.rx1_dr_offset << 4 | self.rx2_data_rate;
+        bytes[1] = self.rx1_delay_s;
+        for (channel, bytes) in self.channels.iter().zip(bytes[2..17].chunks_exact_mut(3)) {
+            bytes.copy_from_slice(&(channel / 100).to_le_bytes()[..3]);
+        }
+        bytes
+    }
+}
+
+const HOUR_MS: u64 = 3_600_000;
+
+/// Start, end and allowed JoinRequest airtime of the period which `elapsed_ms` after the first
+/// JoinRequest falls in: 36 seconds in the first hour, 36 seconds in the next ten hours and 8.7
+/// seconds in every 24 hours after that.
+fn join_period(elapsed_ms: u64) -> (u64, u64, u64) {
+    if elapsed_ms < HOUR_MS {
+        (0, HOUR_MS, 36_000)
+    } else if elapsed_ms < 11 * HOUR_MS {
+        (HOUR_MS, 11 * HOUR_MS, 36_000)
+    } else {
+        let day = (elapsed_ms - 11 * HOUR_MS) / (24 * HOUR_MS);
+        let start = 11 * HOUR_MS + day * 24 * HOUR_MS;
+        (start, start + 24 * HOUR_MS, 8_700)
+    }
+}
+
+/// Decides when the next JoinRequest may be sent. Between attempts the device waits a random,
+/// growing delay, and at least 99 times the airtime of the last attempt to stay within the 1% duty
+/// cycle of the sub-band. On top of that the total airtime of JoinRequests is limited as the
+/// specification requires, see `join_period`.
+///
+/// The state is kept across reboots with [`JoinBackoff::to_bytes`], so that restarting the device
+/// does not reset the limits.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct JoinBackoff {
+    /// Time on the clock of `record_attempt` at which `elapsed_at_base_ms` had passed since the
+    /// first attempt.
+    base_ms: Option<u64>,
+    elapsed_at_base_ms: u64,
+    period_start_ms: Option<u64>,
+    period_airtime_ms: u64,
+    attempts: u32,
+    next_attempt_ms: u64,
+}
+
+impl JoinBackoff {
+    /// Size of the output of [`JoinBackoff::to_bytes`].
+    pub const SIZE: usize = 37;
+
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Time at which the next JoinRequest may be sent, on the same clock as `record_attempt`.
+    pub fn next_attempt_ms(&self) -> u64 {
+        self.next_attempt_ms
+    }
+
+    /// Data rate of the next JoinRequest. Starts at DR5 and goes down to DR0 on every failed
+    /// attempt, so that devices far from the gateway can also join.
+    pub fn data_rate(&self) -> u8 {
+        5 - (self.attempts % 6) as u8
+    }
+
+    /// Time since the first JoinRequest, or `None` before it.
+    fn elapsed_ms(&self, now_ms: u64) -> Option<u64> {
+        self.base_ms
+            .map(|base| self.elapsed_at_base_ms + now_ms.saturating_sub(base))
+    }
+
+    /// Records a JoinRequest sent at `now_ms` which took `airtime_ms` to transmit. `random` must
+    /// be a fresh random number.
+    pub fn record_attempt(&mut self, now_ms: u64, airtime_ms: u64, random: u32) {
+        if self.base_ms.is_none() {
+            self.base_ms = Some(now_ms);
+            self.elapsed_at_base_ms = 0;
+        }
+        let elapsed = self.elapsed_ms(now_ms).unwrap();
+        let (start, end, budget) = join_period(elapsed);
+        if self.period_start_ms != Some(start) {
+            self.period_start_ms = Some(start);
+            self.period_airtime_ms = 0;
+        }
+        self.period_airtime_ms += airtime_ms;
+
+        let delay = crate::ack::retry_delay_ms(self.attempts, random) as u64;
+        self.attempts += 1;
+        let mut next = now_ms + delay.max(airtime_ms * 99);
+        // Assumes that the next attempt takes as long as this one
+        if self.period_airtime_ms + airtime_ms > budget {
+            next = next.max(now_ms + (end - elapsed));
+        }
+        self.next_attempt_ms = next;
+    }
+
+    /// Serializes the state for persistent storage. Times are stored relative to `now_ms`, since
+    /// the clock starts over after a reboot.
+    pub fn to_bytes(&self, now_ms: u64) -> [u8; Self::SIZE] {
+        let mut bytes = [0; Self::SIZE];
+        bytes[0] = self.base_ms.is_some() as u8 | (self.period_start_ms.is_some() as u8) << 1;
+        bytes[1..9].copy_from_slice(&self.elapsed_ms(now_ms).unwrap_or(0).to_be_bytes());
+        bytes[9..17].copy_from_slice(&self.period_start_ms.unwrap_or(0).to_be_bytes());
+        bytes[17..25].copy_from_slice(&self.period_airtime_ms.to_be_bytes());
+        bytes[25..29].copy_from_slice(&self.attempts.to_be_bytes());
+        let wait_ms = self.next_attempt_ms.saturating_sub(now_ms);
+        bytes[29..37].copy_from_slice(&wait_ms.to_be_bytes());
+        bytes
+    }
+
+    /// Restores the output of [`JoinBackoff::to_bytes`] at `now_ms` on the new clock. The time in
+    /// between is unknown, for example while the device was switched off, so it is taken to be
+    /// zero, which never allows JoinRequests earlier than without the reboot. Returns `None` if
+    /// the length is invalid.
+    pub fn from_bytes(bytes: &[u8], now_ms: u64) -> Option<Self> {
+        let bytes: &[u8; Self::SIZE] = bytes.try_into().ok()?;
+        let u64_at = |i: usize| u64::from_be_bytes(bytes[i..i + 8].try_into().unwrap());
+        Some(Self {
+            base_ms: (bytes[0] & 0x01 != 0).then_some(now_ms),
+            elapsed_at_base_ms: u64_at(1),
+            period_start_ms: (bytes[0] & 0x02 != 0).then_some(u64_at(9)),
+            period_airtime_ms: u64_at(17),
+            attempts: u32::from_be_bytes(bytes[25..29].try_into().unwrap()),
+            next_attempt_ms: now_ms + u64_at(29),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn downlink(mhdr: u8, fctrl: u8, fopts: &[u8], port_payload: &[u8]) -> Vec<u8> {
+        let mut phy = alloc::vec![
+            mhdr,
+            0x04,
+            0x03,
+            0x02,
+            0x01,
+            fctrl | fopts.len() as u8,
+            7,
+            1
+        ];
+        phy.extend_from_slice(fopts);
+        phy.extend_from_slice(port_payload);
+        phy.extend_from_slice(&[0xaa; 4]);
+        phy
+    }
+
+    #[test]
+    fn parse_downlink() {
+        let phy = downlink(0x60, 0x20, &[0x06], &[3, 1, 2]);
+        let parsed = Downlink::parse(&phy).unwrap();
+        assert_eq!(
+            parsed,
+            Downlink {
+                confirmed: false,
+                dev_addr: 0x0102_0304,
+                adr: false,
+                ack: true,
+                frame_pending: false,
+                fcnt: 0x0107,
+                fopts: &[0x06],
+                port: Some(3),
+                payload: &[1, 2],
+            }
+        );
+        assert_eq!(parsed.mac_commands(), [MacCommand::DevStatusReq]);
+
+        // Without port
+        let phy = downlink(0xa0, 0, &[], &[]);
+        let parsed = Downlink::parse(&phy).unwrap();
+        assert!(parsed.confirmed);
+        assert_eq!(parsed.port, None);
+
+        // MAC commands on port 0
+        let phy = downlink(0x60, 0, &[], &[0, 0x06]);
+        assert_eq!(
+            Downlink::parse(&phy).unwrap().mac_commands(),
+            [MacCommand::DevStatusReq]
+        );
+
+        assert_eq!(
+            Downlink::parse(&downlink(0x40, 0, &[], &[])),
+            Err(LorawanError::NotADownlink(MType::UnconfirmedUp))
+        );
+        let mut truncated = downlink(0x60, 0, &[0x06], &[]);
+        truncated[5] = 0x0f;
+        assert_eq!(
+            Downlink::parse(&truncated),
+            Err(LorawanError::Truncated { len: 13 })
+        );
+    }
+
+    #[test]
+    fn frame_counter_expansion() {
+        assert_eq!(expand_fcnt(None, 5), 5);
+        assert_eq!(expand_fcnt(Some(5), 6), 6);
+        assert_eq!(expand_fcnt(Some(0xfffe), 2), 0x1_0002);
+        assert_eq!(expand_fcnt(Some(0x1_0002), 0x10), 0x1_0010);
+        // A repeated counter must not be accepted again
+        assert_eq!(expand_fcnt(Some(5), 5), 0x1_0005);
+    }
+
+    #[test]
+    fn parses_until_unknown_command() {
+        let bytes = [
+            0x03, 0x35, 0x07, 0x00, 0x01, // LinkADRReq
+            0x05, 0x23, 0xd2, 0xad, 0x84, // RXParamSetupReq, 869.525 MHz
+            0x06, // DevStatusReq
+            0x7f, 0x01, // unknown, stops parsing
+            0x06,
+        ];
+        assert_eq!(
+            parse_mac_commands(&bytes),
+            [
+                MacCommand::LinkAdrReq {
+                    data_rate: 3,
+                    tx_power: 5,
+                    channel_mask: 0b111,
+                    channel_mask_control: 0,
+                    nb_trans: 1,
+                },
+                MacCommand::RxParamSetupReq {
+                    rx1_dr_offset: 2,
+                    rx2_data_rate: 3,
+                    frequency_hz: 869_525_000,
+                },
+                MacCommand::DevStatusReq,
+            ]
+        );
+        // Truncated command
+        assert_eq!(
+            parse_mac_commands(&[0x06, 0x03, 0x35]),
+            [MacCommand::DevStatusReq]
+        );
+    }
+
+    #[test]
+    fn link_adr_is_applied_atomically() {
+        let mut mac = MacState::new();
+        let request = |data_rate, channel_mask| MacCommand::LinkAdrReq {
+            data_rate,
+            tx_power: 2,
+            channel_mask,
+            channel_mask_control: 0,
+            nb_trans: 2,
+        };
+
+        mac.handle_downlink(&[request(2, 0b011)], 255, 0);
+        assert_eq!(mac.uplink_fopts(), [0x03, 0b111]);
+        assert_eq!((mac.data_rate, mac.tx_power, mac.nb_trans), (2, 2, 2));
+        assert_eq!(mac.channel_mask, 0b011);
+
+        // Channel 5 is not defined, so nothing changes
+        mac.handle_downlink(&[request(4, 0b10_0001)], 255, 0);
+        assert_eq!(mac.uplink_fopts(), [0x03, 0b110]);
+        assert_eq!(mac.data_rate, 2);
+        assert_eq!(mac.channel_mask, 0b011);
+
+        // FSK is not supported
+        mac.handle_downlink(&[request(7, 0b001)], 255, 0);
+        assert_eq!(mac.uplink_fopts(), [0x03, 0b101]);
+        assert_eq!(mac.data_rate, 2);
+    }
+
+    #[test]
+    fn receive_window_answers_repeat_until_downlink() {
+        let mut mac = MacState::new();
+        mac.handle_downlink(
+            &[
+                MacCommand::RxParamSetupReq {
+                    rx1_dr_offset: 1,
+                    rx2_data_rate: 3,
+                    frequency_hz: 869_525_000,
+                },
+                MacCommand::RxTimingSetupReq { delay_s: 0 },
+                MacCommand::DevStatusReq,
+            ],
+            0,
+            -3,
+        );
+        assert_eq!(mac.rx1_data_rate(), 4);
+        assert_eq!(mac.rx2_data_rate, 3);
+        assert_eq!(mac.rx1_delay_ms, 1000);
+        assert_eq!(mac.rx2_delay_ms(), 2000);
+
+        assert_eq!(mac.uplink_fopts(), [0x05, 0b111, 0x08, 0x06, 0, 0x3d]);
+        assert_eq!(mac.uplink_fopts(), [0x05, 0b111, 0x08]);
+        mac.handle_downlink(&[], 0, 0);
+        assert_eq!(mac.uplink_fopts(), []);
+
+        // Out of band frequency
+        mac.handle_downlink(
+            &[MacCommand::RxParamSetupReq {
+                rx1_dr_offset: 0,
+                rx2_data_rate: 0,
+                frequency_hz: 915_000_000,
+            }],
+            0,
+            0,
+        );
+        assert_eq!(mac.uplink_fopts(), [0x05, 0b110]);
+        assert_eq!(mac.rx2_frequency_hz, RX2_FREQUENCY_HZ);
+    }
+
+    #[test]
+    fn answers_which_do_not_fit_are_delayed() {
+        let mut mac = MacState::new();
+        mac.handle_downlink(&[MacCommand::DevStatusReq; 6], 255, 0);
+        assert_eq!(mac.uplink_fopts().len(), 15);
+        assert_eq!(mac.uplink_fopts().len(), 3);
+        assert_eq!(mac.uplink_fopts().len(), 0);
+    }
+
+    #[test]
+    fn new_channels_are_used() {
+        let mut mac = MacState::new();
+        mac.handle_downlink(
+            &[
+                MacCommand::NewChannelReq {
+                    index: 3,
+                    frequency_hz: 867_100_000,
+                    min_data_rate: 0,
+                    max_data_rate: 5,
+                },
+                // The default channels cannot be changed
+                MacCommand::NewChannelReq {
+                    index: 0,
+                    frequency_hz: 867_300_000,
+                    min_data_rate: 0,
+                    max_data_rate: 5,
+                },
+                MacCommand::DlChannelReq {
+                    index: 3,
+                    frequency_hz: 869_100_000,
+                },
+            ],
+            255,
+            0,
+        );
+        assert_eq!(mac.uplink_fopts(), [0x07, 0b11, 0x07, 0b00, 0x0a, 0b11]);
+        assert_eq!(mac.channel_mask, 0b1111);
+
+        let frequencies: Vec<u32> = (0..4)
+            .map(|random| mac.pick_channel(random).unwrap().1.frequency_hz)
+            .collect();
+        assert_eq!(
+            frequencies,
+            [868_100_000, 868_300_000, 868_500_000, 867_100_000]
+        );
+        let (_, channel) = mac.pick_channel(3).unwrap();
+        assert_eq!(mac.rx1_frequency_hz(&channel), 869_100_000);
+
+        // Removing the channel
+        mac.handle_downlink(
+            &[MacCommand::NewChannelReq {
+                index: 3,
+                frequency_hz: 0,
+                min_data_rate: 0,
+                max_data_rate: 0,
+            }],
+            255,
+            0,
+        );
+        assert_eq!(mac.channel_mask, 0b111);
+        assert_eq!(mac.pick_channel(3).unwrap().1.frequency_hz, 868_100_000);
+    }
+
+    #[test]
+    fn data_rate_is_lowered_without_downlinks() {
+        let mut mac = MacState::new();
+        mac.tx_power = 3;
+        assert!(!mac.next_uplink(false));
+        for _ in 1..ADR_ACK_LIMIT {
+            assert!(!mac.next_uplink(true));
+        }
+        assert!(mac.next_uplink(true));
+        for _ in 1..ADR_ACK_DELAY {
+            assert!(mac.next_uplink(true));
+        }
+        assert_eq!((mac.data_rate, mac.tx_power), (5, 3));
+        assert!(mac.next_uplink(true));
+        assert_eq!((mac.data_rate, mac.tx_power), (5, 0));
+        for _ in 0..ADR_ACK_DELAY {
+            mac.next_uplink(true);
+        }
+        assert_eq!(mac.data_rate, 4);
+
+        mac.handle_downlink(&[], 255, 0);
+        assert!(!mac.next_uplink(true));
+    }
+
+    #[test]
+    fn activation_roundtrip() {
+        let keys = SessionKeys {
+            dev_addr: [1, 2, 3, 4],
+            nwk_skey: [5; 16],
+            app_skey: [6; 16],
+        };
+        let otaa = Activation::Otaa {
+            dev_eui: [7; 8],
+            join_eui: [8; 8],
+            app_key: [9; 16],
+        };
+        for activation in [otaa, Activation::Abp(keys)] {
+            let bytes = activation.to_bytes();
+            assert!(bytes.len() <= Activation::MAX_SIZE);
+            assert_eq!(Activation::from_bytes(&bytes), Some(activation));
+            assert_eq!(Activation::from_bytes(&bytes[..bytes.len() - 1]), None);
+        }
+        assert_eq!(SessionKeys::from_bytes(&keys.to_bytes()), Some(keys));
+        assert_eq!(Activation::from_bytes(&[]), None);
+    }
+
+    #[test]
+    fn join_settings() {
+        let mut phy = vec![0x20, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 0x23, 5];
+        phy.extend_from_slice(&[0x18, 0x4f, 0x84, 0xe8, 0x56, 0x84, 0, 0, 0]);
+        phy.extend_from_slice(&[0; 7]);
+        phy.extend_from_slice(&[0xaa; 4]);
+        let settings = JoinSettings::from_join_accept(&phy).unwrap();
+        assert_eq!(
+            settings,
+            JoinSettings {
+                rx1_dr_offset: 2,
+                rx2_data_rate: 3,
+                rx1_delay_s: 5,
+                channels: [867_100_000, 867_300_000, 0, 0, 0],
+            }
+        );
+        assert_eq!(
+            JoinSettings::from_bytes(&settings.to_bytes()),
+            Some(settings)
+        );
+
+        let mut mac = MacState::new();
+        mac.apply_join_settings(&settings);
+        assert_eq!(mac.rx1_delay_ms, 5000);
+        assert_eq!(mac.channel_mask, 0b11111);
+        assert_eq!(mac.pick_channel(4).unwrap().1.frequency_hz, 867_300_000);
+
+        // Without CFList
+        let short = [&phy[..13], &[0xaa; 4]].concat();
+        assert_eq!(
+            JoinSettings::from_join_accept(&short).unwrap().channels,
+            [0; 5]
+        );
+        assert_eq!(JoinSettings::from_join_accept(&phy[..20]), None);
+    }
+
+    #[test]
+    fn join_airtime_is_limited() {
+        let mut backoff = JoinBackoff::new();
+        let mut attempts = Vec::new();
+        while backoff.next_attempt_ms() < 35 * HOUR_MS {
+            let now = backoff.next_attempt_ms().max(1000);
+            attempts.push(now);
+            backoff.record_attempt(now, 1000, 12345);
+        }
+        let count = |from: u64, to: u64| {
+            attempts
+                .iter()
+                .filter(|&&t| (from * HOUR_MS..to * HOUR_MS).contains(&(t - 1000)))
+                .count()
+        };
+        assert_eq!(count(0, 1), 36);
+        assert_eq!(count(1, 11), 36);
+        assert_eq!(count(11, 35), 8);
+
+        // The duty cycle of the sub-band is respected
+        assert!(attempts.windows(2).all(|pair| pair[1] - pair[0] >= 99_000));
+    }
+
+    #[test]
+    fn join_airtime_is_limited_across_reboots() {
+        let mut backoff = JoinBackoff::new();
+        // Time since the first attempt, and the clock of the device, which starts over after
+        // every attempt
+        let (mut elapsed, mut clock) = (0, 1000);
+        let mut attempts = 0;
+        while elapsed < HOUR_MS {
+            backoff.record_attempt(clock, 1000, 12345);
+            attempts += 1;
+            elapsed += backoff.next_attempt_ms() - clock;
+            backoff = JoinBackoff::from_bytes(&backoff.to_bytes(clock), 0).unwrap();
+            clock = backoff.next_attempt_ms();
+        }
+        assert_eq!(attempts, 36);
+        assert_eq!(JoinBackoff::from_bytes(&[0; 36], 0), None);
+
+        let fresh = JoinBackoff::new();
+        assert_eq!(JoinBackoff::from_bytes(&fresh.to_bytes(0), 0), Some(fresh));
+    }
+
+    #[test]
+    fn join_data_rate_goes_down() {
+        let mut backoff = JoinBackoff::new();
+        let mut data_rates = Vec::new();
+        for attempt in 0..7 {
+            data_rates.push(backoff.data_rate());
+            backoff.record_attempt(attempt * 200_000, 100, 0);
+        }
+        assert_eq!(data_rates, [5, 4, 3, 2, 1, 0, 5]);
+    }
+
+    #[test]
+    fn data_rates() {
+        assert_eq!(
+            data_rate(0),
+            Some(DataRate {
+                spreading_factor: 12,
+                bandwidth_hz: 125_000
+            })
+        );
+        assert_eq!(data_rate(5).unwrap().spreading_factor, 7);
+        assert_eq!(data_rate(6).unwrap().bandwidth_hz, 250_000);
+        assert_eq!(data_rate(7), None);
+        assert_eq!(tx_power_dbm(0), Some(16));
+        assert_eq!(tx_power_dbm(7), Some(2));
+        assert_eq!(tx_power_dbm(8), None);
+    }
+}
@@ -0,0 +1,92 @@
+//! MPPT algorithms of the sender.
+//!
+//! The sender can track the maximum power point with different algorithms, which are chosen per
+//! device at runtime. MPPT frames carry the [`Algorithm`] which chose their operating points, so
+//! the receiver can tag the points with it and the algorithms can be compared.
+//!
+//! Over LoRaWAN, the algorithm is selected with a downlink on [`DOWNLINK_PORT`], see
+//! [`parse_downlink`].
+
+/// Algorithm used to track the maximum power point. New algorithms are appended with a new value.
+/// Frames carry the raw value, so a receiver still accepts frames of algorithms it does not know.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Algorithm {
+    /// Perturb and observe: keeps moving the operating point in the direction in which the power
+    /// increased.
+    PerturbAndObserve = 0,
+    /// Incremental conductance: moves the operating point until dI/dV equals -I/V, which is the
+    /// case at the maximum power point.
+    IncrementalConductance = 1,
+}
+
+impl Algorithm {
+    pub const ALL: [Self; 2] = [Self::PerturbAndObserve, Self::IncrementalConductance];
+
+    /// Algorithm of senders which have not been told otherwise.
+    pub const DEFAULT: Self = Self::PerturbAndObserve;
+
+    pub fn from_u8(value: u8) -> Option<Self> {
+        Self::ALL
+            .into_iter()
+            .find(|algorithm| *algorithm as u8 == value)
+    }
+
+    /// Short name, which is used to select the algorithm on the serial port of the sender and as
+    /// the tag of the points in InfluxDB.
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::PerturbAndObserve => "po",
+            Self::IncrementalConductance => "ic",
+        }
+    }
+
+    /// Parses a name as returned by [`Algorithm::name`].
+    pub fn parse(name: &str) -> Option<Self> {
+        Self::ALL
+            .into_iter()
+            .find(|algorithm| algorithm.name().eq_ignore_ascii_case(name))
+    }
+}
+
+/// LoRaWAN port of downlinks which select the algorithm of a sender.
+pub const DOWNLINK_PORT: u8 = 2;
+
+/// Parses the payload of a downlink on [`DOWNLINK_PORT`], which is the single byte of the
+/// algorithm. Returns `None` for any other payload, including algorithms this crate does not know.
+pub fn parse_downlink(payload: &[u8]) -> Option<Algorithm> {
+    match payload {
+        [value] => Algorithm::from_u8(*value),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn names_and_values() {
+        for algorithm in Algorithm::ALL {
+            assert_eq!(Algorithm::parse(algorithm.name()), Some(algorithm));
+            assert_eq!(Algorithm::from_u8(algorithm as u8), Some(algorithm));
+        }
+        assert_eq!(
+            Algorithm::parse("IC"),
+            Some(Algorithm::IncrementalConductance)
+        );
+        assert_eq!(Algorithm::parse("mppt"), None);
+        assert_eq!(Algorithm::from_u8(2), None);
+    }
+
+    #[test]
+    fn downlinks() {
+        assert_eq!(
+            parse_downlink(&[1]),
+            Some(Algorithm::IncrementalConductance)
+        );
+        assert_eq!(parse_downlink(&[0]), Some(Algorithm::PerturbAndObserve));
+        assert_eq!(parse_downlink(&[9]), None);
+        assert_eq!(parse_downlink(&[]), None);
+        assert_eq!(parse_downlink(&[0, 0]), None);
+    }
+}
@@ -0,0 +1,315 @@
+//! Frequency plans of the raw link, and how senders and receivers find each other on them.
+//!
+//! A sender picks a channel of the plan for every message with [`hop_channel`], so a busy channel
+//! or a narrow-band interferer only costs some of its messages. A receiver can only listen on one
+//! channel at a time, so it scans the channels of the plan, spending [`scan_step_ms`] on each, and
+//! stays on a channel for [`LINGER_MS`] after a packet for the rest of the message. The first
+//! packet a sender transmits on a new channel has a preamble of
+//! [`wakeup_preamble_symbols`], long enough to still be going on when the scan comes by.
+//!
+//! Alternatively, all devices can be configured to use a single fixed channel, in which case the
+//! receiver does not scan and packets have the normal preamble of [`PREAMBLE_SYMBOLS`].
+//!
+//! US915 and AS923 limit every transmission to a dwell time of 400 ms. In these plans, packets are
+//! kept short enough with [`FrequencyPlan::max_packet_size`], and the spreading factor is kept low
+//! enough with [`FrequencyPlan::max_spreading_factor`], which rules out the slow spreading factors
+//! and, since the wakeup preamble takes most of the dwell time, all but SF7 and SF8 when scanning.
+
+use crate::airtime::Modulation;
+use crate::fragment::MAX_PACKET_SIZE;
+
+/// Regional frequency plan, named like the LoRaWAN regions it takes its channels from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FrequencyPlan {
+    Eu868,
+    Us915,
+    As923,
+}
+
+impl FrequencyPlan {
+    pub const ALL: [Self; 3] = [Self::Eu868, Self::Us915, Self::As923];
+
+    /// Parses the name of a plan as returned by [`FrequencyPlan::name`]. This is a `const fn` so
+    /// the plan can be chosen at compile time.
+    pub const fn parse(name: &str) -> Option<Self> {
+        let mut i = 0;
+        while i < Self::ALL.len() {
+            if eq_ignore_ascii_case(name.as_bytes(), Self::ALL[i].name().as_bytes()) {
+                return Some(Self::ALL[i]);
+            }
+            i += 1;
+        }
+        None
+    }
+
+    pub const fn name(self) -> &'static str {
+        match self {
+            Self::Eu868 => "EU868",
+            Self::Us915 => "US915",
+            Self::As923 => "AS923",
+        }
+    }
+
+    /// Center frequencies of the 125 kHz channels.
+    pub const fn channels(self) -> &'static [u32] {
+        match self {
+            // The eight channels most LoRaWAN networks use, in sub-bands g and g1 which each have
+            // a duty cycle of 1%
+            Self::Eu868 => &[
+                867_100_000,
+                867_300_000,
+                867_500_000,
+                867_700_000,
+                867_900_000,
+                868_100_000,
+                868_300_000,
+                868_500_000,
+            ],
+            // Sub-band 2, which is what most US915 gateways listen on
+            Self::Us915 => &[
+                903_900_000,
+                904_100_000,
+                904_300_000,
+                904_500_000,
+                904_700_000,
+                904_900_000,
+                905_100_000,
+                905_300_000,
+            ],
+            Self::As923 => &[
+                923_200_000,
+                923_400_000,
+                922_000_000,
+                922_200_000,
+                922_400_000,
+                922_600_000,
+                922_800_000,
+                923_000_000,
+            ],
+        }
+    }
+
+    /// Whether transmissions are limited by the EU868 duty cycle, see [`crate::airtime`].
+    pub const fn has_duty_cycle(self) -> bool {
+        matches!(self, Self::Eu868)
+    }
+
+    /// Longest a single transmission may take, if the plan limits it.
+    pub const fn max_dwell_time_ms(self) -> Option<u64> {
+        match self {
+            Self::Eu868 => None,
+            // AS923 only has a dwell time in some countries, such as Japan, but LoRaWAN devices
+            // assume it by default
+            Self::Us915 | Self::As923 => Some(400),
+        }
+    }
+
+    /// Largest LoRa packet which can be sent at the spreading factor with the preamble, which is
+    /// 0 if not even an empty one stays within the dwell time.
+    pub fn max_packet_size(self, spreading_factor: u8, preamble_symbols: u16) -> usize {
+        let Some(dwell_time_ms) = self.max_dwell_time_ms() else {
+            return MAX_PACKET_SIZE;
+        };
+        let modulation = Modulation {
+            spreading_factor,
+            bandwidth_hz: BANDWIDTH_HZ,
+            coding_rate: 5,
+            preamble_symbols,
+            explicit_header: true,
+            crc: true,
+        };
+        // The airtime grows with the length
+        (0..=MAX_PACKET_SIZE)
+            .take_while(|&len| {
+                crate::airtime::time_on_air_us(&modulation, len) <= dwell_time_ms * 1000
+            })
+            .last()
+            .unwrap_or(0)
+    }
+
+    /// Highest spreading factor at which a packet of [`MIN_PACKET_SIZE`] bytes stays within the
+    /// dwell time, even as the first one on a new channel when there are `channel_count` channels
+    /// to scan. Returns `None` if none does, which makes the configuration unusable.
+    pub fn max_spreading_factor(self, channel_count: usize) -> Option<u8> {
+        (crate::adr::MIN_SPREADING_FACTOR..=crate::adr::MAX_SPREADING_FACTOR)
+            .rev()
+            .find(|&sf| {
+                let preamble_symbols = wakeup_preamble_symbols(sf, channel_count);
+                self.max_packet_size(sf, preamble_symbols) >= MIN_PACKET_SIZE
+            })
+    }
+}
+
+const fn eq_ignore_ascii_case(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    let mut i = 0;
+    while i < a.len() {
+        if !a[i].eq_ignore_ascii_case(&b[i]) {
+            return false;
+        }
+        i += 1;
+    }
+    true
+}
+
+/// Bandwidth of the channels of every plan.
+const BANDWIDTH_HZ: u32 = 125_000;
+
+/// Preamble of packets which the receiver is already listening for.
+pub const PREAMBLE_SYMBOLS: u16 = 8;
+
+/// Smallest packet which must fit in the dwell time. This is an acknowledgement, and as much as
+/// LoRaWAN sends at its slowest data rate in US915.
+pub const MIN_PACKET_SIZE: usize = crate::ack::ACK_SIZE;
+
+/// Symbols the receiver listens for on each channel while scanning, which the SX1276 needs to
+/// detect a preamble.
+pub const SCAN_STEP_SYMBOLS: u64 = 8;
+
+/// Time for retuning the radio on each step of the scan, and for the thread waking up late, which
+/// happens on the 10 ms tick of FreeRTOS.
+pub const SCAN_STEP_MARGIN_MS: u64 = 10;
+
+/// Time the receiver stays on a channel after a packet, so the other fragments of the message and
+/// the acknowledgement do not need a long preamble.
+pub const LINGER_MS: u64 = 2_000;
+
+/// Channel of a plan with `channel_count` channels for the given attempt at sending a message.
+/// Every sender, message and retransmission gets a different pseudo-random channel, so senders
+/// do not keep colliding with each other.
+pub fn hop_channel(sender_id: u8, sequence: u8, attempt: u8, channel_count: usize) -> usize {
+    assert!(channel_count > 0);
+    // Finalizer of MurmurHash3, so that neighbouring inputs end up on unrelated channels
+    let mut x = (sender_id as u32) << 16 | (sequence as u32) << 8 | attempt as u32;
+    x ^= x >> 16;
+    x = x.wrapping_mul(0x85eb_ca6b);
+    x ^= x >> 13;
+    x = x.wrapping_mul(0xc2b2_ae35);
+    x ^= x >> 16;
+    x as usize % channel_count
+}
+
+/// Duration of a symbol in microseconds. At 125 kHz, it lasts 2^SF / 125 ms.
+fn symbol_us(spreading_factor: u8) -> u64 {
+    (1u64 << spreading_factor) * 1_000_000 / BANDWIDTH_HZ as u64
+}
+
+/// Time the receiver listens on each channel while scanning at the spreading factor, which is
+/// [`SCAN_STEP_SYMBOLS`] symbols plus [`SCAN_STEP_MARGIN_MS`].
+pub fn scan_step_ms(spreading_factor: u8) -> u64 {
+    let symbols_us = SCAN_STEP_SYMBOLS * symbol_us(spreading_factor);
+    (symbols_us + 999) / 1000 + SCAN_STEP_MARGIN_MS
+}
+
+/// Preamble of the first packet on a new channel, which lasts for a whole scan of
+/// `channel_count` channels plus one step, so the receiver sees it however far into its scan it
+/// is. With a single channel the receiver does not scan, and the normal preamble is enough.
+pub fn wakeup_preamble_symbols(spreading_factor: u8, channel_count: usize) -> u16 {
+    if channel_count <= 1 {
+        return PREAMBLE_SYMBOLS;
+    }
+    let scan_ms = (channel_count as u64 + 1) * scan_step_ms(spreading_factor);
+    let symbol_us = symbol_us(spreading_factor);
+    let symbols = (scan_ms * 1000 + symbol_us - 1) / symbol_us + PREAMBLE_SYMBOLS as u64;
+    symbols.min(u16::MAX as u64) as u16
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_names() {
+        for plan in FrequencyPlan::ALL {
+            assert_eq!(FrequencyPlan::parse(plan.name()), Some(plan));
+            assert_eq!(plan.channels().len(), 8);
+        }
+        assert_eq!(FrequencyPlan::parse("us915"), Some(FrequencyPlan::Us915));
+        assert_eq!(FrequencyPlan::parse("EU433"), None);
+        assert_eq!(FrequencyPlan::parse(""), None);
+
+        const PLAN: Option<FrequencyPlan> = FrequencyPlan::parse("AS923");
+        assert_eq!(PLAN, Some(FrequencyPlan::As923));
+    }
+
+    #[test]
+    fn eu868_channels_have_duty_cycle() {
+        for &frequency_hz in FrequencyPlan::Eu868.channels() {
+            assert!(crate::airtime::SubBand::from_frequency(frequency_hz).is_some());
+        }
+        assert!(FrequencyPlan::Eu868.has_duty_cycle());
+        assert!(!FrequencyPlan::Us915.has_duty_cycle());
+    }
+
+    #[test]
+    fn hopping_uses_all_channels() {
+        let mut uses = [0u32; 8];
+        for sequence in 0..=255 {
+            uses[hop_channel(75, sequence, 0, 8)] += 1;
+        }
+        // 32 on average
+        assert!(uses.iter().all(|&n| (16..=48).contains(&n)), "{uses:?}");
+
+        assert_eq!(hop_channel(75, 3, 1, 8), hop_channel(75, 3, 1, 8));
+        let first = hop_channel(75, 3, 0, 8);
+        assert!((1..4).any(|attempt| hop_channel(75, 3, attempt, 8) != first));
+        assert_eq!(hop_channel(75, 3, 0, 1), 0);
+    }
+
+    #[test]
+    fn scan_step_follows_spreading_factor() {
+        // 8 symbols of 1.024 ms
+        assert_eq!(scan_step_ms(7), 9 + SCAN_STEP_MARGIN_MS);
+        // 8 symbols of 32.768 ms
+        assert_eq!(scan_step_ms(12), 263 + SCAN_STEP_MARGIN_MS);
+    }
+
+    #[test]
+    fn wakeup_preamble_covers_scan() {
+        assert_eq!(wakeup_preamble_symbols(7, 1), PREAMBLE_SYMBOLS);
+        // 9 steps of 19 ms in 1.024 ms symbols
+        assert_eq!(wakeup_preamble_symbols(7, 8), 167 + 8);
+        // 9 steps of 273 ms in 32.768 ms symbols
+        assert_eq!(wakeup_preamble_symbols(12, 8), 75 + 8);
+    }
+
+    #[test]
+    fn packets_stay_within_dwell_time() {
+        assert_eq!(FrequencyPlan::Eu868.max_dwell_time_ms(), None);
+        assert_eq!(
+            FrequencyPlan::Eu868.max_packet_size(12, 1000),
+            MAX_PACKET_SIZE
+        );
+        assert_eq!(FrequencyPlan::Eu868.max_spreading_factor(8), Some(12));
+
+        let plan = FrequencyPlan::Us915;
+        for sf in 7..=12 {
+            for preamble_symbols in [PREAMBLE_SYMBOLS, wakeup_preamble_symbols(sf, 8)] {
+                let len = plan.max_packet_size(sf, preamble_symbols);
+                let modulation = Modulation {
+                    spreading_factor: sf,
+                    bandwidth_hz: BANDWIDTH_HZ,
+                    coding_rate: 5,
+                    preamble_symbols,
+                    explicit_header: true,
+                    crc: true,
+                };
+                let airtime_us = |len| crate::airtime::time_on_air_us(&modulation, len);
+                if len > 0 {
+                    assert!(airtime_us(len) <= 400_000);
+                }
+                if len < MAX_PACKET_SIZE {
+                    assert!(airtime_us(len + 1) > 400_000);
+                }
+            }
+        }
+
+        // Like DR0 of LoRaWAN in US915
+        assert_eq!(plan.max_spreading_factor(1), Some(10));
+        // The wakeup preamble takes up most of the dwell time
+        assert_eq!(plan.max_spreading_factor(8), Some(8));
+        assert_eq!(plan.max_spreading_factor(64), None);
+    }
+}
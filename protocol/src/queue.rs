@@ -0,0 +1,239 @@
+//! Bounded first-in first-out queue of encoded frames waiting for an acknowledgement.
+//!
+//! The sender keeps every message in the queue until the receiver has acknowledged it, so that
+//! measurements are not lost while the receiver or its Wi-Fi is down. The frames are only stamped
+//! with the uptime of the sender (see [`crate::set_uptime`]) and encrypted when they are sent, so
+//! the time spent in the queue does not shift the timestamps of their points. Messages are sent
+//! oldest first, and a newer message is only sent once all older ones have been acknowledged.
+//!
+//! The queue is stored through a [`QueueStorage`], which is flash on the sender and
+//! [`MemoryStorage`] in tests. Entries are kept in a ring of `capacity` slots. When the queue is
+//! full, the oldest message is dropped to make room for the new one.
+
+use alloc::collections::BTreeMap;
+use alloc::vec::Vec;
+
+/// Position of the queue within the ring of slots.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct QueueState {
+    /// Slot holding the oldest entry.
+    pub head: u16,
+    pub len: u16,
+}
+
+impl QueueState {
+    pub fn to_bytes(self) -> [u8; 4] {
+        let head = self.head.to_be_bytes();
+        let len = self.len.to_be_bytes();
+        [head[0], head[1], len[0], len[1]]
+    }
+
+    pub fn from_bytes(bytes: [u8; 4]) -> Self {
+        Self {
+            head: u16::from_be_bytes([bytes[0], bytes[1]]),
+            len: u16::from_be_bytes([bytes[2], bytes[3]]),
+        }
+    }
+}
+
+/// Persistent storage of the queue entries and state.
+pub trait QueueStorage {
+    fn load_state(&mut self) -> Option<QueueState>;
+    fn store_state(&mut self, state: QueueState);
+    fn load(&mut self, slot: u16) -> Option<Vec<u8>>;
+    fn store(&mut self, slot: u16, entry: &[u8]);
+    fn remove(&mut self, slot: u16);
+}
+
+/// Storage which keeps the queue in RAM, for tests and for devices without persistent storage.
+#[derive(Debug, Default)]
+pub struct MemoryStorage {
+    state: Option<QueueState>,
+    entries: BTreeMap<u16, Vec<u8>>,
+}
+
+impl MemoryStorage {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl QueueStorage for MemoryStorage {
+    fn load_state(&mut self) -> Option<QueueState> {
+        self.state
+    }
+
+    fn store_state(&mut self, state: QueueState) {
+        self.state = Some(state);
+    }
+
+    fn load(&mut self, slot: u16) -> Option<Vec<u8>> {
+        self.entries.get(&slot).cloned()
+    }
+
+    fn store(&mut self, slot: u16, entry: &[u8]) {
+        self.entries.insert(slot, entry.to_vec());
+    }
+
+    fn remove(&mut self, slot: u16) {
+        self.entries.remove(&slot);
+    }
+}
+
+pub struct Queue<S> {
+    storage: S,
+    capacity: u16,
+    state: QueueState,
+}
+
+impl<S: QueueStorage> Queue<S> {
+    /// Opens the queue stored in `storage`. A stored state which does not fit `capacity`, for
+    /// example after the capacity was changed, is discarded.
+    pub fn new(mut storage: S, capacity: u16) -> Self {
+        assert!(capacity > 0);
+        let state = match storage.load_state() {
+            Some(state) if state.head < capacity && state.len <= capacity => state,
+            _ => QueueState::default(),
+        };
+        Self {
+            storage,
+            capacity,
+            state,
+        }
+    }
+
+    pub fn into_storage(self) -> S {
+        self.storage
+    }
+
+    pub fn len(&self) -> usize {
+        self.state.len as usize
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.state.len == 0
+    }
+
+    fn slot(&self, index: u16) -> u16 {
+        ((self.state.head as u32 + index as u32) % self.capacity as u32) as u16
+    }
+
+    /// Appends an entry. Returns true if the queue was full and the oldest entry was dropped.
+    pub fn push(&mut self, entry: &[u8]) -> bool {
+        let dropped = self.state.len == self.capacity;
+        if dropped {
+            self.pop_front();
+        }
+        // The entry is written before the state, so a reset in between leaves the queue intact
+        let slot = self.slot(self.state.len);
+        self.storage.store(slot, entry);
+        self.state.len += 1;
+        self.storage.store_state(self.state);
+        dropped
+    }
+
+    /// Returns the oldest entry. An entry which cannot be read from storage is skipped.
+    pub fn front(&mut self) -> Option<Vec<u8>> {
+        while !self.is_empty() {
+            if let Some(entry) = self.storage.load(self.state.head) {
+                return Some(entry);
+            }
+            self.pop_front();
+        }
+        None
+    }
+
+    /// Removes the oldest entry, once it has been acknowledged.
+    pub fn pop_front(&mut self) {
+        if self.is_empty() {
+            return;
+        }
+        let slot = self.state.head;
+        self.state.head = self.slot(1);
+        self.state.len -= 1;
+        self.storage.store_state(self.state);
+        self.storage.remove(slot);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn first_in_first_out() {
+        let mut queue = Queue::new(MemoryStorage::new(), 4);
+        assert_eq!(queue.front(), None);
+        queue.push(&[1]);
+        queue.push(&[2, 2]);
+        assert_eq!(queue.len(), 2);
+        assert_eq!(queue.front(), Some(vec![1]));
+        // Not acknowledged, so the entry stays
+        assert_eq!(queue.front(), Some(vec![1]));
+        queue.pop_front();
+        assert_eq!(queue.front(), Some(vec![2, 2]));
+        queue.pop_front();
+        assert!(queue.is_empty());
+        queue.pop_front();
+        assert!(queue.is_empty());
+    }
+
+    #[test]
+    fn full_queue_drops_oldest() {
+        let mut queue = Queue::new(MemoryStorage::new(), 3);
+        for i in 0..3 {
+            assert!(!queue.push(&[i]));
+        }
+        assert!(queue.push(&[3]));
+        assert!(queue.push(&[4]));
+        assert_eq!(queue.len(), 3);
+        let mut drained = Vec::new();
+        while let Some(entry) = queue.front() {
+            drained.push(entry[0]);
+            queue.pop_front();
+        }
+        assert_eq!(drained, [2, 3, 4]);
+        // Removed entries do not linger in storage
+        assert!(queue.into_storage().entries.is_empty());
+    }
+
+    #[test]
+    fn survives_restart() {
+        let mut queue = Queue::new(MemoryStorage::new(), 3);
+        for i in 0..5 {
+            queue.push(&[i]);
+        }
+        queue.pop_front();
+
+        let mut queue = Queue::new(queue.into_storage(), 3);
+        assert_eq!(queue.len(), 2);
+        assert_eq!(queue.front(), Some(vec![3]));
+        queue.push(&[5]);
+        queue.pop_front();
+        assert_eq!(queue.front(), Some(vec![4]));
+
+        // A state which does not fit a new capacity is discarded
+        let queue = Queue::new(queue.into_storage(), 1);
+        assert!(queue.is_empty());
+    }
+
+    #[test]
+    fn skips_unreadable_entries() {
+        let mut storage = MemoryStorage::new();
+        storage.store_state(QueueState { head: 0, len: 2 });
+        storage.store(1, &[7]);
+        let mut queue = Queue::new(storage, 4);
+        assert_eq!(queue.front(), Some(vec![7]));
+        assert_eq!(queue.len(), 1);
+    }
+
+    #[test]
+    fn state_roundtrip() {
+        let state = QueueState {
+            head: 0x0102,
+            len: 0x0304,
+        };
+        assert_eq!(state.to_bytes(), [1, 2, 3, 4]);
+        assert_eq!(QueueState::from_bytes(state.to_bytes()), state);
+    }
+}
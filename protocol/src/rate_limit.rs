@@ -0,0 +1,86 @@
+//! Per-sender rate limiting on the receiver.
+//!
+//! Decrypting a message and persisting its frame counter takes time and wears the flash, so the
+//! receiver limits how many messages it processes from each sender. The sender ID is read from the
+//! clear-text envelope header, see [`crate::envelope`], before the message is decrypted. A forged
+//! sender ID can therefore only use up the budget of that sender, not of every sender.
+//!
+//! Each sender has a bucket holding up to `burst` tokens. Processing a message takes one token, and
+//! one token is added back every `interval_ms` milliseconds.
+
+use alloc::collections::BTreeMap;
+
+/// Number of messages a sender may send in a burst. Senders normally send a message every few
+/// minutes, but empty their queue after the receiver was unreachable. They keep their own
+/// [`RateLimiter`] with the same settings, so they stop before the receiver drops their messages.
+pub const BURST: u32 = 10;
+
+/// Time after which another message is allowed.
+pub const INTERVAL_MS: u64 = 30_000;
+
+struct Bucket {
+    tokens: u32,
+    last_refill_ms: u64,
+}
+
+pub struct RateLimiter {
+    burst: u32,
+    interval_ms: u64,
+    buckets: BTreeMap<u8, Bucket>,
+}
+
+impl RateLimiter {
+    pub fn new(burst: u32, interval_ms: u64) -> Self {
+        Self {
+            burst,
+            interval_ms,
+            buckets: BTreeMap::new(),
+        }
+    }
+
+    /// Takes a token from the bucket of the sender. Returns `false` if the bucket is empty, in
+    /// which case the message should be dropped.
+    pub fn allow(&mut self, sender_id: u8, now_ms: u64) -> bool {
+        let burst = self.burst;
+        let bucket = self.buckets.entry(sender_id).or_insert(Bucket {
+            tokens: burst,
+            last_refill_ms: now_ms,
+        });
+
+        let refills = now_ms.saturating_sub(bucket.last_refill_ms) / self.interval_ms.max(1);
+        if refills > 0 {
+            bucket.tokens = (bucket.tokens as u64 + refills).min(burst as u64) as u32;
+            // Keep the remainder, so that frequent calls do not lose partial intervals
+            bucket.last_refill_ms += refills * self.interval_ms.max(1);
+        }
+
+        if bucket.tokens == 0 {
+            return false;
+        }
+        bucket.tokens -= 1;
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn limits_each_sender_separately() {
+        let mut limiter = RateLimiter::new(3, 1000);
+        assert!((0..3).all(|_| limiter.allow(1, 0)));
+        assert!(!limiter.allow(1, 0));
+        // Another sender has its own budget
+        assert!(limiter.allow(2, 0));
+
+        assert!(!limiter.allow(1, 999));
+        assert!(limiter.allow(1, 1000));
+        assert!(!limiter.allow(1, 1500));
+        assert!(limiter.allow(1, 2000));
+
+        // The bucket never holds more than the burst size
+        assert!((0..3).all(|_| limiter.allow(1, 100_000)));
+        assert!(!limiter.allow(1, 100_000));
+    }
+}
@@ -0,0 +1,44 @@
+use std::sync::Mutex;
+
+use embedded_svc::storage::RawStorage;
+use pv_protocol::counter::{CounterAllocator, CounterStore};
+
+/// Stores the end of the reserved counter block in NVS.
+struct NvsCounterStore;
+
+impl CounterStore for NvsCounterStore {
+    fn load(&mut self) -> Option<u32> {
+        let keystore_locked = crate::STORAGE.lock().unwrap();
+
+        let mut counter_target = [0; 4];
+        keystore_locked
+            .get_raw("counter", &mut counter_target)
+            .unwrap()
+            .map(|x| u32::from_be_bytes(x.try_into().unwrap()))
+    }
+
+    fn store(&mut self, value: u32) {
+        println!("Reserved frame counters up to {}", value);
+
+        crate::STORAGE
+            .lock()
+            .unwrap()
+            .set_raw("counter", &value.to_be_bytes())
+            .unwrap();
+    }
+}
+
+lazy_static::lazy_static! {
+    static ref ALLOCATOR: Mutex<CounterAllocator<NvsCounterStore>> =
+        Mutex::new(CounterAllocator::new(NvsCounterStore));
+}
+
+/// Returns the frame counter to use for the next message. A counter is never returned twice, even
+/// across reboots and deep sleep.
+pub fn get_and_increment_counter() -> u32 {
+    ALLOCATOR
+        .lock()
+        .unwrap()
+        .allocate()
+        .expect("Frame counter exhausted, the key must be replaced")
+}
@@ -0,0 +1,83 @@
+use embedded_svc::storage::RawStorage;
+use pv_protocol::key::{EpochKey, KeyRing, KEY_SIZE};
+
+/// Keys are stored per sender ID. A sender only stores its own keys, while the receiver stores the
+/// keys of every sender, so a key read from one device only compromises the messages of that
+/// sender.
+fn storage_key(sender_id: u8) -> String {
+    format!("KEYS{sender_id}")
+}
+
+/// Storage key of firmware versions before key epochs, which kept a single bare key.
+fn legacy_storage_key(sender_id: u8) -> String {
+    format!("KEY{sender_id}")
+}
+
+/// Returns the current and previous key of a sender. Keys which can not be parsed are treated as
+/// missing, so they can be provisioned again.
+pub fn get_keys(sender_id: u8) -> KeyRing {
+    migrate_legacy_key(sender_id);
+    let storage_locked = crate::STORAGE.lock().unwrap();
+    let mut target = [0; KeyRing::MAX_SIZE];
+    match storage_locked.get_raw(&storage_key(sender_id), &mut target) {
+        Ok(Some(stored)) => KeyRing::from_bytes(stored).unwrap_or_else(|| {
+            println!("Ignoring corrupt keys of sender {sender_id}");
+            KeyRing::default()
+        }),
+        Ok(None) => KeyRing::default(),
+        Err(err) => {
+            println!("Failed to read keys of sender {sender_id}: {err:?}");
+            KeyRing::default()
+        }
+    }
+}
+
+/// Converts a key stored by an older firmware version into a key ring with a key of epoch 0,
+/// which is the epoch those versions were deployed with, and erases the old key. A key ring which
+/// was already provisioned takes precedence.
+fn migrate_legacy_key(sender_id: u8) {
+    let mut storage_locked = crate::STORAGE.lock().unwrap();
+    let mut target = [0; KEY_SIZE];
+    let legacy = match storage_locked.get_raw(&legacy_storage_key(sender_id), &mut target) {
+        Ok(Some(stored)) => <[u8; KEY_SIZE]>::try_from(stored).ok(),
+        _ => return,
+    };
+    let provisioned = storage_locked
+        .contains(&storage_key(sender_id))
+        .unwrap_or(false);
+    if provisioned {
+        println!("Dropping legacy key of sender {sender_id}, it has newer keys");
+    } else if let Some(key) = legacy {
+        let mut keys = KeyRing::default();
+        keys.install(EpochKey { epoch: 0, key });
+        storage_locked
+            .set_raw(&storage_key(sender_id), &keys.to_bytes())
+            .unwrap();
+        println!("Migrated the key of sender {sender_id} to epoch 0");
+    } else {
+        println!("Dropping corrupt legacy key of sender {sender_id}");
+    }
+    storage_locked
+        .remove(&legacy_storage_key(sender_id))
+        .unwrap();
+}
+
+/// Makes `key` the current key of a sender, keeping the current key as the previous one.
+pub fn install_key(sender_id: u8, key: EpochKey) {
+    let mut keys = get_keys(sender_id);
+    keys.install(key);
+    crate::STORAGE
+        .lock()
+        .unwrap()
+        .set_raw(&storage_key(sender_id), &keys.to_bytes())
+        .unwrap();
+}
+
+/// Removes all keys of a sender.
+pub fn remove_keys(sender_id: u8) {
+    crate::STORAGE
+        .lock()
+        .unwrap()
+        .remove(&storage_key(sender_id))
+        .unwrap();
+}
@@ -1,61 +1,106 @@
 use aes_gcm::{
-    aead::{Aead, KeyInit},
+    aead::{Aead, KeyInit, Payload},
     Aes256Gcm, Nonce,
 };
+use pv_protocol::ack::{AckHeader, TAG_SIZE};
+use pv_protocol::envelope::{EnvelopeHeader, ENVELOPE_HEADER_SIZE};
+use pv_protocol::key::KEY_SIZE;
+use pv_protocol::MessageType;
 
 #[cfg(feature = "sender")]
-mod nonce;
-
-const KEY: &[u8; 32] = {
-    // Edit the following encryption key before compiling. Note that it must be 32 
-    let my_key: &[u8; 32] = &[
-        0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15, 16, 17, 18, 19, 20, 21, 22, 23, 24,
-        25, 26, 27, 28, 29, 30, 31,
-    ];
-
-    if konst::const_eq!(
-        my_key,
-        &[
-            0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15, 16, 17, 18, 19, 20, 21, 22, 23,
-            24, 25, 26, 27, 28, 29, 30, 31
-        ]
-    ) {
-        panic!("Before compiling, edit the 256-bit encryption key");
-    }
-    my_key
-};
+mod counter;
+mod keys;
+
+pub use keys::{get_keys, install_key, remove_keys};
 
-lazy_static::lazy_static! {
-    static ref CIPHER: Aes256Gcm = {
-        Aes256Gcm::new(generic_array::GenericArray::from_slice(KEY))
-    };
+fn cipher(key: &[u8; KEY_SIZE]) -> Aes256Gcm {
+    Aes256Gcm::new(generic_array::GenericArray::from_slice(key))
 }
 
+/// Length of a message of `len` bytes once it is encrypted.
 #[cfg(feature = "sender")]
-pub fn encrypt(message: &[u8]) -> Vec<u8> {
-    let nonce = nonce::get_and_increment_nonce();
+pub fn encrypted_len(len: usize) -> usize {
+    ENVELOPE_HEADER_SIZE + len + TAG_SIZE
+}
 
-    let mut nonce_bytes = [0; 12];
-    nonce_bytes[10..].copy_from_slice(&nonce.to_be_bytes());
+/// Encrypts a message which is about to be sent at `tx_power_dbm` with the current key of the
+/// sender, returning the envelope header and the encrypted message. Returns `None` if no key has been provisioned.
+#[cfg(feature = "sender")]
+pub fn encrypt(
+    sender_id: u8,
+    message_type: MessageType,
+    tx_power_dbm: u8,
+    message: &[u8],
+) -> Option<(EnvelopeHeader, Vec<u8>)> {
+    let key = get_keys(sender_id).current?;
+    let header = EnvelopeHeader::new(
+        message_type,
+        sender_id,
+        key.epoch,
+        counter::get_and_increment_counter(),
+        tx_power_dbm,
+    );
 
-    let encrypted = CIPHER
-        .encrypt(Nonce::from_slice(&nonce_bytes), message)
+    let encrypted = cipher(&key.key)
+        .encrypt(
+            Nonce::from_slice(&header.nonce()),
+            Payload {
+                msg: message,
+                aad: &header.associated_data(),
+            },
+        )
         .unwrap();
-    [nonce.to_be_bytes().to_vec(), encrypted].concat()
+    Some((header, [header.to_bytes().to_vec(), encrypted].concat()))
 }
 
-pub fn decrypt(message: &[u8]) -> Option<(u16, Vec<u8>)> {
-    if message.len() < 2 {
-        return None;
-    }
+/// Decrypts a message with the key of its sender and key epoch, returning its envelope header and
+/// the plaintext. Returns `None` if the message is too short, no key is stored for the sender and
+/// epoch or the message fails authentication.
+pub fn decrypt(message: &[u8]) -> Option<(EnvelopeHeader, Vec<u8>)> {
+    let (header, ciphertext) = EnvelopeHeader::parse(message).ok()?;
+
+    cipher(get_keys(header.sender_id).get(header.key_epoch)?)
+        .decrypt(
+            Nonce::from_slice(&header.nonce()),
+            Payload {
+                msg: ciphertext,
+                aad: &header.associated_data(),
+            },
+        )
+        .ok()
+        .map(|decrypted| (header, decrypted))
+}
 
-    let nonce = u16::from_be_bytes(message[0..2].try_into().unwrap());
+/// Builds the acknowledgement of a message, authenticated with the key the message was encrypted
+/// with. Returns `None` if that key is not stored.
+#[cfg(feature = "receiver")]
+pub fn seal_ack(header: &AckHeader) -> Option<Vec<u8>> {
+    let tag = cipher(get_keys(header.sender_id).get(header.key_epoch)?)
+        .encrypt(
+            Nonce::from_slice(&header.nonce()),
+            Payload {
+                msg: &[],
+                aad: &header.associated_data(),
+            },
+        )
+        .unwrap();
+    Some([header.to_bytes().to_vec(), tag].concat())
+}
 
-    let mut nonce_bytes = [0; 12];
-    nonce_bytes[10..].copy_from_slice(&nonce.to_be_bytes());
+/// Parses an acknowledgement and checks its tag. Returns `None` if the packet is not a valid
+/// acknowledgement made with a stored key.
+#[cfg(feature = "sender")]
+pub fn verify_ack(packet: &[u8]) -> Option<AckHeader> {
+    let (header, tag) = AckHeader::parse(packet).ok()?;
 
-    CIPHER
-        .decrypt(Nonce::from_slice(&nonce_bytes), &message[2..])
+    cipher(get_keys(header.sender_id).get(header.key_epoch)?)
+        .decrypt(
+            Nonce::from_slice(&header.nonce()),
+            Payload {
+                msg: tag,
+                aad: &header.associated_data(),
+            },
+        )
         .ok()
-        .map(|decrypted| (nonce, decrypted))
+        .map(|_| header)
 }
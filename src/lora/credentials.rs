@@ -0,0 +1,90 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use embedded_svc::storage::RawStorage;
+use pv_protocol::lorawan::{Activation, JoinBackoff, JoinSettings, SessionKeys};
+
+/// Set when the credentials change, so that the current session is dropped before the next uplink.
+static CHANGED: AtomicBool = AtomicBool::new(false);
+
+/// Returns the LoRaWAN credentials of this device, or `None` if none have been provisioned.
+pub fn get_activation() -> Option<Activation> {
+    let storage_locked = crate::STORAGE.lock().unwrap();
+    let mut target = [0; Activation::MAX_SIZE];
+    storage_locked
+        .get_raw("lwact", &mut target)
+        .unwrap()
+        .and_then(Activation::from_bytes)
+}
+
+/// Stores new LoRaWAN credentials. The session of the old credentials is forgotten, so a device
+/// using OTAA joins again before its next uplink, and the frame counters start from zero.
+pub fn set_activation(activation: Activation) {
+    {
+        let mut storage_locked = crate::STORAGE.lock().unwrap();
+        storage_locked
+            .set_raw("lwact", &activation.to_bytes())
+            .unwrap();
+        storage_locked.remove("lwsession").unwrap();
+        storage_locked.remove("lwjoin").unwrap();
+    }
+    super::lorawan::Session::reset_frame_counters();
+    CHANGED.store(true, Ordering::SeqCst);
+}
+
+/// Returns whether the credentials have changed since the last call.
+pub fn take_changed() -> bool {
+    CHANGED.swap(false, Ordering::SeqCst)
+}
+
+/// Returns the session keys and the settings from the last OTAA join, which stay valid across
+/// reboots.
+pub fn get_session() -> Option<(SessionKeys, JoinSettings)> {
+    let storage_locked = crate::STORAGE.lock().unwrap();
+    let mut target = [0; SessionKeys::SIZE];
+    let keys = storage_locked
+        .get_raw("lwsession", &mut target)
+        .unwrap()
+        .and_then(SessionKeys::from_bytes)?;
+    let mut target = [0; JoinSettings::SIZE];
+    let settings = storage_locked
+        .get_raw("lwjoin", &mut target)
+        .unwrap()
+        .and_then(JoinSettings::from_bytes)?;
+    Some((keys, settings))
+}
+
+/// Stores the session keys and settings of a successful join. The frame counters of the new
+/// session start from zero.
+pub fn set_session(keys: &SessionKeys, settings: &JoinSettings) {
+    {
+        let mut storage_locked = crate::STORAGE.lock().unwrap();
+        storage_locked
+            .set_raw("lwsession", &keys.to_bytes())
+            .unwrap();
+        storage_locked
+            .set_raw("lwjoin", &settings.to_bytes())
+            .unwrap();
+    }
+    super::lorawan::Session::reset_frame_counters();
+}
+
+/// Returns the state of the JoinRequest limits, restored at `now_ms`, so that rebooting does not
+/// allow more JoinRequests. See [`JoinBackoff::from_bytes`].
+pub fn get_join_backoff(now_ms: u64) -> JoinBackoff {
+    let storage_locked = crate::STORAGE.lock().unwrap();
+    let mut target = [0; JoinBackoff::SIZE];
+    storage_locked
+        .get_raw("lwjoinbo", &mut target)
+        .unwrap()
+        .and_then(|stored| JoinBackoff::from_bytes(stored, now_ms))
+        .unwrap_or_default()
+}
+
+/// Stores the state of the JoinRequest limits after an attempt at `now_ms`.
+pub fn set_join_backoff(backoff: &JoinBackoff, now_ms: u64) {
+    crate::STORAGE
+        .lock()
+        .unwrap()
+        .set_raw("lwjoinbo", &backoff.to_bytes(now_ms))
+        .unwrap();
+}
@@ -0,0 +1,42 @@
+//! Interrupt on the DIO0 pin of the radio, which goes high when a packet has been received (or
+//! transmitted, depending on the mapping set with `set_dio0_rx_done()` or `set_dio0_tx_done()`).
+
+use std::time::Duration;
+
+use esp_idf_hal::gpio::{AnyInputPin, Input, InterruptType, PinDriver};
+use esp_idf_sys::EspError;
+
+/// Lets a thread sleep until the radio raises DIO0, instead of polling the IRQ register over SPI.
+pub struct Dio0 {
+    pin: PinDriver<'static, AnyInputPin, Input>,
+}
+
+impl Dio0 {
+    pub fn new(pin: PinDriver<'static, AnyInputPin, Input>) -> Self {
+        Self { pin }
+    }
+
+    /// Subscribes to rising edges of the pin. The interrupt wakes the thread which called this
+    /// function, so it must be the one which calls [`Dio0::wait`]. Can be called again if it
+    /// failed.
+    pub fn subscribe(&mut self) -> Result<(), EspError> {
+        // A raw pointer is not Send, so the handle goes into the interrupt handler as a number
+        let task = esp_idf_hal::task::current().unwrap() as usize;
+        self.pin.set_interrupt_type(InterruptType::PosEdge)?;
+        // Safety: the handler only notifies a task, which is allowed in an interrupt
+        unsafe {
+            self.pin.subscribe(move || {
+                esp_idf_hal::task::notify(task as _, 1);
+            })?;
+        }
+        self.pin.enable_interrupt()
+    }
+
+    /// Blocks until DIO0 goes high or `timeout` has passed, and returns true in the first case.
+    pub fn wait(&mut self, timeout: Duration) -> Result<bool, EspError> {
+        let notified = esp_idf_hal::task::wait_notification(Some(timeout)).is_some();
+        // Newer versions of esp-idf-hal disable the interrupt after every edge
+        self.pin.enable_interrupt()?;
+        Ok(notified)
+    }
+}
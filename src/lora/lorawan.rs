@@ -0,0 +1,705 @@
+//! LoRaWAN 1.0.x Class A uplinks, which let the sender report through any LoRaWAN gateway and
+//! network server instead of only through our own receiver.
+//!
+//! Frames are built and decrypted by the `lorawan` crate. The MAC layer, which does not need the
+//! radio, is in `pv_protocol::lorawan`.
+
+use std::fmt;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use embedded_hal_0_2::blocking::delay::DelayMs;
+use embedded_hal_0_2::blocking::spi::{Transfer, Write};
+use embedded_hal_0_2::digital::v2::OutputPin;
+use embedded_svc::storage::RawStorage;
+use lorawan::parser::{AsPhyPayloadBytes, DataHeader};
+use pv_protocol::airtime::{DutyCycleError, DutyCycleLimiter, Modulation};
+use pv_protocol::counter::{CounterAllocator, CounterStore};
+use pv_protocol::lorawan::{JoinBackoff, JoinSettings, MacState, SessionKeys};
+
+use super::sx;
+
+/// Sync word of public LoRaWAN networks.
+const SYNC_WORD: u8 = 0x34;
+
+/// RX1 closes this much before RX2 opens, which leaves time to configure the radio for RX2 before
+/// its downlink starts.
+const RX_SETUP_MS: u64 = 150;
+
+/// Time to keep listening after the header of a packet was received in a window. Long enough for
+/// the largest downlink at SF12.
+const MAX_DOWNLINK_MS: u64 = 3_000;
+
+/// MHDR, DevAddr, FCtrl, FCnt, FPort and MIC of an uplink, which are sent besides FOpts and the
+/// payload.
+const UPLINK_OVERHEAD: usize = 13;
+
+/// Stores the end of the reserved block of uplink frame counters in NVS, like the frame counter of
+/// the raw link.
+struct NvsFcntStore;
+
+impl CounterStore for NvsFcntStore {
+    fn load(&mut self) -> Option<u32> {
+        let keystore_locked = crate::STORAGE.lock().unwrap();
+
+        let mut counter_target = [0; 4];
+        keystore_locked
+            .get_raw("fcntup", &mut counter_target)
+            .unwrap()
+            .map(|x| u32::from_be_bytes(x.try_into().unwrap()))
+    }
+
+    fn store(&mut self, value: u32) {
+        println!("Reserved LoRaWAN uplink frame counters up to {}", value);
+
+        crate::STORAGE
+            .lock()
+            .unwrap()
+            .set_raw("fcntup", &value.to_be_bytes())
+            .unwrap();
+    }
+}
+
+fn load_fcnt_down() -> Option<u32> {
+    let keystore_locked = crate::STORAGE.lock().unwrap();
+
+    let mut counter_target = [0; 4];
+    keystore_locked
+        .get_raw("fcntdown", &mut counter_target)
+        .unwrap()
+        .map(|x| u32::from_be_bytes(x.try_into().unwrap()))
+}
+
+#[derive(Debug)]
+pub enum LorawanError {
+    /// The payload does not fit in an uplink at the current data rate.
+    PayloadTooLarge {
+        len: usize,
+        max: usize,
+    },
+    /// No enabled channel supports the current data rate.
+    NoChannel,
+    /// Every uplink frame counter has been used. The device must join again.
+    FcntExhausted,
+    /// No acknowledgement of a confirmed uplink arrived.
+    NotAcknowledged,
+    /// The device has no session, see [`JoinError`].
+    NotJoined,
+    /// The uplink does not fit in the duty cycle of the sub-band of its channel yet.
+    DutyCycleExhausted {
+        retry_in_ms: u64,
+    },
+    /// The uplink cannot be sent on its channel at all.
+    DutyCycle(DutyCycleError),
+    Radio(String),
+}
+
+impl fmt::Display for LorawanError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::PayloadTooLarge { len, max } => {
+                write!(f, "payload of {len} bytes is larger than {max} bytes")
+            }
+            Self::NoChannel => write!(f, "no channel supports the current data rate"),
+            Self::FcntExhausted => write!(f, "frame counter exhausted, join again"),
+            Self::NotAcknowledged => write!(f, "confirmed uplink was not acknowledged"),
+            Self::NotJoined => write!(f, "not joined to a LoRaWAN network"),
+            Self::DutyCycleExhausted { retry_in_ms } => {
+                write!(
+                    f,
+                    "duty cycle exhausted, next uplink allowed in {retry_in_ms} ms"
+                )
+            }
+            Self::DutyCycle(e) => write!(f, "{e}"),
+            Self::Radio(e) => write!(f, "radio error: {e}"),
+        }
+    }
+}
+
+/// Why a device has no session yet. Apart from `NotProvisioned`, the device can try to join again
+/// later.
+#[derive(Debug)]
+pub enum JoinError {
+    /// No LoRaWAN credentials have been provisioned.
+    NotProvisioned,
+    /// The duty cycle limits of JoinRequests do not allow another attempt yet.
+    Backoff {
+        retry_in_ms: u64,
+    },
+    /// No valid JoinAccept arrived in either receive window.
+    NoJoinAccept,
+    Radio(String),
+}
+
+impl fmt::Display for JoinError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::NotProvisioned => write!(f, "no LoRaWAN credentials provisioned"),
+            Self::Backoff { retry_in_ms } => {
+                write!(f, "next JoinRequest allowed in {} s", retry_in_ms / 1000)
+            }
+            Self::NoJoinAccept => write!(f, "no JoinAccept received"),
+            Self::Radio(e) => write!(f, "radio error: {e}"),
+        }
+    }
+}
+
+impl From<LorawanError> for JoinError {
+    fn from(e: LorawanError) -> Self {
+        match e {
+            LorawanError::Radio(e) => Self::Radio(e),
+            LorawanError::DutyCycleExhausted { retry_in_ms } => Self::Backoff { retry_in_ms },
+            e => Self::Radio(e.to_string()),
+        }
+    }
+}
+
+fn radio_error(e: impl fmt::Debug) -> LorawanError {
+    LorawanError::Radio(format!("{e:?}"))
+}
+
+/// Application data sent by the network server in a downlink.
+#[derive(Debug)]
+pub struct DownlinkData {
+    pub port: u8,
+    pub payload: Vec<u8>,
+}
+
+/// State of an activated device.
+pub struct Session {
+    dev_addr: lorawan::parser::DevAddr<[u8; 4]>,
+    nwk_skey: lorawan::keys::AES128,
+    app_skey: lorawan::keys::AES128,
+    fcnt_up: CounterAllocator<NvsFcntStore>,
+    fcnt_down: Option<u32>,
+    pub mac: MacState,
+    /// A confirmed downlink was received, which is acknowledged in the next uplink.
+    ack_pending: bool,
+}
+
+impl Session {
+    /// Continues the frame counters stored in NVS, which must only be done while the session keys
+    /// stay the same. See [`Session::reset_frame_counters`].
+    pub fn new(
+        dev_addr: lorawan::parser::DevAddr<[u8; 4]>,
+        nwk_skey: lorawan::keys::AES128,
+        app_skey: lorawan::keys::AES128,
+    ) -> Self {
+        Self {
+            dev_addr,
+            nwk_skey,
+            app_skey,
+            fcnt_up: CounterAllocator::new(NvsFcntStore),
+            fcnt_down: load_fcnt_down(),
+            mac: MacState::new(),
+            ack_pending: false,
+        }
+    }
+
+    /// Forgets the stored frame counters. A join creates new session keys, after which both
+    /// counters start from zero.
+    pub fn reset_frame_counters() {
+        let mut keystore_locked = crate::STORAGE.lock().unwrap();
+        keystore_locked.remove("fcntup").unwrap();
+        keystore_locked.remove("fcntdown").unwrap();
+    }
+
+    fn set_fcnt_down(&mut self, fcnt: u32) {
+        self.fcnt_down = Some(fcnt);
+        crate::STORAGE
+            .lock()
+            .unwrap()
+            .set_raw("fcntdown", &fcnt.to_be_bytes())
+            .unwrap();
+    }
+
+    pub fn from_keys(keys: &SessionKeys) -> Self {
+        let dev_addr: lorawan::parser::DevAddr<&[u8; 4]> = (&keys.dev_addr).into();
+        Self::new(
+            dev_addr.to_owned(),
+            lorawan::keys::AES128(keys.nwk_skey),
+            lorawan::keys::AES128(keys.app_skey),
+        )
+    }
+
+    /// Checks and decrypts a downlink. Returns whether it acknowledges the last uplink and the
+    /// application data it carries, or `None` if it is not meant for this device.
+    fn handle_downlink(&mut self, phy: &[u8], snr_db: i8) -> Option<(bool, Option<DownlinkData>)> {
+        // The header is in clear text, and is needed for the full frame counter which the MIC is
+        // calculated over
+        let header = pv_protocol::lorawan::Downlink::parse(phy).ok()?;
+        let fcnt = pv_protocol::lorawan::expand_fcnt(self.fcnt_down, header.fcnt);
+
+        let lorawan::parser::PhyPayload::Data(lorawan::parser::DataPayload::Encrypted(encrypted)) =
+            lorawan::parser::parse(phy.to_vec()).ok()?
+        else {
+            return None;
+        };
+        if encrypted.fhdr().dev_addr().as_ref() != self.dev_addr.as_ref() {
+            println!("Ignoring downlink for another device");
+            return None;
+        }
+        if !encrypted.validate_mic(&self.nwk_skey, fcnt) {
+            println!("Ignoring downlink with invalid MIC");
+            return None;
+        }
+        let decrypted = encrypted
+            .decrypt(Some(&self.nwk_skey), Some(&self.app_skey), fcnt)
+            .ok()?;
+        let downlink = pv_protocol::lorawan::Downlink::parse(decrypted.as_bytes()).ok()?;
+
+        self.set_fcnt_down(fcnt);
+        self.ack_pending = downlink.confirmed;
+
+        let commands = downlink.mac_commands();
+        println!("Got downlink {fcnt} with MAC commands {commands:?}");
+        // The sender is solar powered and cannot measure a battery level
+        self.mac.handle_downlink(&commands, 255, snr_db);
+
+        let data = match downlink.port {
+            Some(port) if port > 0 => Some(DownlinkData {
+                port,
+                payload: downlink.payload.to_vec(),
+            }),
+            _ => None,
+        };
+        Some((downlink.ack, data))
+    }
+}
+
+/// Largest payload which always fits in an uplink at the current data rate, leaving room for MAC
+/// command answers.
+pub fn max_payload_size(mac: &MacState) -> usize {
+    pv_protocol::lorawan::max_payload_size(mac.data_rate) - pv_protocol::lorawan::MAX_FOPTS_SIZE
+}
+
+/// Writes MAC command answers, which are already serialized, into FOpts.
+struct RawMacCommands<'a>(&'a [u8]);
+
+impl lorawan::maccommands::SerializableMacCommand for RawMacCommands<'_> {
+    fn payload_bytes(&self) -> &[u8] {
+        &self.0[1..]
+    }
+
+    fn cid(&self) -> u8 {
+        self.0[0]
+    }
+
+    fn payload_len(&self) -> usize {
+        self.0.len() - 1
+    }
+}
+
+fn configure<SPI, CS, RESET, DELAY, E>(
+    lora: &mut sx::LoRa<SPI, CS, RESET, DELAY>,
+    frequency_hz: u32,
+    data_rate: u8,
+    downlink: bool,
+) -> Result<(), LorawanError>
+where
+    SPI: Transfer<u8, Error = E> + Write<u8, Error = E>,
+    E: fmt::Debug,
+    CS: OutputPin,
+    CS::Error: fmt::Debug,
+    RESET: OutputPin,
+    RESET::Error: fmt::Debug,
+    DELAY: DelayMs<u8>,
+{
+    let modulation = pv_protocol::lorawan::data_rate(data_rate).ok_or(LorawanError::NoChannel)?;
+    // The raw link may use FSK, and is configured again after the uplink
+    lora.set_modem(sx::Modem::LoRa).map_err(radio_error)?;
+    lora.set_mode(sx::RadioMode::Stdby).map_err(radio_error)?;
+    lora.set_frequency_hz(frequency_hz).map_err(radio_error)?;
+    lora.set_spreading_factor(modulation.spreading_factor)
+        .map_err(radio_error)?;
+    lora.set_signal_bandwidth(modulation.bandwidth_hz as i64)
+        .map_err(radio_error)?;
+    lora.set_sync_word(SYNC_WORD).map_err(radio_error)?;
+    // Downlinks are sent with inverted IQ, so that devices do not receive each other's uplinks
+    lora.set_invert_iq(downlink).map_err(radio_error)?;
+    // Downlinks carry no payload CRC
+    lora.set_crc(!downlink).map_err(radio_error)
+}
+
+/// Airtime of an uplink or JoinRequest of `len` bytes at `data_rate`.
+fn airtime_us(data_rate: u8, len: usize) -> Result<u64, LorawanError> {
+    let data_rate = pv_protocol::lorawan::data_rate(data_rate).ok_or(LorawanError::NoChannel)?;
+    let modulation = Modulation {
+        spreading_factor: data_rate.spreading_factor,
+        bandwidth_hz: data_rate.bandwidth_hz,
+        coding_rate: 5,
+        preamble_symbols: 8,
+        explicit_header: true,
+        crc: true,
+    };
+    Ok(pv_protocol::airtime::time_on_air_us(&modulation, len))
+}
+
+/// Counts a transmission against the duty cycle of the sub-band of `frequency_hz`, which the raw
+/// link shares, or fails without counting it if it does not fit yet. `since` is the start of the
+/// clock of `duty_cycle`.
+fn reserve_airtime(
+    duty_cycle: &Mutex<DutyCycleLimiter>,
+    since: Instant,
+    frequency_hz: u32,
+    airtime_us: u64,
+) -> Result<(), LorawanError> {
+    let now_ms = since.elapsed().as_millis() as u64;
+    let mut duty_cycle = duty_cycle.lock().unwrap();
+    match duty_cycle.wait_ms(now_ms, frequency_hz, airtime_us) {
+        Ok(0) => {
+            duty_cycle.record(now_ms, frequency_hz, airtime_us);
+            Ok(())
+        }
+        Ok(retry_in_ms) => Err(LorawanError::DutyCycleExhausted { retry_in_ms }),
+        Err(e) => Err(LorawanError::DutyCycle(e)),
+    }
+}
+
+fn sleep_until(instant: Instant) {
+    if let Some(remaining) = instant.checked_duration_since(Instant::now()) {
+        std::thread::sleep(remaining);
+    }
+}
+
+/// Listens from `opens_at` until `closes_at`, or until a packet whose header arrived in time has
+/// been received. Returns the packet and its SNR in dB.
+fn receive_window<SPI, CS, RESET, DELAY, E>(
+    lora: &mut sx::LoRa<SPI, CS, RESET, DELAY>,
+    frequency_hz: u32,
+    data_rate: u8,
+    opens_at: Instant,
+    mut closes_at: Instant,
+) -> Result<Option<(Vec<u8>, i8)>, LorawanError>
+where
+    SPI: Transfer<u8, Error = E> + Write<u8, Error = E>,
+    E: fmt::Debug,
+    CS: OutputPin,
+    CS::Error: fmt::Debug,
+    RESET: OutputPin,
+    RESET::Error: fmt::Debug,
+    DELAY: DelayMs<u8>,
+{
+    configure(lora, frequency_hz, data_rate, true)?;
+    sleep_until(opens_at);
+    lora.clear_irq().map_err(radio_error)?;
+    lora.set_mode(sx::RadioMode::RxContinuous)
+        .map_err(radio_error)?;
+
+    let mut receiving = false;
+    let received = loop {
+        match lora.rx_status().map_err(radio_error)? {
+            sx::RxStatus::Received(size) => {
+                let packet = lora.read_packet().map_err(radio_error)?[..size].to_vec();
+                // The register holds the SNR in quarters of a dB
+                let snr = lora.get_packet_snr().map_err(radio_error)? as u8 as i8 / 4;
+                break Some((packet, snr));
+            }
+            sx::RxStatus::Receiving if !receiving => {
+                receiving = true;
+                closes_at = closes_at.max(Instant::now() + Duration::from_millis(MAX_DOWNLINK_MS));
+            }
+            _ if Instant::now() >= closes_at => break None,
+            _ => {}
+        }
+        std::thread::sleep(Duration::from_millis(5));
+    };
+    lora.set_mode(sx::RadioMode::Stdby).map_err(radio_error)?;
+    Ok(received)
+}
+
+/// Sends an uplink and listens for a downlink in RX1 and RX2. Unconfirmed uplinks are sent as
+/// often as the network server asked for with NbTrans, and confirmed uplinks up to `retries` more
+/// times until they are acknowledged. Every transmission uses the same frame counter.
+///
+/// Every transmission is counted against `duty_cycle`, whose clock starts at `since`. Fails
+/// without changing the session if the first one does not fit, and stops early if a repetition
+/// does not fit.
+///
+/// Leaves the radio configured for LoRaWAN.
+#[allow(clippy::too_many_arguments)]
+pub fn uplink<SPI, CS, RESET, DELAY, E>(
+    lora: &mut sx::LoRa<SPI, CS, RESET, DELAY>,
+    duty_cycle: &Mutex<DutyCycleLimiter>,
+    since: Instant,
+    session: &mut Session,
+    port: u8,
+    payload: &[u8],
+    confirmed: bool,
+    retries: u8,
+) -> Result<Option<DownlinkData>, LorawanError>
+where
+    SPI: Transfer<u8, Error = E> + Write<u8, Error = E>,
+    E: fmt::Debug,
+    CS: OutputPin,
+    CS::Error: fmt::Debug,
+    RESET: OutputPin,
+    RESET::Error: fmt::Debug,
+    DELAY: DelayMs<u8>,
+{
+    // The MAC state only moves on to the next uplink once the payload is known to fit
+    let mut mac = session.mac.clone();
+    let adr_ack_req = mac.next_uplink(true);
+    let fopts = mac.uplink_fopts();
+    let max = pv_protocol::lorawan::max_payload_size(mac.data_rate) - fopts.len();
+    if payload.len() > max {
+        return Err(LorawanError::PayloadTooLarge {
+            len: payload.len(),
+            max,
+        });
+    }
+    let airtime_us = airtime_us(mac.data_rate, UPLINK_OVERHEAD + fopts.len() + payload.len())?;
+    let random = unsafe { esp_idf_sys::esp_random() };
+    let (_, first_channel) = mac.pick_channel(random).ok_or(LorawanError::NoChannel)?;
+    reserve_airtime(duty_cycle, since, first_channel.frequency_hz, airtime_us)?;
+    session.mac = mac;
+    let fcnt = session
+        .fcnt_up
+        .allocate()
+        .map_err(|_| LorawanError::FcntExhausted)?;
+
+    // ADR is always enabled, since the network server knows the link budget best
+    let mut fctrl = 0x80;
+    if adr_ack_req {
+        fctrl |= 0x40;
+    }
+    if std::mem::take(&mut session.ack_pending) {
+        fctrl |= 0x20;
+    }
+
+    let mac_commands = RawMacCommands(&fopts);
+    let mut mac_command_list: Vec<&dyn lorawan::maccommands::SerializableMacCommand> = vec![];
+    if !fopts.is_empty() {
+        mac_command_list.push(&mac_commands);
+    }
+
+    let mut phy = lorawan::creator::DataPayloadCreator::new();
+    phy.set_confirmed(confirmed)
+        .set_uplink(true)
+        .set_f_port(port)
+        .set_dev_addr(session.dev_addr)
+        .set_fctrl(&lorawan::parser::FCtrl::new(fctrl, true))
+        .set_fcnt(fcnt);
+    let packet = phy
+        .build(
+            payload,
+            &mac_command_list,
+            &session.nwk_skey,
+            &session.app_skey,
+        )
+        .unwrap()
+        .to_vec();
+
+    let mut buffer = [0; 255];
+    buffer[..packet.len()].copy_from_slice(&packet);
+
+    let transmissions = if confirmed {
+        retries as u32 + 1
+    } else {
+        session.mac.nb_trans as u32
+    };
+    for transmission in 0..transmissions {
+        if transmission > 0 && confirmed {
+            // ACK_TIMEOUT of the specification, 1-3 seconds
+            let random = unsafe { esp_idf_sys::esp_random() };
+            std::thread::sleep(Duration::from_millis(1_000 + (random % 2_000) as u64));
+        }
+
+        let channel = if transmission == 0 {
+            first_channel
+        } else {
+            let random = unsafe { esp_idf_sys::esp_random() };
+            let (_, channel) = session
+                .mac
+                .pick_channel(random)
+                .ok_or(LorawanError::NoChannel)?;
+            match reserve_airtime(duty_cycle, since, channel.frequency_hz, airtime_us) {
+                Ok(()) => channel,
+                // The network server got the first transmission, or at least it was sent
+                Err(_) if !confirmed => break,
+                Err(e) => return Err(e),
+            }
+        };
+        let tx_power = pv_protocol::lorawan::tx_power_dbm(session.mac.tx_power).unwrap();
+
+        configure(lora, channel.frequency_hz, session.mac.data_rate, false)?;
+        lora.set_tx_power(tx_power, 1).map_err(radio_error)?;
+
+        println!(
+            "Sending LoRaWAN uplink {fcnt} of {} bytes on {} Hz at DR{}",
+            packet.len(),
+            channel.frequency_hz,
+            session.mac.data_rate
+        );
+        lora.transmit_payload(buffer, packet.len())
+            .map_err(radio_error)?;
+        while lora.transmitting().map_err(radio_error)? {}
+        let sent_at = Instant::now();
+
+        let rx1_at = sent_at + Duration::from_millis(session.mac.rx1_delay_ms as u64);
+        let rx2_at = sent_at + Duration::from_millis(session.mac.rx2_delay_ms() as u64);
+        let mut received = receive_window(
+            lora,
+            session.mac.rx1_frequency_hz(&channel),
+            session.mac.rx1_data_rate(),
+            rx1_at,
+            rx2_at - Duration::from_millis(RX_SETUP_MS),
+        )?;
+        if received.is_none() {
+            received = receive_window(
+                lora,
+                session.mac.rx2_frequency_hz,
+                session.mac.rx2_data_rate,
+                rx2_at,
+                rx2_at + Duration::from_millis(1_000),
+            )?;
+        }
+
+        let Some((ack, data)) =
+            received.and_then(|(packet, snr)| session.handle_downlink(&packet, snr))
+        else {
+            continue;
+        };
+        // Any downlink means the network server got the uplink, so it is not repeated
+        if ack || !confirmed {
+            return Ok(data);
+        }
+    }
+
+    if confirmed {
+        Err(LorawanError::NotAcknowledged)
+    } else {
+        Ok(None)
+    }
+}
+
+/// Sends a single JoinRequest and listens for the JoinAccept in both join receive windows. Fails
+/// with [`JoinError::Backoff`] without using the radio if `backoff` does not allow an attempt yet.
+/// The JoinRequest is counted against `duty_cycle` as well, which the raw link and uplinks share.
+/// `since` is the start of the clock of `backoff` and `duty_cycle`.
+///
+/// Leaves the radio configured for LoRaWAN.
+pub fn join<SPI, CS, RESET, DELAY, E>(
+    lora: &mut sx::LoRa<SPI, CS, RESET, DELAY>,
+    duty_cycle: &Mutex<DutyCycleLimiter>,
+    backoff: &mut JoinBackoff,
+    since: Instant,
+    dev_eui: &[u8; 8],
+    join_eui: &[u8; 8],
+    app_key: &[u8; 16],
+) -> Result<(SessionKeys, JoinSettings), JoinError>
+where
+    SPI: Transfer<u8, Error = E> + Write<u8, Error = E>,
+    E: fmt::Debug,
+    CS: OutputPin,
+    CS::Error: fmt::Debug,
+    RESET: OutputPin,
+    RESET::Error: fmt::Debug,
+    DELAY: DelayMs<u8>,
+{
+    let now_ms = since.elapsed().as_millis() as u64;
+    if now_ms < backoff.next_attempt_ms() {
+        return Err(JoinError::Backoff {
+            retry_in_ms: backoff.next_attempt_ms() - now_ms,
+        });
+    }
+
+    // Every JoinRequest needs a new DevNonce
+    let nonce = super::nonce::Nonce::new().get_nonce();
+
+    let mut phy = lorawan::creator::JoinRequestCreator::new();
+    let key = lorawan::keys::AES128(*app_key);
+    phy.set_app_eui(join_eui);
+    phy.set_dev_eui(dev_eui);
+    phy.set_dev_nonce(&nonce.to_be_bytes());
+    let payload = phy.build(&key).unwrap();
+
+    let mut buffer = [0; 255];
+    buffer[..payload.len()].copy_from_slice(payload);
+
+    let mac = MacState::new();
+    let data_rate = backoff.data_rate();
+    let random = unsafe { esp_idf_sys::esp_random() };
+    let (_, channel) = mac.pick_channel(random).unwrap();
+    reserve_airtime(
+        duty_cycle,
+        since,
+        channel.frequency_hz,
+        airtime_us(data_rate, payload.len())?,
+    )?;
+    configure(lora, channel.frequency_hz, data_rate, false)?;
+    lora.set_tx_power(pv_protocol::lorawan::tx_power_dbm(0).unwrap(), 1)
+        .map_err(radio_error)?;
+
+    println!(
+        "Sending JoinRequest on {} Hz at DR{}",
+        channel.frequency_hz, data_rate
+    );
+    let started_at = Instant::now();
+    let transmit = lora.transmit_payload(buffer, payload.len());
+    let transmitting = transmit.and_then(|()| {
+        while lora.transmitting()? {}
+        Ok(())
+    });
+    let sent_at = Instant::now();
+    // A failed attempt counts against the duty cycle as well
+    let airtime_ms = (sent_at - started_at).as_millis() as u64;
+    let random = unsafe { esp_idf_sys::esp_random() };
+    backoff.record_attempt(now_ms, airtime_ms, random);
+    transmitting.map_err(radio_error)?;
+
+    // RX1 uses the channel and data rate of the JoinRequest, RX2 the defaults of the region
+    let rx1_at =
+        sent_at + Duration::from_millis(pv_protocol::lorawan::JOIN_ACCEPT_DELAY1_MS as u64);
+    let rx2_at = rx1_at + Duration::from_millis(1_000);
+    let windows = [
+        (
+            channel.frequency_hz,
+            data_rate,
+            rx1_at,
+            rx2_at - Duration::from_millis(RX_SETUP_MS),
+        ),
+        (
+            pv_protocol::lorawan::RX2_FREQUENCY_HZ,
+            pv_protocol::lorawan::RX2_DATA_RATE,
+            rx2_at,
+            rx2_at + Duration::from_millis(1_000),
+        ),
+    ];
+    for (frequency_hz, data_rate, opens_at, closes_at) in windows {
+        let Some((packet, _)) = receive_window(lora, frequency_hz, data_rate, opens_at, closes_at)?
+        else {
+            continue;
+        };
+        let payload = match lorawan::parser::parse(packet) {
+            Ok(lorawan::parser::PhyPayload::JoinAccept(
+                lorawan::parser::JoinAcceptPayload::Encrypted(payload),
+            )) => payload.decrypt(&key),
+            msg => {
+                println!("Expected JoinAccept, got {:?}", msg);
+                continue;
+            }
+        };
+        if !payload.validate_mic(&key) {
+            println!("Ignoring JoinAccept with invalid MIC");
+            continue;
+        }
+        let Some(settings) = JoinSettings::from_join_accept(payload.as_bytes()) else {
+            continue;
+        };
+
+        println!("Got JoinAccept: {:?}", payload);
+
+        let nonce_bytes = nonce.to_be_bytes();
+        let dev_nonce: lorawan::parser::DevNonce<_> = (&nonce_bytes).into();
+        let nwk_skey = payload.derive_newskey(&dev_nonce, &key);
+        let app_skey = payload.derive_appskey(&dev_nonce, &key);
+        let keys = SessionKeys {
+            dev_addr: payload.dev_addr().as_ref().try_into().unwrap(),
+            nwk_skey: nwk_skey.0,
+            app_skey: app_skey.0,
+        };
+        return Ok((keys, settings));
+    }
+    Err(JoinError::NoJoinAccept)
+}
@@ -1,34 +1,161 @@
-use std::sync::{Arc, Mutex};
+use std::fmt;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::{Duration, Instant};
 
 use embedded_hal_0_2::blocking::delay::DelayMs;
 use embedded_hal_0_2::blocking::spi::{Transfer, Write};
 use embedded_hal_0_2::digital::v2::OutputPin;
-
+use esp_idf_hal::gpio::{AnyInputPin, Input, PinDriver};
+use pv_protocol::adr::RadioSettings;
+use pv_protocol::airtime::{DutyCycleError, DutyCycleLimiter, Modulation};
+use pv_protocol::plan::FrequencyPlan;
+use pv_sx127x as sx;
+
+pub mod credentials;
+mod dio0;
+pub mod lorawan;
 mod nonce;
-mod sx;
-
-// For OTAA
-const APPKEY: u128 = 0x0728DC78F6C7FD95A3E2BC4E92EB614B;
-const DEVEUI: u64 = 0x70B3D57ED005CD07;
-const APPEUI: u64 = 0x0000000000000000; // Also called JoinEUI
-                                        // ---
-                                        // For ABP:
-const APP_SKEY: u128 = 0xC288AA8452516B9D0966247E84297DDF;
-const NWK_SKEY: u128 = 0x4C3282BF029E8E3CFF1F60A172D5F917;
-const DEV_ADDR: u32 = 0x727493;
-// ---
+
+pub use sx::Modem;
+
+/// Settings of the raw link in FSK mode, see [`Lora::new`]. 50 kbps with a deviation of 25 kHz
+/// occupy 2 * (25 kHz + 50 kbps / 2) = 100 kHz (Carson's rule), which is a receiver bandwidth of
+/// 50 kHz, since the SX1276 counts it on one side of the carrier. The 83.3 kHz used instead leave
+/// 33 kHz for the crystals of sender and receiver being off in opposite directions, which is
+/// ±19 ppm each at 868 MHz. The AFC uses the same bandwidth.
+const FSK_BITRATE: u32 = 50_000;
+const FSK_DEVIATION_HZ: u32 = 25_000;
+const FSK_RX_BANDWIDTH_HZ: u32 = 83_333;
+const FSK_PREAMBLE_BYTES: u16 = 5;
+const FSK_SYNC_WORD: &[u8] = b"PVMS";
+
+/// How a packet was received, to tell how good the link to its sender is.
+#[derive(Debug, Clone, Copy)]
+pub struct LinkMetadata {
+    pub rssi_dbm: i32,
+    pub snr_db: f32,
+    /// Offset of the carrier of the sender from the frequency of the receiver, mostly caused by
+    /// the tolerance and temperature of the crystals.
+    pub frequency_error_hz: i64,
+    pub frequency_hz: u32,
+    pub spreading_factor: u8,
+}
+
+/// Why a packet could not be sent or received on the raw link. The radio can be used again after
+/// any of them, so the packet is skipped instead of restarting the device.
+#[derive(Debug)]
+pub enum LoraError {
+    /// Communication with the radio failed, either over SPI or through one of its pins.
+    Spi(String),
+    /// No packet arrived in time, or a transmission did not finish.
+    Timeout,
+    /// A packet arrived, but its CRC does not match, so it was dropped.
+    Crc,
+    /// A packet can hold at most 255 bytes in LoRa mode, and 63 bytes in FSK mode.
+    PayloadTooLarge { len: usize, max: usize },
+    /// The radio is still transmitting another packet.
+    Busy,
+    /// Someone else kept transmitting on the channel, see `pv_protocol::lbt`.
+    ChannelBusy,
+    /// The packet does not fit in the duty cycle of the sub-band yet.
+    DutyCycleExhausted { retry_in_ms: u64 },
+    /// The packet cannot be sent on the current channel at all.
+    DutyCycle(DutyCycleError),
+    /// The thread receiving packets has stopped, so no more packets arrive.
+    Stopped,
+}
+
+impl fmt::Display for LoraError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Spi(e) => write!(f, "radio error: {e}"),
+            Self::Timeout => write!(f, "timed out"),
+            Self::Crc => write!(f, "packet with invalid CRC"),
+            Self::PayloadTooLarge { len, max } => {
+                write!(f, "payload of {len} bytes is larger than {max} bytes")
+            }
+            Self::Busy => write!(f, "radio is busy transmitting"),
+            Self::ChannelBusy => write!(f, "channel is busy"),
+            Self::DutyCycleExhausted { retry_in_ms } => {
+                write!(
+                    f,
+                    "duty cycle exhausted, next packet allowed in {retry_in_ms} ms"
+                )
+            }
+            Self::DutyCycle(e) => write!(f, "{e}"),
+            Self::Stopped => write!(f, "receiving has stopped"),
+        }
+    }
+}
+
+impl<SPI: fmt::Debug, CS: fmt::Debug, RESET: fmt::Debug> From<sx::Error<SPI, CS, RESET>>
+    for LoraError
+{
+    fn from(e: sx::Error<SPI, CS, RESET>) -> Self {
+        match e {
+            sx::Error::Transmitting => Self::Busy,
+            sx::Error::Timeout => Self::Timeout,
+            sx::Error::Crc => Self::Crc,
+            e => Self::Spi(format!("{e:?}")),
+        }
+    }
+}
+
+impl From<esp_idf_sys::EspError> for LoraError {
+    fn from(e: esp_idf_sys::EspError) -> Self {
+        Self::Spi(format!("{e:?}"))
+    }
+}
+
+#[derive(Debug)]
+pub struct ReceivedPacket {
+    pub payload: Vec<u8>,
+    /// `None` for packets which did not go over the radio, in a build with both the sender and
+    /// the receiver, and in FSK mode, where the radio does not measure the SNR.
+    pub link: Option<LinkMetadata>,
+}
 
 pub struct Lora<SPI, CS, RESET, DELAY> {
     lora: Arc<std::sync::Mutex<sx::LoRa<SPI, CS, RESET, DELAY>>>,
-    nonce: nonce::Nonce,
-    nwk_skey: lorawan::keys::AES128,
-    app_skey: lorawan::keys::AES128,
-    dev_addr: lorawan::parser::DevAddr<[u8; 4]>,
+    plan: FrequencyPlan,
+    /// Modem of the raw link. LoRaWAN always uses LoRa.
+    modem: Modem,
+    /// Frequencies of the raw link in Hz, either all channels of the plan or a single fixed one.
+    channels: Vec<u32>,
+    /// Index in `channels` of the frequency the radio returns to after a LoRaWAN uplink.
+    channel: Arc<Mutex<usize>>,
+    /// The next packet starts with a preamble long enough for a scanning receiver to find it, see
+    /// `pv_protocol::plan`.
+    wakeup: Mutex<bool>,
+    /// Packets are only sent once the channel is free, see `pv_protocol::lbt`.
+    listen_before_talk: bool,
+    /// DIO0 pin of the radio, until the thread receiving packets takes it.
+    dio0: Mutex<Option<PinDriver<'static, AnyInputPin, Input>>>,
+    /// Packets received by the thread started by [`Lora::receive_message`], and the errors it
+    /// recovered from.
+    packets: OnceLock<smol::channel::Receiver<Result<ReceivedPacket, LoraError>>>,
+    /// The radio goes back to receiving after a transmission.
+    listening: AtomicBool,
+    /// Spreading factor and transmit power of the raw link, see `pv_protocol::adr`.
+    raw_settings: Arc<Mutex<RadioSettings>>,
+    /// Airtime of the raw link and of LoRaWAN in the last hour, counted from `started`.
+    duty_cycle: Arc<Mutex<DutyCycleLimiter>>,
+    /// LoRaWAN session, created before the first uplink from the credentials in NVS.
+    session: Arc<Mutex<Option<lorawan::Session>>>,
+    /// When the next JoinRequest may be sent, counted from `started`.
+    join_backoff: Arc<Mutex<pv_protocol::lorawan::JoinBackoff>>,
+    started: Instant,
 
     #[cfg(all(feature = "sender", feature = "receiver"))]
     internal_sender: smol::channel::Sender<Vec<u8>>,
     #[cfg(all(feature = "sender", feature = "receiver"))]
     internal_receiver: smol::lock::Mutex<smol::channel::Receiver<Vec<u8>>>,
+    // Acknowledgements go to the sender instead of back to the receiver
+    #[cfg(all(feature = "sender", feature = "receiver"))]
+    internal_ack_sender: smol::channel::Sender<Vec<u8>>,
+    #[cfg(all(feature = "sender", feature = "receiver"))]
+    internal_ack_receiver: smol::lock::Mutex<smol::channel::Receiver<Vec<u8>>>,
 }
 
 impl<SPI, CS, RESET, DELAY, E> Lora<SPI, CS, RESET, DELAY>
@@ -42,161 +169,431 @@ where
     DELAY: DelayMs<u8> + Send + 'static,
 {
     #[cfg(all(feature = "sender", feature = "receiver"))]
-    pub async fn receive_message(&self) -> Vec<u8> {
-        self.internal_receiver.lock().await.recv().await.unwrap()
+    pub async fn receive_message(&self) -> Result<ReceivedPacket, LoraError> {
+        Ok(ReceivedPacket {
+            payload: self
+                .internal_receiver
+                .lock()
+                .await
+                .recv()
+                .await
+                .map_err(|_| LoraError::Stopped)?,
+            link: None,
+        })
+    }
+
+    /// Waits for a packet. The first call starts a thread which keeps the radio listening, see
+    /// [`Lora::listen`]. Errors are returned once, and the radio keeps listening afterwards.
+    #[cfg(not(all(feature = "sender", feature = "receiver")))]
+    pub async fn receive_message(&self) -> Result<ReceivedPacket, LoraError> {
+        let packets = self.packets.get_or_init(|| {
+            let (sender, receiver) = smol::channel::bounded(4);
+            let dio0 = self.dio0.lock().unwrap().take().unwrap();
+            let lora = Arc::clone(&self.lora);
+            let channels = self.channels.clone();
+            let channel = Arc::clone(&self.channel);
+            let raw_settings = Arc::clone(&self.raw_settings);
+            let modem = self.modem;
+            self.listening.store(true, Ordering::SeqCst);
+            std::thread::Builder::new()
+                .name("lora-rx".to_owned())
+                .spawn(move || {
+                    Self::listen(lora, dio0, modem, channels, channel, raw_settings, sender)
+                })
+                .unwrap();
+            receiver
+        });
+        packets.recv().await.unwrap_or(Err(LoraError::Stopped))
     }
 
+    /// Keeps the radio in continuous receive mode and sends every packet to `packets`. The thread
+    /// sleeps until DIO0 signals a packet, and only locks the radio to read it, so packets can be
+    /// transmitted in between.
+    ///
+    /// With more than one channel, the receiver scans them until it finds a preamble, and stays on
+    /// the channel of a packet for `pv_protocol::plan::LINGER_MS`. The radio stays on the channel
+    /// of the packet, so an acknowledgement is sent where the sender listens for it.
+    ///
+    /// Errors are sent to `packets` as well. After any error but a CRC error, the radio is put
+    /// back in receive mode a second later. If the DIO0 interrupt cannot be set up, this is tried
+    /// again every second.
     #[cfg(not(all(feature = "sender", feature = "receiver")))]
-    pub async fn receive_message(&self) -> Vec<u8> {
+    fn listen(
+        lora: Arc<Mutex<sx::LoRa<SPI, CS, RESET, DELAY>>>,
+        dio0: PinDriver<'static, AnyInputPin, Input>,
+        modem: Modem,
+        channels: Vec<u32>,
+        channel: Arc<Mutex<usize>>,
+        raw_settings: Arc<Mutex<RadioSettings>>,
+        packets: smol::channel::Sender<Result<ReceivedPacket, LoraError>>,
+    ) {
+        let mut dio0 = dio0::Dio0::new(dio0);
+        while let Err(e) = dio0.subscribe() {
+            if packets.send_blocking(Err(e.into())).is_err() {
+                return;
+            }
+            std::thread::sleep(Duration::from_secs(1));
+        }
+
+        let scanning = channels.len() > 1;
+        // Long enough to detect a preamble at the current spreading factor
+        let scan_step = || {
+            let spreading_factor = raw_settings.lock().unwrap().spreading_factor;
+            Duration::from_millis(pv_protocol::plan::scan_step_ms(spreading_factor))
+        };
+        let linger = Duration::from_millis(pv_protocol::plan::LINGER_MS);
+        let mut step_end = Instant::now() + scan_step();
+
+        // Reads the packet which woke the thread, if there is one, and moves on to the next
+        // channel once the current one has been quiet for long enough
+        let poll = |step_end: &mut Instant| -> Result<Option<ReceivedPacket>, LoraError> {
+            let mut lock = lora.lock().unwrap();
+            match lock.rx_status()? {
+                sx::RxStatus::Received(size) => {
+                    let payload = lock.read_packet()?[0..size].to_vec();
+                    let link = match modem {
+                        Modem::LoRa => Some(LinkMetadata {
+                            rssi_dbm: lock.get_packet_rssi()?,
+                            // The register holds the SNR in quarters of a dB
+                            snr_db: (lock.get_packet_snr()? as u8 as i8) as f32 / 4.0,
+                            frequency_error_hz: lock.get_packet_frequency_error()?,
+                            frequency_hz: channels[*channel.lock().unwrap()],
+                            spreading_factor: raw_settings.lock().unwrap().spreading_factor,
+                        }),
+                        Modem::Fsk => None,
+                    };
+                    *step_end = Instant::now() + linger;
+                    return Ok(Some(ReceivedPacket { payload, link }));
+                }
+                // A sender is on this channel, even if this packet was broken
+                sx::RxStatus::CrcError => {
+                    *step_end = Instant::now() + linger;
+                    return Err(LoraError::Crc);
+                }
+                // Keep listening while there is a signal on this channel
+                sx::RxStatus::Receiving => {
+                    *step_end = (*step_end).max(Instant::now() + scan_step())
+                }
+                sx::RxStatus::Listening if scanning && lock.signal_detected()? => {
+                    *step_end = (*step_end).max(Instant::now() + scan_step())
+                }
+                sx::RxStatus::Listening => {
+                    if scanning && Instant::now() >= *step_end {
+                        let mut index = channel.lock().unwrap();
+                        *index = (*index + 1) % channels.len();
+                        lock.set_mode(sx::RadioMode::Stdby)?;
+                        lock.set_frequency_hz(channels[*index])?;
+                        lock.set_mode(sx::RadioMode::RxContinuous)?;
+                        *step_end = Instant::now() + scan_step();
+                    }
+                }
+            }
+            Ok(None)
+        };
+
+        let mut restart = true;
+        loop {
+            let result = if restart {
+                lora.lock()
+                    .unwrap()
+                    .start_rx_continuous()
+                    .map(|()| None)
+                    .map_err(LoraError::from)
+            } else {
+                // Without scanning, the timeout only guards against a missed edge
+                let timeout = if scanning {
+                    step_end.saturating_duration_since(Instant::now())
+                } else {
+                    Duration::from_secs(1)
+                };
+                dio0.wait(timeout)
+                    .map_err(LoraError::from)
+                    .and_then(|_| poll(&mut step_end))
+            };
+
+            // The radio keeps listening after a CRC error, but may have lost its mode otherwise
+            restart = result.as_ref().is_err_and(|e| !matches!(e, LoraError::Crc));
+            let Some(result) = result.transpose() else {
+                continue;
+            };
+            if packets.send_blocking(result).is_err() {
+                return;
+            }
+            if restart {
+                std::thread::sleep(Duration::from_secs(1));
+            }
+        }
+    }
+
+    /// Waits up to `timeout_ms` for a message. Used by the sender to receive acknowledgements.
+    #[cfg(all(feature = "sender", feature = "receiver"))]
+    pub async fn receive_message_timeout(&self, timeout_ms: i32) -> Result<Vec<u8>, LoraError> {
+        smol::future::or(
+            async {
+                let receiver = self.internal_ack_receiver.lock().await;
+                receiver.recv().await.map_err(|_| LoraError::Timeout)
+            },
+            async {
+                smol::Timer::after(std::time::Duration::from_millis(timeout_ms as u64)).await;
+                Err(LoraError::Timeout)
+            },
+        )
+        .await
+    }
+
+    /// Waits up to `timeout_ms` for a message. Used by the sender to receive acknowledgements.
+    #[cfg(not(all(feature = "sender", feature = "receiver")))]
+    pub async fn receive_message_timeout(&self, timeout_ms: i32) -> Result<Vec<u8>, LoraError> {
         let lora = Arc::clone(&self.lora);
-        smol::unblock(move || {
-            println!("Now waiting for LoRa message on sx1276");
+        smol::unblock(move || -> Result<Vec<u8>, LoraError> {
             let mut lock = lora.lock().unwrap();
-            println!("LOCKED");
-            let size = lock.poll_irq(None).unwrap();
-            lock.read_packet().unwrap()[0..size].to_vec()
+            let packet = lock
+                .poll_irq(Some(timeout_ms))
+                .and_then(|size| Ok(lock.read_packet()?[0..size].to_vec()));
+            lock.set_mode(sx::RadioMode::Stdby)?;
+            Ok(packet?)
         })
         .await
     }
 
     #[cfg(all(feature = "sender", feature = "receiver"))]
-    pub async fn send_raw_message(&self, message: &[u8]) -> Result<(), ()> {
-        if message.len() > 255 {
-            panic!(
-                "Cannot transmit more than 255 bytes of data. Attempted to transmit: {:?}",
-                message
-            );
+    pub async fn send_raw_message(
+        &self,
+        message: &[u8],
+        _deadline: Option<Instant>,
+    ) -> Result<(), LoraError> {
+        if message.len() > self.max_packet_size() {
+            return Err(LoraError::PayloadTooLarge {
+                len: message.len(),
+                max: self.max_packet_size(),
+            });
         }
 
-        self.internal_sender.send(message.to_vec()).await.unwrap();
+        if pv_protocol::ack::is_ack(message) {
+            // Nobody may be waiting for the acknowledgement, so do not block on it
+            let _ = self.internal_ack_sender.try_send(message.to_vec());
+        } else {
+            self.internal_sender.send(message.to_vec()).await.unwrap();
+        }
         return Ok(());
     }
 
+    /// Transmits a packet on the raw link. Fails without transmitting if the packet does not fit
+    /// in the duty cycle, see [`Lora::duty_cycle_wait_ms`], or if listen-before-talk is enabled and
+    /// the channel stays busy, or does not become free in time for the transmission to end by
+    /// `deadline`.
     #[cfg(not(all(feature = "sender", feature = "receiver")))]
-    pub async fn send_raw_message(&self, message: &[u8]) -> Result<(), ()> {
-        if message.len() > 255 {
-            panic!(
-                "Cannot transmit more than 255 bytes of data. Attempted to transmit: {:?}",
-                message
-            );
+    pub async fn send_raw_message(
+        &self,
+        message: &[u8],
+        deadline: Option<Instant>,
+    ) -> Result<(), LoraError> {
+        if message.len() > self.max_packet_size() {
+            return Err(LoraError::PayloadTooLarge {
+                len: message.len(),
+                max: self.max_packet_size(),
+            });
         }
 
+        // The long preamble is only used up once the packet is sent, so a retry still has it
+        let preamble_symbols = self.preamble_symbols(*self.wakeup.lock().unwrap());
+        let airtime_us = self.raw_airtime_us(message.len(), preamble_symbols);
+        if self.listen_before_talk {
+            self.wait_for_clear_channel((airtime_us / 1000) as u32, deadline)
+                .await?;
+        }
+        if self.plan.has_duty_cycle() {
+            let now_ms = self.started.elapsed().as_millis() as u64;
+            let mut duty_cycle = self.duty_cycle.lock().unwrap();
+            match duty_cycle.wait_ms(now_ms, self.frequency_hz(), airtime_us) {
+                Ok(0) => duty_cycle.record(now_ms, self.frequency_hz(), airtime_us),
+                Ok(wait_ms) => {
+                    return Err(LoraError::DutyCycleExhausted {
+                        retry_in_ms: wait_ms,
+                    })
+                }
+                Err(e) => return Err(LoraError::DutyCycle(e)),
+            }
+        }
+        *self.wakeup.lock().unwrap() = false;
+
         let mut buffer = [0; 255];
         for (i, c) in message.iter().enumerate() {
             buffer[i] = *c;
         }
         let message_len = message.len();
+        // The radio should be done long before, but a transmission which never finishes must not
+        // keep the radio locked forever
+        let timeout = Duration::from_micros(airtime_us) + Duration::from_secs(1);
 
         let lora = Arc::clone(&self.lora);
+        let listening = self.listening.load(Ordering::SeqCst);
+        let modem = self.modem;
         smol::unblock(move || {
             println!("Transmitting {} bytes.", message_len);
             let mut lock = lora.lock().unwrap();
-            let transmit = lock.transmit_payload(buffer, message_len);
-            while lock.transmitting().unwrap() {
-                println!("Transmitting..");
+            let transmit = Self::transmit(
+                &mut lock,
+                modem,
+                buffer,
+                message_len,
+                preamble_symbols,
+                timeout,
+            );
+            // Restored even if the transmission failed, so the next one gets a chance
+            let restore = if modem == Modem::LoRa {
+                lock.set_preamble_length(pv_protocol::plan::PREAMBLE_SYMBOLS as i64)
+            } else {
+                Ok(())
             }
-
-            match transmit {
-                Ok(_) => {
-                    println!("Successfully transmitted {} bytes.", message_len);
-                    Ok(())
-                }
-                Err(e) => {
-                    println!("Failed to transmit: {:?}", e);
-                    Err(())
+            .and_then(|()| {
+                if listening {
+                    lock.start_rx_continuous()?;
                 }
+                Ok(())
+            })
+            .map_err(LoraError::from);
+
+            match &transmit {
+                Ok(()) => println!("Successfully transmitted {} bytes.", message_len),
+                Err(e) => println!("Failed to transmit: {e}"),
             }
+            transmit.and(restore)
         })
         .await
     }
 
-    fn do_otaa(mut lora: sx::LoRa<SPI, CS, RESET, DELAY>, nonce: nonce::Nonce) -> Self {
-        let mut phy = lorawan::creator::JoinRequestCreator::new();
-        let key = lorawan::keys::AES128(APPKEY.to_be_bytes());
-        phy.set_app_eui(&APPEUI.to_be_bytes());
-        phy.set_dev_eui(&DEVEUI.to_be_bytes());
-        phy.set_dev_nonce(&nonce.get_nonce().to_be_bytes());
-        let payload = phy.build(&key).unwrap();
-
-        let mut buffer = [0; 255];
-        for (i, c) in payload.iter().enumerate() {
-            buffer[i] = *c;
-        }
-        loop {
-            println!("Transmitting JoinRequest.");
-            let transmit = lora.transmit_payload(buffer, payload.len());
-            match transmit {
-                Ok(()) => {
-                    println!("JoinRequest successfully transmitted.");
-                    break;
-                }
-                Err(e) => {
-                    println!("Failed to transmit JoinRequest: {:?}", e);
-                    esp_idf_hal::delay::FreeRtos::delay_ms(1000);
-                    println!("Trying again.");
+    /// Checks with channel activity detection whether another packet is being sent on the channel,
+    /// and waits for a random time while it is, for a packet of `airtime_ms`. Gives up early if the
+    /// packet could not be sent by `deadline` after waiting.
+    #[cfg(not(all(feature = "sender", feature = "receiver")))]
+    async fn wait_for_clear_channel(
+        &self,
+        airtime_ms: u32,
+        deadline: Option<Instant>,
+    ) -> Result<(), LoraError> {
+        for attempt in 0..pv_protocol::lbt::MAX_ATTEMPTS {
+            let lora = Arc::clone(&self.lora);
+            let listening = self.listening.load(Ordering::SeqCst);
+            let busy = smol::unblock(move || -> Result<bool, LoraError> {
+                let mut lock = lora.lock().unwrap();
+                // Takes two symbols, which is at most 66 ms at SF12
+                let busy = lock.detect_channel_activity(1_000);
+                if listening {
+                    lock.start_rx_continuous()?;
                 }
+                Ok(busy?)
+            })
+            .await?;
+            if !busy {
+                return Ok(());
             }
-        }
-
-        println!("Waiting indefinetely for JoinAccept.");
-        lora.poll_irq(None).unwrap();
-        let response = lora.read_packet().unwrap();
 
-        println!("Got LoRa message!");
-
-        let parsed = lorawan::parser::parse(response).unwrap();
-        let payload = match parsed {
-            lorawan::parser::PhyPayload::JoinAccept(
-                lorawan::parser::JoinAcceptPayload::Encrypted(payload),
-            ) => payload.decrypt(&key),
-            msg => {
-                panic!("Expected JoinAccept, got {:?}", msg)
+            let random = unsafe { esp_idf_sys::esp_random() };
+            let backoff_ms = pv_protocol::lbt::backoff_ms(attempt, airtime_ms, random);
+            let ends_at =
+                Instant::now() + Duration::from_millis(backoff_ms as u64 + airtime_ms as u64);
+            if deadline.is_some_and(|deadline| ends_at > deadline) {
+                break;
             }
-        };
-
-        println!("Got JoinAccept: {:?}", payload);
-
-        let nonce_bytes = nonce.get_nonce().to_be_bytes();
-        let dev_nonce: lorawan::parser::DevNonce<_> = (&nonce_bytes).into();
-        let nwk_skey = payload.derive_newskey(&dev_nonce, &key);
-        let app_skey = payload.derive_appskey(&dev_nonce, &key);
-        let dev_addr = payload.dev_addr().to_owned();
-
-        #[cfg(all(feature = "sender", feature = "receiver"))]
-        let (internal_sender, internal_receiver) = smol::channel::bounded(1);
-
-        Self {
-            lora: Arc::new(Mutex::new(lora)),
-            nonce,
-            nwk_skey,
-            app_skey,
-            dev_addr,
+            println!("Channel busy, checking again in {backoff_ms} ms");
+            smol::Timer::after(Duration::from_millis(backoff_ms as u64)).await;
+        }
+        Err(LoraError::ChannelBusy)
+    }
 
-            #[cfg(all(feature = "sender", feature = "receiver"))]
-            internal_receiver: smol::lock::Mutex::new(internal_receiver),
-            #[cfg(all(feature = "sender", feature = "receiver"))]
-            internal_sender,
+    #[cfg(not(all(feature = "sender", feature = "receiver")))]
+    fn transmit(
+        lora: &mut sx::LoRa<SPI, CS, RESET, DELAY>,
+        modem: Modem,
+        buffer: [u8; 255],
+        len: usize,
+        preamble_symbols: u16,
+        timeout: Duration,
+    ) -> Result<(), LoraError> {
+        // The FSK preamble does not change, since the receiver does not scan in FSK mode
+        if modem == Modem::LoRa {
+            lora.set_preamble_length(preamble_symbols as i64)?;
         }
+        lora.transmit_payload(buffer, len)?;
+        let deadline = Instant::now() + timeout;
+        while lora.transmitting()? {
+            println!("Transmitting..");
+            if Instant::now() >= deadline {
+                lora.set_mode(sx::RadioMode::Stdby)?;
+                return Err(LoraError::Timeout);
+            }
+        }
+        Ok(())
     }
 
-    pub fn do_abp(lora: sx::LoRa<SPI, CS, RESET, DELAY>, nonce: nonce::Nonce) -> Self {
-        let dev_addr_bytes = DEV_ADDR.to_be_bytes();
-        let dev_addr_brw: lorawan::parser::DevAddr<&[u8; 4]> = (&dev_addr_bytes).into();
+    /// Creates the radio for the raw link on the channels of `plan`, or only on the channel with
+    /// index `fixed_channel` if it is set. Panics if there is no such channel, or if the dwell time
+    /// of the plan does not allow any spreading factor, see [`Lora::max_spreading_factor`]. With
+    /// `listen_before_talk`, packets are only sent once nobody else is sending on the channel.
+    ///
+    /// The raw link uses `modem`. FSK at 50 kbps is many times faster than LoRa, but has a much
+    /// shorter range and smaller packets, see [`Lora::max_packet_size`]. It needs a fixed channel,
+    /// since the receiver cannot scan for FSK packets, and does not support listen-before-talk.
+    pub fn new(
+        spi: SPI,
+        cs: CS,
+        reset: RESET,
+        dio0: PinDriver<'static, AnyInputPin, Input>,
+        modem: Modem,
+        plan: FrequencyPlan,
+        fixed_channel: Option<usize>,
+        listen_before_talk: bool,
+        delay: DELAY,
+    ) -> Self {
+        let channels = match fixed_channel {
+            Some(index) => vec![plan.channels()[index]],
+            None => plan.channels().to_vec(),
+        };
+        assert!(
+            plan.max_spreading_factor(channels.len()).is_some(),
+            "{} does not allow scanning {} channels within its dwell time",
+            plan.name(),
+            channels.len()
+        );
 
         #[cfg(all(feature = "sender", feature = "receiver"))]
         let (internal_sender, internal_receiver) = smol::channel::bounded(1);
+        #[cfg(all(feature = "sender", feature = "receiver"))]
+        let (internal_ack_sender, internal_ack_receiver) = smol::channel::bounded(1);
 
         Self {
-            lora: Arc::new(Mutex::new(lora)),
-            nonce,
-            app_skey: APP_SKEY.to_be_bytes().into(),
-            nwk_skey: NWK_SKEY.to_be_bytes().into(),
-            dev_addr: dev_addr_brw.to_owned(),
+            lora: Arc::new(Mutex::new(Self::setup_lora(
+                spi,
+                cs,
+                reset,
+                modem,
+                channels[0],
+                delay,
+            ))),
+            plan,
+            modem,
+            channels,
+            channel: Arc::new(Mutex::new(0)),
+            wakeup: Mutex::new(false),
+            listen_before_talk,
+            dio0: Mutex::new(Some(dio0)),
+            packets: OnceLock::new(),
+            listening: AtomicBool::new(false),
+            raw_settings: Arc::new(Mutex::new(RadioSettings::DEFAULT)),
+            duty_cycle: Arc::new(Mutex::new(DutyCycleLimiter::new())),
+            session: Arc::new(Mutex::new(None)),
+            join_backoff: Arc::new(Mutex::new(credentials::get_join_backoff(0))),
+            started: Instant::now(),
 
             #[cfg(all(feature = "sender", feature = "receiver"))]
             internal_receiver: smol::lock::Mutex::new(internal_receiver),
             #[cfg(all(feature = "sender", feature = "receiver"))]
             internal_sender,
+            #[cfg(all(feature = "sender", feature = "receiver"))]
+            internal_ack_receiver: smol::lock::Mutex::new(internal_ack_receiver),
+            #[cfg(all(feature = "sender", feature = "receiver"))]
+            internal_ack_sender,
         }
     }
 
@@ -204,52 +601,310 @@ where
         spi: SPI,
         cs: CS,
         reset: RESET,
-        frequency: i64,
+        modem: Modem,
+        frequency_hz: u32,
         delay: DELAY,
     ) -> sx::LoRa<SPI, CS, RESET, DELAY> {
         println!("Starting communications with sx1276..");
 
-        let mut lora = sx::LoRa::new(spi, cs, reset, frequency, delay)
+        let mut lora = sx::LoRa::new(spi, cs, reset, (frequency_hz / 1_000_000) as i64, delay)
             .expect("Failed to communicate with radio module!");
 
         println!("Communications with sx1276 established!");
 
-        lora.set_coding_rate_4(5).unwrap();
-        lora.set_spreading_factor(7).unwrap();
-        lora.set_preamble_length(8).unwrap();
-        lora.set_signal_bandwidth(125000).unwrap();
-        lora.set_tx_power(17, 1).unwrap();
-        lora.set_crc(true).unwrap();
+        Self::configure_raw_link(&mut lora, modem, frequency_hz, RadioSettings::DEFAULT)
+            .expect("Failed to configure radio module!");
 
         lora
     }
 
-    pub fn new_otaa(spi: SPI, cs: CS, reset: RESET, frequency: i64, delay: DELAY) -> Self {
-        Self::do_otaa(
-            Self::setup_lora(spi, cs, reset, frequency, delay),
-            nonce::Nonce::new(),
-        )
+    /// Settings of the raw link between our senders and receivers. LoRaWAN uplinks change them, so
+    /// they are applied again afterwards.
+    fn configure_raw_link(
+        lora: &mut sx::LoRa<SPI, CS, RESET, DELAY>,
+        modem: Modem,
+        frequency_hz: u32,
+        settings: RadioSettings,
+    ) -> Result<(), LoraError> {
+        lora.set_modem(modem)?;
+        lora.set_frequency_hz(frequency_hz)?;
+        lora.set_tx_power(settings.tx_power_dbm as i32, 1)?;
+        if modem == Modem::Fsk {
+            lora.set_fsk_bitrate(FSK_BITRATE)?;
+            lora.set_fsk_deviation(FSK_DEVIATION_HZ)?;
+            lora.set_fsk_rx_bandwidth(FSK_RX_BANDWIDTH_HZ)?;
+            lora.set_fsk_preamble_length(FSK_PREAMBLE_BYTES)?;
+            lora.set_fsk_sync_word(FSK_SYNC_WORD)?;
+            lora.set_fsk_shaping(sx::Shaping::GaussianBt0_5)?;
+            return Ok(());
+        }
+        lora.set_coding_rate_4(5)?;
+        lora.set_spreading_factor(settings.spreading_factor)?;
+        lora.set_preamble_length(pv_protocol::plan::PREAMBLE_SYMBOLS as i64)?;
+        lora.set_signal_bandwidth(125000)?;
+        lora.set_crc(true)?;
+        lora.set_sync_word(0x12)?;
+        lora.set_invert_iq(false)?;
+        Ok(())
     }
 
-    pub fn new_abp(spi: SPI, cs: CS, reset: RESET, frequency: i64, delay: DELAY) -> Self {
-        Self::do_abp(
-            Self::setup_lora(spi, cs, reset, frequency, delay),
-            nonce::Nonce::new(),
-        )
+    /// Spreading factor and transmit power of the raw link.
+    pub fn raw_settings(&self) -> RadioSettings {
+        *self.raw_settings.lock().unwrap()
+    }
+
+    /// Changes the spreading factor and transmit power of the raw link. The spreading factor is
+    /// ignored in FSK mode. If this fails, the radio may still use the previous settings.
+    pub async fn set_raw_settings(&self, settings: RadioSettings) -> Result<(), LoraError> {
+        *self.raw_settings.lock().unwrap() = settings;
+
+        #[cfg(not(all(feature = "sender", feature = "receiver")))]
+        {
+            let lora = Arc::clone(&self.lora);
+            let listening = self.listening.load(Ordering::SeqCst);
+            let modem = self.modem;
+            smol::unblock(move || -> Result<(), LoraError> {
+                let mut lock = lora.lock().unwrap();
+                lock.set_mode(sx::RadioMode::Stdby)?;
+                if modem == Modem::LoRa {
+                    lock.set_spreading_factor(settings.spreading_factor)?;
+                }
+                lock.set_tx_power(settings.tx_power_dbm as i32, 1)?;
+                if listening {
+                    lock.start_rx_continuous()?;
+                }
+                Ok(())
+            })
+            .await?;
+        }
+        Ok(())
+    }
+
+    /// Modem of the raw link.
+    pub fn modem(&self) -> Modem {
+        self.modem
+    }
+
+    /// Largest packet of the raw link. In a frequency plan with a dwell time, a LoRa packet has to
+    /// fit in it at the current spreading factor, even with the preamble of the first packet on a
+    /// new channel.
+    pub fn max_packet_size(&self) -> usize {
+        match self.modem {
+            Modem::LoRa => self.plan.max_packet_size(
+                self.raw_settings().spreading_factor,
+                self.preamble_symbols(true),
+            ),
+            Modem::Fsk => sx::FSK_MAX_PAYLOAD,
+        }
+    }
+
+    /// Highest spreading factor the raw link may use, which the dwell time of the frequency plan
+    /// may limit, see `pv_protocol::plan`.
+    pub fn max_spreading_factor(&self) -> u8 {
+        self.plan.max_spreading_factor(self.channels.len()).unwrap()
+    }
+
+    /// Number of channels of the raw link, 1 if it uses a fixed channel.
+    pub fn channel_count(&self) -> usize {
+        self.channels.len()
     }
 
-    pub async fn send_message(&mut self, message: &[u8]) -> Result<(), ()> {
-        let mut phy = lorawan::creator::DataPayloadCreator::new();
-        phy.set_confirmed(true)
-            .set_uplink(true)
-            .set_f_port(42)
-            .set_dev_addr(self.dev_addr)
-            .set_fctrl(&lorawan::parser::FCtrl::new(0x80, true)) // ADR: true, all others: false
-            .set_fcnt(17);
-        let payload = phy
-            .build(message, &[], &self.nwk_skey, &self.app_skey)
-            .unwrap();
+    /// Tunes the raw link to the channel with the given index, which must be smaller than
+    /// [`Lora::channel_count`]. If the receiver scans the channels, the next packet gets a long
+    /// preamble so the receiver finds it on the new channel.
+    pub async fn select_channel(&self, index: usize) -> Result<(), LoraError> {
+        let frequency_hz = self.channels[index];
+        *self.channel.lock().unwrap() = index;
+        *self.wakeup.lock().unwrap() = self.channels.len() > 1;
+
+        #[cfg(not(all(feature = "sender", feature = "receiver")))]
+        {
+            let lora = Arc::clone(&self.lora);
+            smol::unblock(move || -> Result<(), LoraError> {
+                let mut lock = lora.lock().unwrap();
+                lock.set_mode(sx::RadioMode::Stdby)?;
+                lock.set_frequency_hz(frequency_hz)?;
+                Ok(())
+            })
+            .await?;
+        }
+        Ok(())
+    }
+
+    /// Frequency of the current channel of the raw link.
+    pub fn frequency_hz(&self) -> u32 {
+        self.channels[*self.channel.lock().unwrap()]
+    }
+
+    fn preamble_symbols(&self, wakeup: bool) -> u16 {
+        if wakeup {
+            pv_protocol::plan::wakeup_preamble_symbols(
+                self.raw_settings().spreading_factor,
+                self.channels.len(),
+            )
+        } else {
+            pv_protocol::plan::PREAMBLE_SYMBOLS
+        }
+    }
+
+    /// Time in microseconds a packet of `len` bytes takes on the raw link. The preamble is only
+    /// used in LoRa mode.
+    pub fn raw_airtime_us(&self, len: usize, preamble_symbols: u16) -> u64 {
+        if self.modem == Modem::Fsk {
+            return pv_protocol::airtime::fsk_time_on_air_us(
+                FSK_BITRATE,
+                FSK_PREAMBLE_BYTES,
+                FSK_SYNC_WORD.len(),
+                len,
+            );
+        }
+        let modulation = Modulation {
+            spreading_factor: self.raw_settings().spreading_factor,
+            bandwidth_hz: 125_000,
+            coding_rate: 5,
+            preamble_symbols,
+            explicit_header: true,
+            crc: true,
+        };
+        pv_protocol::airtime::time_on_air_us(&modulation, len)
+    }
+
+    /// Milliseconds until packets of the given lengths may be sent on the current channel of the
+    /// raw link without exceeding the duty cycle of the sub-band, which is 0 if they may be sent
+    /// right away or the frequency plan has no duty cycle.
+    pub fn duty_cycle_wait_ms(&self, lengths: &[usize]) -> Result<u64, DutyCycleError> {
+        if !self.plan.has_duty_cycle() {
+            return Ok(0);
+        }
+        let wakeup = *self.wakeup.lock().unwrap();
+        let airtime_us = lengths
+            .iter()
+            .enumerate()
+            .map(|(i, &len)| self.raw_airtime_us(len, self.preamble_symbols(wakeup && i == 0)))
+            .sum();
+        let now_ms = self.started.elapsed().as_millis() as u64;
+        self.duty_cycle
+            .lock()
+            .unwrap()
+            .wait_ms(now_ms, self.frequency_hz(), airtime_us)
+    }
 
-        self.send_raw_message(payload).await
+    /// Largest payload of a LoRaWAN uplink at the current data rate.
+    pub fn max_lorawan_payload(&self) -> usize {
+        match &*self.session.lock().unwrap() {
+            Some(session) => lorawan::max_payload_size(&session.mac),
+            None => lorawan::max_payload_size(&pv_protocol::lorawan::MacState::new()),
+        }
+    }
+
+    /// Creates the LoRaWAN session from the credentials in NVS. A device using OTAA continues the
+    /// session of its last join, or otherwise sends a single JoinRequest and listens for the
+    /// JoinAccept, which keeps the radio busy for about seven seconds. Returns immediately if the
+    /// device already has a session, or if the duty cycle does not allow another JoinRequest yet.
+    pub async fn join(&self) -> Result<(), lorawan::JoinError> {
+        let mut session = self.session.lock().unwrap();
+        if credentials::take_changed() {
+            *session = None;
+        }
+        if session.is_some() {
+            return Ok(());
+        }
+
+        let (dev_eui, join_eui, app_key) = match credentials::get_activation() {
+            None => return Err(lorawan::JoinError::NotProvisioned),
+            Some(pv_protocol::lorawan::Activation::Abp(keys)) => {
+                *session = Some(lorawan::Session::from_keys(&keys));
+                return Ok(());
+            }
+            Some(pv_protocol::lorawan::Activation::Otaa {
+                dev_eui,
+                join_eui,
+                app_key,
+            }) => (dev_eui, join_eui, app_key),
+        };
+        if let Some((keys, settings)) = credentials::get_session() {
+            let mut restored = lorawan::Session::from_keys(&keys);
+            restored.mac.apply_join_settings(&settings);
+            *session = Some(restored);
+            return Ok(());
+        }
+        drop(session);
+
+        let lora = Arc::clone(&self.lora);
+        let duty_cycle = Arc::clone(&self.duty_cycle);
+        let join_backoff = Arc::clone(&self.join_backoff);
+        let started = self.started;
+        let modem = self.modem;
+        let frequency_hz = self.frequency_hz();
+        let raw_settings = self.raw_settings();
+        let (keys, settings) = smol::unblock(move || {
+            let mut lock = lora.lock().unwrap();
+            let mut join_backoff = join_backoff.lock().unwrap();
+            let result = lorawan::join(
+                &mut lock,
+                &duty_cycle,
+                &mut join_backoff,
+                started,
+                &dev_eui,
+                &join_eui,
+                &app_key,
+            );
+            if !matches!(result, Err(lorawan::JoinError::Backoff { .. })) {
+                credentials::set_join_backoff(&join_backoff, started.elapsed().as_millis() as u64);
+            }
+            // The raw link has to work again, whether the JoinRequest was answered or not
+            Self::configure_raw_link(&mut lock, modem, frequency_hz, raw_settings)
+                .map_err(|e| lorawan::JoinError::Radio(format!("{e:?}")))?;
+            result
+        })
+        .await?;
+
+        credentials::set_session(&keys, &settings);
+        let mut joined = lorawan::Session::from_keys(&keys);
+        joined.mac.apply_join_settings(&settings);
+        *self.session.lock().unwrap() = Some(joined);
+        Ok(())
+    }
+
+    /// Sends a LoRaWAN uplink on `port` and returns the application data of the downlink, if the
+    /// network server sent one. Confirmed uplinks are retransmitted up to `retries` times until the
+    /// network server acknowledges them. The radio is busy until the receive windows have closed.
+    ///
+    /// Fails with [`lorawan::LorawanError::NotJoined`] unless [`Lora::join`] succeeded before.
+    pub async fn send_message(
+        &self,
+        port: u8,
+        message: &[u8],
+        confirmed: bool,
+        retries: u8,
+    ) -> Result<Option<lorawan::DownlinkData>, lorawan::LorawanError> {
+        let lora = Arc::clone(&self.lora);
+        let session = Arc::clone(&self.session);
+        let duty_cycle = Arc::clone(&self.duty_cycle);
+        let started = self.started;
+        let message = message.to_vec();
+        let modem = self.modem;
+        let frequency_hz = self.frequency_hz();
+        let raw_settings = self.raw_settings();
+        smol::unblock(move || {
+            let mut lock = lora.lock().unwrap();
+            let mut session = session.lock().unwrap();
+            let session = session.as_mut().ok_or(lorawan::LorawanError::NotJoined)?;
+            let result = lorawan::uplink(
+                &mut lock,
+                &duty_cycle,
+                started,
+                session,
+                port,
+                &message,
+                confirmed,
+                retries,
+            );
+            // The raw link has to work again, whether the uplink was sent or not
+            Self::configure_raw_link(&mut lock, modem, frequency_hz, raw_settings)
+                .map_err(|e| lorawan::LorawanError::Radio(format!("{e:?}")))?;
+            result
+        })
+        .await
     }
 }
@@ -14,7 +14,7 @@ mod sender;
 use esp_idf_svc::nvs::{EspDefaultNvsPartition, EspNvs, NvsDefault};
 use esp_idf_sys as _; // If using the `binstart` feature of `esp-idf-sys`, always keep this module imported
 
-use esp_idf_hal::gpio::{Gpio18, PinDriver};
+use esp_idf_hal::gpio::{Gpio18, InputPin, PinDriver};
 use esp_idf_hal::i2c::{I2cConfig, I2cDriver};
 use esp_idf_hal::prelude::FromValueType;
 use esp_idf_hal::{
@@ -73,6 +73,7 @@ async fn do_main() -> ! {
 
     let lora_reset = PinDriver::output(peripherals.pins.gpio12).unwrap();
     let lora_cs = PinDriver::output(peripherals.pins.gpio18).unwrap();
+    let lora_dio0 = PinDriver::input(peripherals.pins.gpio26.downgrade_input()).unwrap();
 
     let lora_spi_config = esp_idf_hal::spi::config::Config::default().baudrate(200.kHz().into());
 
@@ -87,11 +88,68 @@ async fn do_main() -> ! {
     )
     .unwrap();
 
-    let lora = lora::Lora::new_abp(
+    // Frequency plan of the raw link, see pv_protocol::plan. Defaults to EU868.
+    const FREQUENCY_PLAN: pv_protocol::plan::FrequencyPlan =
+        match std::option_env!("FREQUENCY_PLAN") {
+            Some(val) if !val.is_empty() => match pv_protocol::plan::FrequencyPlan::parse(val) {
+                Some(plan) => plan,
+                None => panic!("FREQUENCY_PLAN must be one of EU868, US915 or AS923"),
+            },
+            _ => pv_protocol::plan::FrequencyPlan::Eu868,
+        };
+    // Index of the only channel of the plan to use. If CHANNEL is not set (or empty), senders hop
+    // between all channels and receivers scan them.
+    const CHANNEL: Option<usize> = match std::option_env!("CHANNEL") {
+        Some(val) if !val.is_empty() => {
+            let val = konst::result::unwrap_ctx!(konst::primitive::parse_usize(val));
+            assert!(val < FREQUENCY_PLAN.channels().len());
+            Some(val)
+        }
+        _ => None,
+    };
+    // Whether to check that nobody else is sending before sending a packet, see
+    // pv_protocol::lbt. Defaults to false.
+    const LISTEN_BEFORE_TALK: bool = match std::option_env!("LISTEN_BEFORE_TALK") {
+        Some(val) if !val.is_empty() => {
+            if !konst::eq_str(val, "true") && !konst::eq_str(val, "false") {
+                panic!("Expected environment variable LISTEN_BEFORE_TALK to equal true or false");
+            }
+            konst::eq_str(val, "true")
+        }
+        _ => false,
+    };
+    // Modem of the raw link, LORA or FSK. FSK is much faster over short distances, but needs a
+    // fixed CHANNEL and does not support LISTEN_BEFORE_TALK. Defaults to LORA.
+    const MODEM: lora::Modem = match std::option_env!("MODEM") {
+        Some(val) if !val.is_empty() => {
+            if konst::eq_str(val, "LORA") {
+                lora::Modem::LoRa
+            } else if konst::eq_str(val, "FSK") {
+                lora::Modem::Fsk
+            } else {
+                panic!("Expected environment variable MODEM to equal LORA or FSK");
+            }
+        }
+        _ => lora::Modem::LoRa,
+    };
+    const _: () = assert!(
+        !matches!(MODEM, lora::Modem::Fsk) || CHANNEL.is_some(),
+        "MODEM=FSK needs a fixed CHANNEL"
+    );
+    const _: () = assert!(
+        !matches!(MODEM, lora::Modem::Fsk) || !LISTEN_BEFORE_TALK,
+        "MODEM=FSK does not support LISTEN_BEFORE_TALK"
+    );
+
+    let lora = lora::Lora::new(
         lora_spi_device,
         lora_cs.into_output().unwrap(),
         lora_reset.into_input_output().unwrap(),
-        868,
+        lora_dio0,
+        MODEM,
+        FREQUENCY_PLAN,
+        CHANNEL,
+        LISTEN_BEFORE_TALK,
         esp_idf_hal::delay::FreeRtos,
     );
     let lora: &'static _ = Box::leak(Box::new(lora));
@@ -0,0 +1,21 @@
+use embedded_svc::storage::RawStorage;
+use pv_protocol::adr::{MAX_SPREADING_FACTOR, MIN_SPREADING_FACTOR};
+
+/// Returns the spreading factor which the senders were last told to use, so that the receiver
+/// still hears them after a reboot.
+pub fn get_spreading_factor() -> u8 {
+    let storage_locked = crate::STORAGE.lock().unwrap();
+    let mut target = [0; 1];
+    match storage_locked.get_raw("linksf", &mut target).unwrap() {
+        Some(&[sf]) if (MIN_SPREADING_FACTOR..=MAX_SPREADING_FACTOR).contains(&sf) => sf,
+        _ => MIN_SPREADING_FACTOR,
+    }
+}
+
+pub fn set_spreading_factor(spreading_factor: u8) {
+    crate::STORAGE
+        .lock()
+        .unwrap()
+        .set_raw("linksf", &[spreading_factor])
+        .unwrap();
+}
@@ -1,9 +1,17 @@
+use crate::lora::{LinkMetadata, LoraError};
 use embedded_hal_0_2::blocking::delay::DelayMs;
 use embedded_hal_0_2::blocking::spi::{Transfer, Write};
 use embedded_hal_0_2::digital::v2::OutputPin;
 use esp_idf_hal::delay::FreeRtos;
 use esp_idf_hal::modem::Modem;
 use esp_idf_svc::eventloop::EspSystemEventLoop;
+use pv_protocol::ack::AckHeader;
+use pv_protocol::adr::{RadioSettings, ReceiverAdr, MAX_TX_POWER_DBM};
+use pv_protocol::envelope::{EnvelopeError, EnvelopeHeader};
+use pv_protocol::fragment::{self, Reassembler};
+use pv_protocol::mppt::Algorithm;
+use pv_protocol::rate_limit::{self, RateLimiter};
+use pv_protocol::{DecodeError, Flags, Frame, MessageType};
 use sntp_request::SntpRequest;
 use std::collections::HashMap;
 use std::sync::{Arc, Mutex};
@@ -32,14 +40,58 @@ where
     RESET::Error: std::fmt::Debug,
     DELAY: DelayMs<u8> + Send + 'static,
 {
-    
+    /// Time after which the fragments of an incomplete message are dropped.
+    const REASSEMBLY_TIMEOUT_MS: u64 = 30_000;
 
-    let mut received_nonces = HashMap::new();
+    // `received_at` is when the last packet of the message arrived, after which the sender listens
+    // for the acknowledgement
+    let send_ack = |envelope: EnvelopeHeader,
+                    radio: Option<RadioSettings>,
+                    received_at: std::time::Instant| async move {
+        let ack = AckHeader {
+            sender_id: envelope.sender_id,
+            key_epoch: envelope.key_epoch,
+            counter: envelope.counter,
+            radio,
+        };
+        // The key was just used to decrypt the message, so it is stored
+        let packet = crate::encryption::seal_ack(&ack).unwrap();
+        println!("Sending acknowledgement: {:?}", packet);
+        // Fails if the duty cycle of the receiver is exhausted, or if the channel is busy for longer
+        // than the sender listens. The sender then sends the message again.
+        let deadline =
+            received_at + std::time::Duration::from_millis(pv_protocol::ack::ACK_TIMEOUT_MS as u64);
+        if let Err(e) = lora.send_raw_message(&packet, Some(deadline)).await {
+            display.push(format!("Could not send acknowledgement: {e}"));
+        }
+    };
+
+    let mut rate_limiter = RateLimiter::new(rate_limit::BURST, rate_limit::INTERVAL_MS);
+    let mut replay = super::replay::Replay::new();
+    // Keeps how each fragment was received, see the link point below
+    let mut reassembler: Reassembler<Option<LinkMetadata>> =
+        Reassembler::new(REASSEMBLY_TIMEOUT_MS);
+    let started = std::time::Instant::now();
+
+    // Spreading factor of all senders, and transmit power of each, see pv_protocol::adr
+    let mut adr = ReceiverAdr::new(
+        super::link::get_spreading_factor(),
+        lora.max_spreading_factor(),
+    );
+    let settings = RadioSettings {
+        spreading_factor: adr.spreading_factor(),
+        tx_power_dbm: MAX_TX_POWER_DBM,
+    };
+    if let Err(e) = lora.set_raw_settings(settings).await {
+        display.push(format!("Failed to apply radio settings: {e}"));
+    }
+    // Message type and uptime of the first point of the last message of each sender
+    let mut last_frames: HashMap<u8, (u8, Option<u32>)> = HashMap::new();
 
     loop {
         let start_wait = std::time::SystemTime::now();
         display.push("Waiting for LoRa message..".to_string());
-        let msg = smol::future::race(lora.receive_message(), async {
+        let received = smol::future::race(lora.receive_message(), async {
             smol::Timer::after(std::time::Duration::from_secs(60)).await;
             display.push("Waiting for LoRa  (1 minute)..".to_string());
             smol::Timer::after(std::time::Duration::from_secs(540)).await;
@@ -52,107 +104,241 @@ where
             }
         })
         .await;
+        let packet = match received {
+            Ok(packet) => packet,
+            Err(LoraError::Crc) => {
+                display.push("Got LoRa packet with invalid CRC. Skipping.".to_string());
+                continue;
+            }
+            // Nothing will arrive anymore, so start over
+            Err(LoraError::Stopped) => {
+                display.push("Receiving LoRa messages stopped, restarting".to_string());
+                smol::Timer::after(std::time::Duration::from_secs(5)).await;
+                unsafe { esp_idf_sys::esp_restart() };
+            }
+            Err(e) => {
+                display.push(format!("Failed to receive LoRa message: {e}"));
+                continue;
+            }
+        };
+        let msg = packet.payload;
         display.push(format!("Got LoRa message of {} bytes", msg.len()));
 
-        println!("Got encrypted message: {:?}", msg);
+        println!("Got LoRa packet: {:?}", msg);
 
-        let Some((nonce, decrypted)) = crate::encryption::decrypt(&msg) else {
-            display.push("Decryption failed. Skipping message".to_string());
+        if pv_protocol::ack::is_ack(&msg) {
+            println!("Ignoring acknowledgement sent by another receiver");
             continue;
-        };
+        }
 
-        println!("Got decrypted LoRa message: {:?}", decrypted);
+        let (fragment_header, fragment_payload) = match fragment::parse_fragment(&msg) {
+            Ok(parsed) => parsed,
+            Err(e) => {
+                display.push(format!("Invalid packet ({e}). Skipping."));
+                continue;
+            }
+        };
 
-        if decrypted.len() < 1 {
-            display.push("Message was empty. Skipping.".to_string());
+        let received_at = std::time::Instant::now();
+        let now_ms = received_at.duration_since(started).as_millis() as u64;
+        let Some((msg, links)) =
+            reassembler.insert(fragment_header, fragment_payload, packet.link, now_ms)
+        else {
+            println!(
+                "Got fragment {} of {} from sender {}, waiting for the rest",
+                fragment_header.index + 1,
+                fragment_header.count,
+                fragment_header.sender_id
+            );
             continue;
-        }
+        };
+
+        // The fragment with the lowest SNR limits the link. Only the fragments which make up the
+        // message count, so a forged fragment fails decryption along with the message.
+        let link = links
+            .into_iter()
+            .flatten()
+            .min_by(|a, b| a.snr_db.total_cmp(&b.snr_db));
 
-        let timestamp = super::time::get_current_time().await;
+        println!("Got encrypted message: {:?}", msg);
 
-        if let Some(previous_timestamp) = received_nonces.get(&nonce) {
-            if *previous_timestamp > timestamp - 3600 {
-                display.push(format!(
-                    "Duplicate nonce {nonce} in past hour. Skipping message."
-                ));
+        // Everything up to decryption only looks at the clear-text envelope header, which is
+        // authenticated as associated data once the message is decrypted.
+        let envelope = match EnvelopeHeader::parse(&msg) {
+            Ok((envelope, _)) => envelope,
+            Err(e @ EnvelopeError::UnsupportedVersion(_)) => {
+                display.push(format!("Skipping message: {e}"));
                 continue;
             }
+            Err(e) => {
+                display.push(format!("Invalid message ({e}). Skipping."));
+                continue;
+            }
+        };
+
+        if MessageType::from_u8(envelope.message_type).is_none() {
+            display.push(format!(
+                "Skipping message of unknown type {} from sender {}",
+                envelope.message_type, envelope.sender_id
+            ));
+            continue;
+        }
+
+        if !rate_limiter.allow(envelope.sender_id, now_ms) {
+            display.push(format!(
+                "Too many messages from sender {}. Skipping.",
+                envelope.sender_id
+            ));
+            continue;
+        }
+
+        if let Err(e) = replay.check(envelope.sender_id, envelope.counter) {
+            display.push(format!("Replayed message ({e}). Skipping."));
+            continue;
         }
-        received_nonces.insert(nonce, timestamp);
 
-        let first_byte = decrypted[0];
-        let destination = first_byte & 1;
-        let id = first_byte >> 1;
+        let keys = crate::encryption::get_keys(envelope.sender_id);
+        if keys.get(envelope.key_epoch).is_none() {
+            display.push(format!(
+                "No encryption key for sender {} epoch {}. Skipping message",
+                envelope.sender_id, envelope.key_epoch
+            ));
+            continue;
+        }
 
-        let voltages_and_currents_bytes = if destination == 0 {
-            if decrypted.len() < 3 {
-                display.push("Invalid message. Skipping.".to_string());
+        let Some((envelope, decrypted)) = crate::encryption::decrypt(&msg) else {
+            display.push("Decryption failed. Skipping message".to_string());
+            continue;
+        };
+
+        // Only remember the counter once the message is known to be authentic. Otherwise anyone
+        // could block a sender by sending garbage with a high counter.
+        if let Err(e) = replay.accept(envelope.sender_id, envelope.counter) {
+            display.push(format!("Replayed message ({e}). Skipping."));
+            continue;
+        }
+
+        println!("Got decrypted LoRa message: {:?}", decrypted);
+
+        let (header, frame) = match pv_protocol::decode(&decrypted) {
+            Ok(decoded) => decoded,
+            Err(
+                e @ (DecodeError::UnsupportedVersion(_) | DecodeError::UnknownMessageType { .. }),
+            ) => {
+                display.push(format!("Skipping message: {e}"));
+                continue;
+            }
+            Err(e) => {
+                display.push(format!("Invalid message ({e}). Skipping."));
                 continue;
             }
-            &decrypted[1..decrypted.len() - 2]
-        } else {
-            &decrypted[1..]
         };
 
-        if voltages_and_currents_bytes.len() % 4 != 0 {
-            display.push("Invalid message. Skipping.".to_string());
+        let id = frame.sender_id();
+        if id != envelope.sender_id || header.message_type != envelope.message_type {
+            display.push(format!(
+                "Frame header does not match envelope from sender {}. Skipping.",
+                envelope.sender_id
+            ));
             continue;
         }
 
-        let mut voltages_and_currents = voltages_and_currents_bytes
-            .chunks(4)
-            .map(|chunk| {
-                let voltage =
-                    u16::from_be_bytes(chunk[0..2].try_into().unwrap()) as f32 / (32768.0 / 100.0);
-                let current =
-                    u16::from_be_bytes(chunk[2..4].try_into().unwrap()) as f32 / (32768.0 / 10.0);
-                (
-                    voltage_calibration.calibrate(id, voltage),
-                    current_calibration.calibrate(id, current),
-                )
-            })
-            .collect::<Vec<_>>();
-
-        if destination == 0 {
-            // MPP point
-            let mut timestamp_ms = timestamp * 1000;
-            let millis_between =
-                u16::from_be_bytes(decrypted[decrypted.len() - 2..].try_into().unwrap()) as i64;
+        // A sender which did not get the acknowledgement of its last message sends it again with a
+        // new frame counter. Its first point is measured at the same uptime, which tells it apart.
+        let first_point = (header.message_type, frame.point_uptimes().next());
+        let repeated = last_frames.insert(id, first_point) == Some(first_point);
 
-            println!(
-                "Writing {} MPP points at time={}",
-                voltages_and_currents.len(),
-                timestamp_ms
+        let ack_requested = header.flags.contains(Flags::ACK_REQUESTED);
+        if let Some(link) = link {
+            adr.record(
+                id,
+                link.snr_db.floor() as i16,
+                envelope.tx_power_dbm,
+                ack_requested,
+                now_ms,
             );
+        }
 
-            voltages_and_currents.reverse();
-            for (voltage, current) in voltages_and_currents {
-                influx.write(format!(
-                    "mppt,host=ttgo{} voltage={voltage},current={current} {}",
-                    id,
-                    timestamp_ms * 1_000_000
-                ));
-                timestamp_ms -= millis_between;
+        // Acknowledge before fetching the time, since the sender only listens for a short while
+        if ack_requested {
+            send_ack(envelope, adr.recommend(id), received_at).await;
+        }
+
+        if let Some(spreading_factor) = adr.poll_switch(now_ms) {
+            display.push(format!(
+                "All senders were told, switching to SF{spreading_factor}"
+            ));
+            super::link::set_spreading_factor(spreading_factor);
+            let settings = RadioSettings {
+                spreading_factor,
+                tx_power_dbm: MAX_TX_POWER_DBM,
+            };
+            if let Err(e) = lora.set_raw_settings(settings).await {
+                display.push(format!("Failed to switch to SF{spreading_factor}: {e}"));
             }
-        } else {
-            // Sweep
-            let mut timestamp_ms = timestamp * 1000;
+        }
 
-            println!(
-                "Writing {} MPP points at time={}",
-                voltages_and_currents.len(),
-                timestamp_ms
-            );
+        if repeated {
+            display.push(format!(
+                "Got message from sender {id} again, its points are already stored"
+            ));
+            continue;
+        }
 
-            voltages_and_currents.reverse();
-            for (voltage, current) in voltages_and_currents {
-                influx.write(format!(
-                    "sweep,host=ttgo{} voltage={voltage},current={current} {}",
-                    id,
-                    timestamp_ms * 1_000_000
-                ));
-                timestamp_ms -= 1;
-            }
+        let wall_time_ms = super::time::get_current_time_ms().await;
+
+        // MPPT points are tagged with the algorithm which tracked them, so they can be compared
+        let (measurement, tags) = match &frame {
+            Frame::Mppt { algorithm, .. } => (
+                "mppt",
+                match Algorithm::from_u8(*algorithm) {
+                    Some(algorithm) => format!(",algorithm={}", algorithm.name()),
+                    None => format!(",algorithm={algorithm}"),
+                },
+            ),
+            Frame::Sweep { .. } => ("sweep", String::new()),
+        };
+
+        // The frame carries the uptime of the sender when each point was measured, which is
+        // mapped to wall time using the uptime of the sender when the frame was sent.
+        // Millisecond resolution keeps the link points of messages received in the same second
+        // apart.
+        let received_at_ms = wall_time_ms - received_at.elapsed().as_millis() as i64;
+
+        println!(
+            "Writing {} {measurement} points received at time={received_at_ms}",
+            frame.points().len(),
+        );
+
+        for (point, uptime) in frame.points().iter().zip(frame.point_uptimes()) {
+            let voltage = point.voltage as f32 / (32768.0 / 100.0);
+            let current = point.current as f32 / (32768.0 / 10.0);
+            let voltage = voltage_calibration.calibrate(id, voltage);
+            let current = current_calibration.calibrate(id, current);
+
+            let timestamp_ms =
+                pv_protocol::uptime_to_wall_time(uptime, header.uptime_ms, received_at_ms);
+
+            influx.write(format!(
+                "{measurement},host=ttgo{}{tags} voltage={voltage},current={current} {}",
+                id,
+                timestamp_ms * 1_000_000
+            ));
+        }
+
+        // Only written for messages which decrypted, from the first copy of each of their
+        // fragments. Someone relaying a fragment of the sender faster than it arrives directly
+        // can still change the link.
+        if let Some(link) = link {
+            influx.write(format!(
+                "link,host=ttgo{id} rssi={}i,snr={},freq_error={}i,sf={}i,frequency={}i {}",
+                link.rssi_dbm,
+                link.snr_db,
+                link.frequency_error_hz,
+                link.spreading_factor,
+                link.frequency_hz,
+                received_at_ms * 1_000_000
+            ));
         }
     }
 }
@@ -1,6 +1,8 @@
 mod calibration;
 mod influx;
+mod link;
 mod messages;
+mod replay;
 mod server;
 mod time;
 mod wifi;
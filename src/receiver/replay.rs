@@ -0,0 +1,54 @@
+use embedded_svc::storage::RawStorage;
+use pv_protocol::replay::{ReplayError, ReplayGuard};
+
+/// Keeps track of the highest frame counter accepted from each sender, persisted in NVS so that
+/// replayed messages are rejected after a reboot as well.
+pub struct Replay {
+    guard: ReplayGuard,
+}
+
+fn storage_key(sender_id: u8) -> String {
+    format!("CTR{sender_id}")
+}
+
+impl Replay {
+    pub fn new() -> Self {
+        Self {
+            guard: ReplayGuard::new(),
+        }
+    }
+
+    fn load_stored(&mut self, sender_id: u8) {
+        if self.guard.high_water_mark(sender_id).is_some() {
+            return;
+        }
+        let storage_locked = crate::STORAGE.lock().unwrap();
+        let mut target = [0; 4];
+        if let Some(stored) = storage_locked
+            .get_raw(&storage_key(sender_id), &mut target)
+            .unwrap()
+        {
+            let high_water_mark = u32::from_be_bytes(stored.try_into().unwrap());
+            println!("Found frame counter {high_water_mark} for sender {sender_id} in storage");
+            self.guard.load(sender_id, high_water_mark);
+        }
+    }
+
+    /// Checks the counter of a message before decrypting it.
+    pub fn check(&mut self, sender_id: u8, counter: u32) -> Result<(), ReplayError> {
+        self.load_stored(sender_id);
+        self.guard.check(sender_id, counter)
+    }
+
+    /// Records the counter of a successfully decrypted message.
+    pub fn accept(&mut self, sender_id: u8, counter: u32) -> Result<(), ReplayError> {
+        self.load_stored(sender_id);
+        let high_water_mark = self.guard.accept(sender_id, counter)?;
+        crate::STORAGE
+            .lock()
+            .unwrap()
+            .set_raw(&storage_key(sender_id), &high_water_mark.to_be_bytes())
+            .unwrap();
+        Ok(())
+    }
+}
@@ -173,6 +173,37 @@ pub fn start_server(
         <br />
         <input type="submit" value="Set" style="margin: 0.5em 0 0 auto; display: block;">
     </form>
+    <br />
+    <form method="post" action="/setkey" enctype="application/x-www-form-urlencoded"
+        style="display: inline-block; border: 1px solid lightgrey; padding: 0 1em 1em 1em;">
+        <h2>Set encryption key:</h2>
+        <span style="display: block; width: 500px;">
+            Enter the ID for the device, the key epoch (0-255) and its 256-bit encryption key as 64 hexadecimal
+            characters. The same key and epoch must be provisioned on the sender over its serial port. Use a
+            different key for each device.
+        </span>
+        <br />
+        <span style="display: block; width: 500px;">
+            To rotate a key, enter the new key with a new epoch here first and then on the sender. Messages
+            encrypted with the previous key are still accepted until the key after that is entered.
+        </span>
+        <br />
+        <span style="display: block; width: 500px;">
+            To remove all keys of a device, enter the device ID and leave the other fields empty.
+        </span>
+        <br />
+        <br />
+        <div style="display: grid; grid-template-columns: auto 500px; gap: 0.5em 2em;">
+            Device ID:
+            <input name="devid" type="text" value="">
+            Key epoch:
+            <input name="epoch" type="text" value="">
+            Key:
+            <input name="key" type="password" value="">
+        </div>
+        <br />
+        <input type="submit" value="Set" style="margin: 0.5em 0 0 auto; display: block;">
+    </form>
 </body>
 
 </html>"#
@@ -278,6 +309,58 @@ pub fn start_server(
         })
         .unwrap();
 
+    server
+        .fn_handler("/setkey", Method::Post, |mut req| {
+            let Some(Ok(length)) = req.header("Content-Length").map(|x| x.parse::<usize>()) else {
+                return HandlerResult::Err(HandlerError::new("Invalid header Content-Length"));
+            };
+
+            let mut body = vec![0; length];
+            if req.read_exact(&mut body).is_err() {
+                return Err(HandlerError::new("Failed to read body"));
+            }
+
+            let parsed = url::form_urlencoded::parse(&body);
+            let mut params = parsed
+                .clone()
+                .filter(|p| p.0 == "devid" || p.0 == "epoch" || p.0 == "key")
+                .collect::<HashMap<_, _>>();
+
+            let device_id: u8 = params
+                .remove("devid")
+                .ok_or(HandlerError::new("Missing parameter devid"))?
+                .parse()
+                .map_err(|_| {
+                    HandlerError::new("Failed to parse device id as 8-bit unsigned int")
+                })?;
+            let epoch = params
+                .remove("epoch")
+                .ok_or(HandlerError::new("Missing parameter epoch"))?;
+            let key = params
+                .remove("key")
+                .ok_or(HandlerError::new("Missing parameter key"))?;
+
+            if epoch.trim().is_empty() && key.trim().is_empty() {
+                println!("Removing encryption keys for device {device_id}");
+                crate::encryption::remove_keys(device_id);
+            } else {
+                let epoch = epoch.trim().parse().map_err(|_| {
+                    HandlerError::new("Failed to parse epoch as 8-bit unsigned int")
+                })?;
+                let key = pv_protocol::key::parse_key(&key)
+                    .map_err(|e| HandlerError::new(&format!("Invalid key: {e}")))?;
+                // Never print the key itself
+                println!("Setting encryption key for device {device_id}, epoch {epoch}");
+                crate::encryption::install_key(
+                    device_id,
+                    pv_protocol::key::EpochKey { epoch, key },
+                );
+            }
+
+            Ok(())
+        })
+        .unwrap();
+
     let set_calibration = |voltage: bool, mut req: Request<&mut EspHttpConnection>| {
         let Some(Ok(length)) = req.header("Content-Length").map(|x| x.parse::<usize>()) else {
             return HandlerResult::Err(HandlerError::new("Invalid header Content-Length"));
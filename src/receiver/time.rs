@@ -1,4 +1,5 @@
-use std::time::Duration;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
 
 use sntp_request::SntpRequest;
 
@@ -23,3 +24,22 @@ pub async fn get_current_time() -> i64 {
         }
     }
 }
+
+/// Wall time in milliseconds at an instant, from which [`get_current_time_ms`] counts.
+static CLOCK: Mutex<Option<(i64, Instant)>> = Mutex::new(None);
+
+/// Get the current time as number of milliseconds since Jan 1, 1970. SNTP only gives whole
+/// seconds, so the milliseconds are counted by the local clock, which is only set again when it
+/// leaves the second given by SNTP.
+pub async fn get_current_time_ms() -> i64 {
+    let second_ms = get_current_time().await * 1000;
+    let now = Instant::now();
+    let mut clock = CLOCK.lock().unwrap();
+    let local_ms = clock.map(|(ms, at)| ms + now.duration_since(at).as_millis() as i64);
+    let ms = match local_ms {
+        Some(ms) => ms.clamp(second_ms, second_ms + 999),
+        None => second_ms,
+    };
+    *clock = Some((ms, now));
+    ms
+}
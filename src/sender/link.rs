@@ -0,0 +1,65 @@
+use embedded_svc::storage::RawStorage;
+use pv_protocol::adr::RadioSettings;
+
+/// Port of the LoRaWAN uplinks which carry frames.
+pub const FRAME_PORT: u8 = 1;
+
+/// How the sender reports its measurements. Stored in NVS, so that it can be changed over serial
+/// without building a new firmware.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LinkMode {
+    /// Encrypted messages to our own receiver.
+    Raw,
+    /// LoRaWAN uplinks through any gateway to a network server.
+    Lorawan,
+}
+
+impl LinkMode {
+    pub fn parse(name: &str) -> Option<Self> {
+        match name {
+            "raw" => Some(Self::Raw),
+            "lorawan" => Some(Self::Lorawan),
+            _ => None,
+        }
+    }
+}
+
+pub fn get_link_mode() -> LinkMode {
+    let storage_locked = crate::STORAGE.lock().unwrap();
+    let mut target = [0; 1];
+    match storage_locked.get_raw("link", &mut target).unwrap() {
+        Some([1]) => LinkMode::Lorawan,
+        _ => LinkMode::Raw,
+    }
+}
+
+pub fn set_link_mode(mode: LinkMode) {
+    let value = match mode {
+        LinkMode::Raw => 0,
+        LinkMode::Lorawan => 1,
+    };
+    crate::STORAGE
+        .lock()
+        .unwrap()
+        .set_raw("link", &[value])
+        .unwrap();
+}
+
+/// Returns the settings of the raw link which the receiver recommended last.
+pub fn get_radio_settings() -> RadioSettings {
+    let storage_locked = crate::STORAGE.lock().unwrap();
+    let mut target = [0; 2];
+    storage_locked
+        .get_raw("radio", &mut target)
+        .unwrap()
+        .and_then(|stored| RadioSettings::from_bytes(stored.try_into().unwrap()))
+        .unwrap_or(RadioSettings::DEFAULT)
+}
+
+pub fn set_radio_settings(settings: RadioSettings) {
+    crate::STORAGE
+        .lock()
+        .unwrap()
+        .set_raw("radio", &settings.to_bytes())
+        .unwrap();
+}
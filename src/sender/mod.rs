@@ -1,7 +1,11 @@
 mod compat;
+mod link;
 mod mppt;
+mod provisioning;
+mod queue;
 mod temperature;
 
+use crate::lora::LoraError;
 use embedded_hal_0_2::adc::OneShot;
 use embedded_hal_0_2::blocking::delay::DelayMs;
 use embedded_hal_0_2::blocking::spi::{Transfer, Write};
@@ -13,9 +17,32 @@ use esp_idf_hal::i2c::{I2cConfig, I2cDriver, I2C1};
 use esp_idf_hal::ledc::{config::TimerConfig, LEDC};
 use esp_idf_hal::ledc::{LedcDriver, LedcTimerDriver};
 use esp_idf_hal::prelude::FromValueType;
-use std::cell::RefCell;
+use link::LinkMode;
+use pv_protocol::adr::SenderAdr;
+use pv_protocol::queue::Queue;
+use pv_protocol::rate_limit::{self, RateLimiter};
+use pv_protocol::{Encoding, Flags, Frame, MeasurementPoint, MessageType};
+use std::cell::{Cell, RefCell};
 use std::time::Duration;
 
+/// Encodes the frame both raw and compressed and returns whichever is shorter. Compression only
+/// pays off when consecutive points are close to each other, which is not always the case.
+fn encode_frame(frame: &Frame, flags: Flags, uptime_ms: u32) -> Vec<u8> {
+    let raw = pv_protocol::encode_with_flags(frame, Encoding::Raw, flags, uptime_ms).unwrap();
+    let compressed =
+        pv_protocol::encode_with_flags(frame, Encoding::Compressed, flags, uptime_ms).unwrap();
+    println!(
+        "Encoded frame is {} bytes raw and {} bytes compressed",
+        raw.len(),
+        compressed.len()
+    );
+    if compressed.len() < raw.len() {
+        compressed
+    } else {
+        raw
+    }
+}
+
 pub async fn run_sender<
     I2C: embedded_hal_0_2::blocking::i2c::Write + Send,
     SPI,
@@ -88,79 +115,501 @@ where
         measure_voltage().await,
         measure_current().await,
         channel,
+        mppt::new_algorithm(mppt::get_algorithm()),
         &mut FreeRtos,
     );
+    display.push(format!("MPPT algorithm: {}", mppt.algorithm().name()));
 
     const SENDER_ID: u8 = {
         let val = konst::result::unwrap_ctx!(konst::primitive::parse_u8(std::env!("DEVICE_ID")));
-        assert!(val < 2u8.pow(7));
+        assert!(val <= pv_protocol::MAX_SENDER_ID);
         val
     };
 
-    struct MeasurementPoint {
-        voltage: u16,
-        current: u16,
+    // Number of retransmissions if no acknowledgement arrives. If ACK_RETRIES is not set (or
+    // empty), the sender does not ask for acknowledgements.
+    const ACK_RETRIES: Option<u8> = match std::option_env!("ACK_RETRIES") {
+        Some(val) if !val.is_empty() => {
+            Some(konst::result::unwrap_ctx!(konst::primitive::parse_u8(val)))
+        }
+        _ => None,
+    };
+    // Longest time to wait for the duty cycle to allow a message. Waiting blocks the MPPT, so
+    // messages which would have to wait longer are not sent (or kept in the queue).
+    const MAX_DUTY_CYCLE_WAIT_MS: u64 = 30_000;
+
+    let frame_flags = if ACK_RETRIES.is_some() {
+        Flags::ACK_REQUESTED
+    } else {
+        Flags::default()
+    };
+
+    provisioning::start_serial_provisioning(SENDER_ID);
+    // LoRaWAN uses the session keys of the network instead
+    if link::get_link_mode() == LinkMode::Raw
+        && crate::encryption::get_keys(SENDER_ID).current.is_none()
+    {
+        display
+            .push("No encryption key. Send \"key <epoch> <64 hex chars>\" over serial".to_owned());
+        while crate::encryption::get_keys(SENDER_ID).current.is_none() {
+            smol::Timer::after(Duration::from_secs(1)).await;
+        }
+        display.push("Encryption key stored".to_owned());
+    } else if link::get_link_mode() == LinkMode::Lorawan {
+        // Only tries once, later attempts happen when there is something to send
+        match lora.join().await {
+            Ok(()) => display.push("Joined LoRaWAN network".to_owned()),
+            Err(e) => display.push(format!("Not joined to LoRaWAN network: {e}")),
+        }
     }
 
-    let send_mppt = |points: Vec<MeasurementPoint>, start_time: std::time::SystemTime| async move {
-        const MPPT_DESTINATION: u8 = 0;
+    // Clock used for all timestamps sent to the receiver, which maps it to wall time. The sender
+    // has no network connection of its own to fetch the current time from, so the system time
+    // starts at zero, but unlike an Instant the RTC keeps it running across deep sleep and
+    // software resets.
+    let clock_ms = || {
+        std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap()
+            .as_millis() as u64
+    };
+    // The frames only have room for 32 bits, the receiver handles the wrap around
+    let uptime_ms = || clock_ms() as u32;
+    // Only a power-on reset restarts the clock, after which the uptimes of queued points are
+    // meaningless
+    let clock_restarted = matches!(
+        unsafe { esp_idf_sys::esp_reset_reason() },
+        esp_idf_sys::esp_reset_reason_t_ESP_RST_POWERON
+            | esp_idf_sys::esp_reset_reason_t_ESP_RST_BROWNOUT
+    );
 
-        let first_byte = SENDER_ID << 1 | MPPT_DESTINATION;
+    // Average time between `count` points where the first was measured at `started_at` and the
+    // last one just now.
+    let duration_per_point = |started_at: u32, count: usize| {
+        let total_duration = uptime_ms().wrapping_sub(started_at);
+        println!("Total duration: {total_duration}");
+        (total_duration / (count as u32 - 1).max(1)) as u16
+    };
 
-        let mut message = Vec::with_capacity(1 + points.len() * 4 + 2);
-        message.push(first_byte);
+    // Sequence number which lets the receiver tell the fragments of different messages apart
+    let sequence = Cell::new(0u8);
+
+    // Same limit as the receiver applies to this sender, so messages which it would drop are kept
+    // in the queue instead
+    let rate_limiter = RefCell::new(RateLimiter::new(rate_limit::BURST, rate_limit::INTERVAL_MS));
+    let rate_limiter = &rate_limiter;
+
+    // Spreading factor and transmit power recommended by the receiver in its acknowledgements
+    let adr = RefCell::new(SenderAdr::new(
+        link::get_radio_settings(),
+        lora.max_spreading_factor(),
+    ));
+    let adr = &adr;
+    if let Err(e) = lora.set_raw_settings(adr.borrow().settings()).await {
+        display.push(format!("Failed to apply radio settings: {e}"));
+    }
+    let apply_radio_settings = || async move {
+        let settings = adr.borrow().settings();
+        link::set_radio_settings(settings);
+        // The settings change again after the next acknowledgement or failure
+        if let Err(e) = lora.set_raw_settings(settings).await {
+            display.push(format!("Failed to apply radio settings: {e}"));
+            return;
+        }
+        display.push(format!(
+            "Now sending at SF{} and {} dBm",
+            settings.spreading_factor, settings.tx_power_dbm
+        ));
+    };
 
-        for point in points {
-            message.extend_from_slice(&point.voltage.to_be_bytes());
-            message.extend_from_slice(&point.current.to_be_bytes());
+    // Encrypts and sends an encoded frame. If ACK_RETRIES is set, the message is retransmitted
+    // until it is acknowledged. Returns false if the message should be kept to be sent again later.
+    let transmit = |kind: &'static str, mut frame: Vec<u8>| {
+        let current_sequence = sequence.get();
+        sequence.set(current_sequence.wrapping_add(1));
+        async move {
+            let message_type = pv_protocol::Header::parse(&frame)
+                .ok()
+                .and_then(|(header, _)| MessageType::from_u8(header.message_type));
+            let Some(message_type) = message_type else {
+                // Sending it again would not help
+                display.push(format!("Dropped invalid {kind} message"));
+                return true;
+            };
+            let fragment = |message: &[u8]| {
+                pv_protocol::fragment::fragment_with_size(
+                    SENDER_ID,
+                    current_sequence,
+                    message,
+                    lora.max_packet_size(),
+                )
+            };
+            // The packets only exist once the message is encrypted, but their lengths are known
+            // before
+            let lengths = match fragment(&vec![0; super::encryption::encrypted_len(frame.len())]) {
+                Ok(packets) => packets.iter().map(Vec::len).collect::<Vec<_>>(),
+                Err(e) => {
+                    display.push(format!("Dropped {kind} message: {e}"));
+                    return true;
+                }
+            };
+            display.push(format!(
+                "Sending {kind} message of {} bytes in {} packets",
+                frame.len(),
+                lengths.len()
+            ));
+
+            // Every attempt has its own frame counter, and a late acknowledgement of any of them
+            // counts
+            let mut counters = Vec::new();
+            let retries = ACK_RETRIES.unwrap_or(0) as u32;
+            for attempt in 0..=retries {
+                if attempt > 0 {
+                    let random = unsafe { esp_idf_sys::esp_random() };
+                    let delay = pv_protocol::ack::retry_delay_ms(attempt - 1, random);
+                    display.push(format!(
+                        "No acknowledgement. Retrying in {delay} ms ({attempt}/{retries})"
+                    ));
+                    smol::Timer::after(Duration::from_millis(delay as u64)).await;
+                }
+
+                // Every attempt goes out on another channel, in case this one is busy
+                let channel = pv_protocol::plan::hop_channel(
+                    SENDER_ID,
+                    current_sequence,
+                    attempt as u8,
+                    lora.channel_count(),
+                );
+                if let Err(e) = lora.select_channel(channel).await {
+                    display.push(format!("Cannot send {kind} message: {e}"));
+                    return false;
+                }
+
+                match lora.duty_cycle_wait_ms(&lengths) {
+                    Ok(0) => {}
+                    Ok(wait_ms) if wait_ms <= MAX_DUTY_CYCLE_WAIT_MS => {
+                        display.push(format!("Duty cycle exhausted, waiting {wait_ms} ms"));
+                        smol::Timer::after(Duration::from_millis(wait_ms)).await;
+                    }
+                    Ok(wait_ms) => {
+                        display.push(format!(
+                            "Duty cycle exhausted for {} s, {kind} message not sent",
+                            wait_ms / 1000
+                        ));
+                        return false;
+                    }
+                    Err(e) => {
+                        display.push(format!("Cannot send {kind} message: {e}"));
+                        return false;
+                    }
+                }
+
+                if !rate_limiter.borrow_mut().allow(SENDER_ID, clock_ms()) {
+                    display.push(format!(
+                        "Receiver would drop more messages, {kind} message not sent"
+                    ));
+                    return false;
+                }
+
+                // Stamped and encrypted right before sending, so neither the time in the queue
+                // nor the retry delays end up in the timestamps of the points. The header was
+                // parsed above, so it can be stamped.
+                pv_protocol::set_uptime(&mut frame, uptime_ms()).unwrap();
+                let tx_power_dbm = adr.borrow().settings().tx_power_dbm;
+                let Some((envelope, encrypted)) =
+                    super::encryption::encrypt(SENDER_ID, message_type, tx_power_dbm, &frame)
+                else {
+                    display.push(format!("No encryption key, {kind} message not sent"));
+                    return false;
+                };
+                counters.push(envelope.counter);
+                // Fragmented above with the same length
+                for packet in &fragment(&encrypted).unwrap() {
+                    println!("Sending packet: {:?}", packet);
+                    if let Err(e) = lora.send_raw_message(packet, None).await {
+                        display.push(format!("Failed to send {kind} message: {e}"));
+                        return false;
+                    }
+                }
+
+                if ACK_RETRIES.is_none() {
+                    return true;
+                }
+
+                let deadline = std::time::Instant::now()
+                    + Duration::from_millis(pv_protocol::ack::ACK_TIMEOUT_MS as u64);
+                while let Some(remaining) =
+                    deadline.checked_duration_since(std::time::Instant::now())
+                {
+                    let packet = match lora
+                        .receive_message_timeout(remaining.as_millis() as i32)
+                        .await
+                    {
+                        Ok(packet) => packet,
+                        Err(LoraError::Timeout) => break,
+                        // Probably a packet of another sender, the acknowledgement may still come
+                        Err(LoraError::Crc) => continue,
+                        Err(e) => {
+                            display.push(format!("Failed to receive acknowledgement: {e}"));
+                            break;
+                        }
+                    };
+                    match super::encryption::verify_ack(&packet) {
+                        Some(ack)
+                            if ack.sender_id == SENDER_ID && counters.contains(&ack.counter) =>
+                        {
+                            display.push(format!("Got acknowledgement of {kind} message"));
+                            if adr.borrow_mut().acknowledged(ack.radio, clock_ms()) {
+                                apply_radio_settings().await;
+                            }
+                            return true;
+                        }
+                        // Acknowledgements for other senders or earlier messages
+                        _ => println!(
+                            "Ignoring packet while waiting for acknowledgement: {packet:?}"
+                        ),
+                    }
+                }
+            }
+            display.push(format!("No acknowledgement of {kind} message, giving up"));
+            if adr.borrow_mut().failed(clock_ms()) {
+                apply_radio_settings().await;
+            }
+            false
         }
+    };
 
-        let total_duration = std::time::SystemTime::now()
-            .duration_since(start_time)
-            .unwrap()
-            .as_millis() as u32;
-        println!("Total duration: {total_duration}");
-        let duration_per_point = (total_duration / ((message.len() as u32 - 1) / 4)) as u16;
-        println!("Duration per point: {duration_per_point}");
-        message.extend_from_slice(&duration_per_point.to_be_bytes());
+    // Frames which have not been acknowledged yet. Only used if ACK_RETRIES is set, since
+    // otherwise the sender cannot tell whether a message arrived.
+    let queue = ACK_RETRIES.map(|_| {
+        RefCell::new(Queue::new(
+            queue::NvsQueueStorage::raw(),
+            queue::QUEUE_CAPACITY,
+        ))
+    });
+    let queue = queue.as_ref();
+    let mut legacy_queue = Queue::new(queue::NvsQueueStorage::legacy_raw(), queue::QUEUE_CAPACITY);
+    if !legacy_queue.is_empty() {
+        display.push(format!(
+            "Dropped {} messages queued by an older firmware",
+            legacy_queue.len()
+        ));
+        while !legacy_queue.is_empty() {
+            legacy_queue.pop_front();
+        }
+    }
+    if let Some(queue) = queue {
+        let mut queue = queue.borrow_mut();
+        if clock_restarted && !queue.is_empty() {
+            display.push(format!(
+                "Clock restarted, dropped {} queued messages",
+                queue.len()
+            ));
+            while !queue.is_empty() {
+                queue.pop_front();
+            }
+        } else if !queue.is_empty() {
+            display.push(format!("{} unacknowledged messages in queue", queue.len()));
+        }
+    }
 
+    let send_raw = |kind: &'static str, message: Vec<u8>| async move {
         println!("Sending: {message:?}");
-        let to_send = super::encryption::encrypt(&message);
-        display.push(format!("Sending mppt message of {} bytes", to_send.len()));
-        println!("Sending encrypted message: {:?}", to_send);
-        lora.send_raw_message(&to_send).await.unwrap();
+        let Some(queue) = queue else {
+            transmit(kind, message).await;
+            return;
+        };
+
+        if queue.borrow_mut().push(&message) {
+            display.push("Message queue full, dropped the oldest message".to_owned());
+        }
+
+        // Oldest first, and stop at the first message which is not acknowledged, so that the
+        // points arrive in the order they were measured
+        loop {
+            let (front, len) = {
+                let mut queue = queue.borrow_mut();
+                (queue.front(), queue.len())
+            };
+            let Some(front) = front else {
+                break;
+            };
+            let kind = if len == 1 { kind } else { "queued" };
+            if !transmit(kind, front).await {
+                display.push(format!("{len} messages kept in queue"));
+                break;
+            }
+            queue.borrow_mut().pop_front();
+        }
     };
 
-    let send_sweep = |points: Vec<MeasurementPoint>| async {
-        const SWEEP_DESTINATION: u8 = 1;
+    // Sends a message in LoRaWAN uplinks, split into fragments which fit at the current data
+    // rate. If ACK_RETRIES is set, the uplinks are confirmed by the network server. Returns false
+    // if the message should be kept to be sent again later.
+    let transmit_lorawan = |kind: &'static str, mut message: Vec<u8>| {
+        let current_sequence = sequence.get();
+        sequence.set(current_sequence.wrapping_add(1));
+        async move {
+            // Stamped right before sending, so the time in the queue does not end up in the
+            // timestamps of the points
+            if pv_protocol::set_uptime(&mut message, uptime_ms()).is_err() {
+                display.push(format!("Dropped invalid {kind} message"));
+                return true;
+            }
+            let packets = match pv_protocol::fragment::fragment_with_size(
+                SENDER_ID,
+                current_sequence,
+                &message,
+                lora.max_lorawan_payload(),
+            ) {
+                Ok(packets) => packets,
+                // It may fit once the network server raises the data rate
+                Err(e) => {
+                    display.push(format!("Cannot send {kind} message: {e}"));
+                    return false;
+                }
+            };
+            display.push(format!(
+                "Sending {kind} message of {} bytes in {} LoRaWAN uplinks",
+                message.len(),
+                packets.len()
+            ));
+
+            for packet in &packets {
+                let confirmed = ACK_RETRIES.is_some();
+                let retries = ACK_RETRIES.unwrap_or(0);
+                match lora
+                    .send_message(link::FRAME_PORT, packet, confirmed, retries)
+                    .await
+                {
+                    Ok(Some(downlink)) if downlink.port == pv_protocol::mppt::DOWNLINK_PORT => {
+                        match pv_protocol::mppt::parse_downlink(&downlink.payload) {
+                            Some(algorithm) => mppt::set_algorithm(algorithm),
+                            None => display.push(format!(
+                                "Invalid MPPT algorithm downlink: {:?}",
+                                downlink.payload
+                            )),
+                        }
+                    }
+                    Ok(Some(downlink)) => println!(
+                        "Got downlink on port {}: {:?}",
+                        downlink.port, downlink.payload
+                    ),
+                    Ok(None) => {}
+                    Err(e) => {
+                        display.push(format!("LoRaWAN uplink failed: {e}"));
+                        return false;
+                    }
+                }
+            }
+            true
+        }
+    };
+
+    // Messages which wait for the sender to join a LoRaWAN network or for their uplinks to be
+    // confirmed. Unlike the queue of the raw link it is always used, since joining can fail
+    // whether or not ACK_RETRIES is set.
+    let lorawan_queue = RefCell::new(Queue::new(
+        queue::NvsQueueStorage::lorawan(),
+        queue::QUEUE_CAPACITY,
+    ));
+    let lorawan_queue = &lorawan_queue;
+    if clock_restarted && !lorawan_queue.borrow().is_empty() {
+        let mut lorawan_queue = lorawan_queue.borrow_mut();
+        display.push(format!(
+            "Clock restarted, dropped {} queued LoRaWAN messages",
+            lorawan_queue.len()
+        ));
+        while !lorawan_queue.is_empty() {
+            lorawan_queue.pop_front();
+        }
+    }
 
-        let first_byte = SENDER_ID << 1 | SWEEP_DESTINATION;
+    let send_lorawan = |kind: &'static str, message: Vec<u8>| async move {
+        if lorawan_queue.borrow_mut().push(&message) {
+            display.push("LoRaWAN queue full, dropped the oldest message".to_owned());
+        }
 
-        let mut message = Vec::with_capacity(1 + points.len() * 4);
-        message.push(first_byte);
+        loop {
+            let (front, len) = {
+                let mut queue = lorawan_queue.borrow_mut();
+                (queue.front(), queue.len())
+            };
+            let Some(front) = front else {
+                break;
+            };
+            if let Err(e) = lora.join().await {
+                display.push(format!(
+                    "Not joined to LoRaWAN network ({e}), {len} messages kept in queue"
+                ));
+                break;
+            }
+            let kind = if len == 1 { kind } else { "queued" };
+            if !transmit_lorawan(kind, front).await {
+                display.push(format!("{len} messages kept in queue"));
+                break;
+            }
+            lorawan_queue.borrow_mut().pop_front();
+        }
+    };
 
-        for point in points {
-            message.extend_from_slice(&point.voltage.to_be_bytes());
-            message.extend_from_slice(&point.current.to_be_bytes());
+    let send_frame = |kind: &'static str, frame: Frame| async move {
+        let link_mode = link::get_link_mode();
+        // Confirmed uplinks replace the acknowledgements of the raw link
+        let flags = match link_mode {
+            LinkMode::Raw => frame_flags,
+            LinkMode::Lorawan => Flags::default(),
+        };
+        let message = encode_frame(&frame, flags, uptime_ms());
+
+        match link_mode {
+            LinkMode::Raw if crate::encryption::get_keys(SENDER_ID).current.is_none() => {
+                display.push(format!("No encryption key, dropped {kind} message"));
+            }
+            LinkMode::Raw => send_raw(kind, message).await,
+            LinkMode::Lorawan => send_lorawan(kind, message).await,
         }
+    };
 
-        println!("Sending: {message:?}");
-        let to_send = super::encryption::encrypt(&message);
-        display.push(format!("Sending sweep message of {} bytes", to_send.len()));
-        println!("Sending encrypted message: {:?}", to_send);
-        lora.send_raw_message(&to_send).await.unwrap();
+    let send_mppt = |points: Vec<MeasurementPoint>,
+                     started_at: u32,
+                     algorithm: pv_protocol::mppt::Algorithm| async move {
+        let duration_per_point = duration_per_point(started_at, points.len());
+        println!("Duration per point: {duration_per_point}");
+
+        let frame = Frame::Mppt {
+            sender_id: SENDER_ID,
+            started_at,
+            duration_per_point,
+            algorithm: algorithm as u8,
+            points,
+        };
+        send_frame("mppt", frame).await;
+    };
+
+    let send_sweep = |points: Vec<MeasurementPoint>, started_at: u32| async move {
+        let duration_per_point = duration_per_point(started_at, points.len());
+
+        let frame = Frame::Sweep {
+            sender_id: SENDER_ID,
+            started_at,
+            duration_per_point,
+            points,
+        };
+        send_frame("sweep", frame).await;
     };
 
     let mut count: u64 = 0;
-    let mut start_time = std::time::SystemTime::now();
+    let mut mppt_started_at = uptime_ms();
     let mut mppt_points = Vec::with_capacity(25);
 
     loop {
         if count % 6000 == 0 {
             display.push("Sweep".to_owned());
 
-            let num_points = 40;
+            let num_points = 200;
             let mut sweep_points = Vec::with_capacity(num_points);
+            let mut sweep_started_at = uptime_ms();
 
             struct MaxPoint {
                 duty: f32,
@@ -185,6 +634,9 @@ where
 
                 let voltage = measure_voltage().await;
                 let current = measure_current().await;
+                if x == 0 {
+                    sweep_started_at = uptime_ms();
+                }
 
                 let power = voltage as u32 * current as u32;
                 if power > max_power.power {
@@ -201,14 +653,15 @@ where
 
             if max_power.current < 300 {
                 if !mppt_points.is_empty() {
-                    send_mppt(mppt_points, start_time).await;
+                    send_mppt(mppt_points, mppt_started_at, mppt.algorithm()).await;
                 } else {
                     send_mppt(
                         vec![MeasurementPoint {
                             voltage: max_power.voltage,
                             current: max_power.current,
                         }],
-                        start_time,
+                        uptime_ms(),
+                        mppt.algorithm(),
                     )
                     .await;
                 }
@@ -216,7 +669,7 @@ where
                 unsafe { esp_idf_sys::esp_deep_sleep(60 * 1_000_000) };
             }
 
-            send_sweep(sweep_points).await;
+            send_sweep(sweep_points, sweep_started_at).await;
 
             mppt.set_operating_point(max_power.duty);
         }
@@ -224,6 +677,16 @@ where
         // After a quick measurement of timing 70 ms delay gave 100 ms total MPPT iteration duration
         smol::Timer::after(Duration::from_millis(70)).await;
 
+        if mppt::take_changed() {
+            // The points so far were tracked by the old algorithm
+            if !mppt_points.is_empty() {
+                send_mppt(mppt_points, mppt_started_at, mppt.algorithm()).await;
+                mppt_points = vec![];
+            }
+            mppt.set_algorithm(mppt::new_algorithm(mppt::get_algorithm()));
+            display.push(format!("MPPT algorithm: {}", mppt.algorithm().name()));
+        }
+
         println!("\nRunning MPPT iteration");
         let voltage = measure_voltage().await;
         let current = measure_current().await;
@@ -236,11 +699,13 @@ where
         count += 1;
         println!("Count: {count}");
         if count % 100 == 0 {
+            if mppt_points.is_empty() {
+                mppt_started_at = uptime_ms();
+            }
             mppt_points.push(MeasurementPoint { voltage, current });
             if mppt_points.len() >= 25 {
-                send_mppt(mppt_points, start_time).await;
+                send_mppt(mppt_points, mppt_started_at, mppt.algorithm()).await;
 
-                start_time = std::time::SystemTime::now();
                 mppt_points = vec![];
             }
         }
@@ -1,9 +1,31 @@
-pub fn iteration(
+use pv_protocol::mppt::Algorithm;
+
+/// Incremental conductance, see [`Algorithm::IncrementalConductance`].
+pub struct IncrementalConductance;
+
+impl super::MpptAlgorithm for IncrementalConductance {
+    fn id(&self) -> Algorithm {
+        Algorithm::IncrementalConductance
+    }
+
+    fn iteration(
+        &mut self,
+        prev_output: f32,
+        voltage: i32,
+        current: i32,
+        prev_voltage: i32,
+        prev_current: i32,
+    ) -> f32 {
+        iteration(prev_output, voltage, current, prev_voltage, prev_current)
+    }
+}
+
+fn iteration(
     prev_output: f32,
-    voltage: i16,
-    current: i16,
-    prev_voltage: i16,
-    prev_current: i16,
+    voltage: i32,
+    current: i32,
+    prev_voltage: i32,
+    prev_current: i32,
 ) -> f32 {
     let dv = voltage - prev_voltage;
     let di = current - prev_current;
@@ -28,7 +50,7 @@ pub fn iteration(
             }
         }
     } else {
-        let test = current as i32 + di as i32 * voltage as i32 / dv as i32;
+        let test = current + di * voltage / dv;
         println!("test: {test}");
         if test != 0 {
             if test > 0 {
@@ -1,12 +1,69 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use embedded_svc::storage::RawStorage;
 use esp_idf_hal::ledc::LedcDriver;
+use pv_protocol::mppt::Algorithm;
 
 mod ic;
 mod po;
 
 const INITIAL_GUESS: f32 = 0.2;
 
+/// Set when another algorithm is stored, so that the MPPT switches to it.
+static CHANGED: AtomicBool = AtomicBool::new(false);
+
+/// A way of tracking the maximum power point. Every iteration gets the newest measurement and
+/// the one before, and returns the next duty cycle of the converter.
+pub trait MpptAlgorithm {
+    /// Identifies the algorithm in the MPPT frames.
+    fn id(&self) -> Algorithm;
+
+    fn iteration(
+        &mut self,
+        prev_output: f32,
+        voltage: i32,
+        current: i32,
+        prev_voltage: i32,
+        prev_current: i32,
+    ) -> f32;
+}
+
+/// Creates the implementation of `algorithm`.
+pub fn new_algorithm(algorithm: Algorithm) -> Box<dyn MpptAlgorithm> {
+    match algorithm {
+        Algorithm::PerturbAndObserve => Box::new(po::PerturbAndObserve),
+        Algorithm::IncrementalConductance => Box::new(ic::IncrementalConductance),
+    }
+}
+
+/// Returns the algorithm selected for this device, which is stored in NVS.
+pub fn get_algorithm() -> Algorithm {
+    let storage_locked = crate::STORAGE.lock().unwrap();
+    let mut target = [0; 1];
+    match storage_locked.get_raw("mppt", &mut target).unwrap() {
+        Some([value]) => Algorithm::from_u8(*value).unwrap_or(Algorithm::DEFAULT),
+        _ => Algorithm::DEFAULT,
+    }
+}
+
+/// Selects the algorithm of this device, which the MPPT switches to before its next iteration.
+pub fn set_algorithm(algorithm: Algorithm) {
+    crate::STORAGE
+        .lock()
+        .unwrap()
+        .set_raw("mppt", &[algorithm as u8])
+        .unwrap();
+    CHANGED.store(true, Ordering::SeqCst);
+}
+
+/// Returns whether another algorithm was selected since the last call.
+pub fn take_changed() -> bool {
+    CHANGED.swap(false, Ordering::SeqCst)
+}
+
 pub struct Mppt<'a> {
     ledc_driver: LedcDriver<'a>,
+    algorithm: Box<dyn MpptAlgorithm>,
 
     voltage_old: i32,
     current_old: i32,
@@ -18,6 +75,7 @@ impl<'a> Mppt<'a> {
         initial_voltage: u16,
         initial_current: u16,
         ledc_driver: LedcDriver<'a>,
+        algorithm: Box<dyn MpptAlgorithm>,
         delay: &mut impl embedded_hal_0_2::blocking::delay::DelayMs<u16>,
     ) -> Self {
         let voltage_old = initial_voltage as i32;
@@ -25,6 +83,7 @@ impl<'a> Mppt<'a> {
 
         let mut mppt = Self {
             ledc_driver,
+            algorithm,
 
             voltage_old,
             current_old,
@@ -38,6 +97,16 @@ impl<'a> Mppt<'a> {
         mppt
     }
 
+    /// Algorithm which chooses the operating point.
+    pub fn algorithm(&self) -> Algorithm {
+        self.algorithm.id()
+    }
+
+    /// Continues tracking from the current operating point with another algorithm.
+    pub fn set_algorithm(&mut self, algorithm: Box<dyn MpptAlgorithm>) {
+        self.algorithm = algorithm;
+    }
+
     pub fn set_operating_point(&mut self, mut duty: f32) {
         duty = duty.min(1.0).max(0.0);
         self.output = duty;
@@ -55,7 +124,7 @@ impl<'a> Mppt<'a> {
         println!("Voltage new: {voltage}");
         println!("Current new: {current}");
 
-        self.output = po::iteration(
+        self.output = self.algorithm.iteration(
             self.output,
             voltage.into(),
             current.into(),
@@ -1,4 +1,26 @@
-pub fn iteration(
+use pv_protocol::mppt::Algorithm;
+
+/// Perturb and observe, see [`Algorithm::PerturbAndObserve`].
+pub struct PerturbAndObserve;
+
+impl super::MpptAlgorithm for PerturbAndObserve {
+    fn id(&self) -> Algorithm {
+        Algorithm::PerturbAndObserve
+    }
+
+    fn iteration(
+        &mut self,
+        prev_output: f32,
+        voltage: i32,
+        current: i32,
+        prev_voltage: i32,
+        prev_current: i32,
+    ) -> f32 {
+        iteration(prev_output, voltage, current, prev_voltage, prev_current)
+    }
+}
+
+fn iteration(
     prev_output: f32,
     voltage: i32,
     current: i32,
@@ -0,0 +1,104 @@
+use super::link::{self, LinkMode};
+use pv_protocol::key::{parse_hex, EpochKey, KeyError};
+use pv_protocol::lorawan::{Activation, SessionKeys};
+use pv_protocol::mppt::Algorithm;
+use std::io::BufRead;
+
+const USAGE: &str = "Usage: key <epoch> <64 hexadecimal characters>, link <raw|lorawan>, \
+    mppt <po|ic>, otaa <DevEUI> <JoinEUI> <AppKey> or abp <DevAddr> <NwkSKey> <AppSKey>";
+
+fn parse_otaa(dev_eui: &str, join_eui: &str, app_key: &str) -> Result<Activation, KeyError> {
+    Ok(Activation::Otaa {
+        dev_eui: parse_hex(dev_eui)?,
+        join_eui: parse_hex(join_eui)?,
+        app_key: parse_hex(app_key)?,
+    })
+}
+
+fn parse_abp(dev_addr: &str, nwk_skey: &str, app_skey: &str) -> Result<Activation, KeyError> {
+    Ok(Activation::Abp(SessionKeys {
+        dev_addr: parse_hex(dev_addr)?,
+        nwk_skey: parse_hex(nwk_skey)?,
+        app_skey: parse_hex(app_skey)?,
+    }))
+}
+
+/// Reads provisioning commands from the serial console in a background thread. The commands are
+/// `key <epoch> <64 hexadecimal characters>`, which makes the key the current encryption key of
+/// this sender, `link <raw|lorawan>`, which selects how measurements are sent, `mppt <po|ic>`,
+/// which selects the MPPT algorithm, and `otaa` or `abp` followed by the LoRaWAN credentials of
+/// the device as shown by the network server.
+pub fn start_serial_provisioning(sender_id: u8) {
+    // Without the UART driver, reading stdin returns immediately instead of waiting for input
+    unsafe {
+        esp_idf_sys::esp!(esp_idf_sys::uart_driver_install(
+            esp_idf_sys::CONFIG_ESP_CONSOLE_UART_NUM as _,
+            256,
+            0,
+            0,
+            std::ptr::null_mut(),
+            0,
+        ))
+        .unwrap();
+        esp_idf_sys::esp_vfs_dev_uart_use_driver(esp_idf_sys::CONFIG_ESP_CONSOLE_UART_NUM as _);
+    }
+
+    std::thread::spawn(move || {
+        for line in std::io::stdin().lock().lines() {
+            let Ok(line) = line else {
+                continue;
+            };
+            let mut words = line.split_whitespace();
+            match (
+                words.next(),
+                words.next(),
+                words.next(),
+                words.next(),
+                words.next(),
+            ) {
+                (Some("key"), Some(epoch), Some(key), None, None) => {
+                    let Ok(epoch) = epoch.parse() else {
+                        println!("Invalid epoch {epoch}, expected 0-255");
+                        continue;
+                    };
+                    match pv_protocol::key::parse_key(key) {
+                        Ok(key) => {
+                            crate::encryption::install_key(sender_id, EpochKey { epoch, key });
+                            println!("Stored encryption key for device {sender_id}, epoch {epoch}");
+                        }
+                        Err(e) => println!("Invalid key: {e}"),
+                    }
+                }
+                (Some("link"), Some(mode), None, None, None) => match LinkMode::parse(mode) {
+                    Some(mode) => {
+                        link::set_link_mode(mode);
+                        println!("Sending measurements over {mode:?}");
+                    }
+                    None => println!("Unknown link {mode}, expected raw or lorawan"),
+                },
+                (Some("mppt"), Some(name), None, None, None) => match Algorithm::parse(name) {
+                    Some(algorithm) => {
+                        super::mppt::set_algorithm(algorithm);
+                        println!("Tracking the maximum power point with {algorithm:?}");
+                    }
+                    None => println!("Unknown MPPT algorithm {name}, expected po or ic"),
+                },
+                (Some(activation @ ("otaa" | "abp")), Some(a), Some(b), Some(c), None) => {
+                    let parsed = if activation == "otaa" {
+                        parse_otaa(a, b, c)
+                    } else {
+                        parse_abp(a, b, c)
+                    };
+                    match parsed {
+                        Ok(parsed) => {
+                            crate::lora::credentials::set_activation(parsed);
+                            println!("Stored LoRaWAN credentials for {activation}");
+                        }
+                        Err(e) => println!("Invalid LoRaWAN credentials: {e}"),
+                    }
+                }
+                _ => println!("Unknown command. {USAGE}"),
+            }
+        }
+    });
+}
@@ -0,0 +1,89 @@
+use embedded_svc::storage::RawStorage;
+use pv_protocol::queue::{QueueState, QueueStorage};
+
+/// Number of messages kept while the receiver does not acknowledge them. A sweep takes up to about
+/// 850 bytes, so a full queue fits comfortably in the NVS partition.
+pub const QUEUE_CAPACITY: u16 = 10;
+
+/// Keeps the queue in NVS, so that it survives deep sleep and reboots.
+pub struct NvsQueueStorage {
+    state_key: &'static str,
+    slot_prefix: &'static str,
+}
+
+impl NvsQueueStorage {
+    /// Encoded frames to our own receiver, which are encrypted when they are sent.
+    pub fn raw() -> Self {
+        Self {
+            state_key: "fqueue",
+            slot_prefix: "F",
+        }
+    }
+
+    /// Messages which older firmware versions encrypted before queueing them. They carry the
+    /// uptime at which they were queued, so they are dropped rather than sent.
+    pub fn legacy_raw() -> Self {
+        Self {
+            state_key: "queue",
+            slot_prefix: "Q",
+        }
+    }
+
+    /// Encoded frames which wait for the sender to join a LoRaWAN network, or for confirmed
+    /// uplinks to be acknowledged.
+    pub fn lorawan() -> Self {
+        Self {
+            state_key: "lwqueue",
+            slot_prefix: "LQ",
+        }
+    }
+
+    fn storage_key(&self, slot: u16) -> String {
+        format!("{}{slot}", self.slot_prefix)
+    }
+}
+
+impl QueueStorage for NvsQueueStorage {
+    fn load_state(&mut self) -> Option<QueueState> {
+        let storage_locked = crate::STORAGE.lock().unwrap();
+        let mut target = [0; 4];
+        storage_locked
+            .get_raw(self.state_key, &mut target)
+            .unwrap()
+            .map(|stored| QueueState::from_bytes(stored.try_into().unwrap()))
+    }
+
+    fn store_state(&mut self, state: QueueState) {
+        crate::STORAGE
+            .lock()
+            .unwrap()
+            .set_raw(self.state_key, &state.to_bytes())
+            .unwrap();
+    }
+
+    fn load(&mut self, slot: u16) -> Option<Vec<u8>> {
+        let storage_locked = crate::STORAGE.lock().unwrap();
+        let len = storage_locked.len(&self.storage_key(slot)).unwrap()?;
+        let mut target = vec![0; len];
+        let stored = storage_locked
+            .get_raw(&self.storage_key(slot), &mut target)
+            .unwrap()?;
+        Some(stored.to_vec())
+    }
+
+    fn store(&mut self, slot: u16, entry: &[u8]) {
+        crate::STORAGE
+            .lock()
+            .unwrap()
+            .set_raw(&self.storage_key(slot), entry)
+            .unwrap();
+    }
+
+    fn remove(&mut self, slot: u16) {
+        crate::STORAGE
+            .lock()
+            .unwrap()
+            .remove(&self.storage_key(slot))
+            .unwrap();
+    }
+}
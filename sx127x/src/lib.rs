@@ -0,0 +1,1325 @@
+#![allow(unused_assignments)]
+#![allow(
+    clippy::manual_clamp,
+    clippy::needless_late_init,
+    clippy::let_unit_value,
+    clippy::unnecessary_cast
+)]
+
+//! # sx127x_lora
+//! This is a copy of the `sx127x_lora` crate with the changes needed by the firmware, such as continuous
+//! reception and channels which are not a whole number of megahertz. It does not depend on esp-idf, so the
+//! tests can be run on the host with `cargo test` from the `sx127x` directory, against the simulated radio
+//! in the `sim` module.
+//!
+//!  A platform-agnostic driver for Semtech SX1276/77/78/79 based boards. It supports any device that
+//! implements the `embedded-hal` traits. Devices are connected over SPI and require an extra GPIO pin for
+//! RESET. This cate works with any Semtech based board including:
+//! * Modtronix inAir4, inAir9, and inAir9B
+//! * HopeRF RFM95W, RFM96W, and RFM98W
+//! # Examples
+//! ## Raspberry Pi Basic Send
+//! Utilizes a Raspberry Pi to send a message. The example utilizes the `linux_embedded_hal` crate.
+//! ```ignore
+//! #![feature(extern_crate_item_prelude)]
+//! extern crate sx127x_lora;
+//! extern crate linux_embedded_hal as hal;
+//!
+//! use hal::spidev::{self, SpidevOptions};
+//! use hal::{Pin, Spidev};
+//! use hal::sysfs_gpio::Direction;
+//! use hal::Delay;
+
+//! const LORA_CS_PIN: u64 = 8;
+//! const LORA_RESET_PIN: u64 = 21;
+//! const FREQUENCY: i64 = 915;
+//!
+//! fn main(){
+//!
+//!     let mut spi = Spidev::open("/dev/spidev0.0").unwrap();
+//!     let options = SpidevOptions::new()
+//!         .bits_per_word(8)
+//!         .max_speed_hz(20_000)
+//!         .mode(spidev::SPI_MODE_0)
+//!         .build();
+//!     spi.configure(&options).unwrap();
+//!
+//!     let cs = Pin::new(LORA_CS_PIN);
+//!     cs.export().unwrap();
+//!     cs.set_direction(Direction::Out).unwrap();
+//!
+//!     let reset = Pin::new(LORA_RESET_PIN);
+//!     reset.export().unwrap();
+//!     reset.set_direction(Direction::Out).unwrap();
+//!
+//!     let mut lora = sx127x_lora::LoRa::new(
+//!         spi, cs, reset,  FREQUENCY, Delay)
+//!         .expect("Failed to communicate with radio module!");
+//!
+//!     lora.set_tx_power(17,1); //Using PA_BOOST. See your board for correct pin.
+//!
+//!     let message = "Hello, world!";
+//!     let mut buffer = [0;255];
+//!     for (i,c) in message.chars().enumerate() {
+//!         buffer[i] = c as u8;
+//!     }
+//!
+//!     let transmit = lora.transmit_payload(buffer,message.len());
+//!     match transmit {
+//!         Ok(packet_size) => println!("Sent packet with size: {}", packet_size),
+//!         Err(()) => println!("Error"),
+//!     }
+//! }
+//! ```
+//! ## STM32F429 Blocking Receive
+//! Utilizes a STM32F429 to receive data using the blocking `poll_irq(timeout)` function. It prints
+//! the received packet back out over semihosting. The example utilizes the `stm32f429_hal`, `cortex_m`,
+//! and `panic_semihosting` crates.
+//! ```ignore
+//! #![no_std]
+//! #![no_main]
+//!
+//! extern crate sx127x_lora;
+//! extern crate stm32f429_hal as hal;
+//! extern crate cortex_m;
+//! extern crate panic_semihosting;
+//!
+//! use sx127x_lora::MODE;
+//! use cortex_m_semihosting::*;
+//! use hal::gpio::GpioExt;
+//! use hal::flash::FlashExt;
+//! use hal::rcc::RccExt;
+//! use hal::time::MegaHertz;
+//! use hal::spi::Spi;
+//! use hal::delay::Delay;
+//!
+//! const FREQUENCY: i64 = 915;
+//!
+//! #[entry]
+//! fn main() -> !{
+//!     let cp = cortex_m::Peripherals::take().unwrap();
+//!     let p = hal::stm32f429::Peripherals::take().unwrap();
+//!
+//!     let mut rcc = p.RCC.constrain();
+//!     let mut flash = p.FLASH.constrain();
+//!     let clocks = rcc
+//!         .cfgr
+//!         .sysclk(MegaHertz(64))
+//!         .pclk1(MegaHertz(32))
+//!         .freeze(&mut flash.acr);
+//!
+//!     let mut gpioa = p.GPIOA.split(&mut rcc.ahb1);
+//!     let mut gpiod = p.GPIOD.split(&mut rcc.ahb1);
+//!     let mut gpiof = p.GPIOF.split(&mut rcc.ahb1);
+//!
+//!     let sck = gpioa.pa5.into_af5(&mut gpioa.moder, &mut gpioa.afrl);
+//!     let miso = gpioa.pa6.into_af5(&mut gpioa.moder, &mut gpioa.afrl);
+//!     let mosi = gpioa.pa7.into_af5(&mut gpioa.moder, &mut gpioa.afrl);
+//!     let reset = gpiof.pf13.into_push_pull_output(&mut gpiof.moder, &mut gpiof.otyper);
+//!     let cs = gpiod.pd14.into_push_pull_output(&mut gpiod.moder, &mut gpiod.otyper);
+//!
+//!     let spi = Spi::spi1(
+//!         p.SPI1,
+//!         (sck, miso, mosi),
+//!         MODE,
+//!         MegaHertz(8),
+//!         clocks,
+//!         &mut rcc.apb2,
+//!     );
+//!
+//!     let mut lora = sx127x_lora::LoRa::new(
+//!         spi, cs, reset, FREQUENCY,
+//!         Delay::new(cp.SYST, clocks)).unwrap();
+//!
+//!     loop {
+//!         let poll = lora.poll_irq(Some(30)); //30 Second timeout
+//!         match poll {
+//!             Ok(size) =>{
+//!                hprint!("with Payload: ");
+//!                let buffer = lora.read_packet(); // Received buffer. NOTE: 255 bytes are always returned
+//!                for i in 0..size{
+//!                    hprint!("{}",buffer[i] as char).unwrap();
+//!                }
+//!                hprintln!();
+//!             },
+//!             Err(()) => hprintln!("Timeout").unwrap(),
+//!         }
+//!     }
+//! }
+//! ```
+//! ## Interrupts
+//! `poll_irq()` polls the IRQ register on the radio to determine if a new packet has arrived. To listen
+//! without polling, call `start_rx_continuous()` and wait for an interrupt on the DIO_0 pin (see
+//! `lora::dio0` in the firmware), then get the packet with `rx_status()` and `read_packet()`.
+//! ## FSK
+//! The radio starts in LoRa mode. After `set_modem(Modem::Fsk)`, it sends and receives (G)FSK
+//! packets with the packet engine of the radio instead, configured with the `set_fsk_*` functions.
+//! Transmitting and receiving work the same in both modes, but FSK packets have to fit in the FIFO
+//! of 64 bytes, see [`FSK_MAX_PAYLOAD`]. The other settings of the LoRa modem must not be changed
+//! in FSK mode, since their registers mean something else.
+
+use embedded_hal_0_2::digital::v2::OutputPin;
+use embedded_hal_0_2::blocking::spi::{Transfer, Write};
+use embedded_hal_0_2::blocking::delay::DelayMs;
+use embedded_hal_0_2::spi::{Mode, Phase, Polarity};
+use bit_field::BitField;
+
+pub mod register;
+#[cfg(test)]
+mod sim;
+use self::register::Register;
+use self::register::IRQ;
+use self::register::PaConfig;
+use self::register::{FskIrq1, FskIrq2, FskRegister};
+
+/// Provides the necessary SPI mode configuration for the radio
+pub const MODE: Mode = Mode {
+    phase: Phase::CaptureOnSecondTransition,
+    polarity: Polarity::IdleHigh,
+};
+
+/// Largest payload of an FSK packet, which has to fit in the FIFO together with its length.
+pub const FSK_MAX_PAYLOAD: usize = 63;
+
+/// Modems of the radio, see `set_modem()`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Modem {
+    LoRa,
+    Fsk,
+}
+
+/// Gaussian filter applied to the FSK modulation, which makes it GFSK. A lower BT narrows the
+/// spectrum, at the cost of more intersymbol interference.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Shaping {
+    None = 0,
+    GaussianBt1_0 = 1,
+    GaussianBt0_5 = 2,
+    GaussianBt0_3 = 3,
+}
+
+/// Provides high-level access to Semtech SX1276/77/78/79 based boards connected to a Raspberry Pi
+pub struct LoRa<SPI, CS, RESET, DELAY> {
+    spi: SPI,
+    cs: CS,
+    reset: RESET,
+    delay: DELAY,
+    frequency: i64,
+    pub explicit_header: bool,
+    pub mode: RadioMode,
+    modem: Modem,
+    /// Length of the FSK packet in the FIFO, which `rx_status()` has read already.
+    fsk_payload_length: u8,
+}
+
+#[derive(Debug)]
+pub enum Error<SPI, CS, RESET> {
+    Uninformative,
+    VersionMismatch(u8),
+    CS(CS),
+    Reset(RESET),
+    SPI(SPI),
+    Transmitting,
+    /// No packet was received before the timeout.
+    Timeout,
+    /// A packet was received, but its payload CRC does not match.
+    Crc,
+    /// The payload does not fit in a packet of the current modem.
+    PayloadTooLarge,
+}
+
+use Error::*;
+
+
+impl<SPI, CS, RESET, DELAY, E> LoRa<SPI, CS, RESET, DELAY>
+    where SPI: Transfer<u8, Error = E> + Write<u8, Error = E>,
+          CS: OutputPin, RESET: OutputPin, DELAY: DelayMs<u8>{
+    /// Builds and returns a new instance of the radio. Only one instance of the radio should exist at a time.
+    /// This also preforms a hardware reset of the module and then puts it in standby.
+    pub fn new(spi: SPI, cs: CS, reset: RESET, frequency: i64, delay: DELAY)
+               -> Result<Self, Error<E, CS::Error, RESET::Error>> {
+
+        let mut sx127x = LoRa {
+            spi,
+            cs,
+            reset,
+            delay,
+            frequency,
+            explicit_header: true,
+            mode: RadioMode::Sleep,
+            modem: Modem::LoRa,
+            fsk_payload_length: 0,
+        };
+        sx127x.reset.set_low().map_err(Reset)?;
+        sx127x.delay.delay_ms(10);
+        sx127x.reset.set_high().map_err(Reset)?;
+        sx127x.delay.delay_ms(10);
+        let version = sx127x.read_register(Register::RegVersion.addr())?;
+        if version == 0x12 {
+            sx127x.set_mode(RadioMode::Sleep)?;
+            sx127x.set_frequency(frequency)?;
+            sx127x.write_register(Register::RegFifoTxBaseAddr.addr(),0)?;
+            sx127x.write_register(Register::RegFifoRxBaseAddr.addr(),0)?;
+            let lna = sx127x.read_register(Register::RegLna.addr())?;
+            sx127x.write_register(Register::RegLna.addr(), lna | 0x03)?;
+            sx127x.write_register(Register::RegModemConfig3.addr(), 0x04)?;
+            sx127x.set_mode(RadioMode::Stdby)?;
+            sx127x.cs.set_high().map_err(CS)?;
+            Ok(sx127x)
+        }else{
+            Err(Error::VersionMismatch(version))
+        }
+    }
+
+    /// Transmits up to 255 bytes of data. To avoid the use of an allocator, this takes a fixed 255 u8
+    /// array and a payload size and returns the number of bytes sent if successful.
+    pub fn transmit_payload_busy(&mut self, buffer: [u8; 255], payload_size: usize)
+                            -> Result<usize,Error<E, CS::Error, RESET::Error>>{
+        self.transmit_payload(buffer, payload_size)?;
+        while self.transmitting()? {};
+        Ok(payload_size)
+    }
+
+    pub fn set_dio0_tx_done(&mut self) -> Result<(),Error<E, CS::Error, RESET::Error>> {
+        self.write_register(Register::RegDioMapping1.addr(), 0b01_00_00_00)
+    }
+
+    pub fn set_dio0_rx_done(&mut self) -> Result<(),Error<E, CS::Error, RESET::Error>> {
+        self.write_register(Register::RegDioMapping1.addr(), 0b00_00_00_00)
+    }
+
+    /// Puts the radio in continuous receive mode with DIO0 going high when a packet has been
+    /// received. The radio keeps receiving until the mode is changed, for example by a transmission.
+    pub fn start_rx_continuous(&mut self) -> Result<(),Error<E, CS::Error, RESET::Error>> {
+        self.set_mode(RadioMode::Stdby)?;
+        // DIO0 would stay high for an old packet, and never rise for the next one
+        self.clear_irq()?;
+        self.set_dio0_rx_done()?;
+        self.set_mode(RadioMode::RxContinuous)
+    }
+
+    pub fn transmit_payload(&mut self, buffer: [u8; 255], payload_size: usize)
+                            -> Result<(),Error<E, CS::Error, RESET::Error>>{
+        if self.transmitting()? {
+            Err(Transmitting)
+        }else if self.modem == Modem::Fsk {
+            if payload_size > FSK_MAX_PAYLOAD {
+                return Err(PayloadTooLarge);
+            }
+            self.set_mode(RadioMode::Stdby)?;
+            // Empties the FIFO, in variable length mode the packet starts with its length
+            self.clear_irq()?;
+            self.write_register(Register::RegFifo.addr(), payload_size as u8)?;
+            for byte in buffer.iter().take(payload_size){
+                self.write_register(Register::RegFifo.addr(), *byte)?;
+            }
+            self.set_mode(RadioMode::Tx)
+        }else{
+            self.set_mode(RadioMode::Stdby)?;
+            if self.explicit_header {
+                self.set_explicit_header_mode()?;
+            }else{
+                self.set_implicit_header_mode()?;
+            }
+
+            self.write_register(Register::RegIrqFlags.addr(), 0)?;
+            self.write_register(Register::RegFifoAddrPtr.addr(), 0)?;
+            self.write_register(Register::RegPayloadLength.addr(), 0)?;
+            for byte in buffer.iter().take(payload_size){
+                self.write_register(Register::RegFifo.addr(), *byte)?;
+            }
+            self.write_register(Register::RegPayloadLength.addr(),payload_size as u8)?;
+            self.set_mode(RadioMode::Tx)?;
+            Ok(())
+        }
+    }
+
+    /// Blocks the current thread, returning the size of a packet if one is received or an error is the
+    /// task timed out or the packet has a CRC error. The timeout can be supplied with None to make it poll indefinitely or
+    /// with `Some(timeout_in_mill_seconds)`
+    pub fn poll_irq(&mut self, timeout_ms: Option<i32>) -> Result<usize,Error<E, CS::Error, RESET::Error>>{
+        self.set_mode(RadioMode::RxContinuous)?;
+        let mut count = 0;
+        loop {
+            match self.rx_status()? {
+                RxStatus::Received(size) => return Ok(size),
+                RxStatus::CrcError => return Err(Crc),
+                RxStatus::Listening | RxStatus::Receiving => {}
+            }
+            match timeout_ms {
+                Some(value) if count >= value => return Err(Timeout),
+                Some(_) => {
+                    count += 1;
+                    self.delay.delay_ms(1);
+                }
+                None => self.delay.delay_ms(100),
+            }
+        }
+    }
+
+    /// Checks the IRQ register without blocking, for callers which have put the radio in receive
+    /// mode and need to keep track of time themselves. In FSK mode, the packet engine drops
+    /// packets with a CRC error itself, and a received packet has to be read with `read_packet()`
+    /// before the next one can be received.
+    pub fn rx_status(&mut self) -> Result<RxStatus, Error<E, CS::Error, RESET::Error>> {
+        if self.modem == Modem::Fsk {
+            let irq_flags_2 = self.read_register(FskRegister::RegIrqFlags2.addr())?;
+            if irq_flags_2 & FskIrq2::PayloadReady.addr() == 0 {
+                let irq_flags_1 = self.read_register(FskRegister::RegIrqFlags1.addr())?;
+                if irq_flags_1 & FskIrq1::SyncAddressMatch.addr() != 0 {
+                    return Ok(RxStatus::Receiving);
+                }
+                return Ok(RxStatus::Listening);
+            }
+            // The length comes first in the FIFO, the payload is read by read_packet()
+            self.fsk_payload_length = self.read_register(Register::RegFifo.addr())?;
+            return Ok(RxStatus::Received(self.fsk_payload_length as usize));
+        }
+        let irq_flags = self.read_register(Register::RegIrqFlags.addr())?;
+        if irq_flags & IRQ::IrqRxDoneMask.addr() == 0 {
+            if irq_flags & IRQ::IrqValidHeaderMask.addr() != 0 {
+                return Ok(RxStatus::Receiving);
+            }
+            return Ok(RxStatus::Listening);
+        }
+        self.clear_irq()?;
+        if irq_flags & IRQ::IrqPayloadCrcErrorMask.addr() != 0 {
+            return Ok(RxStatus::CrcError);
+        }
+        Ok(RxStatus::Received(self.read_register(Register::RegRxNbBytes.addr())? as usize))
+    }
+
+    /// Runs channel activity detection, which looks for the preamble of a LoRa packet at the current
+    /// spreading factor and bandwidth for about two symbols, and returns whether it found one. The
+    /// radio is in standby afterwards. Only available in LoRa mode. Fails with `Timeout` if the detection has not finished after
+    /// polling the IRQ register `timeout_ms` times.
+    pub fn detect_channel_activity(&mut self, timeout_ms: i32) -> Result<bool, Error<E, CS::Error, RESET::Error>> {
+        self.set_mode(RadioMode::Stdby)?;
+        self.clear_irq()?;
+        self.set_mode(RadioMode::Cad)?;
+        let mut count = 0;
+        loop {
+            let irq_flags = self.read_register(Register::RegIrqFlags.addr())?;
+            if irq_flags & IRQ::IrqCadDoneMask.addr() != 0 {
+                self.clear_irq()?;
+                // The radio goes back to standby on its own
+                self.mode = RadioMode::Stdby;
+                return Ok(irq_flags & IRQ::IrqCadDetectedMask.addr() != 0);
+            }
+            if count >= timeout_ms {
+                self.set_mode(RadioMode::Stdby)?;
+                return Err(Timeout);
+            }
+            count += 1;
+            self.delay.delay_ms(1);
+        }
+    }
+
+    /// Returns true while the modem has detected a LoRa preamble or is receiving a packet, which
+    /// happens well before `rx_status()` reports a valid header.
+    pub fn signal_detected(&mut self) -> Result<bool, Error<E, CS::Error, RESET::Error>> {
+        if self.modem == Modem::Fsk {
+            let irq_flags_1 = self.read_register(FskRegister::RegIrqFlags1.addr())?;
+            return Ok(irq_flags_1 & FskIrq1::PreambleDetect.addr() != 0);
+        }
+        Ok(self.read_register(Register::RegModemStat.addr())?.get_bit(0))
+    }
+
+    /// Returns the contents of the fifo as a fixed 255 u8 array. This should only be called is there is a
+    /// new packet ready to be read.
+    pub fn read_packet(&mut self) -> Result<[u8; 255], Error<E, CS::Error, RESET::Error>> {
+        let mut buffer = [0 as u8; 255];
+        if self.modem == Modem::Fsk {
+            for i in 0..self.fsk_payload_length {
+                buffer[i as usize] = self.read_register(Register::RegFifo.addr())?;
+            }
+            self.fsk_payload_length = 0;
+            return Ok(buffer);
+        }
+        self.clear_irq()?;
+        let size = self.read_register(Register::RegRxNbBytes.addr())?;
+        let fifo_addr = self.read_register(Register::RegFifoRxCurrentAddr.addr())?;
+        self.write_register(Register::RegFifoAddrPtr.addr(), fifo_addr)?;
+        for i in 0..size {
+            let byte = self.read_register(Register::RegFifo.addr())?;
+            buffer[i as usize] = byte;
+        }
+        self.write_register(Register::RegFifoAddrPtr.addr(), 0)?;
+        Ok(buffer)
+    }
+
+    /// Returns true if the radio is currently transmitting a packet.
+    pub fn transmitting(&mut self) -> Result<bool, Error<E, CS::Error, RESET::Error>> {
+        if (self.read_register(Register::RegOpMode.addr())? & RadioMode::Tx.addr())
+            == RadioMode::Tx.addr() {
+            // Unlike the LoRa modem, the FSK modem stays in Tx mode after the packet has been sent
+            if self.modem == Modem::Fsk
+                && self.read_register(FskRegister::RegIrqFlags2.addr())? & FskIrq2::PacketSent.addr() != 0 {
+                self.set_mode(RadioMode::Stdby)?;
+                return Ok(false);
+            }
+            Ok(true)
+        }else if self.modem == Modem::Fsk {
+            // The address of the LoRa IRQ flags holds RegRxBw in FSK mode
+            Ok(false)
+        }else{
+            if (self.read_register(Register::RegIrqFlags.addr())?
+                & IRQ::IrqTxDoneMask.addr()) != 0{
+                self.write_register(Register::RegIrqFlags.addr(),
+                                    IRQ::IrqTxDoneMask.addr())?;
+            }
+            Ok(false)
+        }
+    }
+
+    /// Clears the radio's IRQ registers. In FSK mode, this also empties the FIFO.
+    pub fn clear_irq(&mut self) -> Result<(), Error<E, CS::Error, RESET::Error>> {
+        if self.modem == Modem::Fsk {
+            self.write_register(FskRegister::RegIrqFlags1.addr(), 0xff)?;
+            return self.write_register(FskRegister::RegIrqFlags2.addr(), FskIrq2::FifoOverrun.addr());
+        }
+        let irq_flags = self.read_register(Register::RegIrqFlags.addr())?;
+        self.write_register(Register::RegIrqFlags.addr(), irq_flags)
+    }
+
+
+    /// Sets the transmit power and pin. Levels can range from 0-14 when the output
+    /// pin = 0(RFO), and form 0-20 when output pin = 1(PaBoost). Power is in dB.
+    /// Default value is `17`.
+    pub fn set_tx_power(&mut self, mut level: i32, output_pin: u8) -> Result<(), Error<E, CS::Error, RESET::Error>>{
+        if PaConfig::PaOutputRfoPin.addr() == output_pin {
+            // RFO
+            if level < 0 {
+                level = 0;
+            } else if level > 14 {
+                level = 14;
+            }
+            self.write_register(Register::RegPaConfig.addr(), (0x70 | level) as u8)
+        } else {
+            // PA BOOST
+            if level > 17 {
+                if level > 20 {
+                    level = 20;
+                }
+                // subtract 3 from level, so 18 - 20 maps to 15 - 17
+                level -= 3;
+
+                // High Power +20 dBm Operation (Semtech SX1276/77/78/79 5.4.3.)
+                self.write_register(Register::RegPaDac.addr(), 0x87)?;
+                self.set_ocp(140)?;
+            }else {
+                if level < 2 {
+                    level = 2;
+                }
+                //Default value PA_HF/LF or +17dBm
+                self.write_register(Register::RegPaDac.addr(), 0x84)?;
+                self.set_ocp(100)?;
+            }
+            level -= 2;
+            self.write_register(Register::RegPaConfig.addr(), PaConfig::PaBoost.addr()
+                | level as u8)
+        }
+    }
+
+    /// Sets the over current protection on the radio(mA).
+    pub fn set_ocp(&mut self, ma: u8) -> Result<(), Error<E, CS::Error, RESET::Error>>{
+        let mut ocp_trim: u8 = 27;
+
+        if ma <= 120 {
+            ocp_trim = (ma - 45) / 5;
+        } else if ma <=240 {
+            ocp_trim = (ma + 30) / 10;
+        }
+        self.write_register(Register::RegOcp.addr(), 0x20 | (0x1F & ocp_trim))
+    }
+
+    /// Sets the state of the radio. Default mode after initiation is `Standby`.
+    pub fn set_mode(&mut self,mode: RadioMode) -> Result<(), Error<E, CS::Error, RESET::Error>> {
+        if self.modem == Modem::Fsk {
+            // FSK modulation without the low frequency mode, the modes are numbered the same
+            self.write_register(Register::RegOpMode.addr(), mode.addr())?;
+            self.mode = mode;
+            return Ok(());
+        }
+        if self.explicit_header {
+            self.set_explicit_header_mode()?;
+        }else{
+            self.set_implicit_header_mode()?;
+        }
+        self.write_register(Register::RegOpMode.addr(),RadioMode::LongRangeMode.addr()
+            | mode.addr())?;
+
+        self.mode = mode;
+        Ok(())
+    }
+
+    /// Switches between the LoRa and the FSK modem, which is only possible in sleep mode. The radio
+    /// is in standby afterwards. The packet engine is set up for packets of up to
+    /// [`FSK_MAX_PAYLOAD`] bytes with their length in front, whitening and a CRC when switching to
+    /// FSK. The settings of each modem are kept while the other one is used.
+    pub fn set_modem(&mut self, modem: Modem) -> Result<(), Error<E, CS::Error, RESET::Error>> {
+        if modem == self.modem {
+            return Ok(());
+        }
+        self.set_mode(RadioMode::Sleep)?;
+        self.modem = modem;
+        self.set_mode(RadioMode::Sleep)?;
+        if modem == Modem::Fsk {
+            // Variable length, whitening and CRC
+            self.write_register(FskRegister::RegPacketConfig1.addr(), 0xd0)?;
+            // Packet mode
+            self.write_register(FskRegister::RegPacketConfig2.addr(), 0x40)?;
+            self.write_register(FskRegister::RegPayloadLength.addr(), FSK_MAX_PAYLOAD as u8)?;
+            // Preamble detector on, with 2 bytes and a tolerance of 10 chips
+            self.write_register(FskRegister::RegPreambleDetect.addr(), 0xaa)?;
+            // AFC and AGC, and start receiving on a preamble
+            self.write_register(FskRegister::RegRxConfig.addr(), 0x1e)?;
+            // Start transmitting as soon as the FIFO is not empty
+            self.write_register(FskRegister::RegFifoThresh.addr(), 0x8f)?;
+        }
+        self.set_mode(RadioMode::Stdby)
+    }
+
+    /// Returns the modem the radio uses.
+    pub fn modem(&self) -> Modem {
+        self.modem
+    }
+
+    /// Sets the bit rate of the FSK modem in bits per second, from 1200 to 300000.
+    pub fn set_fsk_bitrate(&mut self, bitrate: u32) -> Result<(), Error<E, CS::Error, RESET::Error>> {
+        let value = ((32_000_000 + bitrate / 2) / bitrate.max(1)).min(0xffff);
+        self.write_register(FskRegister::RegBitrateMsb.addr(), (value >> 8) as u8)?;
+        self.write_register(FskRegister::RegBitrateLsb.addr(), value as u8)
+    }
+
+    /// Sets the frequency deviation of the FSK modem in Hz.
+    pub fn set_fsk_deviation(&mut self, deviation_hz: u32) -> Result<(), Error<E, CS::Error, RESET::Error>> {
+        // The frequency step is 32 MHz / 2^19
+        let value = (((deviation_hz as u64) << 19) / 32_000_000).min(0x3fff);
+        self.write_register(FskRegister::RegFdevMsb.addr(), (value >> 8) as u8)?;
+        self.write_register(FskRegister::RegFdevLsb.addr(), value as u8)
+    }
+
+    /// Sets the single-side bandwidth of the receiver and of the AFC of the FSK modem to the
+    /// narrowest one which is at least `bandwidth_hz`, up to 250 kHz.
+    pub fn set_fsk_rx_bandwidth(&mut self, bandwidth_hz: u32) -> Result<(), Error<E, CS::Error, RESET::Error>> {
+        // Bandwidth of 32 MHz / (mantissa * 2^(exponent + 2)), from narrow to wide
+        let mut value = 0x01;
+        'search: for exponent in (1..=7).rev() {
+            for (mantissa, code) in [(24, 0b10), (20, 0b01), (16, 0b00)] {
+                if 32_000_000 / (mantissa << (exponent + 2)) >= bandwidth_hz {
+                    value = code << 3 | exponent as u8;
+                    break 'search;
+                }
+            }
+        }
+        self.write_register(FskRegister::RegRxBw.addr(), value)?;
+        self.write_register(FskRegister::RegAfcBw.addr(), value)
+    }
+
+    /// Sets the number of preamble bytes of FSK packets.
+    pub fn set_fsk_preamble_length(&mut self, length: u16) -> Result<(), Error<E, CS::Error, RESET::Error>> {
+        self.write_register(FskRegister::RegPreambleMsb.addr(), (length >> 8) as u8)?;
+        self.write_register(FskRegister::RegPreambleLsb.addr(), length as u8)
+    }
+
+    /// Sets the sync word of FSK packets, of up to 8 bytes. An empty sync word turns off the sync
+    /// word detection.
+    pub fn set_fsk_sync_word(&mut self, sync_word: &[u8]) -> Result<(), Error<E, CS::Error, RESET::Error>> {
+        let sync_word = &sync_word[..sync_word.len().min(8)];
+        if sync_word.is_empty() {
+            // Automatic restart of the receiver after a packet, sync word off
+            return self.write_register(FskRegister::RegSyncConfig.addr(), 0x80);
+        }
+        for (i, byte) in sync_word.iter().enumerate() {
+            self.write_register(FskRegister::RegSyncValue1.addr() + i as u8, *byte)?;
+        }
+        self.write_register(FskRegister::RegSyncConfig.addr(), 0x90 | (sync_word.len() - 1) as u8)
+    }
+
+    /// Sets the Gaussian filter of the FSK modem.
+    pub fn set_fsk_shaping(&mut self, shaping: Shaping) -> Result<(), Error<E, CS::Error, RESET::Error>> {
+        let pa_ramp = self.read_register(FskRegister::RegPaRamp.addr())?;
+        self.write_register(FskRegister::RegPaRamp.addr(), (pa_ramp & 0x9f) | (shaping as u8) << 5)
+    }
+
+    /// Sets the frequency of the radio. Values are in megahertz.
+    /// I.E. 915 MHz must be used for North America. Check regulation for your area.
+    pub fn set_frequency(&mut self, freq: i64) -> Result<(), Error<E, CS::Error, RESET::Error>> {
+        self.frequency = freq;
+        // calculate register values
+        let base = 1;
+        let frf = (freq * (base << 19)) / 32;
+        // write registers
+        self.write_register(Register::RegFrfMsb.addr(), ((frf & 0x00FF_0000) >> 16) as u8)?;
+        self.write_register(Register::RegFrfMid.addr(), ((frf & 0x0000_FF00) >> 8) as u8)?;
+        self.write_register(Register::RegFrfLsb.addr(), (frf & 0x0000_00FF) as u8)
+    }
+
+    /// Sets the frequency of the radio in hertz, for channels which are not a whole number of
+    /// megahertz such as the LoRaWAN channels.
+    pub fn set_frequency_hz(&mut self, freq_hz: u32) -> Result<(), Error<E, CS::Error, RESET::Error>> {
+        self.frequency = freq_hz as i64 / 1_000_000;
+        // The frequency step is 32 MHz / 2^19
+        let frf = ((freq_hz as u64) << 19) / 32_000_000;
+        self.write_register(Register::RegFrfMsb.addr(), ((frf & 0x00FF_0000) >> 16) as u8)?;
+        self.write_register(Register::RegFrfMid.addr(), ((frf & 0x0000_FF00) >> 8) as u8)?;
+        self.write_register(Register::RegFrfLsb.addr(), (frf & 0x0000_00FF) as u8)
+    }
+
+    /// Sets the sync word, which keeps radios of different networks from receiving each other's
+    /// packets. LoRaWAN uses `0x34`. Default value is `0x12`.
+    pub fn set_sync_word(&mut self, sync_word: u8) -> Result<(), Error<E, CS::Error, RESET::Error>> {
+        self.write_register(Register::RegSyncWord.addr(), sync_word)
+    }
+
+    /// Sets the radio to use an explicit header. Default state is `ON`.
+    fn set_explicit_header_mode(&mut self) -> Result<(), Error<E, CS::Error, RESET::Error>> {
+        let reg_modem_config_1 = self.read_register(Register::RegModemConfig1.addr())?;
+        self.write_register(Register::RegModemConfig1.addr(),reg_modem_config_1  & 0xfe)?;
+        self.explicit_header = true;
+        Ok(())
+    }
+
+    /// Sets the radio to use an implicit header. Default state is `OFF`.
+    fn set_implicit_header_mode(&mut self) -> Result<(), Error<E, CS::Error, RESET::Error>> {
+        let reg_modem_config_1 = self.read_register(Register::RegModemConfig1.addr())?;
+        self.write_register(Register::RegModemConfig1.addr(),reg_modem_config_1  & 0x01)?;
+        self.explicit_header = false;
+        Ok(())
+    }
+
+
+    /// Sets the spreading factor of the radio. Supported values are between 6 and 12.
+    /// If a spreading factor of 6 is set, implicit header mode must be used to transmit
+    /// and receive packets. Default value is `7`.
+    pub fn set_spreading_factor(&mut self, mut sf: u8) -> Result<(), Error<E, CS::Error, RESET::Error>> {
+        if sf < 6 {
+            sf = 6;
+        } else if sf > 12 {
+            sf = 12;
+        }
+
+        if sf == 6 {
+            self.write_register(Register::RegDetectionOptimize.addr(), 0xc5)?;
+            self.write_register(Register::RegDetectionThreshold.addr(), 0x0c)?;
+        } else {
+            self.write_register(Register::RegDetectionOptimize.addr(), 0xc3)?;
+            self.write_register(Register::RegDetectionThreshold.addr(), 0x0a)?;
+        }
+        let modem_config_2 = self.read_register(Register::RegModemConfig2.addr())?;
+        self.write_register(Register::RegModemConfig2.addr(), (modem_config_2 & 0x0f)
+            | ((sf << 4) & 0xf0))?;
+        self.set_ldo_flag()?;
+        Ok(())
+    }
+
+    /// Sets the signal bandwidth of the radio. Supported values are: `7800 Hz`, `10400 Hz`,
+    /// `15600 Hz`, `20800 Hz`, `31250 Hz`,`41700 Hz` ,`62500 Hz`,`125000 Hz` and `250000 Hz`
+    /// Default value is `125000 Hz`
+    pub fn set_signal_bandwidth(&mut self, sbw: i64) -> Result<(), Error<E, CS::Error, RESET::Error>> {
+        let bw: i64;
+        match sbw {
+            7_800 => bw = 0,
+            10_400 => bw = 1,
+            15_600 => bw = 2,
+            20_800 => bw = 3,
+            31_250 => bw = 4,
+            41_700 => bw = 5,
+            62_500 => bw = 6,
+            125_000 => bw = 7,
+            250_000 => bw = 8,
+            _ => bw = 9,
+        }
+        let modem_config_1 = self.read_register(Register::RegModemConfig1.addr())?;
+        self.write_register(Register::RegModemConfig1.addr(), (modem_config_1 & 0x0f)
+            | ((bw << 4) as u8))?;
+        self.set_ldo_flag()?;
+        Ok(())
+    }
+
+    /// Sets the coding rate of the radio with the numerator fixed at 4. Supported values
+    /// are between `5` and `8`, these correspond to coding rates of `4/5` and `4/8`.
+    /// Default value is `5`.
+    pub fn set_coding_rate_4(&mut self, mut denominator: u8) -> Result<(), Error<E, CS::Error, RESET::Error>> {
+        if denominator < 5 {
+            denominator = 5;
+        } else if denominator > 8 {
+            denominator = 8;
+        }
+        let cr = denominator - 4;
+        let modem_config_1 = self.read_register(Register::RegModemConfig1.addr())?;
+        self.write_register( Register::RegModemConfig1.addr(), (modem_config_1 & 0xf1)
+            | (cr << 1))
+    }
+
+    /// Sets the preamble length of the radio. Values are between 6 and 65535.
+    /// Default value is `8`.
+    pub fn set_preamble_length(&mut self, length: i64) -> Result<(), Error<E, CS::Error, RESET::Error>> {
+        self.write_register(Register::RegPreambleMsb.addr(), (length >> 8) as u8)?;
+        self.write_register(Register::RegPreambleLsb.addr(), length as u8)
+    }
+
+    /// Enables are disables the radio's CRC check. Default value is `false`.
+    pub fn set_crc(&mut self, value: bool) -> Result<(), Error<E, CS::Error, RESET::Error>> {
+        let modem_config_2 = self.read_register(Register::RegModemConfig2.addr())?;
+        if value {
+            self.write_register(Register::RegModemConfig2.addr(), modem_config_2 | 0x04)
+        }else{
+            self.write_register(Register::RegModemConfig2.addr(), modem_config_2 & 0xfb)
+        }
+    }
+
+    /// Inverts the radio's IQ signals. Default value is `false`.
+    pub fn set_invert_iq(&mut self, value: bool) -> Result<(), Error<E, CS::Error, RESET::Error>> {
+        if value {
+            self.write_register(Register::RegInvertiq.addr(),  0x66)?;
+            self.write_register(Register::RegInvertiq2.addr(), 0x19)
+        }else{
+            self.write_register(Register::RegInvertiq.addr(),  0x27)?;
+            self.write_register(Register::RegInvertiq2.addr(), 0x1d)
+        }
+    }
+
+
+    /// Returns the spreading factor of the radio.
+    pub fn get_spreading_factor(&mut self) -> Result<u8, Error<E, CS::Error, RESET::Error>> {
+        Ok(self.read_register(Register::RegModemConfig2.addr())? >> 4)
+    }
+
+    /// Returns the signal bandwidth of the radio.
+    pub fn get_signal_bandwidth(&mut self) -> Result<i64, Error<E, CS::Error, RESET::Error>> {
+        let bw = self.read_register(Register::RegModemConfig1.addr())? >> 4;
+        let bw = match bw {
+            0 => 7_800,
+            1 => 10_400,
+            2 => 15_600,
+            3 => 20_800,
+            4 => 31_250,
+            5 => 41_700,
+            6 => 62_500,
+            7 => 125_000,
+            8 => 250_000,
+            9 => 500_000,
+            _ => -1,
+        };
+        Ok(bw)
+    }
+
+    /// Returns the RSSI of the last received packet. In FSK mode, this is the current RSSI, which
+    /// is close to the one of a packet right after it was received.
+    pub fn get_packet_rssi(&mut self) -> Result<i32, Error<E, CS::Error, RESET::Error>> {
+        if self.modem == Modem::Fsk {
+            return Ok(-i32::from(self.read_register(FskRegister::RegRssiValue.addr())?) / 2);
+        }
+        Ok(i32::from(self.read_register(Register::RegPktRssiValue.addr())?) - 157)
+    }
+
+    /// Returns the signal to noise radio of the the last received packet.
+    pub fn get_packet_snr(&mut self) -> Result<f64, Error<E, CS::Error, RESET::Error>> {
+        Ok(f64::from(self.read_register(Register::RegPktSnrValue.addr())?))
+    }
+
+    /// Returns the frequency error of the last received packet in Hz.
+    pub fn get_packet_frequency_error(&mut self) -> Result<i64, Error<E, CS::Error, RESET::Error>> {
+        let mut freq_error: i32 = 0;
+        freq_error = i32::from(self.read_register( Register::RegFreqErrorMsb.addr())? & 0xf);
+        freq_error <<= 8i64;
+        freq_error += i32::from(self.read_register(Register::RegFreqErrorMid.addr())?);
+        freq_error <<= 8i64;
+        freq_error += i32::from(self.read_register( Register::RegFreqErrorLsb.addr())?);
+        // The register holds a 20 bit two's complement number
+        if freq_error & 0x8_0000 != 0 {
+            freq_error -= 1 << 20;
+        }
+
+        let f_xtal = 32_000_000; // FXOSC: crystal oscillator (XTAL) frequency (2.5. Chip Specification, p. 14)
+        let f_error = ((f64::from(freq_error) * (1i64 << 24) as f64) / f64::from(f_xtal)) *
+            (self.get_signal_bandwidth()? as f64 / 500_000.0f64); // p. 37
+        Ok(f_error as i64)
+    }
+
+    fn set_ldo_flag(&mut self) -> Result<(), Error<E, CS::Error, RESET::Error>> {
+        let sw = self.get_signal_bandwidth()?;
+        // Section 4.1.1.5, in microseconds, since symbols of SF11 at 125 kHz last 16.384 ms
+        let symbol_duration_us = ((1 as i64) << self.get_spreading_factor()?) * 1_000_000 / sw;
+
+        // Section 4.1.1.6
+        let ldo_on = symbol_duration_us > 16_000;
+
+        let mut config_3 = self.read_register(Register::RegModemConfig3.addr())?;
+        config_3.set_bit(3,ldo_on);
+        self.write_register(Register::RegModemConfig3.addr(), config_3)
+    }
+
+    pub fn read_register(&mut self, reg: u8) -> Result<u8,Error<E, CS::Error, RESET::Error>> {
+        self.cs.set_low().map_err(CS)?;
+
+        self.delay.delay_ms(2);
+
+        let mut buffer = [reg & 0x7f, 0];
+        let transfer = self.spi.transfer(&mut buffer).map_err(SPI)?;
+
+        self.delay.delay_ms(2);
+
+        self.cs.set_high().map_err(CS)?;
+
+        self.delay.delay_ms(2);
+
+        Ok(transfer[1])
+    }
+
+    pub fn write_register(&mut self, reg: u8, byte: u8) -> Result<(),Error<E, CS::Error, RESET::Error>>{
+        self.cs.set_low().map_err(CS)?;
+
+        self.delay.delay_ms(2);
+
+        let buffer = [reg | 0x80, byte];
+        let write = self.spi.write(& buffer).map_err(SPI)?;
+
+        self.delay.delay_ms(2);
+
+        self.cs.set_high().map_err(CS)?;
+
+        self.delay.delay_ms(2);
+
+        Ok(write)
+    }
+}
+/// State of the radio while it is in receive mode.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RxStatus {
+    /// No packet has been detected yet.
+    Listening,
+    /// The header of a packet has been received, but not the whole packet.
+    Receiving,
+    /// A packet of the given size has been received and can be read with `read_packet()`.
+    Received(usize),
+    /// A packet has been received, but its payload CRC does not match, so it is dropped.
+    CrcError,
+}
+
+/// Modes of the radio and their corresponding register values.
+#[derive(Clone, Copy)]
+pub enum RadioMode{
+    LongRangeMode = 0x80,
+    Sleep = 0x00,
+    Stdby = 0x01,
+    Tx = 0x03,
+    RxContinuous = 0x05,
+    RxSingle = 0x06,
+    Cad = 0x07,
+}
+
+impl RadioMode {
+    /// Returns the address of the mode.
+    pub fn addr(self) -> u8 {
+        self as u8
+    }
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sim::{self, Handle, Incoming};
+
+    fn radio() -> (Handle, LoRa<sim::Spi, sim::Cs, sim::Reset, sim::Delay>) {
+        let (chip, spi, cs, reset, delay) = sim::new();
+        let lora = LoRa::new(spi, cs, reset, 868, delay).unwrap();
+        (chip, lora)
+    }
+
+    #[test]
+    fn new_puts_radio_in_lora_standby() {
+        let (chip, lora) = radio();
+        let chip = chip.borrow();
+        assert_eq!(chip.resets, 1);
+        assert!(!chip.cs_low);
+        assert!(chip.long_range_mode());
+        assert_eq!(chip.mode(), RadioMode::Stdby.addr());
+        assert!(lora.explicit_header);
+        // 868 MHz in steps of 32 MHz / 2^19
+        assert_eq!(chip.register(Register::RegFrfMsb), 0xd9);
+        assert_eq!(chip.register(Register::RegFrfMid), 0x00);
+        assert_eq!(chip.register(Register::RegFrfLsb), 0x00);
+        assert_eq!(chip.register(Register::RegFifoTxBaseAddr), 0);
+        assert_eq!(chip.register(Register::RegFifoRxBaseAddr), 0);
+        assert_eq!(chip.register(Register::RegLna), 0x23);
+        assert_eq!(chip.register(Register::RegModemConfig3), 0x04);
+    }
+
+    #[test]
+    fn new_rejects_other_chips() {
+        let (chip, spi, cs, reset, delay) = sim::new();
+        chip.borrow_mut().version = 0x22;
+        assert!(matches!(
+            LoRa::new(spi, cs, reset, 868, delay),
+            Err(Error::VersionMismatch(0x22))
+        ));
+    }
+
+    #[test]
+    fn transmit_payload() {
+        let (chip, mut lora) = radio();
+        let mut buffer = [0; 255];
+        buffer[..3].copy_from_slice(&[1, 2, 3]);
+        lora.transmit_payload(buffer, 3).unwrap();
+        assert_eq!(chip.borrow().mode(), RadioMode::Tx.addr());
+        assert!(matches!(
+            lora.transmit_payload(buffer, 3),
+            Err(Error::Transmitting)
+        ));
+
+        while lora.transmitting().unwrap() {}
+        let chip = chip.borrow();
+        assert_eq!(chip.transmitted, [vec![1, 2, 3]]);
+        assert_eq!(chip.mode(), RadioMode::Stdby.addr());
+        assert!(chip.long_range_mode());
+        assert_eq!(chip.irq_flags(), 0);
+    }
+
+    #[test]
+    fn transmit_payload_busy_waits() {
+        let (chip, mut lora) = radio();
+        let buffer = [7; 255];
+        assert_eq!(lora.transmit_payload_busy(buffer, 255).unwrap(), 255);
+        assert_eq!(chip.borrow().transmitted, [vec![7; 255]]);
+        assert!(!lora.transmitting().unwrap());
+    }
+
+    #[test]
+    fn poll_irq_and_read_packet() {
+        let (chip, mut lora) = radio();
+        chip.borrow_mut().queue_packet(10_000, b"late");
+        assert!(matches!(lora.poll_irq(Some(10)), Err(Error::Timeout)));
+        assert_eq!(chip.borrow().mode(), RadioMode::RxContinuous.addr());
+
+        let now_ms = chip.borrow().now_ms;
+        chip.borrow_mut().queue_packet(now_ms, b"hello");
+        let size = lora.poll_irq(Some(10)).unwrap();
+        assert_eq!(size, 5);
+        assert_eq!(chip.borrow().irq_flags(), 0);
+        assert_eq!(&lora.read_packet().unwrap()[..size], b"hello");
+        assert_eq!(chip.borrow().register(Register::RegFifoAddrPtr), 0);
+
+        // Waits for as long as it takes without a timeout
+        let size = lora.poll_irq(None).unwrap();
+        assert_eq!(&lora.read_packet().unwrap()[..size], b"late");
+    }
+
+    #[test]
+    fn poll_irq_reports_crc_errors() {
+        let (chip, mut lora) = radio();
+        let now_ms = chip.borrow().now_ms;
+        chip.borrow_mut().queue(Incoming {
+            at_ms: now_ms,
+            payload: b"broken".to_vec(),
+            crc_error: true,
+            snr: 0,
+            rssi: 0,
+        });
+        chip.borrow_mut().queue_packet(now_ms + 100, b"intact");
+        assert!(matches!(lora.poll_irq(Some(10)), Err(Error::Crc)));
+        assert_eq!(chip.borrow().irq_flags(), 0);
+        let size = lora.poll_irq(Some(100)).unwrap();
+        assert_eq!(&lora.read_packet().unwrap()[..size], b"intact");
+    }
+
+    #[test]
+    fn continuous_reception() {
+        let (chip, mut lora) = radio();
+        lora.start_rx_continuous().unwrap();
+        assert_eq!(chip.borrow().register(Register::RegDioMapping1), 0);
+        assert_eq!(lora.rx_status().unwrap(), RxStatus::Listening);
+
+        let now_ms = chip.borrow().now_ms;
+        chip.borrow_mut().queue_packet(now_ms, b"first");
+        chip.borrow_mut().queue(Incoming {
+            at_ms: now_ms + 100,
+            payload: b"broken".to_vec(),
+            crc_error: true,
+            snr: 0,
+            rssi: 0,
+        });
+        chip.borrow_mut().queue(Incoming {
+            at_ms: now_ms + 200,
+            payload: b"second".to_vec(),
+            crc_error: false,
+            snr: -30,
+            rssi: 60,
+        });
+
+        assert_eq!(lora.rx_status().unwrap(), RxStatus::Received(5));
+        assert_eq!(&lora.read_packet().unwrap()[..5], b"first");
+
+        let mut next_status = || loop {
+            match lora.rx_status().unwrap() {
+                RxStatus::Listening => {}
+                status => break status,
+            }
+        };
+        assert_eq!(next_status(), RxStatus::CrcError);
+        assert_eq!(chip.borrow().irq_flags(), 0);
+        assert_eq!(next_status(), RxStatus::Received(6));
+        // Written after the first packets in the FIFO
+        assert_eq!(chip.borrow().register(Register::RegFifoRxCurrentAddr), 11);
+        assert_eq!(&lora.read_packet().unwrap()[..6], b"second");
+        assert_eq!(lora.get_packet_snr().unwrap() as u8 as i8, -30);
+        assert_eq!(lora.get_packet_rssi().unwrap(), 60 - 157);
+        assert_eq!(chip.borrow().mode(), RadioMode::RxContinuous.addr());
+    }
+
+    #[test]
+    fn channel_activity_detection() {
+        let (chip, mut lora) = radio();
+        assert!(!lora.detect_channel_activity(100).unwrap());
+        assert_eq!(chip.borrow().mode(), RadioMode::Stdby.addr());
+        assert_eq!(chip.borrow().irq_flags(), 0);
+
+        let now_ms = chip.borrow().now_ms;
+        chip.borrow_mut().busy_until_ms = now_ms + 500;
+        assert!(lora.detect_channel_activity(100).unwrap());
+        assert_eq!(chip.borrow().mode(), RadioMode::Stdby.addr());
+        assert_eq!(chip.borrow().irq_flags(), 0);
+
+        chip.borrow_mut().cad_duration_ms = 10_000;
+        assert!(matches!(lora.detect_channel_activity(10), Err(Error::Timeout)));
+        assert_eq!(chip.borrow().mode(), RadioMode::Stdby.addr());
+    }
+
+    #[test]
+    fn modem_switch() {
+        let (chip, mut lora) = radio();
+        lora.set_modem(Modem::Fsk).unwrap();
+        {
+            let chip = chip.borrow();
+            assert!(!chip.long_range_mode());
+            assert_eq!(chip.mode(), RadioMode::Stdby.addr());
+            assert_eq!(chip.fsk_register(FskRegister::RegPacketConfig1), 0xd0);
+            assert_eq!(chip.fsk_register(FskRegister::RegPayloadLength), 63);
+            // The LoRa registers on the same addresses are kept
+            assert_eq!(chip.register(Register::RegModemConfig3), 0x04);
+        }
+        assert_eq!(lora.modem(), Modem::Fsk);
+        lora.start_rx_continuous().unwrap();
+        assert_eq!(chip.borrow().mode(), RadioMode::RxContinuous.addr());
+        assert!(!chip.borrow().long_range_mode());
+
+        lora.set_modem(Modem::LoRa).unwrap();
+        assert!(chip.borrow().long_range_mode());
+        assert_eq!(chip.borrow().mode(), RadioMode::Stdby.addr());
+        assert_eq!(lora.modem(), Modem::LoRa);
+    }
+
+    #[test]
+    fn fsk_settings() {
+        let (chip, mut lora) = radio();
+        lora.set_modem(Modem::Fsk).unwrap();
+        let pair = |msb: FskRegister, lsb: FskRegister| {
+            let chip = chip.borrow();
+            (chip.fsk_register(msb) as u16) << 8 | chip.fsk_register(lsb) as u16
+        };
+
+        lora.set_fsk_bitrate(50_000).unwrap();
+        assert_eq!(pair(FskRegister::RegBitrateMsb, FskRegister::RegBitrateLsb), 0x0280);
+        lora.set_fsk_deviation(25_000).unwrap();
+        assert_eq!(pair(FskRegister::RegFdevMsb, FskRegister::RegFdevLsb), 0x0199);
+        lora.set_fsk_preamble_length(5).unwrap();
+        assert_eq!(pair(FskRegister::RegPreambleMsb, FskRegister::RegPreambleLsb), 5);
+
+        lora.set_fsk_rx_bandwidth(62_500).unwrap();
+        assert_eq!(chip.borrow().fsk_register(FskRegister::RegRxBw), 0x03);
+        lora.set_fsk_rx_bandwidth(80_000).unwrap();
+        assert_eq!(chip.borrow().fsk_register(FskRegister::RegRxBw), 0x12);
+        assert_eq!(chip.borrow().fsk_register(FskRegister::RegAfcBw), 0x12);
+
+        lora.set_fsk_sync_word(&[1, 2, 3]).unwrap();
+        assert_eq!(chip.borrow().fsk_register(FskRegister::RegSyncConfig), 0x92);
+        assert_eq!(chip.borrow().fsk_registers[0x28..0x2b], [1, 2, 3]);
+        lora.set_fsk_sync_word(&[]).unwrap();
+        assert_eq!(chip.borrow().fsk_register(FskRegister::RegSyncConfig), 0x80);
+
+        lora.set_fsk_shaping(Shaping::GaussianBt0_5).unwrap();
+        assert_eq!(chip.borrow().fsk_register(FskRegister::RegPaRamp), 0x49);
+        lora.set_fsk_shaping(Shaping::None).unwrap();
+        assert_eq!(chip.borrow().fsk_register(FskRegister::RegPaRamp), 0x09);
+    }
+
+    #[test]
+    fn fsk_transmit() {
+        let (chip, mut lora) = radio();
+        lora.set_modem(Modem::Fsk).unwrap();
+        // Checking the FSK modem takes two register reads
+        chip.borrow_mut().tx_duration_ms = 50;
+        let mut buffer = [0; 255];
+        buffer[..3].copy_from_slice(&[1, 2, 3]);
+        lora.transmit_payload(buffer, 3).unwrap();
+        assert_eq!(chip.borrow().mode(), RadioMode::Tx.addr());
+        assert!(matches!(
+            lora.transmit_payload(buffer, 3),
+            Err(Error::Transmitting)
+        ));
+        while lora.transmitting().unwrap() {}
+        assert_eq!(chip.borrow().transmitted, [vec![1, 2, 3]]);
+        assert_eq!(chip.borrow().mode(), RadioMode::Stdby.addr());
+
+        assert!(matches!(
+            lora.transmit_payload(buffer, FSK_MAX_PAYLOAD + 1),
+            Err(Error::PayloadTooLarge)
+        ));
+        assert_eq!(lora.transmit_payload_busy([7; 255], FSK_MAX_PAYLOAD).unwrap(), 63);
+        assert_eq!(chip.borrow().transmitted[1], vec![7; 63]);
+    }
+
+    #[test]
+    fn fsk_transmit_keeps_rx_bandwidth() {
+        let (chip, mut lora) = radio();
+        lora.set_modem(Modem::Fsk).unwrap();
+        // A mantissa of 20 sets bit 3, like the TxDone flag of the LoRa modem
+        lora.set_fsk_rx_bandwidth(100_000).unwrap();
+        assert_eq!(chip.borrow().fsk_register(FskRegister::RegRxBw), 0x0a);
+        for _ in 0..2 {
+            lora.set_mode(RadioMode::RxContinuous).unwrap();
+            lora.transmit_payload([1; 255], 3).unwrap();
+            while lora.transmitting().unwrap() {}
+            assert!(!lora.transmitting().unwrap());
+        }
+        assert_eq!(chip.borrow().fsk_register(FskRegister::RegRxBw), 0x0a);
+        assert_eq!(chip.borrow().fsk_register(FskRegister::RegAfcBw), 0x0a);
+    }
+
+    #[test]
+    fn fsk_receive() {
+        let (chip, mut lora) = radio();
+        lora.set_modem(Modem::Fsk).unwrap();
+        lora.start_rx_continuous().unwrap();
+        assert_eq!(lora.rx_status().unwrap(), RxStatus::Listening);
+        assert!(!lora.signal_detected().unwrap());
+
+        let now_ms = chip.borrow().now_ms;
+        chip.borrow_mut().queue(Incoming {
+            at_ms: now_ms,
+            payload: b"broken".to_vec(),
+            crc_error: true,
+            snr: 0,
+            rssi: 0,
+        });
+        chip.borrow_mut().queue(Incoming {
+            at_ms: now_ms + 100,
+            payload: b"first".to_vec(),
+            crc_error: false,
+            snr: 0,
+            rssi: 180,
+        });
+        chip.borrow_mut().queue_packet(now_ms + 200, b"second");
+
+        // The packet with the CRC error is dropped by the packet engine
+        let size = lora.poll_irq(Some(1_000)).unwrap();
+        assert_eq!(size, 5);
+        assert!(lora.signal_detected().unwrap());
+        assert_eq!(lora.get_packet_rssi().unwrap(), -90);
+        assert_eq!(&lora.read_packet().unwrap()[..size], b"first");
+        assert!(chip.borrow().fsk_fifo.is_empty());
+
+        let size = lora.poll_irq(Some(1_000)).unwrap();
+        assert_eq!(&lora.read_packet().unwrap()[..size], b"second");
+        assert!(matches!(lora.poll_irq(Some(10)), Err(Error::Timeout)));
+    }
+
+    #[test]
+    fn set_spreading_factor() {
+        let (chip, mut lora) = radio();
+        lora.set_crc(true).unwrap();
+        lora.set_spreading_factor(12).unwrap();
+        {
+            let chip = chip.borrow();
+            assert_eq!(chip.register(Register::RegModemConfig2), 0xc4);
+            assert_eq!(chip.register(Register::RegDetectionOptimize), 0xc3);
+            assert_eq!(chip.register(Register::RegDetectionThreshold), 0x0a);
+            // Symbols of 32 ms need the low data rate optimization
+            assert_eq!(chip.register(Register::RegModemConfig3), 0x0c);
+        }
+        assert_eq!(lora.get_spreading_factor().unwrap(), 12);
+
+        lora.set_spreading_factor(6).unwrap();
+        {
+            let chip = chip.borrow();
+            assert_eq!(chip.register(Register::RegModemConfig2), 0x64);
+            assert_eq!(chip.register(Register::RegDetectionOptimize), 0xc5);
+            assert_eq!(chip.register(Register::RegDetectionThreshold), 0x0c);
+            assert_eq!(chip.register(Register::RegModemConfig3), 0x04);
+        }
+
+        lora.set_spreading_factor(13).unwrap();
+        assert_eq!(lora.get_spreading_factor().unwrap(), 12);
+        lora.set_spreading_factor(5).unwrap();
+        assert_eq!(lora.get_spreading_factor().unwrap(), 6);
+    }
+
+    #[test]
+    fn set_signal_bandwidth() {
+        let (chip, mut lora) = radio();
+        lora.set_coding_rate_4(8).unwrap();
+        lora.set_spreading_factor(12).unwrap();
+        assert_eq!(chip.borrow().register(Register::RegModemConfig3), 0x0c);
+
+        lora.set_signal_bandwidth(250_000).unwrap();
+        assert_eq!(chip.borrow().register(Register::RegModemConfig1), 0x88);
+        assert_eq!(lora.get_signal_bandwidth().unwrap(), 250_000);
+        // Symbols of 16.384 ms need it as well
+        assert_eq!(chip.borrow().register(Register::RegModemConfig3), 0x0c);
+
+        lora.set_signal_bandwidth(125_000).unwrap();
+        assert_eq!(chip.borrow().register(Register::RegModemConfig1), 0x78);
+        assert_eq!(lora.get_signal_bandwidth().unwrap(), 125_000);
+        assert_eq!(chip.borrow().register(Register::RegModemConfig3), 0x0c);
+
+        // Symbols of 16.384 ms as well, like those of LoRaWAN DR1
+        lora.set_spreading_factor(11).unwrap();
+        assert_eq!(chip.borrow().register(Register::RegModemConfig3), 0x0c);
+        // Symbols of 8.192 ms do not
+        lora.set_spreading_factor(10).unwrap();
+        assert_eq!(chip.borrow().register(Register::RegModemConfig3), 0x04);
+
+        lora.set_signal_bandwidth(1).unwrap();
+        assert_eq!(lora.get_signal_bandwidth().unwrap(), 500_000);
+    }
+
+    #[test]
+    fn set_tx_power() {
+        let (chip, mut lora) = radio();
+        let registers = |chip: &Handle| {
+            let chip = chip.borrow();
+            (
+                chip.register(Register::RegPaConfig),
+                chip.register(Register::RegPaDac),
+                chip.register(Register::RegOcp),
+            )
+        };
+
+        lora.set_tx_power(17, 1).unwrap();
+        assert_eq!(registers(&chip), (0x8f, 0x84, 0x2b));
+        lora.set_tx_power(20, 1).unwrap();
+        assert_eq!(registers(&chip), (0x8f, 0x87, 0x31));
+        lora.set_tx_power(0, 1).unwrap();
+        assert_eq!(registers(&chip), (0x80, 0x84, 0x2b));
+        lora.set_tx_power(14, 0).unwrap();
+        assert_eq!(registers(&chip).0, 0x7e);
+    }
+
+    #[test]
+    fn frequency_error() {
+        let (chip, mut lora) = radio();
+        let set_error = |error: i32| {
+            let error = error as u32 & 0xf_ffff;
+            let mut chip = chip.borrow_mut();
+            chip.set_register(Register::RegFreqErrorMsb, (error >> 16) as u8);
+            chip.set_register(Register::RegFreqErrorMid, (error >> 8) as u8);
+            chip.set_register(Register::RegFreqErrorLsb, error as u8);
+        };
+        // 2^24 / 32 MHz * 125 kHz / 500 kHz Hz per step
+        set_error(1000);
+        assert_eq!(lora.get_packet_frequency_error().unwrap(), 131);
+        set_error(-1000);
+        assert_eq!(lora.get_packet_frequency_error().unwrap(), -131);
+    }
+}
@@ -0,0 +1,135 @@
+#![allow(dead_code)]
+
+#[derive(Clone, Copy)]
+pub enum Register {
+    RegFifo = 0x00,
+    RegOpMode = 0x01,
+    RegFrfMsb = 0x06,
+    RegFrfMid = 0x07,
+    RegFrfLsb = 0x08,
+    RegPaConfig = 0x09,
+    RegOcp = 0x0b,
+    RegLna = 0x0c,
+    RegFifoAddrPtr = 0x0d,
+    RegFifoTxBaseAddr = 0x0e,
+    RegFifoRxBaseAddr = 0x0f,
+    RegFifoRxCurrentAddr = 0x10,
+    RegIrqFlags = 0x12,
+    RegRxNbBytes = 0x13,
+    RegModemStat = 0x18,
+    RegPktSnrValue = 0x19,
+    RegPktRssiValue = 0x1a,
+    RegModemConfig1 = 0x1d,
+    RegModemConfig2 = 0x1e,
+    RegPreambleMsb = 0x20,
+    RegPreambleLsb = 0x21,
+    RegPayloadLength = 0x22,
+    RegModemConfig3 = 0x26,
+    RegFreqErrorMsb = 0x28,
+    RegFreqErrorMid = 0x29,
+    RegFreqErrorLsb = 0x2a,
+    RegRssiWideband = 0x2c,
+    RegDetectionOptimize = 0x31,
+    RegInvertiq = 0x33,
+    RegDetectionThreshold = 0x37,
+    RegSyncWord = 0x39,
+    RegInvertiq2 = 0x3b,
+    RegDioMapping1 = 0x40,
+    RegVersion = 0x42,
+    RegPaDac = 0x4d,
+}
+/// Registers of the FSK/OOK modem. The registers from 0x0d to 0x3f are on a separate page from the
+/// LoRa registers with the same addresses, and are the ones accessed while the radio is in FSK mode.
+#[derive(Clone, Copy)]
+pub enum FskRegister {
+    RegBitrateMsb = 0x02,
+    RegBitrateLsb = 0x03,
+    RegFdevMsb = 0x04,
+    RegFdevLsb = 0x05,
+    RegPaRamp = 0x0a,
+    RegRxConfig = 0x0d,
+    RegRssiValue = 0x11,
+    RegRxBw = 0x12,
+    RegAfcBw = 0x13,
+    RegPreambleDetect = 0x1f,
+    RegPreambleMsb = 0x25,
+    RegPreambleLsb = 0x26,
+    RegSyncConfig = 0x27,
+    RegSyncValue1 = 0x28,
+    RegPacketConfig1 = 0x30,
+    RegPacketConfig2 = 0x31,
+    RegPayloadLength = 0x32,
+    RegFifoThresh = 0x35,
+    RegIrqFlags1 = 0x3e,
+    RegIrqFlags2 = 0x3f,
+}
+
+#[derive(Clone, Copy)]
+pub enum PaConfig{
+    PaBoost = 0x80,
+    PaOutputRfoPin = 0,
+}
+
+#[derive(Clone, Copy)]
+pub enum IRQ{
+    IrqCadDetectedMask = 0x01,
+    IrqCadDoneMask = 0x04,
+    IrqTxDoneMask = 0x08,
+    IrqValidHeaderMask = 0x10,
+    IrqPayloadCrcErrorMask = 0x20,
+    IrqRxDoneMask = 0x40,
+}
+
+/// Flags in RegIrqFlags1 of the FSK modem.
+#[derive(Clone, Copy)]
+pub enum FskIrq1 {
+    SyncAddressMatch = 0x01,
+    PreambleDetect = 0x02,
+    ModeReady = 0x80,
+}
+
+/// Flags in RegIrqFlags2 of the FSK modem.
+#[derive(Clone, Copy)]
+pub enum FskIrq2 {
+    CrcOk = 0x02,
+    PayloadReady = 0x04,
+    PacketSent = 0x08,
+    FifoOverrun = 0x10,
+    FifoEmpty = 0x40,
+}
+
+impl Register {
+    pub fn addr(self) -> u8 {
+        self as u8
+    }
+}
+
+impl FskRegister {
+    pub fn addr(self) -> u8 {
+        self as u8
+    }
+}
+
+impl FskIrq1 {
+    pub fn addr(self) -> u8 {
+        self as u8
+    }
+}
+
+impl FskIrq2 {
+    pub fn addr(self) -> u8 {
+        self as u8
+    }
+}
+
+impl PaConfig {
+    pub fn addr(self) -> u8 {
+        self as u8
+    }
+}
+
+impl IRQ {
+    pub fn addr(self) -> u8 {
+        self as u8
+    }
+}
\ No newline at end of file
@@ -0,0 +1,492 @@
+//! Simulated SX1276 for the tests, connected to the driver through the same embedded-hal traits
+//! as the real radio.
+//!
+//! The simulation works on the level of the register file: SPI transfers read and write registers
+//! as described in section 4.3 of the datasheet, with the FIFO, the IRQ flags and the operating
+//! modes modelled closely enough to run the driver against it. Time only passes when the driver
+//! calls the delay, so transmissions take [`Chip::tx_duration_ms`] of delays, and packets queued
+//! with [`Chip::queue_packet`] arrive once the radio is receiving and the clock has reached them.
+//! Channel activity detection finds a preamble until [`Chip::busy_until_ms`].
+//!
+//! In FSK mode, the registers from 0x0d on are on a separate page, and the FIFO is a queue of 64
+//! bytes which the packet engine fills and empties. Packets with a CRC error are dropped by the
+//! packet engine, like with CRC auto-clear on the real radio.
+
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::convert::Infallible;
+use std::rc::Rc;
+
+use embedded_hal_0_2::blocking::delay::DelayMs;
+use embedded_hal_0_2::blocking::spi::{Transfer, Write};
+use embedded_hal_0_2::digital::v2::OutputPin;
+
+use crate::register::{FskIrq1, FskIrq2, FskRegister, Register, IRQ};
+use crate::RadioMode;
+
+const MODE_MASK: u8 = 0x07;
+
+/// First register of the FSK page.
+const FSK_PAGE_START: u8 = 0x0d;
+const FSK_FIFO_SIZE: usize = 64;
+
+/// A packet which arrives over the air.
+pub struct Incoming {
+    pub at_ms: u64,
+    pub payload: Vec<u8>,
+    pub crc_error: bool,
+    /// Value of RegPktSnrValue, the SNR in quarters of a dB.
+    pub snr: i8,
+    /// Value of RegPktRssiValue, or of RegRssiValue in FSK mode.
+    pub rssi: u8,
+}
+
+pub struct Chip {
+    pub registers: [u8; 0x80],
+    /// Registers of the FSK modem, of which only those on the FSK page are used.
+    pub fsk_registers: [u8; 0x80],
+    pub fifo: [u8; 256],
+    pub fsk_fifo: VecDeque<u8>,
+    /// Returned by RegVersion, which is not affected by a reset. 0x12 for the SX1276.
+    pub version: u8,
+    pub now_ms: u64,
+    pub tx_duration_ms: u64,
+    pub cad_duration_ms: u64,
+    /// Another radio transmits a preamble on the channel until then.
+    pub busy_until_ms: u64,
+    /// Payloads of all completed transmissions.
+    pub transmitted: Vec<Vec<u8>>,
+    pub resets: u32,
+    pub cs_low: bool,
+    in_reset: bool,
+    tx_end_ms: u64,
+    cad_end_ms: u64,
+    /// Address in the FIFO where the next received packet is written.
+    rx_addr: u8,
+    /// Flags of RegIrqFlags2 which are not derived from the FIFO.
+    fsk_flags: u8,
+    incoming: VecDeque<Incoming>,
+}
+
+impl Chip {
+    fn new() -> Self {
+        let mut chip = Self {
+            registers: [0; 0x80],
+            fsk_registers: [0; 0x80],
+            fifo: [0; 256],
+            fsk_fifo: VecDeque::new(),
+            version: 0x12,
+            now_ms: 0,
+            tx_duration_ms: 10,
+            cad_duration_ms: 2,
+            busy_until_ms: 0,
+            transmitted: Vec::new(),
+            resets: 0,
+            cs_low: false,
+            in_reset: false,
+            tx_end_ms: 0,
+            cad_end_ms: 0,
+            rx_addr: 0,
+            fsk_flags: 0,
+            incoming: VecDeque::new(),
+        };
+        chip.reset();
+        chip
+    }
+
+    /// Register values after a reset, see table 41 of the datasheet.
+    fn reset(&mut self) {
+        self.registers = [0; 0x80];
+        for (register, value) in [
+            (Register::RegOpMode, 0x09),
+            (Register::RegFrfMsb, 0x6c),
+            (Register::RegFrfMid, 0x80),
+            (Register::RegPaConfig, 0x4f),
+            (Register::RegOcp, 0x2b),
+            (Register::RegLna, 0x20),
+            (Register::RegFifoTxBaseAddr, 0x80),
+            (Register::RegModemConfig1, 0x72),
+            (Register::RegModemConfig2, 0x70),
+            (Register::RegPreambleLsb, 0x08),
+            (Register::RegPayloadLength, 0x01),
+            (Register::RegDetectionOptimize, 0xc3),
+            (Register::RegInvertiq, 0x27),
+            (Register::RegDetectionThreshold, 0x0a),
+            (Register::RegSyncWord, 0x12),
+            (Register::RegInvertiq2, 0x1d),
+            (Register::RegPaDac, 0x84),
+        ] {
+            self.registers[register.addr() as usize] = value;
+        }
+        self.registers[FskRegister::RegPaRamp.addr() as usize] = 0x09;
+        self.fsk_registers = [0; 0x80];
+        for (register, value) in [
+            (FskRegister::RegPreambleLsb, 0x03),
+            (FskRegister::RegSyncConfig, 0x93),
+            (FskRegister::RegPacketConfig1, 0x90),
+            (FskRegister::RegPacketConfig2, 0x40),
+            (FskRegister::RegPayloadLength, 0x40),
+        ] {
+            self.fsk_registers[register.addr() as usize] = value;
+        }
+        self.fifo = [0; 256];
+        self.fsk_fifo.clear();
+        self.fsk_flags = 0;
+    }
+
+    pub fn register(&self, register: Register) -> u8 {
+        self.registers[register.addr() as usize]
+    }
+
+    pub fn set_register(&mut self, register: Register, value: u8) {
+        self.registers[register.addr() as usize] = value;
+    }
+
+    /// Value of a register of the FSK modem, from the FSK page if it is on it.
+    pub fn fsk_register(&self, register: FskRegister) -> u8 {
+        if register.addr() >= FSK_PAGE_START {
+            self.fsk_registers[register.addr() as usize]
+        } else {
+            self.registers[register.addr() as usize]
+        }
+    }
+
+    pub fn mode(&self) -> u8 {
+        self.register(Register::RegOpMode) & MODE_MASK
+    }
+
+    pub fn long_range_mode(&self) -> bool {
+        self.register(Register::RegOpMode) & RadioMode::LongRangeMode.addr() != 0
+    }
+
+    pub fn irq_flags(&self) -> u8 {
+        self.register(Register::RegIrqFlags)
+    }
+
+    /// Lets a packet arrive at `at_ms`, or as soon as the radio is receiving after that.
+    pub fn queue_packet(&mut self, at_ms: u64, payload: &[u8]) {
+        self.queue(Incoming {
+            at_ms,
+            payload: payload.to_vec(),
+            crc_error: false,
+            snr: 0,
+            rssi: 0,
+        });
+    }
+
+    pub fn queue(&mut self, incoming: Incoming) {
+        // Kept in the order of arrival
+        let index = self
+            .incoming
+            .partition_point(|packet| packet.at_ms <= incoming.at_ms);
+        self.incoming.insert(index, incoming);
+    }
+
+    fn receiving(&self) -> bool {
+        if !self.long_range_mode() {
+            // The FSK modem only has one receive mode
+            return self.mode() == RadioMode::RxContinuous.addr();
+        }
+        self.mode() == RadioMode::RxContinuous.addr() || self.mode() == RadioMode::RxSingle.addr()
+    }
+
+    /// Finishes transmissions and channel activity detection, and receives packets which are due.
+    fn update(&mut self) {
+        if self.mode() == RadioMode::Cad.addr() && self.now_ms >= self.cad_end_ms {
+            let mut flags = IRQ::IrqCadDoneMask.addr();
+            if self.now_ms < self.busy_until_ms {
+                flags |= IRQ::IrqCadDetectedMask.addr();
+            }
+            self.registers[Register::RegIrqFlags.addr() as usize] |= flags;
+            self.set_mode(RadioMode::Stdby.addr());
+        }
+
+        if !self.long_range_mode() {
+            self.update_fsk();
+            return;
+        }
+
+        if self.mode() == RadioMode::Tx.addr() && self.now_ms >= self.tx_end_ms {
+            let base = self.register(Register::RegFifoTxBaseAddr);
+            let len = self.register(Register::RegPayloadLength);
+            let payload = (0..len)
+                .map(|i| self.fifo[base.wrapping_add(i) as usize])
+                .collect();
+            self.transmitted.push(payload);
+            self.registers[Register::RegIrqFlags.addr() as usize] |= IRQ::IrqTxDoneMask.addr();
+            self.set_mode(RadioMode::Stdby.addr());
+        }
+
+        while self.receiving()
+            && self
+                .incoming
+                .front()
+                .is_some_and(|packet| packet.at_ms <= self.now_ms)
+        {
+            let packet = self.incoming.pop_front().unwrap();
+            for (i, byte) in packet.payload.iter().enumerate() {
+                self.fifo[self.rx_addr.wrapping_add(i as u8) as usize] = *byte;
+            }
+            self.set_register(Register::RegFifoRxCurrentAddr, self.rx_addr);
+            self.set_register(Register::RegRxNbBytes, packet.payload.len() as u8);
+            self.set_register(Register::RegPktSnrValue, packet.snr as u8);
+            self.set_register(Register::RegPktRssiValue, packet.rssi);
+            self.rx_addr = self.rx_addr.wrapping_add(packet.payload.len() as u8);
+            let mut flags = IRQ::IrqValidHeaderMask.addr() | IRQ::IrqRxDoneMask.addr();
+            if packet.crc_error {
+                flags |= IRQ::IrqPayloadCrcErrorMask.addr();
+            }
+            self.registers[Register::RegIrqFlags.addr() as usize] |= flags;
+            if self.mode() == RadioMode::RxSingle.addr() {
+                self.set_mode(RadioMode::Stdby.addr());
+            }
+        }
+    }
+
+    /// The FSK modem stays in Tx mode after sending the packet in the FIFO, and only receives a
+    /// packet once the last one has been read from the FIFO.
+    fn update_fsk(&mut self) {
+        if self.mode() == RadioMode::Tx.addr() && self.now_ms >= self.tx_end_ms {
+            let len = self.fsk_fifo.pop_front().unwrap_or(0) as usize;
+            let payload = self
+                .fsk_fifo
+                .drain(..len.min(self.fsk_fifo.len()))
+                .collect();
+            self.transmitted.push(payload);
+            self.fsk_flags |= FskIrq2::PacketSent.addr();
+            self.tx_end_ms = u64::MAX;
+        }
+
+        while self.receiving()
+            && self.fsk_fifo.is_empty()
+            && self
+                .incoming
+                .front()
+                .is_some_and(|packet| packet.at_ms <= self.now_ms)
+        {
+            let packet = self.incoming.pop_front().unwrap();
+            self.fsk_registers[FskRegister::RegRssiValue.addr() as usize] = packet.rssi;
+            if packet.crc_error {
+                continue;
+            }
+            self.fsk_fifo.push_back(packet.payload.len() as u8);
+            self.fsk_fifo.extend(&packet.payload);
+            self.fsk_registers[FskRegister::RegIrqFlags1.addr() as usize] |=
+                FskIrq1::PreambleDetect.addr() | FskIrq1::SyncAddressMatch.addr();
+            self.fsk_flags |= FskIrq2::PayloadReady.addr() | FskIrq2::CrcOk.addr();
+        }
+    }
+
+    fn fsk_irq_flags_2(&self) -> u8 {
+        let mut flags = self.fsk_flags;
+        if self.fsk_fifo.is_empty() {
+            flags |= FskIrq2::FifoEmpty.addr();
+        }
+        flags
+    }
+
+    fn set_mode(&mut self, mode: u8) {
+        let op_mode = &mut self.registers[Register::RegOpMode.addr() as usize];
+        *op_mode = (*op_mode & !MODE_MASK) | mode;
+    }
+
+    fn write_op_mode(&mut self, value: u8) {
+        let long_range = RadioMode::LongRangeMode.addr();
+        let mut value = value;
+        // LongRangeMode can only be changed on the way into or in sleep mode
+        if self.mode() != RadioMode::Sleep.addr() && value & MODE_MASK != RadioMode::Sleep.addr() {
+            value = (value & !long_range) | (self.register(Register::RegOpMode) & long_range);
+        }
+        let was_receiving = self.receiving();
+        if self.mode() == RadioMode::Tx.addr() && value & MODE_MASK != RadioMode::Tx.addr() {
+            self.fsk_flags &= !FskIrq2::PacketSent.addr();
+        }
+        self.set_register(Register::RegOpMode, value);
+        if self.mode() == RadioMode::Tx.addr() {
+            self.tx_end_ms = self.now_ms + self.tx_duration_ms;
+        }
+        if self.mode() == RadioMode::Cad.addr() {
+            self.cad_end_ms = self.now_ms + self.cad_duration_ms;
+        }
+        if self.receiving() && !was_receiving {
+            self.rx_addr = self.register(Register::RegFifoRxBaseAddr);
+        }
+    }
+
+    fn read(&mut self, address: u8) -> u8 {
+        if !self.long_range_mode() {
+            return self.read_fsk(address);
+        }
+        if address == Register::RegFifo.addr() {
+            let pointer = self.register(Register::RegFifoAddrPtr);
+            self.set_register(Register::RegFifoAddrPtr, pointer.wrapping_add(1));
+            self.fifo[pointer as usize]
+        } else if address == Register::RegVersion.addr() {
+            self.version
+        } else {
+            self.registers[address as usize]
+        }
+    }
+
+    fn read_fsk(&mut self, address: u8) -> u8 {
+        if address == Register::RegFifo.addr() {
+            let byte = self.fsk_fifo.pop_front().unwrap_or(0);
+            if self.fsk_fifo.is_empty() {
+                self.fsk_flags &= !(FskIrq2::PayloadReady.addr() | FskIrq2::CrcOk.addr());
+                self.fsk_registers[FskRegister::RegIrqFlags1.addr() as usize] &=
+                    !(FskIrq1::PreambleDetect.addr() | FskIrq1::SyncAddressMatch.addr());
+            }
+            byte
+        } else if address == FskRegister::RegIrqFlags2.addr() {
+            self.fsk_irq_flags_2()
+        } else if address == Register::RegVersion.addr() {
+            self.version
+        } else if address >= FSK_PAGE_START {
+            self.fsk_registers[address as usize]
+        } else {
+            self.registers[address as usize]
+        }
+    }
+
+    fn write_fsk(&mut self, address: u8, value: u8) {
+        if address == Register::RegFifo.addr() {
+            if self.fsk_fifo.len() < FSK_FIFO_SIZE {
+                self.fsk_fifo.push_back(value);
+            }
+        } else if address == FskRegister::RegIrqFlags1.addr() {
+            // Only the preamble and sync address flags can be cleared
+            let clearable = FskIrq1::PreambleDetect.addr() | FskIrq1::SyncAddressMatch.addr();
+            self.fsk_registers[address as usize] &= !(value & clearable);
+        } else if address == FskRegister::RegIrqFlags2.addr() {
+            // Clearing the overrun flag empties the FIFO
+            if value & FskIrq2::FifoOverrun.addr() != 0 {
+                self.fsk_fifo.clear();
+                self.fsk_flags &= !(FskIrq2::PayloadReady.addr() | FskIrq2::CrcOk.addr());
+            }
+        } else if address == Register::RegOpMode.addr() {
+            self.write_op_mode(value);
+        } else if address >= FSK_PAGE_START {
+            self.fsk_registers[address as usize] = value;
+        } else if address != Register::RegVersion.addr() {
+            self.registers[address as usize] = value;
+        }
+    }
+
+    fn write(&mut self, address: u8, value: u8) {
+        if !self.long_range_mode() {
+            return self.write_fsk(address, value);
+        }
+        if address == Register::RegFifo.addr() {
+            let pointer = self.register(Register::RegFifoAddrPtr);
+            self.set_register(Register::RegFifoAddrPtr, pointer.wrapping_add(1));
+            self.fifo[pointer as usize] = value;
+        } else if address == Register::RegIrqFlags.addr() {
+            // Flags are cleared by writing a 1 to them
+            self.registers[address as usize] &= !value;
+        } else if address == Register::RegOpMode.addr() {
+            self.write_op_mode(value);
+        } else if address != Register::RegVersion.addr() {
+            self.registers[address as usize] = value;
+        }
+    }
+
+    /// One SPI access with the address in the first byte, see section 4.3 of the datasheet.
+    /// Further bytes access the following registers, except for the FIFO.
+    fn access(&mut self, words: &mut [u8]) {
+        assert!(self.cs_low, "SPI access while CS is high");
+        assert!(!self.in_reset, "SPI access during reset");
+        self.update();
+        let write = words[0] & 0x80 != 0;
+        let mut address = words[0] & 0x7f;
+        for word in &mut words[1..] {
+            if write {
+                self.write(address, *word);
+            } else {
+                *word = self.read(address);
+            }
+            if address != Register::RegFifo.addr() {
+                address += 1;
+            }
+        }
+        self.update();
+    }
+}
+
+/// Shared handle to the simulated radio, which the tests keep to look at its state.
+pub type Handle = Rc<RefCell<Chip>>;
+
+pub struct Spi(Handle);
+pub struct Cs(Handle);
+pub struct Reset(Handle);
+pub struct Delay(Handle);
+
+/// Creates a simulated radio and the peripherals the driver needs to talk to it.
+pub fn new() -> (Handle, Spi, Cs, Reset, Delay) {
+    let chip = Rc::new(RefCell::new(Chip::new()));
+    (
+        Rc::clone(&chip),
+        Spi(Rc::clone(&chip)),
+        Cs(Rc::clone(&chip)),
+        Reset(Rc::clone(&chip)),
+        Delay(chip),
+    )
+}
+
+impl Transfer<u8> for Spi {
+    type Error = Infallible;
+
+    fn transfer<'w>(&mut self, words: &'w mut [u8]) -> Result<&'w [u8], Infallible> {
+        self.0.borrow_mut().access(words);
+        Ok(words)
+    }
+}
+
+impl Write<u8> for Spi {
+    type Error = Infallible;
+
+    fn write(&mut self, words: &[u8]) -> Result<(), Infallible> {
+        self.0.borrow_mut().access(&mut words.to_vec());
+        Ok(())
+    }
+}
+
+impl OutputPin for Cs {
+    type Error = Infallible;
+
+    fn set_low(&mut self) -> Result<(), Infallible> {
+        self.0.borrow_mut().cs_low = true;
+        Ok(())
+    }
+
+    fn set_high(&mut self) -> Result<(), Infallible> {
+        self.0.borrow_mut().cs_low = false;
+        Ok(())
+    }
+}
+
+impl OutputPin for Reset {
+    type Error = Infallible;
+
+    fn set_low(&mut self) -> Result<(), Infallible> {
+        self.0.borrow_mut().in_reset = true;
+        Ok(())
+    }
+
+    fn set_high(&mut self) -> Result<(), Infallible> {
+        let mut chip = self.0.borrow_mut();
+        if chip.in_reset {
+            chip.in_reset = false;
+            chip.resets += 1;
+            chip.reset();
+        }
+        Ok(())
+    }
+}
+
+impl DelayMs<u8> for Delay {
+    fn delay_ms(&mut self, ms: u8) {
+        let mut chip = self.0.borrow_mut();
+        chip.now_ms += ms as u64;
+        chip.update();
+    }
+}